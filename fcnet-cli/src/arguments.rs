@@ -1,8 +1,11 @@
 use std::{net::IpAddr, str::FromStr};
 
-use cidr::IpInet;
+use cidr::{IpCidr, IpInet};
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use fcnet_types::FirecrackerIpStack;
+use fcnet_types::{
+    FirecrackerEgressAction, FirecrackerIpStack, FirecrackerMacAddr, FirecrackerNfLayout, FirecrackerPortForward,
+    FirecrackerPortProtocol, FirecrackerPortRange,
+};
 
 #[derive(Parser)]
 #[command(
@@ -12,10 +15,34 @@ use fcnet_types::FirecrackerIpStack;
     propagate_version = true
 )]
 pub struct Cli {
+    #[arg(help = "Optional explicit identifier of the network used to tag its objects", long = "id")]
+    pub id: Option<String>,
     #[arg(help = "Optional explicit path to the \"nft\" binary", long = "nft-path")]
     pub nft_path: Option<String>,
     #[arg(help = "Which IP stack to use", long = "ip-stack", default_value_t)]
     pub ip_stack: IpStackWrapper,
+    #[arg(
+        help = "Which layout to use for nftables rules in the default netns",
+        long = "nf-layout",
+        default_value_t
+    )]
+    pub nf_layout: NfLayoutWrapper,
+    #[arg(
+        help = "Name of the nftables table to place rules into",
+        long = "nf-table",
+        default_value = "fcnet"
+    )]
+    pub nf_table: String,
+    #[arg(
+        help = "Use iptables and ip6tables instead of nftables for rules in the default netns",
+        long = "iptables"
+    )]
+    pub iptables: bool,
+    #[arg(
+        help = "Ensure that IP forwarding is enabled and, for namespaced networks, that the loopback link of the netns is up",
+        long = "manage-sysctls"
+    )]
+    pub manage_sysctls: bool,
     #[arg(help = "The CIDR IP of the guest", long = "guest-ip", default_value_t = IpInet::from_str("172.16.0.2/24").unwrap())]
     pub guest_ip: IpInet,
     #[arg(
@@ -26,14 +53,218 @@ pub struct Cli {
     pub iface_name: String,
     #[arg(help = "Name of the tap device to create", long = "tap", default_value = "tap0")]
     pub tap_name: String,
+    #[arg(help = "UID of the user allowed to open the tap device", long = "tap-owner")]
+    pub tap_owner: Option<u32>,
+    #[arg(help = "GID of the group allowed to open the tap device", long = "tap-group")]
+    pub tap_group: Option<u32>,
+    #[arg(help = "Create the tap device with multiple queues", long = "tap-multi-queue")]
+    pub tap_multi_queue: bool,
+    #[arg(help = "Enable virtio-net headers on the tap device", long = "tap-vnet-hdr")]
+    pub tap_vnet_hdr: bool,
+    #[arg(
+        help = "An offload feature of the tap device to toggle, such as \"rx-gro=off\" (can be repeated)",
+        long = "tap-offload",
+        value_parser = parse_offload
+    )]
+    pub tap_offloads: Vec<(String, bool)>,
+    #[arg(help = "MTU of the tap device", long = "tap-mtu")]
+    pub tap_mtu: Option<u32>,
+    #[arg(help = "Transmit queue length of the tap device", long = "tap-txqueuelen")]
+    pub tap_txqueuelen: Option<u32>,
     #[arg(help = "The CIDR IP of the tap device to create", long = "tap-ip", default_value_t = IpInet::from_str("172.16.0.1/24").unwrap())]
     pub tap_ip: IpInet,
+    #[arg(
+        help = "A port of the guest to expose on the host interface, such as \"8080:80/tcp\", \"127.0.0.1:53/udp\" or \"9000-9010/tcp\" (can be repeated)",
+        long = "port-forward",
+        value_parser = parse_port_forward
+    )]
+    pub port_forwards: Vec<FirecrackerPortForward>,
+    #[arg(
+        help = "What to do with egress traffic of the guest that no egress policy option matches",
+        long = "egress-default"
+    )]
+    pub egress_default: Option<EgressActionWrapper>,
+    #[arg(
+        help = "A CIDR that the guest may reach despite a denying default egress action (can be repeated)",
+        long = "egress-allow-cidr"
+    )]
+    pub egress_allowed_cidrs: Vec<IpCidr>,
+    #[arg(help = "A CIDR that the guest may not reach (can be repeated)", long = "egress-deny-cidr")]
+    pub egress_denied_cidrs: Vec<IpCidr>,
+    #[arg(
+        help = "A destination port that the guest may reach despite a denying default egress action, such as \"53/udp\" or \"8000-8010/tcp\" (can be repeated)",
+        long = "egress-allow-port",
+        value_parser = parse_port_range
+    )]
+    pub egress_allowed_ports: Vec<FirecrackerPortRange>,
+    #[arg(
+        help = "A destination port that the guest may not reach, such as \"25/tcp\" (can be repeated)",
+        long = "egress-deny-port",
+        value_parser = parse_port_range
+    )]
+    pub egress_denied_ports: Vec<FirecrackerPortRange>,
+    #[arg(
+        help = "Deny egress traffic of the guest towards link-local addresses, such as the metadata services of clouds",
+        long = "egress-block-link-local"
+    )]
+    pub egress_block_link_local: bool,
+    #[arg(
+        help = "Deny egress traffic of the guest towards private addresses",
+        long = "egress-block-private"
+    )]
+    pub egress_block_private: bool,
+    #[arg(
+        help = "A CIDR that new connections towards the guest are accepted from (can be repeated)",
+        long = "ingress-expose-cidr"
+    )]
+    pub ingress_exposed_cidrs: Vec<IpCidr>,
+    #[arg(
+        help = "A port of the guest that new connections are accepted to from any address, such as \"22/tcp\" (can be repeated)",
+        long = "ingress-expose-port",
+        value_parser = parse_port_range
+    )]
+    pub ingress_exposed_ports: Vec<FirecrackerPortRange>,
+    #[arg(
+        help = "Allow traffic to be forwarded between the guest and the guests of other networks on the host",
+        long = "no-isolation"
+    )]
+    pub no_isolation: bool,
+    #[arg(
+        help = "A group of networks whose guests can reach each other regardless of isolation (can be repeated)",
+        long = "isolation-group"
+    )]
+    pub isolation_groups: Vec<String>,
+    #[arg(
+        help = "Rate in bytes per second to shape the traffic towards the guest to",
+        long = "shaping-egress-rate",
+        requires = "shaping_egress_burst"
+    )]
+    pub shaping_egress_rate: Option<u64>,
+    #[arg(
+        help = "Burst in bytes of the traffic towards the guest, at least the MTU",
+        long = "shaping-egress-burst",
+        requires = "shaping_egress_rate"
+    )]
+    pub shaping_egress_burst: Option<u32>,
+    #[arg(
+        help = "Rate in bytes per second to shape the traffic from the guest to",
+        long = "shaping-ingress-rate",
+        requires_all = ["shaping_ingress_burst", "shaping_ifb_name"]
+    )]
+    pub shaping_ingress_rate: Option<u64>,
+    #[arg(
+        help = "Burst in bytes of the traffic from the guest, at least the MTU",
+        long = "shaping-ingress-burst",
+        requires = "shaping_ingress_rate"
+    )]
+    pub shaping_ingress_burst: Option<u32>,
+    #[arg(
+        help = "Name of the IFB device to create for shaping the traffic from the guest",
+        long = "shaping-ifb",
+        requires = "shaping_ingress_rate"
+    )]
+    pub shaping_ifb_name: Option<String>,
+    #[arg(
+        help = "MAC of the guest to drop the frames from the guest with other source MACs or IPs than its own for",
+        long = "anti-spoofing-mac"
+    )]
+    pub anti_spoofing_mac: Option<FirecrackerMacAddr>,
     #[command(flatten)]
     pub operation_group: OperationGroup,
     #[command(subcommand)]
     pub subcommands: Subcommands,
 }
 
+fn parse_offload(value: &str) -> Result<(String, bool), String> {
+    match value.rsplit_once('=') {
+        Some((feature, "on")) => Ok((feature.to_string(), true)),
+        Some((feature, "off")) => Ok((feature.to_string(), false)),
+        _ => Err("expected a feature name followed by \"=on\" or \"=off\"".to_string()),
+    }
+}
+
+fn parse_port_forward(value: &str) -> Result<FirecrackerPortForward, String> {
+    let (value, protocol) = match value.rsplit_once('/') {
+        Some((value, "tcp")) => (value, FirecrackerPortProtocol::Tcp),
+        Some((value, "udp")) => (value, FirecrackerPortProtocol::Udp),
+        _ => return Err("expected the port to be followed by \"/tcp\" or \"/udp\"".to_string()),
+    };
+
+    // an IPv6 address is enclosed in brackets, so that its colons aren't mistaken for separators
+    let (host_addr, value) = match value.strip_prefix('[') {
+        Some(value) => {
+            let (host_addr, value) = value.split_once("]:").ok_or("expected \"]:\" after an IPv6 address")?;
+            (Some(host_addr), value)
+        }
+        None => match value.split_once(':') {
+            Some((host_addr, rest)) if host_addr.parse::<IpAddr>().is_ok() => (Some(host_addr), rest),
+            _ => (None, value),
+        },
+    };
+
+    let (host_ports, guest_port) = match value.split_once(':') {
+        Some((host_ports, guest_port)) => (host_ports, Some(guest_port)),
+        None => (value, None),
+    };
+    let (host_port, host_port_end) = match host_ports.split_once('-') {
+        Some((host_port, host_port_end)) => (host_port, Some(host_port_end)),
+        None => (host_ports, None),
+    };
+
+    Ok(FirecrackerPortForward {
+        protocol,
+        host_addr: host_addr
+            .map(IpAddr::from_str)
+            .transpose()
+            .map_err(|err| format!("invalid host address: {err}"))?,
+        host_port: host_port.parse().map_err(|err| format!("invalid host port: {err}"))?,
+        host_port_end: host_port_end
+            .map(u16::from_str)
+            .transpose()
+            .map_err(|err| format!("invalid end of the host port range: {err}"))?,
+        guest_port: guest_port
+            .map(u16::from_str)
+            .transpose()
+            .map_err(|err| format!("invalid guest port: {err}"))?,
+    })
+}
+
+fn parse_port_range(value: &str) -> Result<FirecrackerPortRange, String> {
+    let (ports, protocol) = match value.rsplit_once('/') {
+        Some((ports, "tcp")) => (ports, FirecrackerPortProtocol::Tcp),
+        Some((ports, "udp")) => (ports, FirecrackerPortProtocol::Udp),
+        _ => return Err("expected the port to be followed by \"/tcp\" or \"/udp\"".to_string()),
+    };
+    let (port, port_end) = match ports.split_once('-') {
+        Some((port, port_end)) => (port, Some(port_end)),
+        None => (ports, None),
+    };
+
+    Ok(FirecrackerPortRange {
+        protocol,
+        port: port.parse().map_err(|err| format!("invalid port: {err}"))?,
+        port_end: port_end
+            .map(u16::from_str)
+            .transpose()
+            .map_err(|err| format!("invalid end of the port range: {err}"))?,
+    })
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum EgressActionWrapper {
+    Allow,
+    Deny,
+}
+
+impl From<EgressActionWrapper> for FirecrackerEgressAction {
+    fn from(value: EgressActionWrapper) -> Self {
+        match value {
+            EgressActionWrapper::Allow => FirecrackerEgressAction::Allow,
+            EgressActionWrapper::Deny => FirecrackerEgressAction::Deny,
+        }
+    }
+}
+
 #[derive(ValueEnum, Clone, Copy, Default)]
 pub enum IpStackWrapper {
     #[default]
@@ -42,14 +273,13 @@ pub enum IpStackWrapper {
     Dual,
 }
 
-impl ToString for IpStackWrapper {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for IpStackWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             IpStackWrapper::V4 => "v4",
             IpStackWrapper::V6 => "v6",
             IpStackWrapper::Dual => "dual",
-        }
-        .to_string()
+        })
     }
 }
 
@@ -63,6 +293,31 @@ impl From<IpStackWrapper> for FirecrackerIpStack {
     }
 }
 
+#[derive(ValueEnum, Clone, Copy, Default)]
+pub enum NfLayoutWrapper {
+    #[default]
+    Flat,
+    VerdictMap,
+}
+
+impl std::fmt::Display for NfLayoutWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NfLayoutWrapper::Flat => "flat",
+            NfLayoutWrapper::VerdictMap => "verdict-map",
+        })
+    }
+}
+
+impl From<NfLayoutWrapper> for FirecrackerNfLayout {
+    fn from(value: NfLayoutWrapper) -> Self {
+        match value {
+            NfLayoutWrapper::Flat => FirecrackerNfLayout::Flat,
+            NfLayoutWrapper::VerdictMap => FirecrackerNfLayout::VerdictMap,
+        }
+    }
+}
+
 #[derive(Args)]
 #[group(required = true, multiple = false)]
 pub struct OperationGroup {
@@ -72,6 +327,12 @@ pub struct OperationGroup {
     pub delete: bool,
     #[arg(short = 'C', long = "check", help = "Check the given network")]
     pub check: bool,
+    #[arg(
+        short = 'U',
+        long = "update-egress-policy",
+        help = "Replace the addresses and ports of the egress policy of the given network"
+    )]
+    pub update_egress_policy: bool,
 }
 
 #[derive(Subcommand, Clone)]
@@ -103,5 +364,22 @@ pub enum Subcommands {
             long = "forwarded-guest-ip"
         )]
         forwarded_guest_ip: Option<IpAddr>,
+        #[arg(
+            help = "Optionally, the directory to persist the network namespace in instead of /var/run/netns",
+            long = "netns-dir"
+        )]
+        netns_dir: Option<String>,
+        #[arg(
+            help = "The UID that the Firecracker jailer runs the VMM as, which the tap device is owned by",
+            long = "jailer-uid",
+            requires = "jailer_gid"
+        )]
+        jailer_uid: Option<u32>,
+        #[arg(
+            help = "The GID that the Firecracker jailer runs the VMM as, which the tap device is owned by",
+            long = "jailer-gid",
+            requires = "jailer_uid"
+        )]
+        jailer_gid: Option<u32>,
     },
 }
@@ -1,7 +1,11 @@
 use arguments::{Cli, Subcommands};
 use clap::Parser;
-use fcnet::backend::TokioBackend;
-use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerNetworkType};
+use fcnet::backend::{IptablesBackend, TokioBackend};
+use fcnet_types::{
+    FirecrackerAntiSpoofing, FirecrackerEgressPolicy, FirecrackerIngressPolicy, FirecrackerIngressShaping, FirecrackerIsolation,
+    FirecrackerJailer, FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerNetworkType, FirecrackerNfTable,
+    FirecrackerShaping, FirecrackerSysctls, FirecrackerTapOptions, FirecrackerTokenBucket,
+};
 
 mod arguments;
 
@@ -17,6 +21,9 @@ fn main() {
             veth1_ip,
             veth2_ip,
             forwarded_guest_ip,
+            netns_dir,
+            jailer_uid,
+            jailer_gid,
         } => FirecrackerNetworkType::Namespaced {
             netns_name,
             veth1_name,
@@ -24,26 +31,92 @@ fn main() {
             veth1_ip,
             veth2_ip,
             forwarded_guest_ip,
+            netns_dir,
+            jailer: jailer_uid.zip(jailer_gid).map(|(uid, gid)| FirecrackerJailer { uid, gid }),
         },
     };
 
+    let egress_policy = FirecrackerEgressPolicy {
+        default_action: cli.egress_default.map(Into::into).unwrap_or_default(),
+        allowed_cidrs: cli.egress_allowed_cidrs,
+        denied_cidrs: cli.egress_denied_cidrs,
+        allowed_ports: cli.egress_allowed_ports,
+        denied_ports: cli.egress_denied_ports,
+        block_link_local: cli.egress_block_link_local,
+        block_private: cli.egress_block_private,
+    };
+
     let network = FirecrackerNetwork {
+        id: cli.id,
         nft_path: cli.nft_path,
         ip_stack: cli.ip_stack.into(),
+        nf_layout: cli.nf_layout.into(),
+        nf_table: FirecrackerNfTable {
+            name: cli.nf_table,
+            ..Default::default()
+        },
+        sysctls: FirecrackerSysctls {
+            managed: cli.manage_sysctls,
+            ..Default::default()
+        },
+        port_forwards: cli.port_forwards,
+        // a policy is only set up when any of its options is given
+        egress_policy: (egress_policy != FirecrackerEgressPolicy::default() || cli.egress_default.is_some())
+            .then_some(egress_policy),
+        ingress_policy: FirecrackerIngressPolicy {
+            exposed_cidrs: cli.ingress_exposed_cidrs,
+            exposed_ports: cli.ingress_exposed_ports,
+        },
+        isolation: FirecrackerIsolation {
+            enabled: !cli.no_isolation,
+            groups: cli.isolation_groups,
+        },
+        shaping: FirecrackerShaping {
+            egress: cli
+                .shaping_egress_rate
+                .zip(cli.shaping_egress_burst)
+                .map(|(rate, burst)| FirecrackerTokenBucket {
+                    rate,
+                    burst,
+                    limit: None,
+                }),
+            ingress: cli
+                .shaping_ingress_rate
+                .zip(cli.shaping_ingress_burst)
+                .zip(cli.shaping_ifb_name)
+                .map(|((rate, burst), ifb_name)| FirecrackerIngressShaping {
+                    ifb_name,
+                    bucket: FirecrackerTokenBucket {
+                        rate,
+                        burst,
+                        limit: None,
+                    },
+                }),
+        },
+        anti_spoofing: cli.anti_spoofing_mac.map(|guest_mac| FirecrackerAntiSpoofing { guest_mac }),
         guest_ip: cli.guest_ip,
         iface_name: cli.iface_name,
         tap_name: cli.tap_name,
+        tap_options: FirecrackerTapOptions {
+            owner: cli.tap_owner,
+            group: cli.tap_group,
+            multi_queue: cli.tap_multi_queue,
+            vnet_hdr: cli.tap_vnet_hdr,
+            offloads: cli.tap_offloads.into_iter().collect(),
+            mtu: cli.tap_mtu,
+            txqueuelen: cli.tap_txqueuelen,
+        },
         tap_ip: cli.tap_ip,
         network_type,
     };
 
-    let future = {
+    let operation = {
         if cli.operation_group.add {
-            fcnet::run::<TokioBackend>(&network, FirecrackerNetworkOperation::Add)
+            FirecrackerNetworkOperation::Add
         } else if cli.operation_group.delete {
-            fcnet::run::<TokioBackend>(&network, FirecrackerNetworkOperation::Delete)
+            FirecrackerNetworkOperation::Delete
         } else {
-            fcnet::run::<TokioBackend>(&network, FirecrackerNetworkOperation::Check)
+            FirecrackerNetworkOperation::Check
         }
     };
 
@@ -52,7 +125,21 @@ fn main() {
         return;
     };
 
-    if let Err(err) = runtime.block_on(future) {
-        eprintln!("{err}");
+    let result = match (cli.iptables, cli.operation_group.update_egress_policy) {
+        (true, true) => runtime.block_on(fcnet::update_egress_policy::<IptablesBackend<TokioBackend>>(&network)),
+        (true, false) => runtime.block_on(fcnet::run::<IptablesBackend<TokioBackend>>(&network, operation)),
+        (false, true) => runtime.block_on(fcnet::update_egress_policy::<TokioBackend>(&network)),
+        (false, false) => runtime.block_on(fcnet::run::<TokioBackend>(&network, operation)),
+    };
+
+    match result {
+        // the path of the netns is printed so that it can be handed to the jailer via "--netns"
+        Ok(()) if operation == FirecrackerNetworkOperation::Add => {
+            if let Some(netns_path) = network.netns_path() {
+                println!("{}", netns_path.display());
+            }
+        }
+        Ok(()) => {}
+        Err(err) => eprintln!("{err}"),
     }
 }
@@ -3,24 +3,62 @@
 #[cfg(all(not(feature = "simple"), not(feature = "namespaced")))]
 compile_error!("Either \"simple\" or \"namespaced\" networking feature flags must be enabled");
 
-use std::net::IpAddr;
+use std::{collections::BTreeMap, net::IpAddr, path::PathBuf};
 
-use cidr::IpInet;
+use cidr::{IpCidr, IpInet};
 
 /// A configuration for a Firecracker microVM network.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FirecrackerNetwork {
+    /// The optional explicit identifier of this network, which must be unique on the host. It is used to tag the objects
+    /// belonging to this network, such as nftables rules. See [FirecrackerNetwork::resolved_id] for the fallback that
+    /// is used when it isn't specified.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub id: Option<String>,
     /// The optional explicit path to "nft" to use when invoking it.
     #[cfg_attr(feature = "serde", serde(default))]
     pub nft_path: Option<String>,
     /// The IP stack to use.
     #[cfg_attr(feature = "serde", serde(default))]
     pub ip_stack: FirecrackerIpStack,
+    /// The layout of the nftables rules created for this network in the host network namespace.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub nf_layout: FirecrackerNfLayout,
+    /// The nftables table and base chains that the rules of this network are placed into, both in the host network
+    /// namespace and, for namespaced networks, in the network namespace of this network.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub nf_table: FirecrackerNfTable,
+    /// The sysctls that fcnet ensures for this network, none by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub sysctls: FirecrackerSysctls,
+    /// The ports of the guest that are exposed on the host interface, none by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_forwards: Vec<FirecrackerPortForward>,
+    /// The policy that traffic from the guest towards the host interface is subject to, which is all accepted if
+    /// unspecified.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub egress_policy: Option<FirecrackerEgressPolicy>,
+    /// The policy that new connections from the host interface towards the guest are subject to, which only accepts
+    /// those to forwarded ports by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub ingress_policy: FirecrackerIngressPolicy,
+    /// How this network is isolated from the other networks on the host, which it is by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub isolation: FirecrackerIsolation,
+    /// The traffic shaping applied to the link of this network via queueing disciplines, none by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub shaping: FirecrackerShaping,
+    /// The filtering of the traffic that the guest sends into the tap device against spoofing, none by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub anti_spoofing: Option<FirecrackerAntiSpoofing>,
     /// The name of the host network interface that handles real connectivity (i.e. via Ethernet or Wi-Fi).
     pub iface_name: String,
     /// The name of the tap device to direct Firecracker to use.
     pub tap_name: String,
+    /// The options that the tap device is created with, none of which are set by default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tap_options: FirecrackerTapOptions,
     /// The IP of the tap device to direct Firecracker to use.
     pub tap_ip: IpInet,
     /// The IP of the guest.
@@ -42,6 +80,445 @@ pub enum FirecrackerIpStack {
     Dual,
 }
 
+/// The layout of the nftables rules that a network places into the shared base chains of the host network namespace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FirecrackerNfLayout {
+    /// Flat rules appended directly to the base chains. Evaluating them, as well as locating them when checking or
+    /// deleting a network, is linear in the number of networks on the host.
+    #[default]
+    Flat,
+    /// A chain per network that the base chains dispatch into via verdict maps keyed on interface names and, in
+    /// postrouting where the input interface isn't known, on source addresses. Evaluating the rules and adding or
+    /// deleting a network take constant time regardless of the number of networks on the host, which requires the
+    /// guest IPs of all networks in this layout to be distinct.
+    VerdictMap,
+}
+
+/// The nftables table that fcnet places the rules of a network into, alongside its base chains. All networks sharing
+/// a table must use the same configuration of it, while independent consumers of fcnet on the same host should each use
+/// a table of their own.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirecrackerNfTable {
+    /// The name of the table, "fcnet" by default.
+    pub name: String,
+    /// The NAT chain hooked into postrouting that masquerades egress packets, "postrouting" with a priority of 100 by
+    /// default.
+    pub postrouting_chain: FirecrackerNfBaseChain,
+    /// The NAT chain hooked into prerouting that performs DNAT in the network namespace of a namespaced network,
+    /// "prerouting" with a priority of -100 by default.
+    pub prerouting_chain: FirecrackerNfBaseChain,
+    /// The filter chain hooked into forward that accepts packets forwarded to and from networks, "filter" with a
+    /// priority of 0 by default.
+    pub filter_chain: FirecrackerNfBaseChain,
+}
+
+impl Default for FirecrackerNfTable {
+    fn default() -> Self {
+        Self {
+            name: "fcnet".to_string(),
+            postrouting_chain: FirecrackerNfBaseChain::new("postrouting", 100),
+            prerouting_chain: FirecrackerNfBaseChain::new("prerouting", -100),
+            filter_chain: FirecrackerNfBaseChain::new("filter", 0),
+        }
+    }
+}
+
+/// A base chain of a [FirecrackerNfTable].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerNfBaseChain {
+    /// The name of the chain.
+    pub name: String,
+    /// The priority of the chain within its hook, which orders it relative to the chains of other tables such as those
+    /// of firewalld or Docker.
+    pub priority: i32,
+    /// The policy applied to packets that no rule of the chain has issued a verdict for.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub policy: FirecrackerNfChainPolicy,
+}
+
+impl FirecrackerNfBaseChain {
+    /// Create a base chain with the given name and priority and the default policy of accepting packets.
+    pub fn new(name: impl Into<String>, priority: i32) -> Self {
+        Self {
+            name: name.into(),
+            priority,
+            policy: FirecrackerNfChainPolicy::default(),
+        }
+    }
+}
+
+/// The policy of a [FirecrackerNfBaseChain].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FirecrackerNfChainPolicy {
+    /// Accept packets that no rule has issued a verdict for.
+    #[default]
+    Accept,
+    /// Drop packets that no rule has issued a verdict for.
+    Drop,
+}
+
+/// The options of the tap device of a network, which are applied when adding the network and verified when checking it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirecrackerTapOptions {
+    /// The UID of the user that is allowed to open the tap device besides privileged processes, such as the
+    /// unprivileged user that a jailed Firecracker runs as.
+    pub owner: Option<u32>,
+    /// The GID of the group that is allowed to open the tap device besides privileged processes.
+    pub group: Option<u32>,
+    /// Create the tap device with multiple queues. Every process opening the tap device must request multiple queues
+    /// as well, so this must match the configuration of Firecracker.
+    pub multi_queue: bool,
+    /// Make the tap device prepend a virtio-net header to packets, which Firecracker relies on for offloads. Since
+    /// Firecracker sets this itself when opening the tap device, this mostly matters for other consumers.
+    pub vnet_hdr: bool,
+    /// The offload features of the tap device to enable or disable, keyed by their ethtool names such as
+    /// "tx-checksum-ip-generic", "tx-tcp-segmentation" or "rx-gro". Features that aren't listed keep their defaults.
+    pub offloads: BTreeMap<String, bool>,
+    /// The MTU of the tap device, which otherwise defaults to 1500.
+    pub mtu: Option<u32>,
+    /// The length of the transmit queue of the tap device, which otherwise defaults to 1000.
+    pub txqueuelen: Option<u32>,
+}
+
+/// The sysctls that fcnet ensures are set when adding a network and reports on when checking it. By default, none are
+/// managed and the host is expected to have been configured beforehand, most notably with IP forwarding enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirecrackerSysctls {
+    /// Whether to manage sysctls at all. When enabled, IP forwarding for the IP stack of the network is enabled in the
+    /// host network namespace and, for namespaced networks, in the network namespace of the network, whose loopback
+    /// link is also brought up. Note that enabling IPv6 forwarding makes interfaces stop accepting router advertisements
+    /// unless their "accept_ra" sysctl is set to 2. None of the other options take effect unless this one is enabled.
+    pub managed: bool,
+    /// Enable "route_localnet" on the tap device, so that packets from the guest that are DNATed to a loopback address of
+    /// the host, such as a metadata service listening on 127.0.0.1, are routed instead of being dropped. IPv4 only.
+    pub route_localnet: bool,
+    /// Enable "proxy_arp" on the tap device, so that the host answers ARP requests of the guest for any address it has a
+    /// route to, which is needed when the guest considers such addresses to be on-link. IPv4 only.
+    pub proxy_arp: bool,
+    /// Enable "proxy_ndp" on the tap device, the IPv6 counterpart of "proxy_arp", which only answers neighbor
+    /// solicitations for the addresses that have been added as neighbor proxies on the tap device. IPv6 only.
+    pub proxy_ndp: bool,
+}
+
+/// A port (or range of ports) on the host interface that is forwarded to the guest via DNAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerPortForward {
+    /// The transport protocol of the forwarded port.
+    pub protocol: FirecrackerPortProtocol,
+    /// The address of the host that connections must be destined to in order to be forwarded, any address if
+    /// unspecified. It must be of the same IP version as the guest.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub host_addr: Option<IpAddr>,
+    /// The port on the host, or the first port of the range of ports on the host, to forward.
+    pub host_port: u16,
+    /// The last port of the range of ports on the host to forward, if a range is forwarded.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub host_port_end: Option<u16>,
+    /// The port of the guest that connections are forwarded to. If unspecified, the port on the host is kept, so that a
+    /// range of ports is forwarded to the same range of ports of the guest.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub guest_port: Option<u16>,
+}
+
+/// The transport protocol of a [FirecrackerPortForward] or [FirecrackerPortRange].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FirecrackerPortProtocol {
+    /// TCP, translated to "tcp" in nftables.
+    Tcp,
+    /// UDP, translated to "udp" in nftables.
+    Udp,
+}
+
+impl FirecrackerPortProtocol {
+    /// The name of this protocol in nftables and iptables.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FirecrackerPortProtocol::Tcp => "tcp",
+            FirecrackerPortProtocol::Udp => "udp",
+        }
+    }
+}
+
+impl std::fmt::Display for FirecrackerPortForward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host_addr {
+            Some(IpAddr::V6(host_addr)) => write!(f, "[{host_addr}]:")?,
+            Some(host_addr) => write!(f, "{host_addr}:")?,
+            None => {}
+        }
+
+        write!(f, "{}", self.host_port)?;
+
+        if let Some(host_port_end) = self.host_port_end {
+            write!(f, "-{host_port_end}")?;
+        }
+
+        if let Some(guest_port) = self.guest_port {
+            write!(f, ":{guest_port}")?;
+        }
+
+        write!(f, "/{}", self.protocol.name())
+    }
+}
+
+/// A destination port (or range of ports) that a [FirecrackerEgressPolicy] allows or denies traffic to, or that a
+/// [FirecrackerIngressPolicy] exposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerPortRange {
+    /// The transport protocol of the port.
+    pub protocol: FirecrackerPortProtocol,
+    /// The port, or the first port of the range of ports.
+    pub port: u16,
+    /// The last port of the range of ports, if a range is meant.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_end: Option<u16>,
+}
+
+impl std::fmt::Display for FirecrackerPortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.port)?;
+
+        if let Some(port_end) = self.port_end {
+            write!(f, "-{port_end}")?;
+        }
+
+        write!(f, "/{}", self.protocol.name())
+    }
+}
+
+/// A firewall policy for the traffic that a guest sends towards the host interface, which is rendered as nftables rules
+/// looking up named sets of addresses and ports.
+///
+/// Traffic to a denied destination is dropped first, after which traffic to an allowed destination is accepted and all
+/// remaining traffic is subject to the default action, so a denial always takes precedence. A destination is allowed
+/// or denied if either its address or its port is. Since the addresses and ports reside in sets, they can be changed
+/// on a live network, whereas changing the default action requires recreating the network. CIDRs of an IP version
+/// that the network doesn't use are ignored.
+///
+/// Traffic that belongs to an established connection or is related to one is always accepted, so that the replies to
+/// connections towards the guest, such as those to its forwarded ports, pass regardless of the policy. Consequently,
+/// denying a destination on a live network doesn't affect the connections to it that are already established.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirecrackerEgressPolicy {
+    /// The action for traffic that is neither denied nor allowed, which is accepting it by default.
+    pub default_action: FirecrackerEgressAction,
+    /// The networks that traffic is allowed to, which only matters with a default action of denying traffic.
+    pub allowed_cidrs: Vec<IpCidr>,
+    /// The networks that traffic is denied to.
+    pub denied_cidrs: Vec<IpCidr>,
+    /// The ports that traffic is allowed to on any address, which only matters with a default action of denying
+    /// traffic.
+    pub allowed_ports: Vec<FirecrackerPortRange>,
+    /// The ports that traffic is denied to on any address.
+    pub denied_ports: Vec<FirecrackerPortRange>,
+    /// Deny traffic to link-local addresses, 169.254.0.0/16 and fe80::/10, which is where the metadata services of
+    /// cloud providers such as 169.254.169.254 reside.
+    pub block_link_local: bool,
+    /// Deny traffic to private addresses: the RFC 1918 ranges 10.0.0.0/8, 172.16.0.0/12 and 192.168.0.0/16, and the
+    /// unique local IPv6 addresses in fc00::/7.
+    pub block_private: bool,
+}
+
+/// What a [FirecrackerEgressPolicy] does with traffic that is neither allowed nor denied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FirecrackerEgressAction {
+    /// Accept the traffic, translated to "accept" in nftables.
+    #[default]
+    Allow,
+    /// Drop the traffic, translated to "drop" in nftables.
+    Deny,
+}
+
+/// A firewall policy for the traffic that arrives on the host interface and is forwarded towards a guest.
+///
+/// Replies to connections that the guest initiated, as well as related traffic such as ICMP errors, are always
+/// accepted, and so are new connections to forwarded ports. Any other new connection is only accepted if it originates
+/// from an exposed network or is destined to an exposed port, and is dropped otherwise. CIDRs of an IP version that the
+/// network doesn't use are ignored.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirecrackerIngressPolicy {
+    /// The networks that new connections towards the guest are accepted from.
+    pub exposed_cidrs: Vec<IpCidr>,
+    /// The ports of the guest that new connections are accepted to from any address.
+    pub exposed_ports: Vec<FirecrackerPortRange>,
+}
+
+/// The isolation of a network from the other networks on the host, whose guests could otherwise reach each other via
+/// the forward path of the host.
+///
+/// An isolated network only has traffic forwarded between its link, which is the tap device of a simple network and the
+/// outer end of the veth pair of a namespaced one, and the host interface, so that everything forwarded between it and
+/// the links of other networks is dropped. Networks that share a group are exempt from this and can always reach each
+/// other, regardless of whether either of them is isolated. The names of groups become part of the names of nftables
+/// sets shared by all networks in the same table, so they are best kept to letters, digits and underscores.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirecrackerIsolation {
+    /// Whether the network is isolated, which is the default.
+    pub enabled: bool,
+    /// The names of the groups that the network belongs to, none by default.
+    pub groups: Vec<String>,
+}
+
+impl Default for FirecrackerIsolation {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            groups: Vec::new(),
+        }
+    }
+}
+
+/// The traffic shaping of a network, which attaches queueing disciplines over rtnetlink to its link: the tap device of a
+/// simple network and the outer end of the veth pair of a namespaced one.
+///
+/// Unlike the rate limiters of Firecracker, which throttle each virtio device on its own from within the microVM
+/// process, this shapes the traffic in the kernel of the host, where it is also subject to the queueing of the host.
+/// Egress and ingress refer to the directions of the link as seen from the host: its egress is the traffic that the
+/// host sends towards the guest, while its ingress is the traffic that the guest sends towards the host.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirecrackerShaping {
+    /// The token bucket of a TBF qdisc that replaces the root qdisc of the link, shaping the traffic towards the guest.
+    pub egress: Option<FirecrackerTokenBucket>,
+    /// The shaping of the traffic from the guest, which is redirected from the ingress qdisc of the link to an IFB device
+    /// and shaped by a TBF qdisc on its way out of it.
+    pub ingress: Option<FirecrackerIngressShaping>,
+}
+
+/// The shaping of the traffic that arrives on the link of a network, which can only be queued once it is redirected to
+/// an IFB device.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerIngressShaping {
+    /// The name of the IFB device that is created for the network and deleted alongside it.
+    pub ifb_name: String,
+    /// The token bucket of the TBF qdisc of the IFB device.
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub bucket: FirecrackerTokenBucket,
+}
+
+/// The token bucket of a TBF qdisc, which lets traffic pass at a sustained rate with bursts of up to its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerTokenBucket {
+    /// The sustained rate in bytes per second, which must not be zero.
+    pub rate: u64,
+    /// The size of the bucket in bytes, which must be at least the MTU of the link for packets of that size to pass.
+    pub burst: u32,
+    /// The number of bytes that may be queued while waiting for tokens before packets are dropped, which defaults to
+    /// the burst plus the bytes passing at the rate within 50 milliseconds. See [FirecrackerTokenBucket::resolved_limit].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub limit: Option<u32>,
+}
+
+impl FirecrackerTokenBucket {
+    /// Get the number of bytes that may be queued: either the explicitly specified limit or, if it isn't specified, the
+    /// burst plus the bytes passing at the rate within 50 milliseconds.
+    pub fn resolved_limit(&self) -> u32 {
+        self.limit
+            .unwrap_or_else(|| u32::try_from(self.rate / 20).unwrap_or(u32::MAX).saturating_add(self.burst))
+    }
+}
+
+/// The filtering of the frames that a guest sends into its tap device, which drops those whose source MAC isn't the MAC
+/// of the guest or whose source IP isn't the guest IP, so that the guest can't impersonate other hosts towards the host
+/// or, via the forward path, towards other networks.
+///
+/// The frames are filtered on the ingress hook of the tap device by a chain of the network in a netdev table named like
+/// the table of the network, before they reach anything else on the host. Besides Ethernet and IP headers, ARP packets
+/// need to carry the MAC and IP of the guest as their sender, IPv6 neighbor advertisements may only advertise the guest
+/// IP, router advertisements and redirects are dropped, and so are packets of the IP version that the guest doesn't use
+/// and anything besides IP and ARP. Packets from the unspecified address and IPv6 link-local addresses pass, as DHCP,
+/// duplicate address detection and neighbor discovery rely on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerAntiSpoofing {
+    /// The MAC of the guest, which must match the "guest_mac" of the network interface of the microVM in Firecracker.
+    pub guest_mac: FirecrackerMacAddr,
+}
+
+/// A MAC address, which is formatted and parsed as six colon-separated pairs of hexadecimal digits, such as
+/// "06:00:ac:10:00:02".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
+pub struct FirecrackerMacAddr(pub [u8; 6]);
+
+impl std::fmt::Display for FirecrackerMacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl std::str::FromStr for FirecrackerMacAddr {
+    type Err = FirecrackerMacAddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut octets = [0; 6];
+        let mut parts = s.split(':');
+
+        for octet in octets.iter_mut() {
+            *octet = parts
+                .next()
+                .filter(|part| part.len() == 2)
+                .and_then(|part| u8::from_str_radix(part, 16).ok())
+                .ok_or(FirecrackerMacAddrParseError)?;
+        }
+
+        match parts.next() {
+            Some(_) => Err(FirecrackerMacAddrParseError),
+            None => Ok(Self(octets)),
+        }
+    }
+}
+
+impl TryFrom<String> for FirecrackerMacAddr {
+    type Error = FirecrackerMacAddrParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<FirecrackerMacAddr> for String {
+    fn from(value: FirecrackerMacAddr) -> Self {
+        value.to_string()
+    }
+}
+
+/// The error of parsing a [FirecrackerMacAddr] from a string that isn't formatted like one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FirecrackerMacAddrParseError;
+
+impl std::fmt::Display for FirecrackerMacAddrParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected a MAC address of six colon-separated pairs of hexadecimal digits")
+    }
+}
+
+impl std::error::Error for FirecrackerMacAddrParseError {}
+
 /// The type of Firecracker network to work with.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -65,18 +542,93 @@ pub enum FirecrackerNetworkType {
         veth2_ip: IpInet,
         #[cfg_attr(feature = "serde", serde(default))]
         forwarded_guest_ip: Option<IpAddr>,
+        /// The directory that the network namespace is persisted in, [DEFAULT_NETNS_DIR] if unspecified. See
+        /// [FirecrackerNetwork::netns_path] for the resulting path of the network namespace.
+        #[cfg_attr(feature = "serde", serde(default))]
+        netns_dir: Option<String>,
+        /// The Firecracker jailer that the microVM of this network is run with, if any.
+        #[cfg_attr(feature = "serde", serde(default))]
+        jailer: Option<FirecrackerJailer>,
     },
 }
 
+/// The directory that network namespaces are persisted in by default, which is the same one that iproute2 uses.
+#[cfg(feature = "namespaced")]
+#[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+pub const DEFAULT_NETNS_DIR: &str = "/var/run/netns";
+
+/// The Firecracker jailer that the microVM of a namespaced network is run with. The jailer enters the network namespace
+/// handed to it via "--netns" before dropping privileges to the given UID and GID, so the tap device must be owned by
+/// them for the jailed Firecracker to be able to open it.
+#[cfg(feature = "namespaced")]
+#[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerJailer {
+    /// The UID passed to the jailer via "--uid", which the tap device is owned by unless
+    /// [FirecrackerTapOptions::owner] is set explicitly.
+    pub uid: u32,
+    /// The GID passed to the jailer via "--gid", which the tap device is owned by unless
+    /// [FirecrackerTapOptions::group] is set explicitly.
+    pub gid: u32,
+}
+
 impl FirecrackerNetwork {
+    /// Get the identifier of this network: either the explicitly specified one or, if it isn't specified, the name of
+    /// the network namespace for namespaced networks and the name of the tap device for simple networks, both of which
+    /// are already unique on the host.
+    pub fn resolved_id(&self) -> &str {
+        if let Some(ref id) = self.id {
+            return id;
+        }
+
+        match self.network_type {
+            #[cfg(feature = "simple")]
+            FirecrackerNetworkType::Simple => &self.tap_name,
+            #[cfg(feature = "namespaced")]
+            FirecrackerNetworkType::Namespaced { ref netns_name, .. } => netns_name,
+        }
+    }
+
+    /// Get the options of the tap device with the owner and group defaulting to the UID and GID of the jailer of a
+    /// namespaced network.
+    pub fn resolved_tap_options(&self) -> FirecrackerTapOptions {
+        match self.network_type {
+            #[cfg(feature = "namespaced")]
+            FirecrackerNetworkType::Namespaced {
+                jailer: Some(jailer), ..
+            } => FirecrackerTapOptions {
+                owner: self.tap_options.owner.or(Some(jailer.uid)),
+                group: self.tap_options.group.or(Some(jailer.gid)),
+                ..self.tap_options.clone()
+            },
+            _ => self.tap_options.clone(),
+        }
+    }
+
+    /// Get the path of the network namespace of a namespaced network, which is to be handed to the Firecracker jailer
+    /// via "--netns". Simple networks don't have a network namespace of their own.
+    pub fn netns_path(&self) -> Option<PathBuf> {
+        match self.network_type {
+            #[cfg(feature = "simple")]
+            FirecrackerNetworkType::Simple => None,
+            #[cfg(feature = "namespaced")]
+            FirecrackerNetworkType::Namespaced {
+                ref netns_name,
+                ref netns_dir,
+                ..
+            } => Some(PathBuf::from(netns_dir.as_deref().unwrap_or(DEFAULT_NETNS_DIR)).join(netns_name)),
+        }
+    }
+
     /// Format a kernel boot argument that can be added so that all routing setup in the guest is performed
     /// by the kernel automatically with iproute2 not needed in the guest.
     pub fn guest_ip_boot_arg(&self, guest_iface_name: impl AsRef<str>) -> String {
         format!(
             "ip={}::{}:{}::{}:off",
-            self.guest_ip.address().to_string(),
-            self.tap_ip.address().to_string(),
-            self.guest_ip.mask().to_string(),
+            self.guest_ip.address(),
+            self.tap_ip.address(),
+            self.guest_ip.mask(),
             guest_iface_name.as_ref()
         )
     }
@@ -93,3 +645,26 @@ pub enum FirecrackerNetworkOperation {
     /// Delete this network from the host.
     Delete,
 }
+
+/// The traffic of a FirecrackerNetwork that its nftables rules have accepted since they were added or their counters
+/// were last reset. Directions are as seen from the guest: egress is the traffic that the guest sends out via the host
+/// interface and ingress is the traffic towards the guest from the host interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerNetworkCounters {
+    /// The traffic accepted by the egress forward rule and the rules of the egress policy.
+    pub egress: FirecrackerTrafficCounter,
+    /// The traffic accepted by the ingress forward rule and the rules of port forwards and the ingress policy.
+    pub ingress: FirecrackerTrafficCounter,
+    /// The traffic masqueraded by the masquerade rule. Since NAT rules only see the first packet of every connection,
+    /// its packets are the number of connections that the guest initiated via the host interface.
+    pub masquerade: FirecrackerTrafficCounter,
+}
+
+/// The number of packets and bytes that were counted by one or more nftables rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerTrafficCounter {
+    pub packets: u64,
+    pub bytes: u64,
+}
@@ -0,0 +1,315 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use fcnet_types::{FirecrackerAntiSpoofing, FirecrackerNetwork};
+use nftables::{
+    batch::Batch,
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Prefix},
+    schema::{Chain, FlushObject, NfCmd, NfListObject, NfObject, Nftables, Rule, Table},
+    stmt::{Match, Operator, Statement},
+    types::{NfChainPolicy, NfChainType, NfFamily, NfHook},
+};
+
+use crate::{
+    backend::Backend,
+    layout::{check_tagged_rules, NfTaggedRule},
+    rollback::{Rollback, RollbackObject},
+    util::{apply_ruleset, nf_anti_spoofing_rule_tag, FirecrackerNetworkExt},
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState, FirecrackerNetworkObjectType,
+};
+
+/// The prefix of the names of the anti-spoofing chains of networks, which is followed by the identifier of the network.
+pub const ANTI_SPOOFING_CHAIN_PREFIX: &str = "anti_spoofing-";
+
+/// The priority of the anti-spoofing chains on the ingress hook of tap devices, which is that of "filter" in nft.
+const ANTI_SPOOFING_CHAIN_PRIORITY: i32 = 0;
+
+/// The objects filtering the frames of the guest on the ingress hook of the tap device, if the network has
+/// anti-spoofing: the netdev table, the chain of the network and its rules. For namespaced networks, they are added
+/// alongside the other nftables objects inside of the netns, which removes them once it is removed itself.
+pub fn anti_spoofing_objects(network: &FirecrackerNetwork) -> Vec<NfListObject<'static>> {
+    let Some(ref anti_spoofing) = network.anti_spoofing else {
+        return Vec::new();
+    };
+
+    let mut objects = vec![
+        NfListObject::Table(Table {
+            family: NfFamily::NetDev,
+            name: network.nf_table.name.clone().into(),
+            handle: None,
+        }),
+        NfListObject::Chain(anti_spoofing_chain(network)),
+    ];
+    objects.extend(
+        anti_spoofing_rules(network, anti_spoofing)
+            .into_iter()
+            .map(|tagged_rule| NfListObject::Rule(tagged_rule.rule)),
+    );
+    objects
+}
+
+/// Add the anti-spoofing objects of a simple network, which requires its tap device to already exist, recording the
+/// chain for rollback. The netdev table is shared with other networks and is therefore left in place.
+pub async fn add_anti_spoofing<B: Backend>(
+    network: &FirecrackerNetwork,
+    rollback: &mut Rollback<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    if network.anti_spoofing.is_none() {
+        return Ok(());
+    }
+
+    let mut batch = Batch::new();
+
+    for object in anti_spoofing_objects(network) {
+        batch.add(object);
+    }
+
+    apply_ruleset::<B>(&batch.to_nftables(), network.nft_program()).await?;
+    rollback.push(RollbackObject::NfChain(anti_spoofing_chain_ref(network)));
+    Ok(())
+}
+
+/// Delete the anti-spoofing chain of a simple network alongside its rules, which must happen before its tap device is
+/// deleted, as the kernel would otherwise keep the chain around without a hook.
+pub async fn delete_anti_spoofing<B: Backend>(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    if network.anti_spoofing.is_none() {
+        return Ok(());
+    }
+
+    let mut batch = Batch::new();
+    delete_chain(&mut batch, anti_spoofing_chain_ref(network));
+    apply_ruleset::<B>(&batch.to_nftables(), network.nft_program()).await
+}
+
+/// Flush and delete the given chain, which only needs to carry its family, table and name.
+pub fn delete_chain(batch: &mut Batch<'static>, chain: Chain<'static>) {
+    batch.add_cmd(NfCmd::Flush(FlushObject::Chain(chain.clone())));
+    batch.delete(NfListObject::Chain(chain));
+}
+
+/// Check the anti-spoofing chain of a network and its rules, which are located by their tags, within the ruleset of the
+/// network namespace that the tap device resides in. Everything is reported as missing without a ruleset.
+pub fn check_anti_spoofing(
+    network: &FirecrackerNetwork,
+    current_ruleset: Option<&Nftables<'static>>,
+    report: &mut FirecrackerNetworkCheckReport,
+) {
+    let Some(ref anti_spoofing) = network.anti_spoofing else {
+        return;
+    };
+
+    let chain = anti_spoofing_chain(network);
+    let chain_state = match current_ruleset
+        .iter()
+        .flat_map(|ruleset| ruleset.objects.iter())
+        .find_map(|object| match object {
+            NfObject::ListObject(NfListObject::Chain(current_chain))
+                if current_chain.family == chain.family
+                    && current_chain.table == chain.table
+                    && current_chain.name == chain.name =>
+            {
+                Some(current_chain)
+            }
+            _ => None,
+        }) {
+        Some(current_chain)
+            if current_chain._type == chain._type
+                && current_chain.hook == chain.hook
+                && current_chain.prio == chain.prio
+                && current_chain.dev == chain.dev
+                && current_chain.policy.unwrap_or(NfChainPolicy::Accept) == NfChainPolicy::Accept =>
+        {
+            FirecrackerNetworkObjectState::Present
+        }
+        Some(_) => FirecrackerNetworkObjectState::Mismatched,
+        None => FirecrackerNetworkObjectState::Missing,
+    };
+
+    report.push(
+        FirecrackerNetworkObjectType::NfAntiSpoofingChain,
+        chain.name.as_ref(),
+        chain_state,
+    );
+    check_tagged_rules(current_ruleset, &anti_spoofing_rules(network, anti_spoofing), report);
+}
+
+/// The anti-spoofing chain of a network as it is added: a base chain on the ingress hook of its tap device that accepts
+/// everything its rules don't drop.
+fn anti_spoofing_chain(network: &FirecrackerNetwork) -> Chain<'static> {
+    Chain {
+        _type: Some(NfChainType::Filter),
+        hook: Some(NfHook::Ingress),
+        prio: Some(ANTI_SPOOFING_CHAIN_PRIORITY),
+        dev: Some(network.tap_name.clone().into()),
+        policy: Some(NfChainPolicy::Accept),
+        ..anti_spoofing_chain_ref(network)
+    }
+}
+
+/// The anti-spoofing chain of a network as it is referred to when flushing and deleting it.
+fn anti_spoofing_chain_ref(network: &FirecrackerNetwork) -> Chain<'static> {
+    Chain {
+        family: NfFamily::NetDev,
+        table: network.nf_table.name.clone().into(),
+        name: format!("{ANTI_SPOOFING_CHAIN_PREFIX}{}", network.resolved_id()).into(),
+        newname: None,
+        handle: None,
+        _type: None,
+        hook: None,
+        prio: None,
+        dev: None,
+        policy: None,
+    }
+}
+
+/// The rules of the anti-spoofing chain of a network, each of which drops the frames matching all of its statements.
+/// The rules checking the IP addresses of the guest depend on its version, while frames of the other version are
+/// dropped altogether.
+fn anti_spoofing_rules(network: &FirecrackerNetwork, anti_spoofing: &FirecrackerAntiSpoofing) -> Vec<NfTaggedRule> {
+    let guest_mac = Expression::String(anti_spoofing.guest_mac.to_string().into());
+    let guest_addr = Expression::String(network.guest_ip.address().to_string().into());
+    let mut rules = vec![(
+        "ether",
+        format!("ether saddr != {}", anti_spoofing.guest_mac),
+        vec![payload_match("ether", "saddr", guest_mac.clone(), Operator::NEQ)],
+    )];
+
+    match network.guest_ip.address() {
+        IpAddr::V4(guest_addr_v4) => {
+            // ARP probes and DHCP discoveries are sent before the guest has an address, from the unspecified one
+            let unspecified_addr = Expression::String(Ipv4Addr::UNSPECIFIED.to_string().into());
+
+            rules.extend([
+                (
+                    "protocol",
+                    "meta protocol != { ip, arp }".to_string(),
+                    vec![protocol_match("ip", Operator::NEQ), protocol_match("arp", Operator::NEQ)],
+                ),
+                (
+                    "arp-ether",
+                    format!("arp saddr ether != {}", anti_spoofing.guest_mac),
+                    vec![payload_match("arp", "saddr ether", guest_mac, Operator::NEQ)],
+                ),
+                (
+                    "arp-ip",
+                    format!("arp saddr ip != {guest_addr_v4}"),
+                    vec![
+                        payload_match("arp", "saddr ip", guest_addr.clone(), Operator::NEQ),
+                        payload_match("arp", "saddr ip", unspecified_addr.clone(), Operator::NEQ),
+                    ],
+                ),
+                (
+                    "ip",
+                    format!("ip saddr != {guest_addr_v4}"),
+                    vec![
+                        payload_match("ip", "saddr", guest_addr, Operator::NEQ),
+                        payload_match("ip", "saddr", unspecified_addr, Operator::NEQ),
+                    ],
+                ),
+            ]);
+        }
+        IpAddr::V6(guest_addr_v6) => {
+            // neighbor discovery is performed from link-local addresses and duplicate address detection from the
+            // unspecified one, neither of which are reachable from beyond the tap device
+            let link_local_prefix = Expression::Named(NamedExpression::Prefix(Prefix {
+                addr: Box::new(Expression::String("fe80::".into())),
+                len: 10,
+            }));
+
+            rules.extend([
+                (
+                    "protocol",
+                    "meta protocol != ip6".to_string(),
+                    vec![protocol_match("ip6", Operator::NEQ)],
+                ),
+                (
+                    "ip6",
+                    format!("ip6 saddr != {guest_addr_v6}"),
+                    vec![
+                        payload_match("ip6", "saddr", guest_addr.clone(), Operator::NEQ),
+                        payload_match("ip6", "saddr", link_local_prefix.clone(), Operator::NEQ),
+                        payload_match(
+                            "ip6",
+                            "saddr",
+                            Expression::String(Ipv6Addr::UNSPECIFIED.to_string().into()),
+                            Operator::NEQ,
+                        ),
+                    ],
+                ),
+                (
+                    "router-advert",
+                    "icmpv6 type nd-router-advert".to_string(),
+                    vec![payload_match(
+                        "icmpv6",
+                        "type",
+                        Expression::String("nd-router-advert".into()),
+                        Operator::EQ,
+                    )],
+                ),
+                (
+                    "redirect",
+                    "icmpv6 type nd-redirect".to_string(),
+                    vec![payload_match(
+                        "icmpv6",
+                        "type",
+                        Expression::String("nd-redirect".into()),
+                        Operator::EQ,
+                    )],
+                ),
+                (
+                    "neighbor-advert",
+                    format!("icmpv6 taddr != {guest_addr_v6}"),
+                    vec![
+                        payload_match(
+                            "icmpv6",
+                            "type",
+                            Expression::String("nd-neighbor-advert".into()),
+                            Operator::EQ,
+                        ),
+                        payload_match("icmpv6", "taddr", guest_addr, Operator::NEQ),
+                        payload_match("icmpv6", "taddr", link_local_prefix, Operator::NEQ),
+                    ],
+                ),
+            ]);
+        }
+    }
+
+    let chain = anti_spoofing_chain_ref(network);
+
+    rules
+        .into_iter()
+        .map(|(kind, identifier, matches)| NfTaggedRule {
+            object_type: FirecrackerNetworkObjectType::NfAntiSpoofingRule,
+            identifier,
+            rule: Rule {
+                family: chain.family,
+                table: chain.table.clone(),
+                chain: chain.name.clone(),
+                expr: matches.into_iter().chain([Statement::Drop(None)]).collect::<Vec<_>>().into(),
+                handle: None,
+                index: None,
+                comment: Some(nf_anti_spoofing_rule_tag(network.resolved_id(), kind).into()),
+            },
+        })
+        .collect()
+}
+
+#[inline]
+fn payload_match(protocol: &'static str, field: &'static str, right: Expression<'static>, op: Operator) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: protocol.into(),
+            field: field.into(),
+        }))),
+        right,
+        op,
+    })
+}
+
+#[inline]
+fn protocol_match(protocol: &'static str, op: Operator) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Protocol })),
+        right: Expression::String(protocol.into()),
+        op,
+    })
+}
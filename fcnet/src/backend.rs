@@ -1,18 +1,37 @@
 #[cfg(feature = "smol-backend")]
 use async_executor::{Executor, LocalExecutor};
 use netlink_proto::Connection;
+use nftables::{helper::NftablesError, schema::Rule};
 use rtnetlink::packet_route::RouteNetlinkMessage;
 use std::future::Future;
+#[cfg(any(feature = "netlink-nftables", feature = "iptables"))]
+use std::marker::PhantomData;
 #[cfg(feature = "smol-backend")]
 use std::sync::{Arc, OnceLock};
+#[cfg(feature = "udp")]
+use std::{io, net::SocketAddr};
+
+#[cfg(feature = "netlink-nftables")]
+#[cfg_attr(docsrs, doc(cfg(feature = "netlink-nftables")))]
+pub use crate::netlink_nftables::NetlinkNftablesDriver;
+
+#[cfg(feature = "iptables")]
+#[cfg_attr(docsrs, doc(cfg(feature = "iptables")))]
+pub use crate::iptables::IptablesDriver;
 
 /// The [Backend] trait encapsulates the async-runtime-dependent functionality that is needed for fcnet
 /// to function.
 pub trait Backend: Send + Sync + 'static {
     /// The [rtnetlink::sys::AsyncSocket] (async fd implementation) used by this backend.
     type NetlinkSocket: rtnetlink::sys::AsyncSocket + Send;
-    /// The [nftables_async] driver used by this backend.
-    type NftablesDriver: nftables_async::driver::Driver;
+    /// The [nftables_async] helper used by this backend, which is usually an [nftables_async::driver::Driver] invoking
+    /// "nft", but can also be a [NetlinkNftablesDriver] when the "netlink-nftables" feature is enabled or an
+    /// [IptablesDriver] when the "iptables" feature is enabled.
+    type NftablesDriver: nftables_async::helper::Helper;
+    /// The [AsyncUdpSocket] used by this backend to serve DHCP and DNS to guests.
+    #[cfg(feature = "udp")]
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "dhcp", feature = "dns"))))]
+    type UdpSocket: AsyncUdpSocket;
 
     /// Spawn a netlink [Connection] onto this async runtime, detaching the spawned task to have it run
     /// in the background.
@@ -22,6 +41,60 @@ pub trait Backend: Send + Sync + 'static {
     /// This will be called in a separate OS thread spawned by fcnet for the purposes of calling setns
     /// within it to operate within the context of another network namespace.
     fn block_on_current_thread<O, F: Future<Output = O>>(future: F) -> O;
+
+    /// Read the given rules of the current ruleset by their handles and reset their counters, atomically for every rule,
+    /// returning them as they were beforehand, or [None] if the [Backend::NftablesDriver] can't do so, in which case
+    /// fcnet resets the counters by replacing the rules instead. Only the [NetlinkNftablesDriver] can do so.
+    fn reset_nf_rules(rules: &[Rule<'_>]) -> impl Future<Output = Result<Option<Vec<Rule<'static>>>, NftablesError>> + Send {
+        let _ = rules;
+        std::future::ready(Ok(None))
+    }
+}
+
+/// A UDP socket that is registered with the async runtime of a [Backend].
+#[cfg(feature = "udp")]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "dhcp", feature = "dns"))))]
+pub trait AsyncUdpSocket: Send + Sync + Sized + 'static {
+    /// Register the given non-blocking [std::net::UdpSocket] with the async runtime, which must be done within it.
+    fn from_std(socket: std::net::UdpSocket) -> io::Result<Self>;
+
+    /// Receive a datagram into the given buffer, returning its length and the address it was sent from.
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    /// Send the given datagram to the given address, returning the number of bytes sent.
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+#[cfg(all(feature = "udp", feature = "tokio-backend"))]
+#[cfg_attr(docsrs, doc(cfg(all(any(feature = "dhcp", feature = "dns"), feature = "tokio-backend"))))]
+impl AsyncUdpSocket for tokio::net::UdpSocket {
+    fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        tokio::net::UdpSocket::from_std(socket)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        tokio::net::UdpSocket::recv_from(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send {
+        tokio::net::UdpSocket::send_to(self, buf, addr)
+    }
+}
+
+#[cfg(all(feature = "udp", feature = "smol-backend"))]
+#[cfg_attr(docsrs, doc(cfg(all(any(feature = "dhcp", feature = "dns"), feature = "smol-backend"))))]
+impl AsyncUdpSocket for async_io::Async<std::net::UdpSocket> {
+    fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        async_io::Async::new(socket)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        async_io::Async::<std::net::UdpSocket>::recv_from(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send {
+        async_io::Async::<std::net::UdpSocket>::send_to(self, buf, addr)
+    }
 }
 
 /// A [Backend] implementation that uses the tokio crate for async I/O and its current-thread executor.
@@ -34,6 +107,8 @@ pub struct TokioBackend;
 impl Backend for TokioBackend {
     type NetlinkSocket = netlink_proto::sys::TokioSocket;
     type NftablesDriver = nftables_async::driver::TokioDriver;
+    #[cfg(feature = "udp")]
+    type UdpSocket = tokio::net::UdpSocket;
 
     fn spawn_connection(connection: Connection<RouteNetlinkMessage, Self::NetlinkSocket>) {
         tokio::task::spawn(connection);
@@ -76,6 +151,8 @@ impl SmolBackend {
 impl Backend for SmolBackend {
     type NetlinkSocket = netlink_proto::sys::SmolSocket;
     type NftablesDriver = nftables_async::driver::AsyncProcessDriver;
+    #[cfg(feature = "udp")]
+    type UdpSocket = async_io::Async<std::net::UdpSocket>;
 
     fn spawn_connection(connection: Connection<RouteNetlinkMessage, Self::NetlinkSocket>) {
         SMOL_EXECUTOR
@@ -89,3 +166,64 @@ impl Backend for SmolBackend {
         async_io::block_on(LocalExecutor::new().run(future))
     }
 }
+
+/// A [Backend] implementation that wraps another [Backend], retaining its async runtime integration but replacing its
+/// nftables driver with a [NetlinkNftablesDriver] that talks nf_tables over netlink instead of invoking "nft". The
+/// nftables driver of the wrapped [Backend] is used as the fallback for rulesets that can't be handled natively.
+#[cfg(feature = "netlink-nftables")]
+#[cfg_attr(docsrs, doc(cfg(feature = "netlink-nftables")))]
+pub struct NetlinkNftablesBackend<B: Backend> {
+    _marker: PhantomData<fn() -> B>,
+}
+
+#[cfg(feature = "netlink-nftables")]
+#[cfg_attr(docsrs, doc(cfg(feature = "netlink-nftables")))]
+impl<B: Backend> Backend for NetlinkNftablesBackend<B> {
+    type NetlinkSocket = B::NetlinkSocket;
+    type NftablesDriver = NetlinkNftablesDriver<B::NetlinkSocket, B::NftablesDriver>;
+    #[cfg(feature = "udp")]
+    type UdpSocket = B::UdpSocket;
+
+    fn spawn_connection(connection: Connection<RouteNetlinkMessage, Self::NetlinkSocket>) {
+        B::spawn_connection(connection);
+    }
+
+    fn block_on_current_thread<O, F: Future<Output = O>>(future: F) -> O {
+        B::block_on_current_thread(future)
+    }
+
+    fn reset_nf_rules(rules: &[Rule<'_>]) -> impl Future<Output = Result<Option<Vec<Rule<'static>>>, NftablesError>> + Send {
+        NetlinkNftablesDriver::<B::NetlinkSocket, B::NftablesDriver>::reset_rules(rules)
+    }
+}
+
+/// A [Backend] implementation that wraps another [Backend], retaining its async runtime integration but replacing its
+/// nftables driver with an [IptablesDriver] that expresses rulesets through iptables and ip6tables, for hosts where
+/// "nft" can't be used. The process driver of the wrapped [Backend] is used to invoke iptables-save and
+/// iptables-restore. Only the flat layout is supported, and anti-spoofing isn't, since filtering the frames arriving on
+/// a tap device requires a netdev table.
+#[cfg(feature = "iptables")]
+#[cfg_attr(docsrs, doc(cfg(feature = "iptables")))]
+pub struct IptablesBackend<B: Backend> {
+    _marker: PhantomData<fn() -> B>,
+}
+
+#[cfg(feature = "iptables")]
+#[cfg_attr(docsrs, doc(cfg(feature = "iptables")))]
+impl<B: Backend> Backend for IptablesBackend<B>
+where
+    B::NftablesDriver: nftables_async::driver::Driver,
+{
+    type NetlinkSocket = B::NetlinkSocket;
+    type NftablesDriver = IptablesDriver<B::NftablesDriver>;
+    #[cfg(feature = "udp")]
+    type UdpSocket = B::UdpSocket;
+
+    fn spawn_connection(connection: Connection<RouteNetlinkMessage, Self::NetlinkSocket>) {
+        B::spawn_connection(connection);
+    }
+
+    fn block_on_current_thread<O, F: Future<Output = O>>(future: F) -> O {
+        B::block_on_current_thread(future)
+    }
+}
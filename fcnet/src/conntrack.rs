@@ -0,0 +1,240 @@
+use std::{io, net::IpAddr};
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkType};
+use rtnetlink::{
+    packet_core::{
+        parse_ip, NetlinkBuffer, NlaBuffer, NlasIterator, NLA_ALIGNTO, NLA_F_NESTED, NLA_HEADER_SIZE, NLMSG_ALIGNTO, NLMSG_DONE,
+        NLMSG_ERROR, NLM_F_ACK, NLM_F_DUMP, NLM_F_REQUEST,
+    },
+    sys::AsyncSocket,
+};
+
+use crate::{
+    backend::Backend,
+    util::{open_netfilter_socket, recv_netlink, send_netlink},
+    FirecrackerNetworkError,
+};
+
+const NLMSG_HEADER_LEN: usize = 16;
+const NFGENMSG_LEN: usize = 4;
+const NFNL_SUBSYS_CTNETLINK: u16 = 1;
+const IPCTNL_MSG_CT_NEW: u16 = 0;
+const IPCTNL_MSG_CT_GET: u16 = 1;
+const IPCTNL_MSG_CT_DELETE: u16 = 2;
+const CTA_TUPLE_ORIG: u16 = 1;
+const CTA_TUPLE_REPLY: u16 = 2;
+const CTA_ZONE: u16 = 18;
+const CTA_TUPLE_IP: u16 = 1;
+const CTA_IP_V4_SRC: u16 = 1;
+const CTA_IP_V4_DST: u16 = 2;
+const CTA_IP_V6_SRC: u16 = 3;
+const CTA_IP_V6_DST: u16 = 4;
+/// The number of deletions sent in a single datagram, whose acknowledgements must fit into the receive buffer of the
+/// socket at once.
+const CONNTRACK_DELETE_BATCH_SIZE: usize = 64;
+
+/// A conntrack entry to be deleted, which is identified by its family, its original tuple and its zone.
+struct ConntrackEntry {
+    family: u8,
+    orig_tuple: Vec<u8>,
+    zone: Option<Vec<u8>>,
+}
+
+/// Delete every conntrack entry in the outer network namespace whose original or reply tuple references an address of
+/// this network that is seen there: its guest IP for simple networks, or its veth2 IP and forwarded guest IP for
+/// namespaced networks, whose guest IP is only ever seen within the netns. Otherwise, the next network reusing any of
+/// these addresses would inherit the NAT bindings of the entries until they time out. The entries within the netns of a
+/// namespaced network are removed alongside the netns.
+pub async fn flush_conntrack<B: Backend>(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    flush_conntrack_entries::<B::NetlinkSocket>(&conntrack_addrs(network))
+        .await
+        .map_err(FirecrackerNetworkError::ConntrackError)
+}
+
+fn conntrack_addrs(network: &FirecrackerNetwork) -> Vec<IpAddr> {
+    match network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => vec![network.guest_ip.address()],
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced {
+            netns_name: _,
+            veth1_name: _,
+            veth2_name: _,
+            veth1_ip: _,
+            veth2_ip,
+            forwarded_guest_ip,
+            netns_dir: _,
+            jailer: _,
+        } => [veth2_ip.address()].into_iter().chain(forwarded_guest_ip).collect(),
+    }
+}
+
+/// Dump the conntrack entries of the family of the given addresses over ctnetlink and delete the matching entries in
+/// batches once the dump has completed, since a netlink socket can't serve other requests in the midst of a dump.
+/// Entries that expire in between are skipped.
+async fn flush_conntrack_entries<S: AsyncSocket + Send>(addrs: &[IpAddr]) -> io::Result<()> {
+    let mut socket = open_netfilter_socket::<S>()?;
+    let mut entries = Vec::new();
+
+    // the kernel leaves out the entries of other families from the dump, unless it is asked for every family
+    let family = if addrs.iter().all(IpAddr::is_ipv4) {
+        libc::AF_INET
+    } else if addrs.iter().all(IpAddr::is_ipv6) {
+        libc::AF_INET6
+    } else {
+        libc::AF_UNSPEC
+    };
+
+    send_netlink(
+        &mut socket,
+        &ctnetlink_message(IPCTNL_MSG_CT_GET, NLM_F_REQUEST | NLM_F_DUMP, family as u8, &[]),
+    )
+    .await?;
+
+    'dump: loop {
+        let buf = recv_netlink(&mut socket).await?;
+
+        for message in messages(&buf) {
+            match message.message_type() {
+                NLMSG_DONE => break 'dump,
+                NLMSG_ERROR => check_error(message.payload())?,
+                msg_type if msg_type == (NFNL_SUBSYS_CTNETLINK << 8) | IPCTNL_MSG_CT_NEW => {
+                    entries.extend(matching_entry(message.payload(), addrs));
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    for batch in entries.chunks(CONNTRACK_DELETE_BATCH_SIZE) {
+        let mut buf = Vec::new();
+
+        for entry in batch {
+            let mut attrs = Vec::new();
+            put_attr(&mut attrs, CTA_TUPLE_ORIG | NLA_F_NESTED, &entry.orig_tuple);
+
+            if let Some(ref zone) = entry.zone {
+                put_attr(&mut attrs, CTA_ZONE, zone);
+            }
+
+            buf.extend(ctnetlink_message(
+                IPCTNL_MSG_CT_DELETE,
+                NLM_F_REQUEST | NLM_F_ACK,
+                entry.family,
+                &attrs,
+            ));
+        }
+
+        send_netlink(&mut socket, &buf).await?;
+
+        // the kernel processes every message of the datagram and acknowledges each of them on its own
+        let mut acks = 0;
+
+        while acks < batch.len() {
+            let buf = recv_netlink(&mut socket).await?;
+
+            for message in messages(&buf).filter(|message| message.message_type() == NLMSG_ERROR) {
+                acks += 1;
+
+                match check_error(message.payload()) {
+                    Err(err) if err.raw_os_error() == Some(libc::ENOENT) => continue,
+                    result => result?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The conntrack entry carried by the given body of a ctnetlink message, if either of its tuples references any of the
+/// given addresses.
+fn matching_entry(payload: &[u8], addrs: &[IpAddr]) -> Option<ConntrackEntry> {
+    let family = *payload.first()?;
+    let mut orig_tuple = None;
+    let mut references_addr = false;
+    let mut zone = None;
+
+    for nla in NlasIterator::new(payload.get(NFGENMSG_LEN..)?).flatten() {
+        match nla.kind() {
+            CTA_TUPLE_ORIG | CTA_TUPLE_REPLY => {
+                references_addr |= tuple_addrs(nla.value()).any(|addr| addrs.contains(&addr));
+
+                if nla.kind() == CTA_TUPLE_ORIG {
+                    orig_tuple = Some(nla.value().to_vec());
+                }
+            }
+            CTA_ZONE => zone = Some(nla.value().to_vec()),
+            _ => continue,
+        }
+    }
+
+    match references_addr {
+        true => Some(ConntrackEntry {
+            family,
+            orig_tuple: orig_tuple?,
+            zone,
+        }),
+        false => None,
+    }
+}
+
+/// The source and destination addresses of the given tuple.
+fn tuple_addrs(tuple: &[u8]) -> impl Iterator<Item = IpAddr> + '_ {
+    NlasIterator::new(tuple)
+        .flatten()
+        .filter(|nla| nla.kind() == CTA_TUPLE_IP)
+        .flat_map(|nla| NlasIterator::new(nla_value(nla)).flatten())
+        .filter(|nla| matches!(nla.kind(), CTA_IP_V4_SRC | CTA_IP_V4_DST | CTA_IP_V6_SRC | CTA_IP_V6_DST))
+        .filter_map(|nla| parse_ip(nla.value()).ok())
+}
+
+/// The payload of the given attribute, which unlike [NlaBuffer::value] borrows from the underlying buffer.
+fn nla_value(nla: NlaBuffer<&[u8]>) -> &[u8] {
+    let len = nla.length() as usize;
+    &nla.into_inner()[NLA_HEADER_SIZE..len]
+}
+
+fn ctnetlink_message(msg_type: u16, flags: u16, family: u8, attrs: &[u8]) -> Vec<u8> {
+    let len = NLMSG_HEADER_LEN + NFGENMSG_LEN + attrs.len();
+    let mut buf = vec![0; len];
+    let mut message = NetlinkBuffer::new(&mut buf);
+    message.set_length(len as u32);
+    message.set_message_type((NFNL_SUBSYS_CTNETLINK << 8) | msg_type);
+    message.set_flags(flags);
+    message.set_sequence_number(1);
+    // nfgenmsg: family, version and big-endian resource id, the latter two of which are zero
+    message.payload_mut()[0] = family;
+    message.payload_mut()[NFGENMSG_LEN..].copy_from_slice(attrs);
+    buf
+}
+
+fn put_attr(buf: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    buf.extend_from_slice(&((NLA_HEADER_SIZE + value.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len().next_multiple_of(NLA_ALIGNTO), 0);
+}
+
+/// Fail with the errno carried by the given body of an [NLMSG_ERROR] message, unless it is an acknowledgement.
+fn check_error(payload: &[u8]) -> io::Result<()> {
+    match payload
+        .get(..4)
+        .map(|code| i32::from_ne_bytes([code[0], code[1], code[2], code[3]]))
+    {
+        Some(0) => Ok(()),
+        Some(code) => Err(io::Error::from_raw_os_error(-code)),
+        None => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
+
+/// An iterator over the netlink messages in a received datagram.
+fn messages(mut buf: &[u8]) -> impl Iterator<Item = NetlinkBuffer<&[u8]>> {
+    std::iter::from_fn(move || {
+        let message = NetlinkBuffer::new_checked(buf).ok()?;
+        buf = &buf[(message.length() as usize)
+            .next_multiple_of(NLMSG_ALIGNTO as usize)
+            .min(buf.len())..];
+        Some(message)
+    })
+}
@@ -0,0 +1,383 @@
+use std::{
+    marker::PhantomData,
+    ops::Deref,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkCounters, FirecrackerNetworkOperation, FirecrackerNetworkType};
+use nftables::schema::{NfListObject, NfObject, Nftables};
+
+#[cfg(feature = "dhcp")]
+use crate::dhcp;
+#[cfg(feature = "dns")]
+use crate::dns;
+#[cfg(feature = "namespaced")]
+use crate::namespaced;
+#[cfg(feature = "simple")]
+use crate::simple;
+use crate::{
+    backend::Backend,
+    conntrack, counters, egress, gc,
+    layout::NfChangeset,
+    many,
+    util::{get_current_ruleset_with_verdict_maps, parse_nf_dispatch_rule_tag},
+    vmap::NfVerdictMap,
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkGcEntry, FirecrackerNetworkGcOptions,
+    FirecrackerNetworkSnapshot,
+};
+
+/// The number of netns worker threads of a [FirecrackerNetworkContext] created via [FirecrackerNetworkContext::new].
+#[cfg(feature = "namespaced")]
+#[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+pub const DEFAULT_NETNS_WORKER_COUNT: usize = 4;
+
+/// A long-lived context for running operations on [FirecrackerNetwork]s via the given [Backend], which should be
+/// created once per process and shared. It owns:
+///
+/// - The rtnetlink connections used by operations in the outer network namespace. As the kernel only allows a single
+///   dump at a time per netlink socket, concurrent operations each lease a connection of their own, so there are as
+///   many connections as there have ever been concurrent operations, and a single one if the context isn't shared.
+/// - A cached view of the nftables tables, which lets adds skip listing the ruleset. The view is refreshed whenever the
+///   ruleset gets listed anyway and is dropped after a failed transaction, so that the next operation lists the ruleset
+///   again should anything other than fcnet have modified the table.
+/// - With the "namespaced" feature, a pool of worker threads that each run a single async executor of the [Backend] and
+///   enter the network namespaces of namespaced networks, instead of a new OS thread and executor per operation.
+///
+/// The free functions of this crate, such as [run](crate::run), create a transient context for every call.
+pub struct FirecrackerNetworkContext<B: Backend> {
+    netlink_handles: Mutex<Vec<rtnetlink::Handle>>,
+    nf_table_view: Mutex<Option<Nftables<'static>>>,
+    #[cfg(feature = "namespaced")]
+    netns_workers: Option<namespaced::NetNsWorkers>,
+    phantom: PhantomData<B>,
+}
+
+impl<B: Backend> FirecrackerNetworkContext<B> {
+    /// Create a context, which must be done within the async runtime of the [Backend] since the rtnetlink connection
+    /// is spawned onto it. With the "namespaced" feature, [DEFAULT_NETNS_WORKER_COUNT] netns worker threads are spawned.
+    pub fn new() -> Result<Self, FirecrackerNetworkError> {
+        #[cfg(feature = "namespaced")]
+        return Self::with_netns_workers(DEFAULT_NETNS_WORKER_COUNT);
+        #[cfg(not(feature = "namespaced"))]
+        Self::new_transient()
+    }
+
+    /// Create a context like [FirecrackerNetworkContext::new] does, but with the given number of netns worker threads,
+    /// which is raised to 1 if 0 is given.
+    #[cfg(feature = "namespaced")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+    pub fn with_netns_workers(netns_worker_count: usize) -> Result<Self, FirecrackerNetworkError> {
+        let mut context = Self::new_transient()?;
+        context.netns_workers = Some(namespaced::NetNsWorkers::new::<B>(netns_worker_count)?);
+        Ok(context)
+    }
+
+    /// Create a context without netns worker threads for a single call of a free function.
+    pub(crate) fn new_transient() -> Result<Self, FirecrackerNetworkError> {
+        let netlink_handle = new_netlink_handle::<B>()?;
+
+        Ok(Self {
+            netlink_handles: Mutex::new(vec![netlink_handle]),
+            nf_table_view: Mutex::new(None),
+            #[cfg(feature = "namespaced")]
+            netns_workers: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Run a [FirecrackerNetworkOperation] on a [FirecrackerNetwork], see [run](crate::run).
+    pub async fn run(
+        &self,
+        network: &FirecrackerNetwork,
+        operation: FirecrackerNetworkOperation,
+    ) -> Result<(), FirecrackerNetworkError> {
+        match &network.network_type {
+            #[cfg(feature = "simple")]
+            FirecrackerNetworkType::Simple => simple::run::<B>(network, self, operation).await,
+            #[cfg(feature = "namespaced")]
+            FirecrackerNetworkType::Namespaced {
+                netns_name: _,
+                veth1_name: _,
+                veth2_name: _,
+                veth1_ip: _,
+                veth2_ip: _,
+                forwarded_guest_ip: _,
+                netns_dir: _,
+                jailer: _,
+            } => namespaced::run::<B>(operation, network, self).await,
+        }
+    }
+
+    /// Run many [FirecrackerNetworkOperation]s on [FirecrackerNetwork]s at once, see [run_many](crate::run_many).
+    pub async fn run_many(
+        &self,
+        operations: &[(FirecrackerNetwork, FirecrackerNetworkOperation)],
+    ) -> Vec<Result<(), FirecrackerNetworkError>> {
+        many::run_many::<B>(operations, self).await
+    }
+
+    /// Check every object that is expected to exist on the host for a [FirecrackerNetwork], see [check](crate::check).
+    pub async fn check(&self, network: &FirecrackerNetwork) -> Result<FirecrackerNetworkCheckReport, FirecrackerNetworkError> {
+        match &network.network_type {
+            #[cfg(feature = "simple")]
+            FirecrackerNetworkType::Simple => simple::check::<B>(network, self).await,
+            #[cfg(feature = "namespaced")]
+            FirecrackerNetworkType::Namespaced {
+                netns_name: _,
+                veth1_name: _,
+                veth2_name: _,
+                veth1_ip: _,
+                veth2_ip: _,
+                forwarded_guest_ip: _,
+                netns_dir: _,
+                jailer: _,
+            } => namespaced::check_report::<B>(network, self).await,
+        }
+    }
+
+    /// Inspect the live state of a [FirecrackerNetwork], see [inspect](crate::inspect).
+    pub async fn inspect(&self, network: &FirecrackerNetwork) -> Result<FirecrackerNetworkSnapshot, FirecrackerNetworkError> {
+        match &network.network_type {
+            #[cfg(feature = "simple")]
+            FirecrackerNetworkType::Simple => simple::inspect::<B>(network, self).await,
+            #[cfg(feature = "namespaced")]
+            FirecrackerNetworkType::Namespaced {
+                netns_name: _,
+                veth1_name: _,
+                veth2_name: _,
+                veth1_ip: _,
+                veth2_ip: _,
+                forwarded_guest_ip: _,
+                netns_dir: _,
+                jailer: _,
+            } => namespaced::inspect_snapshot::<B>(network, self).await,
+        }
+    }
+
+    /// Replace the addresses and ports of the egress policy of a [FirecrackerNetwork] that has already been added, see
+    /// [update_egress_policy](crate::update_egress_policy).
+    pub async fn update_egress_policy(&self, network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+        egress::update_egress_policy::<B>(network, self).await
+    }
+
+    /// Read the traffic counted by the rules of a [FirecrackerNetwork], see
+    /// [get_traffic_counters](crate::get_traffic_counters).
+    pub async fn get_traffic_counters(
+        &self,
+        network: &FirecrackerNetwork,
+    ) -> Result<FirecrackerNetworkCounters, FirecrackerNetworkError> {
+        counters::get_traffic_counters::<B>(network, self).await
+    }
+
+    /// Read and then reset the traffic counted by the rules of a [FirecrackerNetwork], see
+    /// [reset_traffic_counters](crate::reset_traffic_counters).
+    pub async fn reset_traffic_counters(
+        &self,
+        network: &FirecrackerNetwork,
+    ) -> Result<FirecrackerNetworkCounters, FirecrackerNetworkError> {
+        counters::reset_traffic_counters::<B>(network, self).await
+    }
+
+    /// Delete the conntrack entries referencing the addresses of a [FirecrackerNetwork], see
+    /// [flush_conntrack](crate::flush_conntrack).
+    pub async fn flush_conntrack(&self, network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+        conntrack::flush_conntrack::<B>(network).await
+    }
+
+    /// Serve DHCP to the guest of a [FirecrackerNetwork] on its tap device, see [serve_dhcp](crate::serve_dhcp).
+    #[cfg(feature = "dhcp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dhcp")))]
+    pub async fn serve_dhcp(
+        &self,
+        network: &FirecrackerNetwork,
+        options: &crate::FirecrackerNetworkDhcpOptions,
+    ) -> Result<(), FirecrackerNetworkError> {
+        dhcp::serve_dhcp::<B>(network, options, self).await
+    }
+
+    /// Forward the DNS queries of the guest of a [FirecrackerNetwork] to upstreams, see [serve_dns](crate::serve_dns).
+    #[cfg(feature = "dns")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+    pub async fn serve_dns(
+        &self,
+        network: &FirecrackerNetwork,
+        options: &crate::FirecrackerNetworkDnsOptions,
+    ) -> Result<(), FirecrackerNetworkError> {
+        dns::serve_dns::<B>(network, options, self).await
+    }
+
+    /// Remove every object owned by fcnet on the host that doesn't belong to any of the given [FirecrackerNetwork]s,
+    /// see [collect_garbage](crate::collect_garbage).
+    pub async fn collect_garbage(
+        &self,
+        networks: &[FirecrackerNetwork],
+        options: &FirecrackerNetworkGcOptions,
+    ) -> Result<Vec<FirecrackerNetworkGcEntry>, FirecrackerNetworkError> {
+        gc::collect_garbage::<B>(networks, options, self).await
+    }
+
+    /// Lease an rtnetlink connection to the outer network namespace, creating one should all of them be leased.
+    pub(crate) fn netlink_handle(&self) -> Result<NetlinkHandleLease<'_>, FirecrackerNetworkError> {
+        let netlink_handle = self.netlink_handles.lock().unwrap_or_else(PoisonError::into_inner).pop();
+
+        Ok(NetlinkHandleLease {
+            netlink_handle: Some(match netlink_handle {
+                Some(netlink_handle) => netlink_handle,
+                None => new_netlink_handle::<B>()?,
+            }),
+            netlink_handles: &self.netlink_handles,
+        })
+    }
+
+    /// List the current ruleset of the outer network namespace, refreshing the cached view of the nftables tables.
+    pub(crate) async fn get_current_ruleset(
+        &self,
+        nft_program: Option<&str>,
+    ) -> Result<Nftables<'static>, FirecrackerNetworkError> {
+        self.get_current_ruleset_with_verdict_maps(nft_program)
+            .await
+            .map(|(current_ruleset, _)| current_ruleset)
+    }
+
+    /// List the current ruleset of the outer network namespace alongside its verdict maps, refreshing the cached view
+    /// of the nftables tables.
+    pub(crate) async fn get_current_ruleset_with_verdict_maps(
+        &self,
+        nft_program: Option<&str>,
+    ) -> Result<(Nftables<'static>, Vec<NfVerdictMap>), FirecrackerNetworkError> {
+        let (current_ruleset, verdict_maps) = get_current_ruleset_with_verdict_maps::<B>(nft_program).await?;
+
+        let objects = current_ruleset
+            .objects
+            .iter()
+            .filter(|object| match object {
+                NfObject::ListObject(NfListObject::Table(_) | NfListObject::Chain(_)) => true,
+                // of the rules, only those dispatching into the chains of networks are looked up when adding
+                NfObject::ListObject(NfListObject::Rule(rule)) => {
+                    rule.comment.as_deref().and_then(parse_nf_dispatch_rule_tag).is_some()
+                }
+                _ => false,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        *self.lock_nf_table_view() = Some(Nftables { objects: objects.into() });
+
+        Ok((current_ruleset, verdict_maps))
+    }
+
+    /// Build a changeset via the given function from a view of the nftables tables in the outer network namespace that
+    /// suffices for adding rules to it, which is the cached one if available unless the current ruleset is needed, and
+    /// apply it. Should the transaction fail with the cached view, for instance because the tables were flushed or
+    /// recreated outside of fcnet, the view is dropped and the changeset is built from a fresh listing and applied once
+    /// more. Returns the changeset that was applied.
+    pub(crate) async fn build_and_apply_nf_changeset(
+        &self,
+        nft_program: Option<&str>,
+        needs_current_ruleset: bool,
+        mut build_changeset: impl FnMut(&Nftables<'static>) -> Result<NfChangeset, FirecrackerNetworkError>,
+    ) -> Result<NfChangeset, FirecrackerNetworkError> {
+        let nf_table_view = match needs_current_ruleset {
+            true => None,
+            false => self.lock_nf_table_view().clone(),
+        };
+
+        if let Some(nf_table_view) = nf_table_view {
+            let changeset = build_changeset(&nf_table_view)?;
+
+            if self.apply_nf_changeset(&changeset, nft_program).await.is_ok() {
+                return Ok(changeset);
+            }
+        }
+
+        let current_ruleset = self.get_current_ruleset(nft_program).await?;
+        let changeset = build_changeset(&current_ruleset)?;
+        self.apply_nf_changeset(&changeset, nft_program).await?;
+        Ok(changeset)
+    }
+
+    /// Drop the cached view of the nftables tables, so that the next add lists the current ruleset again.
+    pub(crate) fn invalidate_nf_table_view(&self) {
+        *self.lock_nf_table_view() = None;
+    }
+
+    /// Apply the given changeset to the outer network namespace unless it is empty, keeping the cached view of the
+    /// nftables tables up to date with the base objects it adds or dropping it should the transaction fail.
+    pub(crate) async fn apply_nf_changeset(
+        &self,
+        changeset: &NfChangeset,
+        nft_program: Option<&str>,
+    ) -> Result<(), FirecrackerNetworkError> {
+        if changeset.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(err) = changeset.apply::<B>(nft_program).await {
+            self.invalidate_nf_table_view();
+            return Err(err);
+        }
+
+        if let Some(nf_table_view) = self.lock_nf_table_view().as_mut() {
+            nf_table_view
+                .objects
+                .to_mut()
+                .extend(changeset.base_objects().iter().cloned().map(NfObject::ListObject));
+        }
+
+        Ok(())
+    }
+
+    /// Run the given future on a thread that has entered the given network namespace: one of the netns worker threads if
+    /// this context has them, or otherwise a new OS thread.
+    #[cfg(feature = "namespaced")]
+    pub(crate) async fn run_in_netns<O: 'static + Send>(
+        &self,
+        netns: crate::netns::NetNs,
+        future: impl 'static + Send + std::future::Future<Output = Result<O, FirecrackerNetworkError>>,
+    ) -> Result<O, FirecrackerNetworkError> {
+        namespaced::run_in_netns::<B, O>(self.netns_workers.as_ref(), netns, future).await
+    }
+
+    #[inline]
+    fn lock_nf_table_view(&self) -> MutexGuard<'_, Option<Nftables<'static>>> {
+        // the view is replaced as a whole, so it can't be left in an inconsistent state by a panic
+        self.nf_table_view.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// An rtnetlink connection leased from a [FirecrackerNetworkContext], which is returned to it once dropped.
+pub(crate) struct NetlinkHandleLease<'a> {
+    netlink_handle: Option<rtnetlink::Handle>,
+    netlink_handles: &'a Mutex<Vec<rtnetlink::Handle>>,
+}
+
+impl Deref for NetlinkHandleLease<'_> {
+    type Target = rtnetlink::Handle;
+
+    fn deref(&self) -> &Self::Target {
+        self.netlink_handle.as_ref().expect("Netlink handle was already returned")
+    }
+}
+
+impl Drop for NetlinkHandleLease<'_> {
+    fn drop(&mut self) {
+        if let Some(netlink_handle) = self.netlink_handle.take() {
+            self.netlink_handles
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(netlink_handle);
+        }
+    }
+}
+
+fn new_netlink_handle<B: Backend>() -> Result<rtnetlink::Handle, FirecrackerNetworkError> {
+    let (connection, netlink_handle, _) =
+        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+    B::spawn_connection(connection);
+    Ok(netlink_handle)
+}
+
+impl<B: Backend> std::fmt::Debug for FirecrackerNetworkContext<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirecrackerNetworkContext").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,187 @@
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkCounters};
+use nftables::{
+    schema::{NfCmd, NfListObject, NfObject, Nftables, Rule},
+    stmt::{AnonymousCounter, Counter, Statement},
+};
+
+use crate::{
+    backend::Backend,
+    context::FirecrackerNetworkContext,
+    util::{apply_ruleset, nf_rule_tag_object_type, FirecrackerNetworkExt},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType,
+};
+
+/// The statements that end a rule with the given verdict, which are preceded by an anonymous counter if the verdict
+/// accepts or masquerades traffic, so that the traffic a network lets through can be read from its rules.
+pub fn counted(verdict: Statement<'static>) -> Vec<Statement<'static>> {
+    match verdict {
+        Statement::Accept(_) | Statement::Masquerade(_) => vec![zeroed_counter(), verdict],
+        verdict => vec![verdict],
+    }
+}
+
+/// Whether the given statements of two rules are equal besides their counters, which only listed rules carry the values
+/// of and which rules created by older versions of fcnet lack.
+pub fn statements_match(left: &[Statement], right: &[Statement]) -> bool {
+    uncounted(left).eq(uncounted(right))
+}
+
+#[inline]
+fn uncounted<'a, 'b>(statements: &'a [Statement<'b>]) -> impl Iterator<Item = &'a Statement<'b>> {
+    statements
+        .iter()
+        .filter(|statement| !matches!(statement, Statement::Counter(_)))
+}
+
+/// Read the traffic counted by the rules of a network, see [get_traffic_counters](crate::get_traffic_counters).
+pub async fn get_traffic_counters<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<FirecrackerNetworkCounters, FirecrackerNetworkError> {
+    let current_ruleset = context.get_current_ruleset(network.nft_program()).await?;
+    let (counters, _) = count_traffic(network, &current_ruleset)?;
+    Ok(counters)
+}
+
+/// Read and then reset the traffic counted by the rules of a network, see
+/// [reset_traffic_counters](crate::reset_traffic_counters).
+pub async fn reset_traffic_counters<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<FirecrackerNetworkCounters, FirecrackerNetworkError> {
+    let current_ruleset = context.get_current_ruleset(network.nft_program()).await?;
+    let (counters, counted_rules) = count_traffic(network, &current_ruleset)?;
+
+    let counted_rules = counted_rules.into_iter().cloned().collect::<Vec<_>>();
+
+    // the counters are read from the rules as they were right before being reset, so no traffic is lost in between
+    if let Some(reset_rules) = B::reset_nf_rules(&counted_rules)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?
+    {
+        let mut counters = FirecrackerNetworkCounters::default();
+
+        for rule in reset_rules.iter() {
+            if let Some(object_type) = counted_object_type(network, rule) {
+                add_traffic(&mut counters, object_type, rule);
+            }
+        }
+
+        return Ok(counters);
+    }
+
+    // otherwise, the counters are reset by replacing each counted rule with an identical one whose counter starts from
+    // zero, which loses the traffic counted since the listing and reverts changes made to the rules in the meantime
+    let objects = counted_rules
+        .into_iter()
+        .map(|mut rule| {
+            rule.expr = rule
+                .expr
+                .iter()
+                .map(|statement| match statement {
+                    Statement::Counter(_) => zeroed_counter(),
+                    statement => statement.clone(),
+                })
+                .collect::<Vec<_>>()
+                .into();
+            NfObject::CmdObject(NfCmd::Replace(rule))
+        })
+        .collect::<Vec<_>>();
+
+    if !objects.is_empty() {
+        apply_ruleset::<B>(&Nftables { objects: objects.into() }, network.nft_program()).await?;
+    }
+
+    Ok(counters)
+}
+
+/// Sum up the counters of the rules of a network in the current ruleset by the direction of the traffic they accept,
+/// returning the counters alongside the rules they were read from. Fails with [FirecrackerNetworkError::ObjectNotFound]
+/// should the network not have been added.
+fn count_traffic<'a>(
+    network: &FirecrackerNetwork,
+    current_ruleset: &'a Nftables<'static>,
+) -> Result<(FirecrackerNetworkCounters, Vec<&'a Rule<'static>>), FirecrackerNetworkError> {
+    let mut counters = FirecrackerNetworkCounters::default();
+    let mut counted_rules = Vec::new();
+    let mut has_egress_forward_rule = false;
+
+    for object in current_ruleset.objects.iter() {
+        let NfObject::ListObject(NfListObject::Rule(rule)) = object else {
+            continue;
+        };
+
+        let Some(object_type) = counted_object_type(network, rule) else {
+            continue;
+        };
+
+        has_egress_forward_rule |= object_type == FirecrackerNetworkObjectType::NfEgressForwardRule;
+
+        if add_traffic(&mut counters, object_type, rule) {
+            counted_rules.push(rule);
+        }
+    }
+
+    if !has_egress_forward_rule {
+        return Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::NfEgressForwardRule,
+        ));
+    }
+
+    Ok((counters, counted_rules))
+}
+
+/// The object type of the given rule if it's tagged as one of the rules of a network.
+fn counted_object_type(network: &FirecrackerNetwork, rule: &Rule) -> Option<FirecrackerNetworkObjectType> {
+    if rule.table != network.nf_table.name || rule.family != network.nf_family() {
+        return None;
+    }
+
+    rule.comment
+        .as_deref()
+        .and_then(|comment| nf_rule_tag_object_type(comment, network.resolved_id()))
+}
+
+/// Add the traffic counted by the given rule of a network to the counter of the direction of the traffic that rules of
+/// its object type accept, returning whether it counts traffic.
+fn add_traffic(counters: &mut FirecrackerNetworkCounters, object_type: FirecrackerNetworkObjectType, rule: &Rule) -> bool {
+    let counter = match object_type {
+        FirecrackerNetworkObjectType::NfEgressForwardRule | FirecrackerNetworkObjectType::NfEgressPolicyRule => {
+            &mut counters.egress
+        }
+        FirecrackerNetworkObjectType::NfIngressForwardRule
+        | FirecrackerNetworkObjectType::NfPortForwardRule
+        | FirecrackerNetworkObjectType::NfIngressExposedRule => &mut counters.ingress,
+        FirecrackerNetworkObjectType::NfMasqueradeRule => &mut counters.masquerade,
+        _ => return false,
+    };
+
+    match accepted_traffic(rule) {
+        Some((packets, bytes)) => {
+            counter.packets += packets;
+            counter.bytes += bytes;
+            true
+        }
+        None => false,
+    }
+}
+
+/// The packets and bytes counted by the counter of the given rule right before it accepts or masquerades traffic, if it
+/// has one. Counters before other verdicts are disregarded, as only the iptables driver lists them.
+fn accepted_traffic(rule: &Rule) -> Option<(u64, u64)> {
+    rule.expr.windows(2).find_map(|statements| match statements {
+        [Statement::Counter(Counter::Anonymous(counter)), Statement::Accept(_) | Statement::Masquerade(_)] => {
+            let counter = counter.clone().unwrap_or_default();
+            Some((
+                counter.packets.unwrap_or_default() as u64,
+                counter.bytes.unwrap_or_default() as u64,
+            ))
+        }
+        _ => None,
+    })
+}
+
+#[inline]
+fn zeroed_counter() -> Statement<'static> {
+    Statement::Counter(Counter::Anonymous(Some(AnonymousCounter::default())))
+}
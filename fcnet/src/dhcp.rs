@@ -0,0 +1,603 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use fcnet_types::{FirecrackerMacAddr, FirecrackerNetwork, FirecrackerNetworkType};
+
+#[cfg(feature = "namespaced")]
+use crate::namespaced;
+use crate::{
+    backend::{AsyncUdpSocket, Backend},
+    context::FirecrackerNetworkContext,
+    FirecrackerNetworkError,
+};
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHER: u8 = 1;
+const HLEN_ETHER: u8 = 6;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const MAGIC_COOKIE_OFFSET: usize = 236;
+const OPTIONS_OFFSET: usize = 240;
+// BOOTP relay agents and some clients drop messages shorter than that of BOOTP, which has 64 bytes of vendor options
+const MIN_MESSAGE_LEN: usize = 300;
+// the largest message that a client must be able to receive, which is also the largest one sent by well-behaved clients
+const MAX_MESSAGE_LEN: usize = 576;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+const MAX_OPTION_LEN: usize = u8::MAX as usize;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+const DHCPINFORM: u8 = 8;
+
+/// The lease time offered by a DHCP server run with the default [FirecrackerNetworkDhcpOptions], which is a day.
+pub const DEFAULT_DHCP_LEASE_TIME: u32 = 86400;
+
+/// The options of a DHCP server run by [serve_dhcp](crate::serve_dhcp).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FirecrackerNetworkDhcpOptions {
+    /// The DNS servers that are handed out to the guest, none by default.
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// The number of seconds that the guest IP is leased for, after half of which the guest renews its lease.
+    pub lease_time: u32,
+}
+
+impl Default for FirecrackerNetworkDhcpOptions {
+    fn default() -> Self {
+        Self {
+            dns_servers: Vec::new(),
+            lease_time: DEFAULT_DHCP_LEASE_TIME,
+        }
+    }
+}
+
+/// The addresses that a DHCP server of a network hands out, alongside the MAC of the guest it is limited to.
+struct DhcpLease<'a> {
+    guest_addr: Ipv4Addr,
+    tap_addr: Ipv4Addr,
+    subnet_mask: Ipv4Addr,
+    guest_mac: Option<FirecrackerMacAddr>,
+    options: &'a FirecrackerNetworkDhcpOptions,
+}
+
+/// A DHCP message sent by a client, of which only the options that a server of a single lease looks at are parsed.
+struct DhcpRequest<'a> {
+    message: &'a [u8],
+    message_type: u8,
+    requested_addr: Option<Ipv4Addr>,
+    server_id: Option<Ipv4Addr>,
+}
+
+/// Serve DHCP to the guest of a network on its tap device, see [serve_dhcp](crate::serve_dhcp).
+pub async fn serve_dhcp<B: Backend>(
+    network: &FirecrackerNetwork,
+    options: &FirecrackerNetworkDhcpOptions,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    let (IpAddr::V4(guest_addr), IpAddr::V4(tap_addr), IpAddr::V4(subnet_mask)) =
+        (network.guest_ip.address(), network.tap_ip.address(), network.guest_ip.mask())
+    else {
+        return Err(FirecrackerNetworkError::ForbiddenIpv6InDhcp);
+    };
+
+    let lease = DhcpLease {
+        guest_addr,
+        tap_addr,
+        subnet_mask,
+        guest_mac: network.anti_spoofing.map(|anti_spoofing| anti_spoofing.guest_mac),
+        options,
+    };
+    let socket = open_socket::<B>(network, context).await?;
+    let socket = B::UdpSocket::from_std(socket).map_err(FirecrackerNetworkError::IoError)?;
+    let mut buf = [0; MAX_MESSAGE_LEN];
+
+    loop {
+        let (len, _) = socket.recv_from(&mut buf).await.map_err(FirecrackerNetworkError::DhcpError)?;
+
+        let Some((reply, addr)) = parse_request(&buf[..len]).and_then(|request| lease.reply(&request)) else {
+            continue;
+        };
+
+        // a reply that can't be sent, such as while the guest hasn't attached to the tap device yet, is lost like any
+        // other datagram and the guest retransmits its request
+        let _ = socket.send_to(&reply, addr).await;
+    }
+}
+
+/// Open the socket of the DHCP server of a network within the network namespace that its tap device resides in, which
+/// it remains bound to when used from any other thread.
+#[cfg_attr(not(feature = "namespaced"), allow(unused_variables))]
+async fn open_socket<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<std::net::UdpSocket, FirecrackerNetworkError> {
+    match network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => open_udp_socket(&network.tap_name).map_err(FirecrackerNetworkError::IoError),
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced { .. } => {
+            let netns = namespaced::get_netns(network)?;
+            let tap_name = network.tap_name.clone();
+
+            context
+                .run_in_netns(netns, async move {
+                    open_udp_socket(&tap_name).map_err(FirecrackerNetworkError::IoError)
+                })
+                .await
+        }
+    }
+}
+
+/// Open a non-blocking UDP socket on the DHCP server port that only sends and receives via the given device. Other
+/// servers may be bound to the same port on other devices, which is why the address is reused.
+fn open_udp_socket(dev_name: &str) -> io::Result<std::net::UdpSocket> {
+    // SAFETY: socket has no memory safety preconditions
+    let fd = match unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) } {
+        -1 => return Err(io::Error::last_os_error()),
+        // SAFETY: the fd was just opened and isn't owned by anything else
+        fd => unsafe { OwnedFd::from_raw_fd(fd) },
+    };
+
+    set_socket_option(&fd, libc::SO_REUSEADDR, &1i32.to_ne_bytes())?;
+    set_socket_option(&fd, libc::SO_BROADCAST, &1i32.to_ne_bytes())?;
+    set_socket_option(&fd, libc::SO_BINDTODEVICE, dev_name.as_bytes())?;
+
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: DHCP_SERVER_PORT.to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(Ipv4Addr::UNSPECIFIED).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+
+    // SAFETY: the fd is valid for the duration of the call and bind only reads the given length of the address
+    match unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(std::net::UdpSocket::from(fd)),
+    }
+}
+
+fn set_socket_option(fd: &OwnedFd, option: libc::c_int, value: &[u8]) -> io::Result<()> {
+    // SAFETY: the fd is valid for the duration of the call and setsockopt only reads the given length of the value
+    match unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Parse a DHCP message sent by a client from an Ethernet interface, disregarding anything else arriving on the port.
+fn parse_request(message: &[u8]) -> Option<DhcpRequest<'_>> {
+    if message.len() < OPTIONS_OFFSET
+        || message[0] != BOOTREQUEST
+        || message[1] != HTYPE_ETHER
+        || message[2] != HLEN_ETHER
+        || message[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET] != MAGIC_COOKIE
+    {
+        return None;
+    }
+
+    let mut message_type = None;
+    let mut requested_addr = None;
+    let mut server_id = None;
+    let mut options = &message[OPTIONS_OFFSET..];
+
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+
+        let (len, rest) = rest.split_first()?;
+        let value = rest.get(..*len as usize)?;
+        options = &rest[*len as usize..];
+
+        match *code {
+            OPTION_MESSAGE_TYPE => message_type = value.first().copied(),
+            OPTION_REQUESTED_IP => requested_addr = parse_addr(value),
+            OPTION_SERVER_ID => server_id = parse_addr(value),
+            _ => {}
+        }
+    }
+
+    Some(DhcpRequest {
+        message,
+        message_type: message_type?,
+        requested_addr,
+        server_id,
+    })
+}
+
+impl DhcpLease<'_> {
+    /// The reply to a request alongside the address to send it to, if the request is to be answered. Requests that
+    /// select another server or come from another MAC than the guest's are ignored, while requests for anything other
+    /// than the guest IP are refused.
+    fn reply(&self, request: &DhcpRequest) -> Option<(Vec<u8>, SocketAddr)> {
+        if self.guest_mac.is_some_and(|guest_mac| request.message[28..34] != guest_mac.0) {
+            return None;
+        }
+
+        let client_addr = parse_addr(&request.message[12..16]).filter(|addr| !addr.is_unspecified());
+
+        let message_type = match request.message_type {
+            DHCPDISCOVER => DHCPOFFER,
+            DHCPREQUEST => {
+                if request.server_id.is_some_and(|server_id| server_id != self.tap_addr) {
+                    return None;
+                }
+
+                match request.requested_addr.or(client_addr) == Some(self.guest_addr) {
+                    true => DHCPACK,
+                    false => DHCPNAK,
+                }
+            }
+            DHCPINFORM => DHCPACK,
+            _ => return None,
+        };
+
+        let mut reply = vec![0; OPTIONS_OFFSET];
+        reply[0] = BOOTREPLY;
+        reply[1] = HTYPE_ETHER;
+        reply[2] = HLEN_ETHER;
+        // the transaction ID, seconds and flags, and the client IP
+        reply[4..16].copy_from_slice(&request.message[4..16]);
+        // the relay agent IP and client hardware address
+        reply[24..44].copy_from_slice(&request.message[24..44]);
+        reply[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut reply, OPTION_MESSAGE_TYPE, &[message_type]);
+        push_option(&mut reply, OPTION_SERVER_ID, &self.tap_addr.octets());
+
+        if message_type != DHCPNAK {
+            // an informing client already has an address, so it is only handed the configuration besides the lease
+            if request.message_type != DHCPINFORM {
+                reply[16..20].copy_from_slice(&self.guest_addr.octets());
+                push_option(&mut reply, OPTION_LEASE_TIME, &self.options.lease_time.to_be_bytes());
+            }
+
+            push_option(&mut reply, OPTION_SUBNET_MASK, &self.subnet_mask.octets());
+            push_option(&mut reply, OPTION_ROUTER, &self.tap_addr.octets());
+
+            if !self.options.dns_servers.is_empty() {
+                let dns_servers = self
+                    .options
+                    .dns_servers
+                    .iter()
+                    .take(MAX_OPTION_LEN / 4)
+                    .flat_map(|dns_server| dns_server.octets())
+                    .collect::<Vec<_>>();
+                push_option(&mut reply, OPTION_DNS_SERVERS, &dns_servers);
+            }
+        }
+
+        reply.push(OPTION_END);
+        reply.resize(reply.len().max(MIN_MESSAGE_LEN), OPTION_PAD);
+
+        // a client that already has an address can be reached by it, while any other client can't receive unicasts
+        // before it has configured the address it is offered
+        let addr = match client_addr {
+            Some(client_addr) if message_type != DHCPNAK => client_addr,
+            _ => Ipv4Addr::BROADCAST,
+        };
+
+        Some((reply, SocketAddr::V4(SocketAddrV4::new(addr, DHCP_CLIENT_PORT))))
+    }
+}
+
+#[inline]
+fn parse_addr(value: &[u8]) -> Option<Ipv4Addr> {
+    <[u8; 4]>::try_from(value).ok().map(Ipv4Addr::from)
+}
+
+#[inline]
+fn push_option(buf: &mut Vec<u8>, code: u8, value: &[u8]) {
+    buf.push(code);
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+    use fcnet_types::FirecrackerMacAddr;
+
+    use super::{
+        parse_request, DhcpLease, FirecrackerNetworkDhcpOptions, BOOTREPLY, BOOTREQUEST, DHCPACK, DHCPDISCOVER, DHCPINFORM,
+        DHCPNAK, DHCPOFFER, DHCPREQUEST, DHCP_CLIENT_PORT, HLEN_ETHER, HTYPE_ETHER, MAGIC_COOKIE, MAGIC_COOKIE_OFFSET,
+        MIN_MESSAGE_LEN, OPTIONS_OFFSET, OPTION_DNS_SERVERS, OPTION_END, OPTION_LEASE_TIME, OPTION_MESSAGE_TYPE,
+        OPTION_REQUESTED_IP, OPTION_ROUTER, OPTION_SERVER_ID, OPTION_SUBNET_MASK,
+    };
+
+    const GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 2);
+    const TAP_ADDR: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 1);
+    const GUEST_MAC: FirecrackerMacAddr = FirecrackerMacAddr([0x06, 0x00, 0xac, 0x10, 0x00, 0x02]);
+    const XID: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+    fn options() -> FirecrackerNetworkDhcpOptions {
+        FirecrackerNetworkDhcpOptions {
+            dns_servers: vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(8, 8, 8, 8)],
+            lease_time: 3600,
+        }
+    }
+
+    fn lease(options: &FirecrackerNetworkDhcpOptions, guest_mac: Option<FirecrackerMacAddr>) -> DhcpLease<'_> {
+        DhcpLease {
+            guest_addr: GUEST_ADDR,
+            tap_addr: TAP_ADDR,
+            subnet_mask: Ipv4Addr::new(255, 255, 255, 0),
+            guest_mac,
+            options,
+        }
+    }
+
+    /// A message sent by a client with the given MAC and client IP, padded like that of a DHCP client.
+    fn message(mac: FirecrackerMacAddr, client_addr: Ipv4Addr, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut message = vec![0; OPTIONS_OFFSET];
+        message[0] = BOOTREQUEST;
+        message[1] = HTYPE_ETHER;
+        message[2] = HLEN_ETHER;
+        message[4..8].copy_from_slice(&XID);
+        message[12..16].copy_from_slice(&client_addr.octets());
+        message[28..34].copy_from_slice(&mac.0);
+        message[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+        for (code, value) in options {
+            message.push(*code);
+            message.push(value.len() as u8);
+            message.extend_from_slice(value);
+        }
+
+        message.push(OPTION_END);
+        message.resize(MIN_MESSAGE_LEN, 0);
+        message
+    }
+
+    fn reply_to(lease: &DhcpLease, message: &[u8]) -> Option<(Vec<u8>, SocketAddr)> {
+        lease.reply(&parse_request(message).expect("message should be well-formed"))
+    }
+
+    /// The value of the given option of a reply.
+    fn reply_option(reply: &[u8], code: u8) -> Option<&[u8]> {
+        let mut options = &reply[OPTIONS_OFFSET..];
+
+        while let [option_code, len, rest @ ..] = options {
+            if *option_code == OPTION_END {
+                break;
+            }
+
+            let (value, rest) = rest.split_at(*len as usize);
+            if *option_code == code {
+                return Some(value);
+            }
+
+            options = rest;
+        }
+
+        None
+    }
+
+    #[track_caller]
+    fn assert_reply_header(reply: &[u8], message_type: u8, your_addr: Ipv4Addr) {
+        assert!(reply.len() >= MIN_MESSAGE_LEN);
+        assert_eq!(reply[0], BOOTREPLY);
+        assert_eq!(reply[4..8], XID);
+        assert_eq!(reply[16..20], your_addr.octets());
+        assert_eq!(reply[28..34], GUEST_MAC.0);
+        assert_eq!(reply[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET], MAGIC_COOKIE);
+        assert_eq!(reply_option(reply, OPTION_MESSAGE_TYPE), Some(&[message_type][..]));
+        assert_eq!(reply_option(reply, OPTION_SERVER_ID), Some(&TAP_ADDR.octets()[..]));
+    }
+
+    fn broadcast() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT))
+    }
+
+    #[test]
+    fn discover_is_offered_guest_ip() {
+        let options = options();
+        let lease = lease(&options, None);
+        let (reply, addr) = reply_to(
+            &lease,
+            &message(GUEST_MAC, Ipv4Addr::UNSPECIFIED, &[(OPTION_MESSAGE_TYPE, &[DHCPDISCOVER])]),
+        )
+        .expect("discover should be answered");
+
+        assert_eq!(addr, broadcast());
+        assert_reply_header(&reply, DHCPOFFER, GUEST_ADDR);
+        assert_eq!(reply_option(&reply, OPTION_LEASE_TIME), Some(&3600u32.to_be_bytes()[..]));
+        assert_eq!(reply_option(&reply, OPTION_SUBNET_MASK), Some(&[255, 255, 255, 0][..]));
+        assert_eq!(reply_option(&reply, OPTION_ROUTER), Some(&TAP_ADDR.octets()[..]));
+        assert_eq!(reply_option(&reply, OPTION_DNS_SERVERS), Some(&[1, 1, 1, 1, 8, 8, 8, 8][..]));
+    }
+
+    #[test]
+    fn request_for_guest_ip_is_acked() {
+        let options = FirecrackerNetworkDhcpOptions::default();
+        let lease = lease(&options, None);
+        let (reply, addr) = reply_to(
+            &lease,
+            &message(
+                GUEST_MAC,
+                Ipv4Addr::UNSPECIFIED,
+                &[
+                    (OPTION_MESSAGE_TYPE, &[DHCPREQUEST]),
+                    (OPTION_REQUESTED_IP, &GUEST_ADDR.octets()),
+                    (OPTION_SERVER_ID, &TAP_ADDR.octets()),
+                ],
+            ),
+        )
+        .expect("request should be answered");
+
+        assert_eq!(addr, broadcast());
+        assert_reply_header(&reply, DHCPACK, GUEST_ADDR);
+        assert_eq!(reply_option(&reply, OPTION_DNS_SERVERS), None);
+    }
+
+    #[test]
+    fn renewal_is_acked_by_unicast() {
+        let options = options();
+        let lease = lease(&options, None);
+        let (reply, addr) = reply_to(
+            &lease,
+            &message(GUEST_MAC, GUEST_ADDR, &[(OPTION_MESSAGE_TYPE, &[DHCPREQUEST])]),
+        )
+        .expect("renewal should be answered");
+
+        assert_eq!(addr, SocketAddr::V4(SocketAddrV4::new(GUEST_ADDR, DHCP_CLIENT_PORT)));
+        assert_reply_header(&reply, DHCPACK, GUEST_ADDR);
+    }
+
+    #[test]
+    fn request_for_another_address_is_refused() {
+        let options = options();
+        let lease = lease(&options, None);
+        let (reply, addr) = reply_to(
+            &lease,
+            &message(
+                GUEST_MAC,
+                Ipv4Addr::UNSPECIFIED,
+                &[(OPTION_MESSAGE_TYPE, &[DHCPREQUEST]), (OPTION_REQUESTED_IP, &[172, 16, 0, 3])],
+            ),
+        )
+        .expect("request should be answered");
+
+        assert_eq!(addr, broadcast());
+        assert_reply_header(&reply, DHCPNAK, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(reply_option(&reply, OPTION_LEASE_TIME), None);
+        assert_eq!(reply_option(&reply, OPTION_ROUTER), None);
+    }
+
+    #[test]
+    fn request_selecting_another_server_is_ignored() {
+        let options = options();
+        let lease = lease(&options, None);
+        let message = message(
+            GUEST_MAC,
+            Ipv4Addr::UNSPECIFIED,
+            &[
+                (OPTION_MESSAGE_TYPE, &[DHCPREQUEST]),
+                (OPTION_REQUESTED_IP, &GUEST_ADDR.octets()),
+                (OPTION_SERVER_ID, &[172, 16, 0, 254]),
+            ],
+        );
+
+        assert!(reply_to(&lease, &message).is_none());
+    }
+
+    #[test]
+    fn inform_is_acked_without_lease() {
+        let options = options();
+        let lease = lease(&options, None);
+        let (reply, addr) = reply_to(
+            &lease,
+            &message(GUEST_MAC, GUEST_ADDR, &[(OPTION_MESSAGE_TYPE, &[DHCPINFORM])]),
+        )
+        .expect("inform should be answered");
+
+        assert_eq!(addr, SocketAddr::V4(SocketAddrV4::new(GUEST_ADDR, DHCP_CLIENT_PORT)));
+        assert_reply_header(&reply, DHCPACK, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(reply_option(&reply, OPTION_LEASE_TIME), None);
+        assert_eq!(reply_option(&reply, OPTION_ROUTER), Some(&TAP_ADDR.octets()[..]));
+    }
+
+    #[test]
+    fn foreign_mac_is_ignored() {
+        let options = options();
+        let lease = lease(&options, Some(GUEST_MAC));
+        let foreign_mac = FirecrackerMacAddr([0x06, 0x00, 0xac, 0x10, 0x00, 0x03]);
+
+        let foreign = message(foreign_mac, Ipv4Addr::UNSPECIFIED, &[(OPTION_MESSAGE_TYPE, &[DHCPDISCOVER])]);
+        assert!(reply_to(&lease, &foreign).is_none());
+
+        let own = message(GUEST_MAC, Ipv4Addr::UNSPECIFIED, &[(OPTION_MESSAGE_TYPE, &[DHCPDISCOVER])]);
+        assert!(reply_to(&lease, &own).is_some());
+    }
+
+    #[test]
+    fn malformed_messages_are_ignored() {
+        let discover = message(GUEST_MAC, Ipv4Addr::UNSPECIFIED, &[(OPTION_MESSAGE_TYPE, &[DHCPDISCOVER])]);
+
+        let mut reply = discover.clone();
+        reply[0] = BOOTREPLY;
+        let mut not_ethernet = discover.clone();
+        not_ethernet[1] = 6;
+        let mut no_cookie = discover.clone();
+        no_cookie[MAGIC_COOKIE_OFFSET] = 0;
+        // an option whose length runs past the end of the message
+        let mut overlong_option = discover[..OPTIONS_OFFSET].to_vec();
+        overlong_option.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, DHCPDISCOVER, OPTION_REQUESTED_IP, 4, 172]);
+        let no_message_type = message(
+            GUEST_MAC,
+            Ipv4Addr::UNSPECIFIED,
+            &[(OPTION_REQUESTED_IP, &GUEST_ADDR.octets())],
+        );
+
+        for message in [reply, not_ethernet, no_cookie, overlong_option, no_message_type] {
+            assert!(parse_request(&message).is_none());
+        }
+
+        // messages of a type that a server doesn't answer are parsed but ignored
+        let options = options();
+        let decline = message(GUEST_MAC, Ipv4Addr::UNSPECIFIED, &[(OPTION_MESSAGE_TYPE, &[4])]);
+        assert!(reply_to(&lease(&options, None), &decline).is_none());
+    }
+
+    #[test]
+    fn truncated_messages_are_handled_without_panicking() {
+        let options = options();
+        let lease = lease(&options, Some(GUEST_MAC));
+        let request = message(
+            GUEST_MAC,
+            Ipv4Addr::UNSPECIFIED,
+            &[
+                (OPTION_MESSAGE_TYPE, &[DHCPREQUEST]),
+                (OPTION_REQUESTED_IP, &GUEST_ADDR.octets()),
+                (OPTION_SERVER_ID, &TAP_ADDR.octets()),
+            ],
+        );
+
+        // truncating the message within the options only leaves a request if an option ends right before the cut
+        for len in 0..request.len() {
+            if let Some(parsed) = parse_request(&request[..len]) {
+                assert!(len > OPTIONS_OFFSET, "{len}");
+                let _ = lease.reply(&parsed);
+            }
+        }
+    }
+}
@@ -0,0 +1,756 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    pin::pin,
+};
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkType};
+use futures_util::future::{select, Either};
+
+#[cfg(feature = "namespaced")]
+use crate::namespaced;
+use crate::{
+    backend::{AsyncUdpSocket, Backend},
+    context::FirecrackerNetworkContext,
+    FirecrackerNetworkError,
+};
+
+const DNS_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+const MAX_LABEL_LEN: usize = 63;
+/// The number of queries forwarded via an upstream socket before it is replaced by one bound to another random port.
+const UPSTREAM_SOCKET_QUERIES: usize = 16;
+/// The number of queries that may await their replies at once, beyond which the oldest one is forgotten.
+const MAX_PENDING_QUERIES: usize = 1024;
+
+// the flags in the third byte of the header: whether the message is a response, its opcode and whether recursion is
+// desired, and in the fourth byte: whether recursion is available and the response code
+const FLAG_QR: u8 = 0x80;
+const OPCODE_MASK: u8 = 0x78;
+const FLAG_RD: u8 = 0x01;
+const FLAG_RA: u8 = 0x80;
+
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+
+/// The options of a DNS forwarder run by [serve_dns](crate::serve_dns).
+///
+/// Domains match themselves and all of their subdomains, case-insensitively and regardless of a trailing dot, so that
+/// "example.com" matches both "example.com" and "www.example.com" but not "notexample.com".
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FirecrackerNetworkDnsOptions {
+    /// The upstream resolvers that queries are forwarded to, starting with the first one. A query that the guest
+    /// retransmits while awaiting the reply is forwarded to the next one. Without upstreams, every query that isn't
+    /// denied fails.
+    pub upstreams: Vec<SocketAddr>,
+    /// The domains that the guest may resolve, or all domains if none are specified.
+    pub allowed_domains: Vec<String>,
+    /// The domains that the guest may not resolve even if they are allowed, which are answered with NXDOMAIN.
+    pub denied_domains: Vec<String>,
+}
+
+/// The only question of a query or reply, whose name is lowercased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DnsQuestion {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    end: usize,
+}
+
+/// A query forwarded upstream under a random ID of the forwarder, which its reply is matched by alongside its question.
+struct PendingQuery {
+    client_addr: SocketAddr,
+    client_id: u16,
+    question: DnsQuestion,
+    upstream_index: usize,
+    sequence: u64,
+}
+
+/// What to do with a query received from the guest.
+enum DnsAction {
+    Forward(SocketAddr),
+    Reply(Vec<u8>),
+}
+
+/// The state of the DNS forwarder of a network: the queries it awaits the replies to, which are keyed both by the ID
+/// they were forwarded under and by the client and ID they were received with, and ordered by when they were forwarded.
+struct DnsForwarder<'a> {
+    network_id: &'a str,
+    upstream_addrs: Vec<SocketAddr>,
+    allowed_domains: Vec<String>,
+    denied_domains: Vec<String>,
+    pending_queries: HashMap<u16, PendingQuery>,
+    upstream_ids: HashMap<(SocketAddr, u16), u16>,
+    next_sequence: u64,
+}
+
+/// Forward the DNS queries of the guest of a network to its upstreams, see [serve_dns](crate::serve_dns).
+pub async fn serve_dns<B: Backend>(
+    network: &FirecrackerNetwork,
+    options: &FirecrackerNetworkDnsOptions,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    let socket = open_socket::<B>(network, context).await?;
+    let socket = B::UdpSocket::from_std(socket).map_err(FirecrackerNetworkError::IoError)?;
+    let ipv6_upstreams = options.upstreams.iter().any(SocketAddr::is_ipv6);
+    // the current upstream socket forwards queries, while the previous one still receives the replies to those it forwarded
+    let mut upstream_sockets = [
+        open_upstream_socket::<B>(ipv6_upstreams)?,
+        open_upstream_socket::<B>(ipv6_upstreams)?,
+    ];
+    let mut upstream_socket_queries = 0;
+
+    let mut forwarder = DnsForwarder::new(
+        network.resolved_id(),
+        upstream_addrs(&options.upstreams, ipv6_upstreams),
+        options,
+    );
+    let mut query_buf = vec![0; MAX_MESSAGE_LEN];
+    let mut reply_bufs = [vec![0; MAX_MESSAGE_LEN], vec![0; MAX_MESSAGE_LEN]];
+
+    if forwarder.upstream_addrs.is_empty() {
+        tracing::warn!(
+            network = forwarder.network_id,
+            "Serving DNS without upstreams fails every query that isn't denied"
+        );
+    }
+
+    tracing::info!(network = forwarder.network_id, addr = %network.tap_ip.address(), "Serving DNS to the guest");
+
+    loop {
+        let [current_socket, previous_socket] = &upstream_sockets;
+        let [current_reply_buf, previous_reply_buf] = &mut reply_bufs;
+
+        // receiving is cancel-safe, so the receives that didn't complete can be dropped and started again
+        let received = match select(
+            pin!(socket.recv_from(&mut query_buf)),
+            select(
+                pin!(current_socket.recv_from(current_reply_buf)),
+                pin!(previous_socket.recv_from(previous_reply_buf)),
+            ),
+        )
+        .await
+        {
+            Either::Left((result, _)) => Either::Left(result.map_err(FirecrackerNetworkError::DnsError)?),
+            Either::Right((Either::Left((result, _)), _)) => {
+                Either::Right((result.map_err(FirecrackerNetworkError::DnsError)?, 0))
+            }
+            Either::Right((Either::Right((result, _)), _)) => {
+                Either::Right((result.map_err(FirecrackerNetworkError::DnsError)?, 1))
+            }
+        };
+
+        match received {
+            Either::Left((len, client_addr)) => match forwarder.query(&mut query_buf[..len], client_addr) {
+                Some(DnsAction::Forward(upstream_addr)) => {
+                    // the guest retransmits the query should no reply arrive, which is then forwarded to the next upstream
+                    if let Err(err) = upstream_sockets[0].send_to(&query_buf[..len], upstream_addr).await {
+                        tracing::warn!(network = forwarder.network_id, %upstream_addr, ?err, "Could not forward a DNS query");
+                    }
+
+                    upstream_socket_queries += 1;
+
+                    if upstream_socket_queries == UPSTREAM_SOCKET_QUERIES {
+                        upstream_socket_queries = 0;
+
+                        match open_upstream_socket::<B>(ipv6_upstreams) {
+                            Ok(upstream_socket) => {
+                                upstream_sockets[1] = std::mem::replace(&mut upstream_sockets[0], upstream_socket);
+                            }
+                            Err(err) => {
+                                tracing::warn!(network = forwarder.network_id, ?err, "Could not replace the upstream socket")
+                            }
+                        }
+                    }
+                }
+                Some(DnsAction::Reply(reply)) => {
+                    let _ = socket.send_to(&reply, client_addr).await;
+                }
+                None => {}
+            },
+            Either::Right(((len, upstream_addr), socket_index)) => {
+                let reply = &mut reply_bufs[socket_index][..len];
+
+                if let Some(client_addr) = forwarder.reply(reply, upstream_addr) {
+                    let _ = socket.send_to(reply, client_addr).await;
+                }
+            }
+        }
+    }
+}
+
+/// Open the socket that receives the queries of the guest on the tap IP of a network within the network namespace that
+/// its tap device resides in, which it remains bound to when used from any other thread.
+#[cfg_attr(not(feature = "namespaced"), allow(unused_variables))]
+async fn open_socket<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<UdpSocket, FirecrackerNetworkError> {
+    let addr = SocketAddr::new(network.tap_ip.address(), DNS_PORT);
+
+    match network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => open_udp_socket(addr).map_err(FirecrackerNetworkError::IoError),
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced { .. } => {
+            let netns = namespaced::get_netns(network)?;
+
+            context
+                .run_in_netns(netns, async move {
+                    open_udp_socket(addr).map_err(FirecrackerNetworkError::IoError)
+                })
+                .await
+        }
+    }
+}
+
+/// Open a socket that queries are forwarded upstream with in the network namespace of the calling thread, which is the
+/// outer one, so that the upstreams are reached like from the host instead of being subject to the policies of the
+/// network. The socket is bound to a port picked at random by the kernel, and is an IPv6 socket if any upstream is an
+/// IPv6 one.
+fn open_upstream_socket<B: Backend>(ipv6: bool) -> Result<B::UdpSocket, FirecrackerNetworkError> {
+    let addr = match ipv6 {
+        true => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        false => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+
+    open_udp_socket(SocketAddr::new(addr, 0))
+        .and_then(B::UdpSocket::from_std)
+        .map_err(FirecrackerNetworkError::IoError)
+}
+
+/// The addresses that the given upstreams are reached at from an upstream socket, which reaches IPv4 upstreams via their
+/// IPv4-mapped addresses if it is an IPv6 socket.
+fn upstream_addrs(upstreams: &[SocketAddr], ipv6: bool) -> Vec<SocketAddr> {
+    upstreams
+        .iter()
+        .map(|upstream| match upstream.ip() {
+            IpAddr::V4(addr) if ipv6 => SocketAddr::new(IpAddr::V6(addr.to_ipv6_mapped()), upstream.port()),
+            _ => *upstream,
+        })
+        .collect()
+}
+
+fn open_udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+impl<'a> DnsForwarder<'a> {
+    fn new(network_id: &'a str, upstream_addrs: Vec<SocketAddr>, options: &FirecrackerNetworkDnsOptions) -> Self {
+        Self {
+            network_id,
+            upstream_addrs,
+            allowed_domains: options
+                .allowed_domains
+                .iter()
+                .map(|domain| normalize_domain(domain))
+                .collect(),
+            denied_domains: options.denied_domains.iter().map(|domain| normalize_domain(domain)).collect(),
+            pending_queries: HashMap::new(),
+            upstream_ids: HashMap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Decide what to do with a query received from the guest, replacing its ID with the one it is forwarded under.
+    /// Anything that isn't a query is disregarded.
+    fn query(&mut self, query: &mut [u8], client_addr: SocketAddr) -> Option<DnsAction> {
+        if query.len() < HEADER_LEN || query[2] & FLAG_QR != 0 {
+            return None;
+        }
+
+        if query[2] & OPCODE_MASK != 0 {
+            return Some(DnsAction::Reply(error_reply(query, None, RCODE_NOTIMP)));
+        }
+
+        let Some(question) = parse_question(query) else {
+            return Some(DnsAction::Reply(error_reply(query, None, RCODE_FORMERR)));
+        };
+
+        if !self.is_allowed(&question.name) {
+            tracing::debug!(
+                network = self.network_id,
+                %client_addr,
+                name = question.name,
+                qtype = question.qtype,
+                "Denied a DNS query"
+            );
+            return Some(DnsAction::Reply(error_reply(query, Some(question.end), RCODE_NXDOMAIN)));
+        }
+
+        if self.upstream_addrs.is_empty() {
+            tracing::debug!(
+                network = self.network_id,
+                %client_addr,
+                name = question.name,
+                qtype = question.qtype,
+                "Failed a DNS query without upstreams"
+            );
+            return Some(DnsAction::Reply(error_reply(query, Some(question.end), RCODE_SERVFAIL)));
+        }
+
+        let client_id = u16::from_be_bytes([query[0], query[1]]);
+        let (upstream_id, upstream_index) = match self.upstream_ids.get(&(client_addr, client_id)) {
+            // a query is only retransmitted while awaiting its reply, so the upstream it was forwarded to may be down
+            Some(upstream_id) => {
+                let pending_query = self.pending_queries.get_mut(upstream_id)?;
+                pending_query.upstream_index = (pending_query.upstream_index + 1) % self.upstream_addrs.len();
+                (*upstream_id, pending_query.upstream_index)
+            }
+            None => {
+                if self.pending_queries.len() >= MAX_PENDING_QUERIES {
+                    self.evict_oldest_query();
+                }
+
+                // the ID is random so that replies can't be spoofed by guessing it
+                let upstream_id = match self.unused_upstream_id() {
+                    Ok(upstream_id) => upstream_id,
+                    Err(err) => {
+                        tracing::warn!(
+                            network = self.network_id,
+                            ?err,
+                            "Could not pick the ID to forward a DNS query under"
+                        );
+                        return Some(DnsAction::Reply(error_reply(query, Some(question.end), RCODE_SERVFAIL)));
+                    }
+                };
+
+                self.pending_queries.insert(
+                    upstream_id,
+                    PendingQuery {
+                        client_addr,
+                        client_id,
+                        question: question.clone(),
+                        upstream_index: 0,
+                        sequence: self.next_sequence,
+                    },
+                );
+                self.upstream_ids.insert((client_addr, client_id), upstream_id);
+                self.next_sequence += 1;
+                (upstream_id, 0)
+            }
+        };
+
+        let upstream_addr = self.upstream_addrs[upstream_index];
+        tracing::debug!(
+            network = self.network_id,
+            %client_addr,
+            name = question.name,
+            qtype = question.qtype,
+            %upstream_addr,
+            "Forwarded a DNS query"
+        );
+
+        query[..2].copy_from_slice(&upstream_id.to_be_bytes());
+        Some(DnsAction::Forward(upstream_addr))
+    }
+
+    /// Match a reply received from an upstream to the query it answers, restoring the ID that the query was received
+    /// with and returning the client to relay it to. Replies from anything but an upstream are disregarded.
+    fn reply(&mut self, reply: &mut [u8], upstream_addr: SocketAddr) -> Option<SocketAddr> {
+        if reply.len() < HEADER_LEN || reply[2] & FLAG_QR == 0 || !self.upstream_addrs.contains(&upstream_addr) {
+            return None;
+        }
+
+        // a reply that doesn't echo the question of the query awaiting it is spoofed or stale, so the query keeps
+        // awaiting its reply
+        let upstream_id = u16::from_be_bytes([reply[0], reply[1]]);
+        if self.pending_queries.get(&upstream_id)?.question != parse_question(reply)? {
+            return None;
+        }
+
+        let pending_query = self.pending_queries.remove(&upstream_id)?;
+        self.upstream_ids
+            .remove(&(pending_query.client_addr, pending_query.client_id));
+
+        reply[..2].copy_from_slice(&pending_query.client_id.to_be_bytes());
+        Some(pending_query.client_addr)
+    }
+
+    fn evict_oldest_query(&mut self) {
+        let Some(upstream_id) = self
+            .pending_queries
+            .iter()
+            .min_by_key(|(_, pending_query)| pending_query.sequence)
+            .map(|(upstream_id, _)| *upstream_id)
+        else {
+            return;
+        };
+
+        if let Some(evicted_query) = self.pending_queries.remove(&upstream_id) {
+            self.upstream_ids
+                .remove(&(evicted_query.client_addr, evicted_query.client_id));
+        }
+    }
+
+    fn unused_upstream_id(&self) -> io::Result<u16> {
+        loop {
+            let upstream_id = random_id()?;
+
+            if !self.pending_queries.contains_key(&upstream_id) {
+                return Ok(upstream_id);
+            }
+        }
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        (self.allowed_domains.is_empty() || self.allowed_domains.iter().any(|domain| matches_domain(name, domain)))
+            && !self.denied_domains.iter().any(|domain| matches_domain(name, domain))
+    }
+}
+
+/// Parse the question of a query or reply, which must be the only one and can't be compressed, as there is nothing
+/// before it. Labels containing dots are refused, since their name couldn't be told apart from one with more labels.
+fn parse_question(query: &[u8]) -> Option<DnsQuestion> {
+    if query.get(4..6)? != [0, 1] {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut offset = HEADER_LEN;
+
+    loop {
+        let len = *query.get(offset)? as usize;
+        offset += 1;
+
+        match len {
+            0 => break,
+            len if len > MAX_LABEL_LEN => return None,
+            len => {
+                let label = query.get(offset..offset + len)?;
+
+                if label.contains(&b'.') {
+                    return None;
+                }
+
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += len;
+            }
+        }
+    }
+
+    let qtype = u16::from_be_bytes([*query.get(offset)?, *query.get(offset + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(offset + 2)?, *query.get(offset + 3)?]);
+
+    Some(DnsQuestion {
+        name: labels.join("."),
+        qtype,
+        qclass,
+        end: offset + 4,
+    })
+}
+
+/// A random ID to forward a query under.
+fn random_id() -> io::Result<u16> {
+    let mut id = [0; 2];
+
+    // SAFETY: getrandom only writes up to the given length into the buffer, which is valid for the duration of the call
+    match unsafe { libc::getrandom(id.as_mut_ptr() as *mut libc::c_void, id.len(), 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        len if len as usize == id.len() => Ok(u16::from_ne_bytes(id)),
+        _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+/// A reply to a query that fails with the given response code, echoing the question of the query if it was parsed.
+fn error_reply(query: &[u8], question_end: Option<usize>, rcode: u8) -> Vec<u8> {
+    let mut reply = query[..question_end.unwrap_or(HEADER_LEN)].to_vec();
+    reply[2] = FLAG_QR | (query[2] & (OPCODE_MASK | FLAG_RD));
+    reply[3] = FLAG_RA | rcode;
+    reply[4..6].copy_from_slice(&u16::from(question_end.is_some()).to_be_bytes());
+    reply[6..HEADER_LEN].fill(0);
+    reply
+}
+
+#[inline]
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[inline]
+fn matches_domain(name: &str, domain: &str) -> bool {
+    name == domain || name.strip_suffix(domain).is_some_and(|subdomain| subdomain.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{
+        error_reply, matches_domain, normalize_domain, parse_question, DnsAction, DnsForwarder, DnsQuestion,
+        FirecrackerNetworkDnsOptions, FLAG_QR, FLAG_RA, FLAG_RD, HEADER_LEN, MAX_PENDING_QUERIES, RCODE_FORMERR, RCODE_NOTIMP,
+        RCODE_NXDOMAIN, RCODE_SERVFAIL,
+    };
+
+    const QTYPE_A: u16 = 1;
+    const QCLASS_IN: u16 = 1;
+
+    /// A query with the given ID for the given labels, which may be given with uppercase letters or dots.
+    fn query(id: u16, labels: &[&[u8]], qtype: u16) -> Vec<u8> {
+        let mut query = Vec::new();
+        query.extend_from_slice(&id.to_be_bytes());
+        query.extend_from_slice(&[FLAG_RD, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        for label in labels {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label);
+        }
+
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&QCLASS_IN.to_be_bytes());
+        query
+    }
+
+    /// The reply of an upstream to the given query, with a single answer that isn't looked at.
+    fn reply(query: &[u8]) -> Vec<u8> {
+        let mut reply = query.to_vec();
+        reply[2] |= FLAG_QR;
+        reply[3] = FLAG_RA;
+        reply[6..8].copy_from_slice(&1u16.to_be_bytes());
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        reply
+    }
+
+    fn client_addr() -> SocketAddr {
+        "172.16.0.2:40000".parse().unwrap()
+    }
+
+    fn upstream_addrs() -> Vec<SocketAddr> {
+        vec!["1.1.1.1:53".parse().unwrap(), "8.8.8.8:53".parse().unwrap()]
+    }
+
+    fn dns_options(allowed_domains: &[&str], denied_domains: &[&str]) -> FirecrackerNetworkDnsOptions {
+        FirecrackerNetworkDnsOptions {
+            upstreams: Vec::new(),
+            allowed_domains: allowed_domains.iter().map(|domain| domain.to_string()).collect(),
+            denied_domains: denied_domains.iter().map(|domain| domain.to_string()).collect(),
+        }
+    }
+
+    #[track_caller]
+    fn assert_error_reply(action: Option<DnsAction>, query: &[u8], rcode: u8) {
+        let Some(DnsAction::Reply(reply)) = action else {
+            panic!("query should be answered by the forwarder");
+        };
+
+        assert_eq!(reply[..2], query[..2]);
+        assert_eq!(reply[2], FLAG_QR | FLAG_RD);
+        assert_eq!(reply[3], FLAG_RA | rcode);
+    }
+
+    #[test]
+    fn question_is_parsed() {
+        let query = query(1, &[b"www", b"Example", b"COM"], QTYPE_A);
+
+        assert_eq!(
+            parse_question(&query),
+            Some(DnsQuestion {
+                name: "www.example.com".to_string(),
+                qtype: QTYPE_A,
+                qclass: QCLASS_IN,
+                end: query.len(),
+            })
+        );
+    }
+
+    #[test]
+    fn root_question_is_parsed() {
+        let query = query(1, &[], 2);
+        assert_eq!(parse_question(&query).map(|question| question.name), Some(String::new()));
+    }
+
+    #[test]
+    fn malformed_questions_are_rejected() {
+        // a label that contains a dot would otherwise match "example.com"
+        assert_eq!(parse_question(&query(1, &[b"example.com"], QTYPE_A)), None);
+
+        // labels longer than 63 bytes, including compression pointers, which can't point anywhere before the question
+        assert_eq!(parse_question(&query(1, &[&[b'a'; 64]], QTYPE_A)), None);
+        let mut compressed = query(1, &[], QTYPE_A);
+        compressed.splice(HEADER_LEN..HEADER_LEN + 1, [0xc0, 0x0c]);
+        assert_eq!(parse_question(&compressed), None);
+
+        let mut no_question = query(1, &[b"example", b"com"], QTYPE_A);
+        no_question[5] = 0;
+        assert_eq!(parse_question(&no_question), None);
+
+        let mut two_questions = query(1, &[b"example", b"com"], QTYPE_A);
+        two_questions[5] = 2;
+        assert_eq!(parse_question(&two_questions), None);
+    }
+
+    #[test]
+    fn truncated_questions_are_rejected() {
+        let query = query(1, &[b"www", b"example", b"com"], QTYPE_A);
+
+        for len in 0..query.len() {
+            assert_eq!(parse_question(&query[..len]), None, "{len}");
+        }
+    }
+
+    #[test]
+    fn error_reply_echoes_question() {
+        let query = query(0x1234, &[b"example", b"com"], QTYPE_A);
+        let reply = error_reply(&query, Some(query.len()), RCODE_NXDOMAIN);
+
+        assert_eq!(reply.len(), query.len());
+        assert_eq!(reply[..2], [0x12, 0x34]);
+        assert_eq!(reply[2], FLAG_QR | FLAG_RD);
+        assert_eq!(reply[3], FLAG_RA | RCODE_NXDOMAIN);
+        assert_eq!(reply[4..HEADER_LEN], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(reply[HEADER_LEN..], query[HEADER_LEN..]);
+    }
+
+    #[test]
+    fn error_reply_without_question_is_header() {
+        let mut query = query(0x1234, &[b"example", b"com"], QTYPE_A);
+        // an inverse query, whose opcode is echoed
+        query[2] |= 0x08;
+        let reply = error_reply(&query, None, RCODE_NOTIMP);
+
+        assert_eq!(reply.len(), HEADER_LEN);
+        assert_eq!(reply[2], FLAG_QR | 0x08 | FLAG_RD);
+        assert_eq!(reply[3], FLAG_RA | RCODE_NOTIMP);
+        assert_eq!(reply[4..], [0; 8]);
+    }
+
+    #[test]
+    fn domains_match_themselves_and_subdomains() {
+        let domain = normalize_domain("Example.COM.");
+        assert_eq!(domain, "example.com");
+
+        assert!(matches_domain("example.com", &domain));
+        assert!(matches_domain("www.example.com", &domain));
+        assert!(matches_domain("a.b.example.com", &domain));
+        assert!(!matches_domain("notexample.com", &domain));
+        assert!(!matches_domain("example.com.evil", &domain));
+        assert!(!matches_domain("com", &domain));
+    }
+
+    #[test]
+    fn denied_domains_override_allowed_ones() {
+        let options = dns_options(&["example.com", "test."], &["ads.example.com"]);
+        let forwarder = DnsForwarder::new("test", upstream_addrs(), &options);
+
+        assert!(forwarder.is_allowed("example.com"));
+        assert!(forwarder.is_allowed("www.example.com"));
+        assert!(forwarder.is_allowed("foo.test"));
+        assert!(!forwarder.is_allowed("ads.example.com"));
+        assert!(!forwarder.is_allowed("x.ads.example.com"));
+        assert!(!forwarder.is_allowed("notexample.com"));
+
+        let options = dns_options(&[], &["ads.example.com"]);
+        let forwarder = DnsForwarder::new("test", upstream_addrs(), &options);
+        assert!(forwarder.is_allowed("anything.org"));
+        assert!(!forwarder.is_allowed("ads.example.com"));
+    }
+
+    #[test]
+    fn queries_are_answered_by_forwarder() {
+        let options = dns_options(&["example.com"], &[]);
+        let mut forwarder = DnsForwarder::new("test", upstream_addrs(), &options);
+
+        let mut denied = query(1, &[b"example", b"org"], QTYPE_A);
+        let action = forwarder.query(&mut denied, client_addr());
+        assert_error_reply(action, &denied, RCODE_NXDOMAIN);
+
+        let mut dotted = query(2, &[b"evil.org", b"example", b"com"], QTYPE_A);
+        let action = forwarder.query(&mut dotted, client_addr());
+        assert_error_reply(action, &dotted, RCODE_FORMERR);
+
+        let mut no_upstreams = DnsForwarder::new("test", Vec::new(), &options);
+        let mut allowed = query(3, &[b"example", b"com"], QTYPE_A);
+        let action = no_upstreams.query(&mut allowed, client_addr());
+        assert_error_reply(action, &allowed, RCODE_SERVFAIL);
+
+        // replies that arrive on the port of the forwarder aren't answered
+        let mut reply = reply(&query(4, &[b"example", b"com"], QTYPE_A));
+        assert!(forwarder.query(&mut reply, client_addr()).is_none());
+        assert!(forwarder.pending_queries.is_empty());
+    }
+
+    #[test]
+    fn reply_is_relayed_under_client_id() {
+        let options = dns_options(&[], &[]);
+        let mut forwarder = DnsForwarder::new("test", upstream_addrs(), &options);
+        let mut query = query(0x4242, &[b"example", b"com"], QTYPE_A);
+
+        let Some(DnsAction::Forward(upstream_addr)) = forwarder.query(&mut query, client_addr()) else {
+            panic!("query should be forwarded");
+        };
+        assert_eq!(upstream_addr, upstream_addrs()[0]);
+
+        // a retransmission is forwarded to the next upstream under the same ID
+        let upstream_id = query[..2].to_vec();
+        query[..2].copy_from_slice(&0x4242u16.to_be_bytes());
+        let Some(DnsAction::Forward(upstream_addr)) = forwarder.query(&mut query, client_addr()) else {
+            panic!("retransmission should be forwarded");
+        };
+        assert_eq!(upstream_addr, upstream_addrs()[1]);
+        assert_eq!(query[..2], upstream_id);
+
+        let mut reply = reply(&query);
+        assert_eq!(forwarder.reply(&mut reply, upstream_addrs()[0]), Some(client_addr()));
+        assert_eq!(reply[..2], 0x4242u16.to_be_bytes());
+        assert!(forwarder.pending_queries.is_empty());
+        assert!(forwarder.upstream_ids.is_empty());
+    }
+
+    #[test]
+    fn mismatched_replies_are_dropped() {
+        let options = dns_options(&[], &[]);
+        let mut forwarder = DnsForwarder::new("test", upstream_addrs(), &options);
+        let mut query = query(0x4242, &[b"example", b"com"], QTYPE_A);
+        assert!(matches!(
+            forwarder.query(&mut query, client_addr()),
+            Some(DnsAction::Forward(_))
+        ));
+
+        // a reply for another name under the same ID, such as a spoofed one
+        let mut spoofed = reply(&query);
+        spoofed[HEADER_LEN + 1] = b'x';
+        assert_eq!(forwarder.reply(&mut spoofed, upstream_addrs()[0]), None);
+
+        // a reply for another type of record
+        let mut other_type = reply(&query);
+        other_type[query.len() - 3] = 28;
+        assert_eq!(forwarder.reply(&mut other_type, upstream_addrs()[0]), None);
+
+        // a reply that doesn't come from an upstream
+        let mut foreign = reply(&query);
+        assert_eq!(forwarder.reply(&mut foreign, "9.9.9.9:53".parse().unwrap()), None);
+
+        // the genuine reply is still relayed, even if the upstream changed the case of the name
+        let mut genuine = reply(&query);
+        genuine[HEADER_LEN + 1] = b'E';
+        assert_eq!(forwarder.reply(&mut genuine, upstream_addrs()[0]), Some(client_addr()));
+    }
+
+    #[test]
+    fn oldest_query_is_evicted() {
+        let options = dns_options(&[], &[]);
+        let mut forwarder = DnsForwarder::new("test", upstream_addrs(), &options);
+        let mut first = query(0, &[b"example", b"com"], QTYPE_A);
+        forwarder.query(&mut first, client_addr());
+
+        for id in 1..=MAX_PENDING_QUERIES as u16 {
+            forwarder.query(&mut query(id, &[b"example", b"com"], QTYPE_A), client_addr());
+        }
+
+        assert_eq!(forwarder.pending_queries.len(), MAX_PENDING_QUERIES);
+        assert!(!forwarder.upstream_ids.contains_key(&(client_addr(), 0)));
+        assert!(forwarder.upstream_ids.contains_key(&(client_addr(), 1)));
+        assert!(!forwarder
+            .pending_queries
+            .values()
+            .any(|pending_query| pending_query.client_id == 0));
+    }
+}
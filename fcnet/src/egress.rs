@@ -0,0 +1,469 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use fcnet_types::{
+    FirecrackerEgressAction, FirecrackerEgressPolicy, FirecrackerIpStack, FirecrackerNetwork, FirecrackerPortProtocol,
+};
+use nftables::{
+    expr::{Expression, NamedExpression, Payload, PayloadField, Prefix, Range},
+    schema::{NfListObject, NfObject, Nftables, Rule, Set, SetFlag, SetType, SetTypeValue},
+    stmt::{Match, Operator, Statement},
+};
+
+use crate::{
+    backend::Backend,
+    context::FirecrackerNetworkContext,
+    counters::counted,
+    ingress::established_match,
+    layout::{find_tagged_rule, has_tagged_rule, NfChangeset, NfDispatchKey, NfDispatchedRule, NfSet},
+    many::nf_rules,
+    port_forward::{iifname_match, oifname_match},
+    util::{nf_egress_policy_rule_tag, nf_rule_tag_object_type, parse_nf_rule_tag, FirecrackerNetworkExt},
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState, FirecrackerNetworkObjectType,
+};
+
+/// The kind that the tag of the rule of an egress policy accepting established and related traffic carries in place of
+/// the kind of a set.
+const ESTABLISHED_RULE_KIND: &str = "established";
+
+/// What the sets of an egress policy are keyed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EgressSetKey {
+    Ipv4Daddr,
+    Ipv6Daddr,
+    Dport(FirecrackerPortProtocol),
+}
+
+impl EgressSetKey {
+    fn name(&self) -> &'static str {
+        match self {
+            EgressSetKey::Ipv4Daddr => "ip",
+            EgressSetKey::Ipv6Daddr => "ip6",
+            EgressSetKey::Dport(protocol) => protocol.name(),
+        }
+    }
+
+    fn set_type(&self) -> SetType {
+        match self {
+            EgressSetKey::Ipv4Daddr => SetType::Ipv4Addr,
+            EgressSetKey::Ipv6Daddr => SetType::Ipv6Addr,
+            EgressSetKey::Dport(_) => SetType::InetService,
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self {
+            EgressSetKey::Ipv4Daddr => 32,
+            EgressSetKey::Ipv6Daddr => 128,
+            EgressSetKey::Dport(_) => 16,
+        }
+    }
+
+    fn expr(&self) -> Expression<'static> {
+        let (protocol, field) = match self {
+            EgressSetKey::Ipv4Daddr => ("ip", "daddr"),
+            EgressSetKey::Ipv6Daddr => ("ip6", "daddr"),
+            EgressSetKey::Dport(protocol) => (protocol.name(), "dport"),
+        };
+
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: protocol.into(),
+            field: field.into(),
+        })))
+    }
+
+    /// Turn an interval of keys into an element shaped like those in the JSON output of nft: a single key, a prefix if
+    /// the interval is one, or a range.
+    fn element(&self, (start, end): (u128, u128)) -> Expression<'static> {
+        let value = |key: u128| match self {
+            EgressSetKey::Ipv4Daddr => Expression::String(IpAddr::from((key as u32).to_be_bytes()).to_string().into()),
+            EgressSetKey::Ipv6Daddr => Expression::String(IpAddr::from(key.to_be_bytes()).to_string().into()),
+            EgressSetKey::Dport(_) => Expression::Number(key as u32),
+        };
+        let host_mask = end - start;
+
+        if start == end {
+            value(start)
+        } else if *self != EgressSetKey::Dport(FirecrackerPortProtocol::Tcp)
+            && *self != EgressSetKey::Dport(FirecrackerPortProtocol::Udp)
+            && host_mask & host_mask.wrapping_add(1) == 0
+            && start & host_mask == 0
+        {
+            Expression::Named(NamedExpression::Prefix(Prefix {
+                addr: Box::new(value(start)),
+                len: self.bits() - host_mask.count_ones(),
+            }))
+        } else {
+            Expression::Range(Box::new(Range {
+                range: [value(start), value(end)],
+            }))
+        }
+    }
+}
+
+/// The rules of the egress policy of a network, each alongside the set it looks up, that traffic arriving on the given
+/// link and leaving via the host interface passes through before reaching the egress forward rule. Traffic of
+/// established or related connections is accepted first, so that the replies to connections towards the guest aren't
+/// subject to the policy. Denied destinations are dropped before allowed ones are accepted, and the rules accepting
+/// allowed destinations only exist with a default action of denying traffic. The rules are dispatched via the same key
+/// as the egress forward rule, so that they precede it in the chain of the network in the verdict map layout as well.
+pub fn egress_policy_rules(network: &FirecrackerNetwork, iifname: &str) -> Vec<NfDispatchedRule> {
+    let Some(ref policy) = network.egress_policy else {
+        return Vec::new();
+    };
+
+    let mut keys = Vec::new();
+
+    if matches!(network.ip_stack, FirecrackerIpStack::V4 | FirecrackerIpStack::Dual) {
+        keys.push(EgressSetKey::Ipv4Daddr);
+    }
+
+    if matches!(network.ip_stack, FirecrackerIpStack::V6 | FirecrackerIpStack::Dual) {
+        keys.push(EgressSetKey::Ipv6Daddr);
+    }
+
+    keys.push(EgressSetKey::Dport(FirecrackerPortProtocol::Tcp));
+    keys.push(EgressSetKey::Dport(FirecrackerPortProtocol::Udp));
+
+    let mut actions = vec![FirecrackerEgressAction::Deny];
+
+    if policy.default_action == FirecrackerEgressAction::Deny {
+        actions.push(FirecrackerEgressAction::Allow);
+    }
+
+    let mut rules = vec![NfDispatchedRule::new(
+        network,
+        Rule {
+            family: network.nf_family(),
+            table: network.nf_table.name.clone().into(),
+            chain: network.nf_table.filter_chain.name.clone().into(),
+            expr: [
+                iifname_match(iifname),
+                oifname_match(&network.iface_name),
+                established_match(),
+            ]
+            .into_iter()
+            .chain(counted(Statement::Accept(None)))
+            .collect(),
+            handle: None,
+            index: None,
+            comment: Some(nf_egress_policy_rule_tag(network.resolved_id(), ESTABLISHED_RULE_KIND).into()),
+        },
+        NfDispatchKey::Iifname(iifname.to_string()),
+    )];
+
+    for action in actions {
+        for key in keys.iter() {
+            let set_kind = format!("{}_{}", action_name(action), key.name());
+            let set_name = format!("egress_{set_kind}-{}", network.resolved_id());
+            let set = Set {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                name: set_name.clone().into(),
+                handle: None,
+                set_type: SetTypeValue::Single(key.set_type()),
+                policy: None,
+                flags: Some(HashSet::from([SetFlag::Interval])),
+                elem: None,
+                timeout: None,
+                gc_interval: None,
+                size: None,
+                comment: None,
+            };
+            let rule = Rule {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                chain: network.nf_table.filter_chain.name.clone().into(),
+                expr: [
+                    iifname_match(iifname),
+                    oifname_match(&network.iface_name),
+                    Statement::Match(Match {
+                        left: key.expr(),
+                        right: Expression::String(format!("@{set_name}").into()),
+                        op: Operator::EQ,
+                    }),
+                ]
+                .into_iter()
+                .chain(counted(verdict(action)))
+                .collect(),
+                handle: None,
+                index: None,
+                comment: Some(nf_egress_policy_rule_tag(network.resolved_id(), &set_kind).into()),
+            };
+
+            rules.push(
+                NfDispatchedRule::new(network, rule, NfDispatchKey::Iifname(iifname.to_string())).looking_up(NfSet {
+                    set,
+                    elements: merge_intervals(intervals(policy, action, *key))
+                        .into_iter()
+                        .map(|interval| key.element(interval))
+                        .collect(),
+                    shared: false,
+                }),
+            );
+        }
+    }
+
+    rules
+}
+
+/// The verdict of the egress forward rule of a network, which is the default action of its egress policy.
+pub fn egress_forward_verdict(network: &FirecrackerNetwork) -> Statement<'static> {
+    match network.egress_policy {
+        Some(FirecrackerEgressPolicy {
+            default_action: FirecrackerEgressAction::Deny,
+            ..
+        }) => verdict(FirecrackerEgressAction::Deny),
+        _ => verdict(FirecrackerEgressAction::Allow),
+    }
+}
+
+/// Locate the rules of the egress policy among the given rules of a network within the current ruleset by their tags,
+/// failing if any of them is missing.
+pub fn locate_egress_policy_rules(
+    current_ruleset: &Nftables<'static>,
+    rules: &[NfDispatchedRule],
+) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError> {
+    rules
+        .iter()
+        .filter(|rule| is_egress_policy_rule(rule))
+        .map(|rule| {
+            find_tagged_rule(current_ruleset, &rule.rule)
+                .cloned()
+                .ok_or(FirecrackerNetworkError::ObjectNotFound(
+                    FirecrackerNetworkObjectType::NfEgressPolicyRule,
+                ))
+        })
+        .collect()
+}
+
+/// Check the sets of the egress policy among the given rules of a network and the rules looking them up, which are
+/// located by their tags in whichever chain they reside in. A set whose elements differ from the policy is mismatched.
+pub fn check_egress_policy(
+    current_ruleset: &Nftables<'static>,
+    rules: &[NfDispatchedRule],
+    report: &mut FirecrackerNetworkCheckReport,
+) {
+    for rule in rules.iter().filter(|rule| is_egress_policy_rule(rule)) {
+        let Some(ref set) = rule.set else {
+            report.push_found(
+                FirecrackerNetworkObjectType::NfEgressPolicyRule,
+                ESTABLISHED_RULE_KIND,
+                has_tagged_rule(current_ruleset, &rule.rule),
+            );
+            continue;
+        };
+
+        let set_state = match find_set(current_ruleset, &set.set) {
+            Some(current_set)
+                if current_set.set_type == set.set.set_type
+                    && current_set.flags == set.set.flags
+                    && element_intervals(current_set.elem.as_deref().unwrap_or_default()) == element_intervals(&set.elements) =>
+            {
+                FirecrackerNetworkObjectState::Present
+            }
+            Some(_) => FirecrackerNetworkObjectState::Mismatched,
+            None => FirecrackerNetworkObjectState::Missing,
+        };
+
+        report.push(
+            FirecrackerNetworkObjectType::NfEgressPolicySet,
+            set.set.name.as_ref(),
+            set_state,
+        );
+        report.push_found(
+            FirecrackerNetworkObjectType::NfEgressPolicyRule,
+            format!("@{}", set.set.name),
+            has_tagged_rule(current_ruleset, &rule.rule),
+        );
+    }
+}
+
+/// Replace the addresses and ports of the egress policy of a network that has already been added with those of its
+/// given configuration, see [update_egress_policy](crate::update_egress_policy).
+pub async fn update_egress_policy<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    let rules = nf_rules(network)
+        .into_iter()
+        .filter(is_egress_policy_rule)
+        .collect::<Vec<_>>();
+    let current_ruleset = context.get_current_ruleset(network.nft_program()).await?;
+    let mut policy_rule_tags = HashSet::new();
+
+    for rule in rules.iter() {
+        if !has_tagged_rule(&current_ruleset, &rule.rule) {
+            return Err(FirecrackerNetworkError::ObjectNotFound(
+                FirecrackerNetworkObjectType::NfEgressPolicyRule,
+            ));
+        }
+
+        policy_rule_tags.extend(rule.rule.comment.as_deref());
+    }
+
+    // a policy rule beyond those of the given configuration means that the default action was changed or the policy
+    // removed, neither of which can be done by updating the sets
+    let has_extra_policy_rules = current_ruleset.objects.iter().any(|object| match object {
+        NfObject::ListObject(NfListObject::Rule(rule)) => {
+            rule.family == network.nf_family()
+                && rule.table == network.nf_table.name
+                && rule.comment.as_deref().is_some_and(|comment| {
+                    nf_rule_tag_object_type(comment, network.resolved_id())
+                        == Some(FirecrackerNetworkObjectType::NfEgressPolicyRule)
+                        && !policy_rule_tags.contains(comment)
+                })
+        }
+        _ => false,
+    });
+
+    if has_extra_policy_rules {
+        return Err(FirecrackerNetworkError::ObjectMismatched(
+            FirecrackerNetworkObjectType::NfEgressPolicyRule,
+        ));
+    }
+
+    let mut changeset = NfChangeset::default();
+    changeset.populate_sets(&rules);
+    context.apply_nf_changeset(&changeset, network.nft_program()).await
+}
+
+/// The intervals of keys that the set of the given policy with the given action and key contains before merging them.
+fn intervals(policy: &FirecrackerEgressPolicy, action: FirecrackerEgressAction, key: EgressSetKey) -> Vec<(u128, u128)> {
+    let ports = match action {
+        FirecrackerEgressAction::Allow => &policy.allowed_ports,
+        FirecrackerEgressAction::Deny => &policy.denied_ports,
+    };
+
+    if let EgressSetKey::Dport(protocol) = key {
+        return ports
+            .iter()
+            .filter(|port| port.protocol == protocol)
+            .map(|port| {
+                let port_end = port.port_end.unwrap_or(port.port);
+                (port.port.min(port_end) as u128, port.port.max(port_end) as u128)
+            })
+            .collect();
+    }
+
+    let mut prefixes = match action {
+        FirecrackerEgressAction::Allow => &policy.allowed_cidrs,
+        FirecrackerEgressAction::Deny => &policy.denied_cidrs,
+    }
+    .iter()
+    .map(|cidr| (cidr.first_address(), cidr.network_length()))
+    .collect::<Vec<_>>();
+
+    if action == FirecrackerEgressAction::Deny && policy.block_link_local {
+        prefixes.push((IpAddr::from([169, 254, 0, 0]), 16));
+        prefixes.push((IpAddr::from([0xfe80, 0, 0, 0, 0, 0, 0, 0]), 10));
+    }
+
+    if action == FirecrackerEgressAction::Deny && policy.block_private {
+        prefixes.push((IpAddr::from([10, 0, 0, 0]), 8));
+        prefixes.push((IpAddr::from([172, 16, 0, 0]), 12));
+        prefixes.push((IpAddr::from([192, 168, 0, 0]), 16));
+        prefixes.push((IpAddr::from([0xfc00, 0, 0, 0, 0, 0, 0, 0]), 7));
+    }
+
+    prefixes
+        .into_iter()
+        .filter_map(|(addr, len)| match (key, addr) {
+            (EgressSetKey::Ipv4Daddr, IpAddr::V4(addr)) => Some(prefix_interval(u32::from(addr) as u128, len, 32)),
+            (EgressSetKey::Ipv6Daddr, IpAddr::V6(addr)) => Some(prefix_interval(u128::from(addr), len, 128)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Merge overlapping and adjacent intervals, which nftables requires of the elements of an interval set.
+fn merge_intervals(mut intervals: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(intervals.len());
+
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= last_end.saturating_add(1) => *last_end = (*last_end).max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+/// The merged intervals of keys that the given elements of a set cover, or [None] if any of them isn't shaped like
+/// those of an egress policy.
+fn element_intervals(elements: &[Expression]) -> Option<Vec<(u128, u128)>> {
+    let intervals = elements
+        .iter()
+        .map(|element| match element {
+            Expression::Named(NamedExpression::Prefix(Prefix { addr, len })) => match addr.as_ref() {
+                Expression::String(addr) => match addr.parse::<IpAddr>().ok()? {
+                    IpAddr::V4(addr) => Some(prefix_interval(u32::from(addr) as u128, *len as u8, 32)),
+                    IpAddr::V6(addr) => Some(prefix_interval(u128::from(addr), *len as u8, 128)),
+                },
+                _ => None,
+            },
+            Expression::Range(range) => Some((element_key(&range.range[0])?, element_key(&range.range[1])?)),
+            element => element_key(element).map(|key| (key, key)),
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(merge_intervals(intervals))
+}
+
+fn element_key(expr: &Expression) -> Option<u128> {
+    match expr {
+        Expression::Number(number) => Some(*number as u128),
+        Expression::String(addr) => match addr.parse::<IpAddr>().ok()? {
+            IpAddr::V4(addr) => Some(u32::from(addr) as u128),
+            IpAddr::V6(addr) => Some(u128::from(addr)),
+        },
+        _ => None,
+    }
+}
+
+#[inline]
+fn prefix_interval(addr: u128, len: u8, bits: u32) -> (u128, u128) {
+    let host_mask = match bits.saturating_sub(len as u32) {
+        128 => u128::MAX,
+        host_bits => (1 << host_bits) - 1,
+    };
+
+    (addr & !host_mask, addr | host_mask)
+}
+
+fn find_set<'a>(current_ruleset: &'a Nftables<'static>, set: &Set<'static>) -> Option<&'a Set<'static>> {
+    current_ruleset.objects.iter().find_map(|object| match object {
+        NfObject::ListObject(NfListObject::Set(current_set))
+            if current_set.family == set.family && current_set.table == set.table && current_set.name == set.name =>
+        {
+            Some(current_set.as_ref())
+        }
+        _ => None,
+    })
+}
+
+/// Whether the given rule of a network is one of the rules of its egress policy, as opposed to other rules that look up
+/// sets such as those of isolation groups.
+fn is_egress_policy_rule(rule: &NfDispatchedRule) -> bool {
+    matches!(
+        rule.rule.comment.as_deref().and_then(parse_nf_rule_tag),
+        Some((_, FirecrackerNetworkObjectType::NfEgressPolicyRule))
+    )
+}
+
+#[inline]
+fn action_name(action: FirecrackerEgressAction) -> &'static str {
+    match action {
+        FirecrackerEgressAction::Allow => "allow",
+        FirecrackerEgressAction::Deny => "deny",
+    }
+}
+
+#[inline]
+fn verdict(action: FirecrackerEgressAction) -> Statement<'static> {
+    match action {
+        FirecrackerEgressAction::Allow => Statement::Accept(None),
+        FirecrackerEgressAction::Deny => Statement::Drop(None),
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashSet;
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkType, FirecrackerNfTable};
+use futures_util::TryStreamExt;
+use nftables::{
+    batch::Batch,
+    expr::{Expression, Verdict},
+    schema::{Chain, Element, FlushObject, NfCmd, NfListObject, NfObject},
+    stmt::{JumpTarget, Statement},
+    types::NfFamily,
+};
+use rtnetlink::packet_route::link::LinkAttribute;
+
+#[cfg(feature = "namespaced")]
+use crate::netns::{DirNetNsEnvironment, NetNs, NetNsEnvironment};
+use crate::{
+    backend::Backend,
+    context::FirecrackerNetworkContext,
+    isolation::ISOLATION_GROUP_SET_PREFIX,
+    util::{apply_ruleset, parse_nf_rule_tag},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType,
+};
+#[cfg(feature = "namespaced")]
+use fcnet_types::DEFAULT_NETNS_DIR;
+#[cfg(feature = "namespaced")]
+use std::path::PathBuf;
+
+/// The options of a garbage collection performed by [collect_garbage](crate::collect_garbage).
+///
+/// fcnet doesn't impose a naming convention on links and network namespaces, so only those whose names start with one
+/// of the configured prefixes are considered to be owned by fcnet. nftables rules are instead recognized by the tags
+/// fcnet places into their comments, so untagged rules created by older versions of fcnet are never removed. The chains
+/// of networks in the verdict map layout, their anti-spoofing chains and the sets of egress policies are recognized by
+/// the tagged rules within and looking them up respectively. The sets of isolation groups are shared between networks,
+/// so only the elements of links that don't belong to any of the given networks are removed from them, and a set is
+/// removed as a whole once none of the given networks belongs to its group anymore.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FirecrackerNetworkGcOptions {
+    /// The optional explicit path to "nft" to use when invoking it.
+    pub nft_path: Option<String>,
+    /// The nftables table and base chains to look for orphaned rules in, which should match the [FirecrackerNfTable] of
+    /// the networks that were added.
+    pub nf_table: FirecrackerNfTable,
+    /// The name prefixes of links in the host network namespace that are owned by fcnet: the tap devices of simple
+    /// networks, the veth pairs of namespaced networks and the IFB devices of networks whose ingress is shaped.
+    pub link_name_prefixes: Vec<String>,
+    /// The name prefixes of network namespaces that are owned by fcnet.
+    #[cfg(feature = "namespaced")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+    pub netns_name_prefixes: Vec<String>,
+    /// The directories to look for orphaned network namespaces in besides the default one and those of the given
+    /// networks, which is needed for networks whose network namespace was persisted in a custom directory.
+    #[cfg(feature = "namespaced")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+    pub netns_dirs: Vec<String>,
+    /// Whether to only report the orphaned objects instead of removing them.
+    pub dry_run: bool,
+}
+
+/// An orphaned object that was found, and unless performing a dry run, removed by [collect_garbage](crate::collect_garbage).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FirecrackerNetworkGcEntry {
+    /// The type of the object.
+    pub object_type: FirecrackerNetworkObjectType,
+    /// A human-readable identifier of the object: the name of a link, network namespace, chain or set, the tag of a rule,
+    /// or a link alongside the set of an isolation group that it is an element of.
+    pub identifier: String,
+}
+
+pub async fn collect_garbage<B: Backend>(
+    networks: &[FirecrackerNetwork],
+    options: &FirecrackerNetworkGcOptions,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<Vec<FirecrackerNetworkGcEntry>, FirecrackerNetworkError> {
+    let netlink_handle = &context.netlink_handle()?;
+    let mut network_ids = HashSet::new();
+    let mut link_names = HashSet::new();
+    #[cfg(feature = "namespaced")]
+    let mut netns_paths = HashSet::new();
+    #[cfg(feature = "namespaced")]
+    let mut netns_dirs = vec![DEFAULT_NETNS_DIR];
+
+    for network in networks {
+        network_ids.insert(network.resolved_id());
+        link_names.insert(network.tap_name.as_str());
+        link_names.extend(network.shaping.ingress.as_ref().map(|ingress| ingress.ifb_name.as_str()));
+
+        match network.network_type {
+            #[cfg(feature = "simple")]
+            FirecrackerNetworkType::Simple => {}
+            #[cfg(feature = "namespaced")]
+            FirecrackerNetworkType::Namespaced {
+                netns_name: _,
+                ref veth1_name,
+                ref veth2_name,
+                veth1_ip: _,
+                veth2_ip: _,
+                forwarded_guest_ip: _,
+                ref netns_dir,
+                jailer: _,
+            } => {
+                netns_paths.extend(network.netns_path());
+                netns_dirs.extend(netns_dir.as_deref());
+                link_names.insert(veth1_name.as_str());
+                // the inner end of the veth pair remains in the host netns if adding the network was interrupted
+                link_names.insert(veth2_name.as_str());
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+
+    // rules go first, so that no rule is left referencing a removed link
+    let (current_ruleset, verdict_maps) = context
+        .get_current_ruleset_with_verdict_maps(options.nft_path.as_deref())
+        .await?;
+    let mut batch = Batch::new();
+    let mut batch_is_empty = true;
+    let mut orphaned_chains = Vec::new();
+    let mut orphaned_sets = HashSet::new();
+    let mut live_group_sets = HashSet::new();
+    let base_chains = [
+        options.nf_table.postrouting_chain.name.as_str(),
+        options.nf_table.prerouting_chain.name.as_str(),
+        options.nf_table.filter_chain.name.as_str(),
+    ];
+
+    for object in current_ruleset.objects.iter() {
+        if let NfObject::ListObject(NfListObject::Rule(rule)) = object {
+            if rule.table != options.nf_table.name {
+                continue;
+            }
+
+            let Some((network_id, object_type)) = rule.comment.as_deref().and_then(parse_nf_rule_tag) else {
+                continue;
+            };
+
+            if network_ids.contains(network_id) {
+                if object_type == FirecrackerNetworkObjectType::NfIsolationGroupRule {
+                    live_group_sets.extend(looked_up_sets(&rule.expr).map(|set_name| (rule.family, set_name.to_string())));
+                }
+
+                continue;
+            }
+
+            entries.push(FirecrackerNetworkGcEntry {
+                object_type,
+                identifier: rule.comment.as_deref().unwrap_or_default().to_string(),
+            });
+
+            // the sets of an egress policy are removed once no rule looks them up anymore
+            if object_type == FirecrackerNetworkObjectType::NfEgressPolicyRule {
+                orphaned_sets.extend(looked_up_sets(&rule.expr).map(|set_name| (rule.family, set_name.to_string())));
+            }
+
+            // the chain of a network in the verdict map layout is removed as a whole, alongside the elements
+            // dispatching into it, and so is its anti-spoofing chain in the netdev table
+            if !base_chains.contains(&rule.chain.as_ref()) {
+                if !orphaned_chains.contains(&(rule.family, rule.chain.clone())) {
+                    orphaned_chains.push((rule.family, rule.chain.clone()));
+                }
+
+                continue;
+            }
+
+            batch.delete(NfListObject::Rule(rule.clone()));
+            batch_is_empty = false;
+        }
+    }
+
+    for (family, chain) in orphaned_chains {
+        for verdict_map in verdict_maps.iter() {
+            if verdict_map.family != family || verdict_map.table != options.nf_table.name {
+                continue;
+            }
+
+            for (key, verdict) in verdict_map.elements.iter() {
+                if matches!(verdict, Verdict::Jump(JumpTarget { target }) if *target == chain) {
+                    batch.delete(NfListObject::Element(Element {
+                        family,
+                        table: options.nf_table.name.clone().into(),
+                        name: verdict_map.name.clone().into(),
+                        elem: vec![Expression::String(key.clone().into())].into(),
+                    }));
+                }
+            }
+        }
+
+        let chain = Chain {
+            family,
+            table: options.nf_table.name.clone().into(),
+            name: chain,
+            newname: None,
+            handle: None,
+            _type: None,
+            hook: None,
+            prio: None,
+            dev: None,
+            policy: None,
+        };
+        entries.push(FirecrackerNetworkGcEntry {
+            object_type: match family {
+                NfFamily::NetDev => FirecrackerNetworkObjectType::NfAntiSpoofingChain,
+                _ => FirecrackerNetworkObjectType::NfNetworkChain,
+            },
+            identifier: chain.name.to_string(),
+        });
+        batch.add_cmd(NfCmd::Flush(FlushObject::Chain(chain.clone())));
+        batch.delete(NfListObject::Chain(chain));
+        batch_is_empty = false;
+    }
+
+    for object in current_ruleset.objects.iter() {
+        if let NfObject::ListObject(NfListObject::Set(set)) = object {
+            if set.table != options.nf_table.name {
+                continue;
+            }
+
+            if orphaned_sets.contains(&(set.family, set.name.to_string())) {
+                entries.push(FirecrackerNetworkGcEntry {
+                    object_type: FirecrackerNetworkObjectType::NfEgressPolicySet,
+                    identifier: set.name.to_string(),
+                });
+                let mut set = set.clone();
+                set.elem = None;
+                batch.delete(NfListObject::Set(set));
+                batch_is_empty = false;
+                continue;
+            }
+
+            if !set.name.starts_with(ISOLATION_GROUP_SET_PREFIX) {
+                continue;
+            }
+
+            let elements = set.elem.as_deref().unwrap_or_default();
+            let orphaned_elements = elements
+                .iter()
+                .filter(|element| !matches!(element, Expression::String(link) if link_names.contains(link.as_ref())))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if orphaned_elements.len() == elements.len() && !live_group_sets.contains(&(set.family, set.name.to_string())) {
+                entries.push(FirecrackerNetworkGcEntry {
+                    object_type: FirecrackerNetworkObjectType::NfIsolationGroupSet,
+                    identifier: set.name.to_string(),
+                });
+                let mut set = set.clone();
+                set.elem = None;
+                batch.delete(NfListObject::Set(set));
+                batch_is_empty = false;
+            } else if !orphaned_elements.is_empty() {
+                for element in orphaned_elements.iter() {
+                    if let Expression::String(link) = element {
+                        entries.push(FirecrackerNetworkGcEntry {
+                            object_type: FirecrackerNetworkObjectType::NfIsolationGroupSet,
+                            identifier: format!("{link} in {}", set.name),
+                        });
+                    }
+                }
+
+                batch.delete(NfListObject::Element(Element {
+                    family: set.family,
+                    table: set.table.clone(),
+                    name: set.name.clone(),
+                    elem: orphaned_elements.into(),
+                }));
+                batch_is_empty = false;
+            }
+        }
+    }
+
+    if !options.dry_run && !batch_is_empty {
+        apply_ruleset::<B>(&batch.to_nftables(), options.nft_path.as_deref()).await?;
+    }
+
+    let mut orphaned_links = Vec::new();
+    let mut link_message_stream = netlink_handle.link().get().execute();
+
+    while let Some(link_message) = link_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        for attribute in &link_message.attributes {
+            if let LinkAttribute::IfName(name) = attribute {
+                if has_any_prefix(name, &options.link_name_prefixes) && !link_names.contains(name.as_str()) {
+                    orphaned_links.push((link_message.header.index, name.clone()));
+                }
+            }
+        }
+    }
+
+    for (link_idx, name) in orphaned_links {
+        if !options.dry_run {
+            match netlink_handle.link().del(link_idx).execute().await {
+                Ok(()) => {}
+                // removing one end of a veth pair also removes the other one
+                Err(rtnetlink::Error::NetlinkError(message)) if message.raw_code() == -libc::ENODEV => {}
+                Err(err) => return Err(FirecrackerNetworkError::NetlinkOperationError(err)),
+            }
+        }
+
+        entries.push(FirecrackerNetworkGcEntry {
+            object_type: FirecrackerNetworkObjectType::IpLink,
+            identifier: name,
+        });
+    }
+
+    // everything residing inside a netns, including its end of a veth pair, is removed alongside it
+    #[cfg(feature = "namespaced")]
+    {
+        netns_dirs.extend(options.netns_dirs.iter().map(String::as_str));
+        netns_dirs.sort_unstable();
+        netns_dirs.dedup();
+
+        for netns_dir in netns_dirs {
+            let env = DirNetNsEnvironment(PathBuf::from(netns_dir));
+
+            for netns_name in NetNs::list_names_from_env(env.clone()).map_err(FirecrackerNetworkError::NetnsError)? {
+                let netns_path = env.persist_dir().join(&netns_name);
+
+                if !has_any_prefix(&netns_name, &options.netns_name_prefixes) || netns_paths.contains(&netns_path) {
+                    continue;
+                }
+
+                if !options.dry_run {
+                    NetNs::get_from_env(&netns_name, env.clone())
+                        .and_then(|netns| netns.remove())
+                        .map_err(FirecrackerNetworkError::NetnsError)?;
+                }
+
+                entries.push(FirecrackerNetworkGcEntry {
+                    object_type: FirecrackerNetworkObjectType::NetNs,
+                    // network namespaces outside of the default directory are told apart by their full paths
+                    identifier: match netns_dir == DEFAULT_NETNS_DIR {
+                        true => netns_name,
+                        false => netns_path.display().to_string(),
+                    },
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Iterate over the names of the sets that the given statements of a rule look up.
+fn looked_up_sets<'a>(statements: &'a [Statement<'static>]) -> impl Iterator<Item = &'a str> {
+    statements.iter().filter_map(|statement| match statement {
+        Statement::Match(nf_match) => match nf_match.right {
+            Expression::String(ref right) => right.strip_prefix('@'),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+#[inline]
+fn has_any_prefix(name: &str, prefixes: &[String]) -> bool {
+    // an empty prefix would match every link or netns on the host, including ones unrelated to fcnet
+    prefixes
+        .iter()
+        .any(|prefix| !prefix.is_empty() && name.starts_with(prefix.as_str()))
+}
@@ -0,0 +1,169 @@
+use cidr::IpCidr;
+use fcnet_types::{FirecrackerIpStack, FirecrackerNetwork, FirecrackerPortRange};
+use nftables::{
+    expr::{Expression, NamedExpression, Payload, PayloadField, Prefix, Range, CT},
+    schema::Rule,
+    stmt::{Match, Operator, Statement},
+};
+
+use crate::{
+    counters::counted,
+    layout::NfTaggedRule,
+    port_forward::{iifname_match, oifname_match},
+    util::{nf_port_rule_tag, nf_rule_tag, parse_nf_rule_tag, FirecrackerNetworkExt},
+    FirecrackerNetworkObjectType,
+};
+
+/// The expression of the ingress forward rule of a network, which accepts the traffic from the host interface towards
+/// the given link that belongs to connections the guest initiated or is related to them, such as ICMP errors.
+pub fn ingress_forward_expr(network: &FirecrackerNetwork, link: &str) -> Vec<Statement<'static>> {
+    [iifname_match(&network.iface_name), oifname_match(link), established_match()]
+        .into_iter()
+        .chain(counted(Statement::Accept(None)))
+        .collect()
+}
+
+/// Match traffic that belongs to an established connection or is related to one.
+pub fn established_match() -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::CT(CT {
+            key: "state".into(),
+            family: None,
+            dir: None,
+        })),
+        right: Expression::List(vec![
+            Expression::String("established".into()),
+            Expression::String("related".into()),
+        ]),
+        op: Operator::IN,
+    })
+}
+
+/// The rules of the ingress policy of a network towards the given link, which follow those accepting connections to the
+/// forwarded ports of the network: one accepting new connections from each exposed network and to each exposed port,
+/// and one dropping all other traffic from the host interface. These rules are undispatched in the verdict map layout,
+/// as the rules of forwarded ports that they must come after reside in the base chain.
+pub fn ingress_policy_rules(network: &FirecrackerNetwork, link: &str) -> Vec<NfTaggedRule> {
+    let policy = &network.ingress_policy;
+    let exposed_cidrs = policy
+        .exposed_cidrs
+        .iter()
+        .map(|cidr| (cidr.to_string(), cidr_applies(network, cidr).then(|| saddr_match(cidr))));
+    let exposed_ports = policy
+        .exposed_ports
+        .iter()
+        .map(|port_range| (port_range.to_string(), Some(dport_match(port_range))));
+    let mut rules = Vec::new();
+
+    // the index spans both lists, so that the tags of the rules stay unique
+    for (index, (identifier, exposed_match)) in exposed_cidrs.chain(exposed_ports).enumerate() {
+        let Some(exposed_match) = exposed_match else {
+            continue;
+        };
+
+        rules.push(NfTaggedRule {
+            object_type: FirecrackerNetworkObjectType::NfIngressExposedRule,
+            identifier: format!("{identifier} to {link}"),
+            rule: filter_rule(
+                network,
+                [iifname_match(&network.iface_name), oifname_match(link), exposed_match]
+                    .into_iter()
+                    .chain(counted(Statement::Accept(None)))
+                    .collect(),
+                nf_port_rule_tag(
+                    network.resolved_id(),
+                    FirecrackerNetworkObjectType::NfIngressExposedRule,
+                    index,
+                ),
+            ),
+        });
+    }
+
+    rules.push(NfTaggedRule {
+        object_type: FirecrackerNetworkObjectType::NfIngressDropRule,
+        identifier: format!("{} to {link}", network.iface_name),
+        rule: filter_rule(
+            network,
+            vec![iifname_match(&network.iface_name), oifname_match(link), Statement::Drop(None)],
+            nf_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfIngressDropRule),
+        ),
+    });
+
+    rules
+}
+
+/// Whether the given rule is one of the rules of an ingress policy, which networks added by older versions of fcnet
+/// lack along with a tagged ingress forward rule.
+pub fn is_ingress_policy_rule(rule: &Rule) -> bool {
+    matches!(
+        rule.comment.as_deref().and_then(parse_nf_rule_tag),
+        Some((
+            _,
+            FirecrackerNetworkObjectType::NfIngressExposedRule | FirecrackerNetworkObjectType::NfIngressDropRule
+        ))
+    )
+}
+
+fn filter_rule(network: &FirecrackerNetwork, expr: Vec<Statement<'static>>, tag: String) -> Rule<'static> {
+    Rule {
+        family: network.nf_family(),
+        table: network.nf_table.name.clone().into(),
+        chain: network.nf_table.filter_chain.name.clone().into(),
+        expr: expr.into(),
+        handle: None,
+        index: None,
+        comment: Some(tag.into()),
+    }
+}
+
+/// Whether an exposed network is of an IP version that the network uses.
+fn cidr_applies(network: &FirecrackerNetwork, cidr: &IpCidr) -> bool {
+    match cidr {
+        IpCidr::V4(_) => network.ip_stack != FirecrackerIpStack::V6,
+        IpCidr::V6(_) => network.ip_stack != FirecrackerIpStack::V4,
+    }
+}
+
+/// Match the source address against an exposed network, which is shaped like in the JSON output of nft: a single
+/// address for a host and a prefix otherwise.
+fn saddr_match(cidr: &IpCidr) -> Statement<'static> {
+    let (protocol, host_len) = match cidr {
+        IpCidr::V4(_) => ("ip", 32),
+        IpCidr::V6(_) => ("ip6", 128),
+    };
+    let addr = Expression::String(cidr.first_address().to_string().into());
+
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: protocol.into(),
+            field: "saddr".into(),
+        }))),
+        right: match cidr.network_length() {
+            len if len == host_len => addr,
+            len => Expression::Named(NamedExpression::Prefix(Prefix {
+                addr: Box::new(addr),
+                len: len.into(),
+            })),
+        },
+        op: Operator::EQ,
+    })
+}
+
+fn dport_match(port_range: &FirecrackerPortRange) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: port_range.protocol.name().into(),
+            field: "dport".into(),
+        }))),
+        right: match port_range.port_end {
+            Some(port_end) => Expression::Range(Box::new(Range {
+                range: [
+                    Expression::Number(port_range.port.into()),
+                    Expression::Number(port_end.into()),
+                ],
+            })),
+            None => Expression::Number(port_range.port.into()),
+        },
+        op: Operator::EQ,
+    })
+}
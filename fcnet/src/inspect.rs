@@ -0,0 +1,131 @@
+use std::net::IpAddr;
+#[cfg(feature = "namespaced")]
+use std::path::PathBuf;
+
+use cidr::IpInet;
+use futures_util::TryStreamExt;
+use nftables::schema::Rule;
+use rtnetlink::packet_route::{
+    address::AddressAttribute,
+    link::{LinkAttribute, LinkFlags, State},
+};
+
+use crate::{util::get_link, FirecrackerNetworkError, FirecrackerNetworkObjectType};
+
+/// A snapshot of the live state of a [FirecrackerNetwork](fcnet_types::FirecrackerNetwork) as it exists on the host,
+/// produced by [inspect](crate::inspect). Objects that don't exist on the host are represented by [None] or are absent
+/// from their respective [Vec].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNetworkSnapshot {
+    /// The tap device. For namespaced networks, it resides inside the network namespace.
+    pub tap_link: Option<FirecrackerNetworkLinkSnapshot>,
+    /// The nftables rules belonging to this network in the "fcnet" table of the host network namespace.
+    pub nf_rules: Vec<FirecrackerNetworkRuleSnapshot>,
+    /// The state specific to namespaced networks, [None] for simple networks.
+    #[cfg(feature = "namespaced")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+    pub namespaced: Option<FirecrackerNetworkNamespacedSnapshot>,
+}
+
+/// The part of a [FirecrackerNetworkSnapshot] that is specific to namespaced networks.
+#[cfg(feature = "namespaced")]
+#[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNetworkNamespacedSnapshot {
+    /// The path of the network namespace's bind mount, if it exists.
+    pub netns_path: Option<PathBuf>,
+    /// The end of the veth pair residing in the host network namespace.
+    pub veth1_link: Option<FirecrackerNetworkLinkSnapshot>,
+    /// The end of the veth pair residing in the network namespace.
+    pub veth2_link: Option<FirecrackerNetworkLinkSnapshot>,
+    /// The nftables rules belonging to this network in the "fcnet" table of the network namespace.
+    pub inner_nf_rules: Vec<FirecrackerNetworkRuleSnapshot>,
+    /// The route in the host network namespace that directs traffic for the forwarded guest IP into the network
+    /// namespace.
+    pub forward_route: Option<FirecrackerNetworkRouteSnapshot>,
+}
+
+/// The live state of a network link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNetworkLinkSnapshot {
+    /// The name of the link.
+    pub name: String,
+    /// The index of the link in its network namespace.
+    pub index: u32,
+    /// Whether the link is administratively up.
+    pub up: bool,
+    /// The operational state of the link as reported by the kernel.
+    pub oper_state: Option<State>,
+    /// The MTU of the link.
+    pub mtu: Option<u32>,
+    /// The hardware (MAC) address of the link.
+    pub hardware_address: Option<Vec<u8>>,
+    /// All IP addresses assigned to the link.
+    pub addresses: Vec<IpInet>,
+}
+
+/// An nftables rule that belongs to a network, alongside the role it plays in the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNetworkRuleSnapshot {
+    /// The role of the rule.
+    pub object_type: FirecrackerNetworkObjectType,
+    /// The rule as listed by nftables, including its handle.
+    pub rule: Rule<'static>,
+}
+
+/// The live state of a network route.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNetworkRouteSnapshot {
+    /// The destination of the route.
+    pub destination: IpAddr,
+    /// The gateway of the route.
+    pub gateway: Option<IpAddr>,
+    /// The index of the link that the route goes out of.
+    pub output_link_index: Option<u32>,
+}
+
+pub(crate) async fn inspect_link(
+    link: &str,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<Option<FirecrackerNetworkLinkSnapshot>, FirecrackerNetworkError> {
+    let Some(link_message) = get_link(link.to_string(), netlink_handle).await? else {
+        return Ok(None);
+    };
+
+    let mut snapshot = FirecrackerNetworkLinkSnapshot {
+        name: link.to_string(),
+        index: link_message.header.index,
+        up: link_message.header.flags.contains(LinkFlags::Up),
+        oper_state: None,
+        mtu: None,
+        hardware_address: None,
+        addresses: Vec::new(),
+    };
+
+    for attribute in link_message.attributes {
+        match attribute {
+            LinkAttribute::OperState(state) => snapshot.oper_state = Some(state),
+            LinkAttribute::Mtu(mtu) => snapshot.mtu = Some(mtu),
+            LinkAttribute::Address(address) => snapshot.hardware_address = Some(address),
+            _ => continue,
+        }
+    }
+
+    let mut address_message_stream = netlink_handle.address().get().set_link_index_filter(snapshot.index).execute();
+
+    while let Some(address_message) = address_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        for attribute in &address_message.attributes {
+            if let AddressAttribute::Address(address) = attribute {
+                if let Ok(inet) = IpInet::new(*address, address_message.header.prefix_len) {
+                    snapshot.addresses.push(inet);
+                }
+            }
+        }
+    }
+
+    Ok(Some(snapshot))
+}
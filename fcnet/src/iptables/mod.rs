@@ -0,0 +1,552 @@
+//! An iptables driver for hosts whose "nft" is too old to support JSON or that are managed purely through
+//! iptables-legacy. The rules that fcnet creates are expressed as iptables and ip6tables rules, with every nftables base
+//! chain being stood in for by a dedicated chain such as "FCNET-POSTROUTING" that the corresponding built-in chain jumps
+//! into, and the ruleset is listed back in the shape of the nftables ruleset it stands in for.
+
+use std::{ffi::OsStr, io, marker::PhantomData, sync::LazyLock};
+
+use futures_util::lock::Mutex;
+use nftables::{
+    helper::NftablesError,
+    schema::{Chain, NfCmd, NfListObject, NfObject, Nftables, Rule, Table},
+    stmt::{AnonymousCounter, Counter, Statement},
+    types::{NfChainPolicy, NfChainType, NfHook},
+};
+use nftables_async::{driver::Driver, helper::Helper};
+
+use crate::{
+    counters::statements_match,
+    util::parse_nf_rule_tag,
+    vmap::{parse_ruleset, serialize_ruleset, split_ruleset, NfRulesetObject},
+};
+
+mod rule;
+mod script;
+
+use rule::{
+    builtin_chain, builtin_chain_hook, chain_name, decode_jump, decode_rule, encode_jump, encode_rule, rule_comment,
+    rule_families, IptFamily, JumpTag,
+};
+use script::{parse_save, RestoreScript, SavedTable};
+
+/// An [nftables_async] [Helper] that applies and lists rulesets via iptables-restore and iptables-save (and their
+/// ip6tables counterparts), spawning them through the [Driver] `D`.
+///
+/// Only the subset of nftables that fcnet creates in the flat layout can be expressed: adding the postrouting,
+/// prerouting and forward base chains and adding or deleting rules that match interfaces (or all but one of them),
+/// addresses or networks, TCP or UDP ports and conntrack states and then accept, drop, masquerade, SNAT or DNAT packets,
+/// as well as replacing such rules to reset their counters. Anything else, including the verdict maps of the verdict
+/// map layout, the sets of egress policies and isolation groups and base chains with a drop policy, fails to apply.
+/// Since iptables has no priorities, the chains standing in for base chains are jumped into from the start of their
+/// built-in chains, and the priority of a base chain is only recorded so that it can be listed back. Rules of inet
+/// tables are placed into both iptables and ip6tables unless they pertain to addresses of a single family. As iptables
+/// counts the traffic of every rule, every rule is listed with a counter in front of its target.
+///
+/// Each iptables table of each family is committed atomically, but a ruleset spanning several of them isn't. Rulesets
+/// are translated against the state of the tables saved beforehand, so applying them is serialized within the process,
+/// and nothing besides fcnet is assumed to modify the chains standing in for base chains. The program and arguments
+/// meant for "nft" are ignored.
+pub struct IptablesDriver<D> {
+    _marker: PhantomData<fn() -> D>,
+}
+
+/// Held from saving the state of the tables until the translated ruleset has been restored, so that no other ruleset is
+/// applied in between by this process.
+static APPLY_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+impl<D: Driver> Helper for IptablesDriver<D> {
+    async fn apply_ruleset_with_args<
+        'a,
+        P: AsRef<OsStr> + Sync + ?Sized,
+        A: AsRef<OsStr> + Sync + ?Sized + 'a,
+        I: IntoIterator<Item = &'a A> + Send,
+    >(
+        nftables: &Nftables<'_>,
+        _program: Option<&P>,
+        _args: I,
+    ) -> Result<(), NftablesError> {
+        let objects = nftables
+            .objects
+            .iter()
+            .cloned()
+            .map(NfRulesetObject::Object)
+            .collect::<Vec<_>>();
+        apply_ruleset::<D>(&objects).await
+    }
+
+    async fn apply_ruleset_raw<
+        'a,
+        P: AsRef<OsStr> + Sync + ?Sized,
+        A: AsRef<OsStr> + Sync + ?Sized + 'a,
+        I: IntoIterator<Item = &'a A> + Send,
+    >(
+        payload: String,
+        _program: Option<&P>,
+        _args: I,
+    ) -> Result<(), NftablesError> {
+        let objects = parse_ruleset(&payload).map_err(NftablesError::NftInvalidJson)?;
+        apply_ruleset::<D>(&objects).await
+    }
+
+    async fn get_current_ruleset_with_args<
+        'a,
+        P: AsRef<OsStr> + Sync + ?Sized,
+        A: AsRef<OsStr> + Sync + ?Sized + 'a,
+        I: IntoIterator<Item = &'a A> + Send,
+    >(
+        _program: Option<&P>,
+        _args: I,
+    ) -> Result<Nftables<'static>, NftablesError> {
+        list_ruleset::<D>().await.map(|objects| split_ruleset(objects).0)
+    }
+
+    async fn get_current_ruleset_raw<
+        'a,
+        P: AsRef<OsStr> + Sync + ?Sized,
+        A: AsRef<OsStr> + Sync + ?Sized + 'a,
+        I: IntoIterator<Item = &'a A> + Send,
+    >(
+        _program: Option<&P>,
+        _args: I,
+    ) -> Result<String, NftablesError> {
+        list_ruleset::<D>().await.map(|objects| serialize_ruleset(&objects))
+    }
+}
+
+/// An iptables chain standing in for an nftables base chain, as found via the rule jumping into it.
+struct IptBaseChain {
+    jump_tag: JumpTag,
+    table: &'static str,
+    chain: String,
+    chain_type: NfChainType,
+    hook: NfHook,
+}
+
+/// One family of iptables that a ruleset is being translated into, alongside the state of it that was saved beforehand.
+struct IptTranslation {
+    family: IptFamily,
+    chains: Vec<(String, String)>,
+    base_chains: Vec<IptBaseChain>,
+    saved_tables: Vec<SavedTable>,
+    script: RestoreScript,
+}
+
+impl IptTranslation {
+    fn new(family: IptFamily, saved_tables: Vec<SavedTable>) -> Self {
+        Self {
+            family,
+            chains: saved_tables
+                .iter()
+                .flat_map(|table| table.chains.iter().map(|chain| (table.name.clone(), chain.clone())))
+                .collect(),
+            base_chains: find_base_chains(&saved_tables),
+            saved_tables,
+            script: RestoreScript::default(),
+        }
+    }
+
+    fn add_base_chain(&mut self, jump_tag: JumpTag, chain_type: NfChainType, hook: NfHook) -> Result<(), NftablesError> {
+        if self.base_chain(&jump_tag).is_some() {
+            return Ok(());
+        }
+
+        let (table, builtin) = builtin_chain(chain_type, hook)
+            .ok_or_else(|| unsupported("base chains other than postrouting and prerouting NAT and forward filter chains"))?;
+        let chain = chain_name(jump_tag.family, &jump_tag.table, &jump_tag.chain)
+            .ok_or_else(|| unsupported("table and chain names this long"))?;
+
+        if !self
+            .chains
+            .iter()
+            .any(|(saved_table, saved_chain)| saved_table == table && *saved_chain == chain)
+        {
+            self.script.push(table, &["-N".to_string(), chain.clone()]);
+            self.chains.push((table.to_string(), chain.clone()));
+        }
+
+        let mut args = vec!["-I".to_string(), builtin.to_string(), "1".to_string()];
+        args.extend(encode_jump(&jump_tag, &chain).ok_or_else(|| unsupported("families other than ip, ip6 and inet"))?);
+        self.script.push(table, &args);
+
+        self.base_chains.push(IptBaseChain {
+            jump_tag,
+            table,
+            chain,
+            chain_type,
+            hook,
+        });
+        Ok(())
+    }
+
+    fn push_rule(&mut self, command: &str, rule: &Rule) -> Result<(), NftablesError> {
+        let base_chain = rule_base_chain(&self.base_chains, rule)?;
+        let mut args = vec![command.to_string(), base_chain.chain.clone()];
+        args.extend(encode_rule(&rule.expr, rule.comment.as_deref()).ok_or_else(|| unsupported("rules of this kind"))?);
+
+        self.script.push(base_chain.table, &args);
+        Ok(())
+    }
+
+    /// Reset the counters of the rule that has the same statements and comment as the given one, which is located by its
+    /// position in its chain as saved beforehand, as iptables can only zero a rule by its position. Rules can therefore
+    /// only be replaced in a ruleset that doesn't also add or delete rules in front of them, and the position stays
+    /// valid until the restore since the chain is owned by fcnet and the [APPLY_LOCK] is held in between.
+    fn zero_rule(&mut self, rule: &Rule) -> Result<(), NftablesError> {
+        let base_chain = rule_base_chain(&self.base_chains, rule)?;
+        let position = self
+            .saved_tables
+            .iter()
+            .filter(|saved_table| saved_table.name == base_chain.table)
+            .flat_map(|saved_table| saved_table.rules.iter())
+            .filter(|saved_rule| saved_rule.chain == base_chain.chain)
+            .position(|saved_rule| {
+                decode_rule(base_chain.jump_tag.family, &saved_rule.args, AnonymousCounter::default()).is_some_and(
+                    |(statements, comment)| {
+                        statements_match(&statements, &rule.expr) && comment.as_deref() == rule.comment.as_deref()
+                    },
+                )
+            })
+            .ok_or_else(|| {
+                iptables_error(
+                    io::ErrorKind::NotFound,
+                    format!("The rule to be replaced doesn't exist in the chain {}", base_chain.chain),
+                )
+            })?;
+
+        self.script.push(
+            base_chain.table,
+            &["-Z".to_string(), base_chain.chain.clone(), (position + 1).to_string()],
+        );
+        Ok(())
+    }
+
+    fn base_chain(&self, jump_tag: &JumpTag) -> Option<&IptBaseChain> {
+        self.base_chains.iter().find(|base_chain| {
+            base_chain.jump_tag.family == jump_tag.family
+                && base_chain.jump_tag.table == jump_tag.table
+                && base_chain.jump_tag.chain == jump_tag.chain
+        })
+    }
+}
+
+async fn apply_ruleset<D: Driver>(objects: &[NfRulesetObject<'_>]) -> Result<(), NftablesError> {
+    let _guard = APPLY_LOCK.lock().await;
+    let mut translations: Vec<IptTranslation> = Vec::new();
+
+    for object in objects {
+        match object {
+            // tables only exist implicitly through the chains standing in for their base chains
+            NfRulesetObject::Object(NfObject::CmdObject(NfCmd::Add(NfListObject::Table(_)))) => {}
+            NfRulesetObject::Object(NfObject::CmdObject(NfCmd::Add(NfListObject::Chain(chain)))) => {
+                let (Some(chain_type), Some(hook), Some(priority)) = (chain._type, chain.hook, chain.prio) else {
+                    return Err(unsupported("chains other than base chains"));
+                };
+
+                if chain.policy == Some(NfChainPolicy::Drop) {
+                    return Err(unsupported("base chains with a drop policy"));
+                }
+
+                let families =
+                    rule_families(chain.family, &[]).ok_or_else(|| unsupported("families other than ip, ip6 and inet"))?;
+
+                for family in families {
+                    let jump_tag = JumpTag {
+                        family: chain.family,
+                        table: chain.table.to_string(),
+                        chain: chain.name.to_string(),
+                        priority,
+                    };
+                    translation::<D>(&mut translations, family)
+                        .await?
+                        .add_base_chain(jump_tag, chain_type, hook)?;
+                }
+            }
+            NfRulesetObject::Object(NfObject::CmdObject(NfCmd::Add(NfListObject::Rule(rule)))) => {
+                push_rule::<D>(&mut translations, "-A", rule).await?
+            }
+            NfRulesetObject::Object(NfObject::CmdObject(NfCmd::Delete(NfListObject::Rule(rule)))) => {
+                push_rule::<D>(&mut translations, "-D", rule).await?
+            }
+            NfRulesetObject::Object(NfObject::CmdObject(NfCmd::Replace(rule))) => {
+                // the counters of iptables rules can be reset, but not set
+                if rule.expr.iter().any(|statement| {
+                    matches!(
+                        statement,
+                        Statement::Counter(Counter::Anonymous(Some(AnonymousCounter { packets, bytes })))
+                            if packets.unwrap_or_default() != 0 || bytes.unwrap_or_default() != 0
+                    )
+                }) {
+                    return Err(unsupported("replacing rules other than to reset their counters"));
+                }
+
+                let families =
+                    rule_families(rule.family, &rule.expr).ok_or_else(|| unsupported("families other than ip, ip6 and inet"))?;
+
+                for family in families {
+                    translation::<D>(&mut translations, family).await?.zero_rule(rule)?;
+                }
+            }
+            NfRulesetObject::VerdictMap(_) | NfRulesetObject::AddVerdictMap(_) => return Err(unsupported("verdict maps")),
+            _ => {
+                return Err(unsupported(
+                    "commands other than adding base chains and adding, deleting or replacing rules",
+                ))
+            }
+        }
+    }
+
+    for translation in translations {
+        if translation.script.is_empty() {
+            continue;
+        }
+
+        run::<D>(
+            translation.family.restore_program(),
+            &["--noflush", "--wait"],
+            Some(translation.script.serialize().as_bytes()),
+            "applying ruleset",
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn push_rule<D: Driver>(
+    translations: &mut Vec<IptTranslation>,
+    command: &str,
+    rule: &Rule<'_>,
+) -> Result<(), NftablesError> {
+    let families = rule_families(rule.family, &rule.expr).ok_or_else(|| unsupported("families other than ip, ip6 and inet"))?;
+
+    for family in families {
+        translation::<D>(translations, family).await?.push_rule(command, rule)?;
+    }
+
+    Ok(())
+}
+
+/// Get the translation into the given family, saving the current state of the family when it is first needed.
+async fn translation<D: Driver>(
+    translations: &mut Vec<IptTranslation>,
+    family: IptFamily,
+) -> Result<&mut IptTranslation, NftablesError> {
+    let index = match translations.iter().position(|translation| translation.family == family) {
+        Some(index) => index,
+        None => {
+            let saved_tables = save::<D>(family).await?;
+            translations.push(IptTranslation::new(family, saved_tables));
+            translations.len() - 1
+        }
+    };
+
+    Ok(&mut translations[index])
+}
+
+/// List the tables, base chains and rules that the iptables chains standing in for base chains amount to. Rules that
+/// aren't supported are skipped unless they are tagged by fcnet, in which case listing fails.
+async fn list_ruleset<D: Driver>() -> Result<Vec<NfRulesetObject<'static>>, NftablesError> {
+    let mut tables = Vec::new();
+    let mut chains = Vec::new();
+    let mut rules = Vec::new();
+
+    for family in IptFamily::ALL {
+        let saved_tables = save::<D>(family).await?;
+        let base_chains = find_base_chains(&saved_tables);
+
+        for base_chain in base_chains.iter() {
+            let table = Table {
+                family: base_chain.jump_tag.family,
+                name: base_chain.jump_tag.table.clone().into(),
+                handle: None,
+            };
+            let chain = Chain {
+                family: base_chain.jump_tag.family,
+                table: base_chain.jump_tag.table.clone().into(),
+                name: base_chain.jump_tag.chain.clone().into(),
+                newname: None,
+                handle: None,
+                _type: Some(base_chain.chain_type),
+                hook: Some(base_chain.hook),
+                prio: Some(base_chain.jump_tag.priority),
+                dev: None,
+                policy: Some(NfChainPolicy::Accept),
+            };
+
+            // the chains of inet tables are listed by both families
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+
+            if !chains.contains(&chain) {
+                chains.push(chain);
+            }
+        }
+
+        for saved_table in saved_tables.iter() {
+            for saved_rule in saved_table.rules.iter() {
+                let Some(base_chain) = base_chains
+                    .iter()
+                    .find(|base_chain| base_chain.table == saved_table.name && base_chain.chain == saved_rule.chain)
+                else {
+                    continue;
+                };
+
+                let counter = AnonymousCounter {
+                    packets: Some(saved_rule.packets as usize),
+                    bytes: Some(saved_rule.bytes as usize),
+                };
+                let Some((statements, comment)) = decode_rule(base_chain.jump_tag.family, &saved_rule.args, counter) else {
+                    if rule_comment(&saved_rule.args).and_then(parse_nf_rule_tag).is_some() {
+                        return Err(unsupported("tagged rules that were modified"));
+                    }
+
+                    continue;
+                };
+
+                let rule = Rule {
+                    family: base_chain.jump_tag.family,
+                    table: base_chain.jump_tag.table.clone().into(),
+                    chain: base_chain.jump_tag.chain.clone().into(),
+                    expr: statements.into(),
+                    handle: None,
+                    index: None,
+                    comment: comment.map(Into::into),
+                };
+
+                // rules of inet tables that pertain to both families are listed by both, counting the traffic of both
+                match rules.iter_mut().find(|listed_rule: &&mut Rule| {
+                    listed_rule.family == rule.family
+                        && listed_rule.table == rule.table
+                        && listed_rule.chain == rule.chain
+                        && listed_rule.comment == rule.comment
+                        && statements_match(&listed_rule.expr, &rule.expr)
+                }) {
+                    Some(listed_rule) => add_counters(listed_rule, &rule),
+                    None => rules.push(rule),
+                }
+            }
+        }
+    }
+
+    Ok(tables
+        .into_iter()
+        .map(NfListObject::Table)
+        .chain(chains.into_iter().map(NfListObject::Chain))
+        .chain(rules.into_iter().map(NfListObject::Rule))
+        .map(|object| NfRulesetObject::Object(NfObject::ListObject(object)))
+        .collect())
+}
+
+/// Add the values of the counters of a rule listed by another family to those of the same rule listed before.
+fn add_counters(listed_rule: &mut Rule<'static>, rule: &Rule<'static>) {
+    let counters = rule.expr.iter().filter_map(|statement| match statement {
+        Statement::Counter(Counter::Anonymous(Some(counter))) => Some(counter),
+        _ => None,
+    });
+    let listed_counters = listed_rule.expr.to_mut().iter_mut().filter_map(|statement| match statement {
+        Statement::Counter(Counter::Anonymous(Some(counter))) => Some(counter),
+        _ => None,
+    });
+
+    for (listed_counter, counter) in listed_counters.zip(counters) {
+        listed_counter.packets = Some(listed_counter.packets.unwrap_or_default() + counter.packets.unwrap_or_default());
+        listed_counter.bytes = Some(listed_counter.bytes.unwrap_or_default() + counter.bytes.unwrap_or_default());
+    }
+}
+
+/// The chain standing in for the base chain that the given rule resides in.
+fn rule_base_chain<'a>(base_chains: &'a [IptBaseChain], rule: &Rule) -> Result<&'a IptBaseChain, NftablesError> {
+    base_chains
+        .iter()
+        .find(|base_chain| {
+            base_chain.jump_tag.family == rule.family
+                && base_chain.jump_tag.table == rule.table
+                && base_chain.jump_tag.chain == rule.chain
+        })
+        .ok_or_else(|| {
+            iptables_error(
+                io::ErrorKind::NotFound,
+                format!("The chain {} of the table {} doesn't exist", rule.chain, rule.table),
+            )
+        })
+}
+
+/// Find the iptables chains standing in for base chains via the rules of built-in chains that jump into them.
+fn find_base_chains(saved_tables: &[SavedTable]) -> Vec<IptBaseChain> {
+    let mut base_chains = Vec::new();
+
+    for saved_table in saved_tables.iter() {
+        for saved_rule in saved_table.rules.iter() {
+            let Some((chain_type, hook)) = builtin_chain_hook(&saved_table.name, &saved_rule.chain) else {
+                continue;
+            };
+            let Some((table, _)) = builtin_chain(chain_type, hook) else {
+                continue;
+            };
+
+            let Some((jump_tag, chain)) = decode_jump(&saved_rule.args) else {
+                continue;
+            };
+
+            if saved_table.has_chain(&chain) {
+                base_chains.push(IptBaseChain {
+                    jump_tag,
+                    table,
+                    chain,
+                    chain_type,
+                    hook,
+                });
+            }
+        }
+    }
+
+    base_chains
+}
+
+async fn save<D: Driver>(family: IptFamily) -> Result<Vec<SavedTable>, NftablesError> {
+    let output = run::<D>(family.save_program(), &["-c"], None, "saving the ruleset").await?;
+    parse_save(&output).ok_or_else(|| iptables_error(io::ErrorKind::InvalidData, "The output of iptables-save is malformed"))
+}
+
+async fn run<D: Driver>(program: &str, args: &[&str], stdin: Option<&[u8]>, hint: &str) -> Result<String, NftablesError> {
+    let args = args.iter().map(OsStr::new).collect::<Vec<_>>();
+    let output = D::run_process(OsStr::new(program), &args, stdin)
+        .await
+        .map_err(|inner| NftablesError::NftExecution {
+            program: program.into(),
+            inner,
+        })?;
+
+    let stdout = String::from_utf8(output.stdout).map_err(|inner| NftablesError::NftOutputEncoding {
+        program: program.into(),
+        inner,
+    })?;
+
+    if !output.status.success() {
+        return Err(NftablesError::NftFailed {
+            program: program.into(),
+            hint: hint.to_string(),
+            stdout,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    Ok(stdout)
+}
+
+#[inline]
+fn unsupported(what: &str) -> NftablesError {
+    iptables_error(
+        io::ErrorKind::Unsupported,
+        format!("The iptables driver doesn't support {what}"),
+    )
+}
+
+#[inline]
+fn iptables_error(kind: io::ErrorKind, message: impl Into<String>) -> NftablesError {
+    NftablesError::NftExecution {
+        program: "iptables".into(),
+        inner: io::Error::new(kind, message.into()),
+    }
+}
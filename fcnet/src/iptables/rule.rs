@@ -0,0 +1,847 @@
+use std::net::IpAddr;
+
+use nftables::{
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Prefix, Range, CT},
+    stmt::{AnonymousCounter, Counter, Match, NATFamily, Operator, Statement, NAT},
+    types::{NfChainType, NfFamily, NfHook},
+};
+
+use crate::util::nat_proto_from_addr;
+
+/// The maximum length of the name of an iptables chain.
+const IPT_CHAIN_NAME_MAXLEN: usize = 28;
+/// The prefix of the comments of the rules in built-in chains that jump into the chains standing in for base chains,
+/// which is distinct from the prefix of the tags of rules so that such jumps are never mistaken for rules of networks.
+const IPT_JUMP_TAG_PREFIX: &str = "fcnet-chain";
+/// The conntrack states that nft and the conntrack match of iptables both know, in the order that nft lists them in.
+const CT_STATES: [&str; 5] = ["invalid", "established", "related", "new", "untracked"];
+
+/// The address family of an iptables ruleset, which iptables and ip6tables keep separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IptFamily {
+    V4,
+    V6,
+}
+
+impl IptFamily {
+    pub const ALL: [IptFamily; 2] = [IptFamily::V4, IptFamily::V6];
+
+    pub fn save_program(self) -> &'static str {
+        match self {
+            IptFamily::V4 => "iptables-save",
+            IptFamily::V6 => "ip6tables-save",
+        }
+    }
+
+    pub fn restore_program(self) -> &'static str {
+        match self {
+            IptFamily::V4 => "iptables-restore",
+            IptFamily::V6 => "ip6tables-restore",
+        }
+    }
+
+    fn of_addr(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => IptFamily::V4,
+            IpAddr::V6(_) => IptFamily::V6,
+        }
+    }
+}
+
+/// The iptables table and built-in chain that jump into the chain standing in for a base chain of the given type
+/// hooked into the given hook, or [None] if no built-in chain corresponds to it.
+pub fn builtin_chain(chain_type: NfChainType, hook: NfHook) -> Option<(&'static str, &'static str)> {
+    match (chain_type, hook) {
+        (NfChainType::NAT, NfHook::Postrouting) => Some(("nat", "POSTROUTING")),
+        (NfChainType::NAT, NfHook::Prerouting) => Some(("nat", "PREROUTING")),
+        (NfChainType::Filter, NfHook::Forward) => Some(("filter", "FORWARD")),
+        _ => None,
+    }
+}
+
+/// The type and hook of the base chains that the given built-in chain of the given iptables table jumps on behalf of.
+pub fn builtin_chain_hook(table: &str, chain: &str) -> Option<(NfChainType, NfHook)> {
+    match (table, chain) {
+        ("nat", "POSTROUTING") => Some((NfChainType::NAT, NfHook::Postrouting)),
+        ("nat", "PREROUTING") => Some((NfChainType::NAT, NfHook::Prerouting)),
+        ("filter", "FORWARD") => Some((NfChainType::Filter, NfHook::Forward)),
+        _ => None,
+    }
+}
+
+/// The name of the iptables chain standing in for the given base chain of the given nftables table in the given family,
+/// such as "FCNET-POSTROUTING", or [None] if it would be too long. The chains of inet tables are kept apart from those
+/// of ip and ip6 tables with the same name, such as "FCNET-INET-POSTROUTING".
+pub fn chain_name(family: NfFamily, table: &str, chain: &str) -> Option<String> {
+    let name = match family {
+        NfFamily::INet => format!("{table}-inet-{chain}"),
+        _ => format!("{table}-{chain}"),
+    }
+    .to_uppercase();
+    (name.len() <= IPT_CHAIN_NAME_MAXLEN).then_some(name)
+}
+
+/// A base chain as recorded in the comment of the rule jumping into the iptables chain standing in for it, since
+/// iptables has no notion of tables, families or priorities of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JumpTag {
+    pub family: NfFamily,
+    pub table: String,
+    pub chain: String,
+    pub priority: i32,
+}
+
+impl JumpTag {
+    pub fn format(&self) -> Option<String> {
+        let family = match self.family {
+            NfFamily::IP => "ip",
+            NfFamily::IP6 => "ip6",
+            NfFamily::INet => "inet",
+            _ => return None,
+        };
+
+        Some(format!(
+            "{IPT_JUMP_TAG_PREFIX}:{family}:{}:{}:{}",
+            self.table, self.chain, self.priority
+        ))
+    }
+
+    pub fn parse(comment: &str) -> Option<Self> {
+        let mut parts = comment.strip_prefix(IPT_JUMP_TAG_PREFIX)?.strip_prefix(':')?.split(':');
+
+        let family = match parts.next()? {
+            "ip" => NfFamily::IP,
+            "ip6" => NfFamily::IP6,
+            "inet" => NfFamily::INet,
+            _ => return None,
+        };
+        let table = parts.next()?.to_string();
+        let chain = parts.next()?.to_string();
+        let priority = parts.next()?.parse().ok()?;
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Self {
+                family,
+                table,
+                chain,
+                priority,
+            }),
+        }
+    }
+}
+
+/// Encode the rule of a built-in chain that jumps into the iptables chain standing in for the base chain with the given
+/// tag.
+pub fn encode_jump(jump_tag: &JumpTag, target: &str) -> Option<Vec<String>> {
+    Some(vec![
+        "-m".to_string(),
+        "comment".to_string(),
+        "--comment".to_string(),
+        jump_tag.format()?,
+        "-j".to_string(),
+        target.to_string(),
+    ])
+}
+
+/// Decode the rule of a built-in chain into the tag of the base chain it jumps on behalf of and the iptables chain it
+/// jumps into, if it is such a jump.
+pub fn decode_jump(args: &[String]) -> Option<(JumpTag, String)> {
+    match args {
+        [m, module, option, comment, j, target] if m == "-m" && module == "comment" && option == "--comment" && j == "-j" => {
+            Some((JumpTag::parse(comment)?, target.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// The comment of an iptables rule, which can be read even if the rest of the rule isn't supported.
+pub fn rule_comment(args: &[String]) -> Option<&str> {
+    args.windows(2)
+        .find(|pair| pair[0] == "--comment")
+        .map(|pair| pair[1].as_str())
+}
+
+/// The iptables families that a rule of the given nftables family with the given statements is placed into: rules of
+/// inet tables go into both unless they match or translate addresses of a single family.
+pub fn rule_families(family: NfFamily, statements: &[Statement]) -> Option<Vec<IptFamily>> {
+    match family {
+        NfFamily::IP => Some(vec![IptFamily::V4]),
+        NfFamily::IP6 => Some(vec![IptFamily::V6]),
+        NfFamily::INet => match statements.iter().find_map(statement_family) {
+            Some(ipt_family) => Some(vec![ipt_family]),
+            None => Some(IptFamily::ALL.to_vec()),
+        },
+        _ => None,
+    }
+}
+
+fn statement_family(statement: &Statement) -> Option<IptFamily> {
+    match statement {
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, .. }))),
+            ..
+        }) => match protocol.as_ref() {
+            "ip" => Some(IptFamily::V4),
+            "ip6" => Some(IptFamily::V6),
+            _ => None,
+        },
+        Statement::SNAT(Some(nat)) | Statement::DNAT(Some(nat)) => nat_target(nat).map(|(addr, _)| IptFamily::of_addr(addr)),
+        _ => None,
+    }
+}
+
+/// Encode the statements and comment of a rule into the arguments of an iptables rule specification, or return [None]
+/// if any of them can't be expressed with iptables.
+pub fn encode_rule(statements: &[Statement], comment: Option<&str>) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut target = None;
+
+    for statement in statements {
+        if target.is_some() {
+            return None;
+        }
+
+        match statement {
+            Statement::Match(Match {
+                left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field }))),
+                right,
+                op: Operator::EQ,
+            }) if protocol == "tcp" || protocol == "udp" => {
+                let option = match field.as_ref() {
+                    "sport" => "--sport",
+                    "dport" => "--dport",
+                    _ => return None,
+                };
+                let ports = match right {
+                    Expression::Number(port) => port.to_string(),
+                    Expression::Range(range) => match &range.range {
+                        [Expression::Number(start), Expression::Number(end)] => format!("{start}:{end}"),
+                        _ => return None,
+                    },
+                    _ => return None,
+                };
+
+                args.extend(["-p", protocol, "-m", protocol, option, &ports].map(str::to_string));
+            }
+            Statement::Match(Match {
+                left:
+                    Expression::Named(NamedExpression::CT(CT {
+                        key,
+                        family: None,
+                        dir: None,
+                    })),
+                right,
+                op: Operator::IN,
+            }) if key == "state" => {
+                let states = match right {
+                    Expression::String(state) => vec![state.as_ref()],
+                    Expression::List(states) => states
+                        .iter()
+                        .map(|state| match state {
+                            Expression::String(state) => Some(state.as_ref()),
+                            _ => None,
+                        })
+                        .collect::<Option<_>>()?,
+                    _ => return None,
+                };
+
+                if states.iter().any(|state| !CT_STATES.contains(state)) {
+                    return None;
+                }
+
+                args.extend(["-m", "conntrack", "--ctstate", &states.join(",").to_uppercase()].map(str::to_string));
+            }
+            Statement::Match(Match {
+                left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field }))),
+                right: Expression::Named(NamedExpression::Prefix(Prefix { addr, len })),
+                op: Operator::EQ,
+            }) if protocol == "ip" || protocol == "ip6" => {
+                let Expression::String(addr) = addr.as_ref() else {
+                    return None;
+                };
+                let option = match field.as_ref() {
+                    "saddr" => "-s",
+                    "daddr" => "-d",
+                    _ => return None,
+                };
+
+                args.extend([option.to_string(), format!("{addr}/{len}")]);
+            }
+            Statement::Match(Match {
+                left,
+                right: Expression::String(value),
+                op,
+            }) if !value.starts_with('@') => {
+                let option = match left {
+                    Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })) => "-i",
+                    Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })) => "-o",
+                    Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field })))
+                        if protocol == "ip" || protocol == "ip6" =>
+                    {
+                        match field.as_ref() {
+                            "saddr" => "-s",
+                            "daddr" => "-d",
+                            _ => return None,
+                        }
+                    }
+                    _ => return None,
+                };
+
+                match op {
+                    Operator::EQ => {}
+                    // interfaces are the only values that are ever negated
+                    Operator::NEQ if option == "-i" || option == "-o" => args.push("!".to_string()),
+                    _ => return None,
+                }
+
+                args.extend([option.to_string(), value.to_string()]);
+            }
+            Statement::Accept(_) => target = Some(vec!["ACCEPT".to_string()]),
+            Statement::Drop(_) => target = Some(vec!["DROP".to_string()]),
+            Statement::Masquerade(None) => target = Some(vec!["MASQUERADE".to_string()]),
+            Statement::SNAT(Some(nat)) => {
+                target = Some(vec!["SNAT".to_string(), "--to-source".to_string(), encode_nat_target(nat)?])
+            }
+            Statement::DNAT(Some(nat)) => {
+                target = Some(vec![
+                    "DNAT".to_string(),
+                    "--to-destination".to_string(),
+                    encode_nat_target(nat)?,
+                ])
+            }
+            // iptables counts the traffic of every rule regardless
+            Statement::Counter(Counter::Anonymous(_)) => {}
+            _ => return None,
+        }
+    }
+
+    if let Some(comment) = comment {
+        args.extend(["-m", "comment", "--comment", comment].map(str::to_string));
+    }
+
+    args.push("-j".to_string());
+    args.extend(target?);
+    Some(args)
+}
+
+/// Decode the arguments of an iptables rule in a chain of the given nftables family into statements and a comment, or
+/// return [None] if any of them isn't supported. The statements come out in the order that iptables-save prints
+/// matches in, which needn't be the order that they were added in, and the target is preceded by a counter with the
+/// given values, since iptables counts the traffic of every rule.
+pub fn decode_rule(
+    family: NfFamily,
+    args: &[String],
+    counter: AnonymousCounter,
+) -> Option<(Vec<Statement<'static>>, Option<String>)> {
+    let mut statements = Vec::new();
+    let mut comment = None;
+    // the protocol given via "-p", which is only supported in front of the match of its ports
+    let mut protocol = None;
+    let mut args = args.iter().map(String::as_str);
+
+    while let Some(arg) = args.next() {
+        match arg {
+            "-i" | "-o" | "!" => {
+                let (option, op) = match arg {
+                    "!" => (args.next()?, Operator::NEQ),
+                    option => (option, Operator::EQ),
+                };
+
+                statements.push(Statement::Match(Match {
+                    left: Expression::Named(NamedExpression::Meta(Meta {
+                        key: match option {
+                            "-i" => MetaKey::Iifname,
+                            "-o" => MetaKey::Oifname,
+                            _ => return None,
+                        },
+                    })),
+                    right: Expression::String(args.next()?.to_string().into()),
+                    op,
+                }));
+            }
+            "-s" | "-d" => {
+                let (addr, right) = decode_addr_match(args.next()?)?;
+                statements.push(Statement::Match(Match {
+                    left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                        protocol: nat_proto_from_addr(addr),
+                        field: match arg {
+                            "-s" => "saddr",
+                            _ => "daddr",
+                        }
+                        .into(),
+                    }))),
+                    right,
+                    op: Operator::EQ,
+                }));
+            }
+            "-p" if protocol.is_none() => protocol = Some(args.next()?),
+            "-m" => match args.next()? {
+                "comment" => {
+                    if args.next()? != "--comment" {
+                        return None;
+                    }
+
+                    comment = Some(args.next()?.to_string());
+                }
+                "conntrack" => {
+                    if args.next()? != "--ctstate" {
+                        return None;
+                    }
+
+                    // iptables-save lists the states in an order of its own, so they are put into the order of nft
+                    let names = args.next()?.to_lowercase();
+                    let mut states = CT_STATES
+                        .into_iter()
+                        .filter(|state| names.split(',').any(|name| name == *state))
+                        .map(|state| Expression::String(state.into()))
+                        .collect::<Vec<_>>();
+
+                    // states unknown to nft, such as SNAT, would otherwise be lost
+                    if states.is_empty() || states.len() != names.split(',').count() {
+                        return None;
+                    }
+
+                    statements.push(Statement::Match(Match {
+                        left: Expression::Named(NamedExpression::CT(CT {
+                            key: "state".into(),
+                            family: None,
+                            dir: None,
+                        })),
+                        right: match states.len() {
+                            1 => states.pop()?,
+                            _ => Expression::List(states),
+                        },
+                        op: Operator::IN,
+                    }));
+                }
+                module @ ("tcp" | "udp") if protocol.take() == Some(module) => {
+                    let field = match args.next()? {
+                        "--sport" => "sport",
+                        "--dport" => "dport",
+                        _ => return None,
+                    };
+                    let ports = args.next()?;
+                    let right = match ports.split_once(':') {
+                        Some((start, end)) => Expression::Range(Box::new(Range {
+                            range: [
+                                Expression::Number(start.parse::<u16>().ok()?.into()),
+                                Expression::Number(end.parse::<u16>().ok()?.into()),
+                            ],
+                        })),
+                        None => Expression::Number(ports.parse::<u16>().ok()?.into()),
+                    };
+
+                    statements.push(Statement::Match(Match {
+                        left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                            protocol: module.to_string().into(),
+                            field: field.into(),
+                        }))),
+                        right,
+                        op: Operator::EQ,
+                    }));
+                }
+                _ => return None,
+            },
+            "-j" => {
+                let statement = match args.next()? {
+                    "ACCEPT" => Statement::Accept(None),
+                    "DROP" => Statement::Drop(None),
+                    "MASQUERADE" => Statement::Masquerade(None),
+                    target @ ("SNAT" | "DNAT") => {
+                        let option = args.next()?;
+                        let (addr, port) = decode_nat_target(args.next()?)?;
+                        let nat = Some(NAT {
+                            addr: Some(Expression::String(addr.to_string().into())),
+                            family: match family {
+                                NfFamily::INet => Some(match addr {
+                                    IpAddr::V4(_) => NATFamily::IP,
+                                    IpAddr::V6(_) => NATFamily::IP6,
+                                }),
+                                _ => None,
+                            },
+                            port: port.map(|port| Expression::Number(port.into())),
+                            flags: None,
+                        });
+
+                        match (target, option) {
+                            ("SNAT", "--to-source") => Statement::SNAT(nat),
+                            ("DNAT", "--to-destination") => Statement::DNAT(nat),
+                            _ => return None,
+                        }
+                    }
+                    _ => return None,
+                };
+
+                statements.push(Statement::Counter(Counter::Anonymous(Some(counter.clone()))));
+                statements.push(statement);
+
+                if args.next().is_some() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    // a protocol that isn't followed by a match of its ports would otherwise be lost
+    if protocol.is_some() {
+        return None;
+    }
+
+    Some((statements, comment))
+}
+
+fn nat_target(nat: &NAT) -> Option<(IpAddr, Option<u16>)> {
+    match nat {
+        NAT {
+            addr: Some(Expression::String(addr)),
+            port,
+            flags: None,
+            ..
+        } => {
+            let port = match port {
+                Some(Expression::Number(port)) => Some(u16::try_from(*port).ok()?),
+                Some(_) => return None,
+                None => None,
+            };
+
+            Some((addr.parse().ok()?, port))
+        }
+        _ => None,
+    }
+}
+
+/// Encode the address and optional port that a NAT statement translates to in the notation of iptables, which puts
+/// IPv6 addresses into brackets when they are followed by a port.
+fn encode_nat_target(nat: &NAT) -> Option<String> {
+    Some(match nat_target(nat)? {
+        (addr, None) => addr.to_string(),
+        (IpAddr::V4(addr), Some(port)) => format!("{addr}:{port}"),
+        (IpAddr::V6(addr), Some(port)) => format!("[{addr}]:{port}"),
+    })
+}
+
+fn decode_nat_target(value: &str) -> Option<(IpAddr, Option<u16>)> {
+    if let Ok(addr) = value.parse::<IpAddr>() {
+        return Some((addr, None));
+    }
+
+    let (addr, port) = match value.strip_prefix('[') {
+        Some(value) => value.split_once("]:")?,
+        None => value.split_once(':')?,
+    };
+
+    Some((addr.parse().ok()?, Some(port.parse().ok()?)))
+}
+
+/// Decode an address or network matched by iptables into the address and the right-hand side of the match, which
+/// iptables-save prints with the prefix length even for a single host.
+fn decode_addr_match(value: &str) -> Option<(IpAddr, Expression<'static>)> {
+    let (addr, prefix_len) = match value.split_once('/') {
+        Some((addr, prefix_len)) => (addr.parse::<IpAddr>().ok()?, Some(prefix_len.parse::<u32>().ok()?)),
+        None => (value.parse::<IpAddr>().ok()?, None),
+    };
+    let host_len = match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+
+    let right = match prefix_len {
+        None => Expression::String(addr.to_string().into()),
+        Some(prefix_len) if prefix_len == host_len => Expression::String(addr.to_string().into()),
+        Some(prefix_len) if prefix_len < host_len => Expression::Named(NamedExpression::Prefix(Prefix {
+            addr: Box::new(Expression::String(addr.to_string().into())),
+            len: prefix_len,
+        })),
+        Some(_) => return None,
+    };
+
+    Some((addr, right))
+}
+
+#[cfg(test)]
+mod tests {
+    use nftables::{
+        expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Prefix, Range, CT},
+        stmt::{AnonymousCounter, Counter, Match, NATFamily, Operator, Statement, NAT},
+        types::NfFamily,
+    };
+
+    use super::{chain_name, decode_jump, decode_rule, encode_jump, encode_rule, rule_comment, JumpTag};
+
+    fn meta(key: MetaKey) -> Expression<'static> {
+        Expression::Named(NamedExpression::Meta(Meta { key }))
+    }
+
+    fn payload(protocol: &'static str, field: &'static str) -> Expression<'static> {
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: protocol.into(),
+            field: field.into(),
+        })))
+    }
+
+    fn string(value: &'static str) -> Expression<'static> {
+        Expression::String(value.into())
+    }
+
+    fn matching(left: Expression<'static>, op: Operator, right: Expression<'static>) -> Statement<'static> {
+        Statement::Match(Match { left, right, op })
+    }
+
+    fn counter(packets: usize, bytes: usize) -> AnonymousCounter {
+        AnonymousCounter {
+            packets: Some(packets),
+            bytes: Some(bytes),
+        }
+    }
+
+    fn counted(counter: AnonymousCounter) -> Statement<'static> {
+        Statement::Counter(Counter::Anonymous(Some(counter)))
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[track_caller]
+    fn assert_round_trip(family: NfFamily, statements: Vec<Statement<'static>>, comment: Option<&str>) {
+        let counter = counter(3, 180);
+        let args = encode_rule(&statements, comment).expect("statements should be supported by iptables");
+        let mut expected = statements;
+        let verdict = expected.pop().unwrap();
+        expected.extend([counted(counter.clone()), verdict]);
+
+        assert_eq!(
+            decode_rule(family, &args, counter),
+            Some((expected, comment.map(str::to_string)))
+        );
+    }
+
+    #[test]
+    fn masquerade_rule_round_trips() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(payload("ip", "saddr"), Operator::EQ, string("172.16.0.2")),
+                matching(meta(MetaKey::Oifname), Operator::EQ, string("eth0")),
+                Statement::Masquerade(None),
+            ],
+            Some("fcnet:1:masq"),
+        );
+    }
+
+    #[test]
+    fn forward_rules_round_trip() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(meta(MetaKey::Iifname), Operator::EQ, string("tap0")),
+                matching(meta(MetaKey::Oifname), Operator::NEQ, string("tap0")),
+                Statement::Accept(None),
+            ],
+            Some("fcnet:1:forward"),
+        );
+        assert_round_trip(
+            NfFamily::IP6,
+            vec![
+                matching(
+                    Expression::Named(NamedExpression::CT(CT {
+                        key: "state".into(),
+                        family: None,
+                        dir: None,
+                    })),
+                    Operator::IN,
+                    Expression::List(vec![string("established"), string("related")]),
+                ),
+                matching(payload("ip6", "daddr"), Operator::EQ, string("fd00::2")),
+                Statement::Drop(None),
+            ],
+            None,
+        );
+    }
+
+    #[test]
+    fn prefix_and_port_matches_round_trip() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(
+                    payload("ip", "daddr"),
+                    Operator::EQ,
+                    Expression::Named(NamedExpression::Prefix(Prefix {
+                        addr: Box::new(string("10.0.0.0")),
+                        len: 8,
+                    })),
+                ),
+                matching(payload("tcp", "dport"), Operator::EQ, Expression::Number(22)),
+                matching(
+                    payload("udp", "sport"),
+                    Operator::EQ,
+                    Expression::Range(Box::new(Range {
+                        range: [Expression::Number(1000), Expression::Number(2000)],
+                    })),
+                ),
+                Statement::Accept(None),
+            ],
+            None,
+        );
+    }
+
+    #[test]
+    fn nat_rules_round_trip() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![Statement::SNAT(Some(NAT {
+                addr: Some(string("192.168.0.1")),
+                family: None,
+                port: None,
+                flags: None,
+            }))],
+            None,
+        );
+        assert_round_trip(
+            NfFamily::INet,
+            vec![Statement::DNAT(Some(NAT {
+                addr: Some(string("fd00::2")),
+                family: Some(NATFamily::IP6),
+                port: Some(Expression::Number(8080)),
+                flags: None,
+            }))],
+            Some("fcnet:1:dnat/8080"),
+        );
+    }
+
+    #[test]
+    fn rule_printed_by_iptables_save_decodes() {
+        let (statements, comment) = decode_rule(
+            NfFamily::IP,
+            &args(concat!(
+                "-s 172.16.0.2/32 -p tcp -m tcp --dport 80 -m conntrack --ctstate RELATED,ESTABLISHED ",
+                "-m comment --comment fcnet:1:fwd -j DNAT --to-destination 172.16.0.2:8080"
+            )),
+            counter(1, 60),
+        )
+        .expect("rule should be supported");
+
+        assert_eq!(comment.as_deref(), Some("fcnet:1:fwd"));
+        assert_eq!(
+            statements,
+            vec![
+                matching(payload("ip", "saddr"), Operator::EQ, string("172.16.0.2")),
+                matching(payload("tcp", "dport"), Operator::EQ, Expression::Number(80)),
+                matching(
+                    Expression::Named(NamedExpression::CT(CT {
+                        key: "state".into(),
+                        family: None,
+                        dir: None,
+                    })),
+                    Operator::IN,
+                    Expression::List(vec![string("established"), string("related")]),
+                ),
+                counted(counter(1, 60)),
+                Statement::DNAT(Some(NAT {
+                    addr: Some(string("172.16.0.2")),
+                    family: None,
+                    port: Some(Expression::Number(8080)),
+                    flags: None,
+                })),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_statements_are_not_encoded() {
+        // set lookups can't be expressed with iptables
+        let lookup = [
+            matching(payload("ip", "saddr"), Operator::EQ, string("@addrs")),
+            Statement::Accept(None),
+        ];
+        assert_eq!(encode_rule(&lookup, None), None);
+
+        // nothing may follow the target
+        let trailing = [Statement::Accept(None), Statement::Drop(None)];
+        assert_eq!(encode_rule(&trailing, None), None);
+
+        // a rule without a target isn't a rule of iptables
+        let untargeted = [matching(meta(MetaKey::Iifname), Operator::EQ, string("tap0"))];
+        assert_eq!(encode_rule(&untargeted, None), None);
+
+        let unknown_state = [
+            matching(
+                Expression::Named(NamedExpression::CT(CT {
+                    key: "state".into(),
+                    family: None,
+                    dir: None,
+                })),
+                Operator::IN,
+                string("snat"),
+            ),
+            Statement::Accept(None),
+        ];
+        assert_eq!(encode_rule(&unknown_state, None), None);
+    }
+
+    #[test]
+    fn unsupported_rules_are_not_decoded() {
+        for line in [
+            "-j LOG",
+            "-p tcp -j ACCEPT",
+            "-m conntrack --ctstate SNAT -j ACCEPT",
+            "-s 10.0.0.0/33 -j ACCEPT",
+            "-j ACCEPT -i tap0",
+            "-j DNAT --to-source 10.0.0.1",
+            "-i",
+        ] {
+            assert_eq!(
+                decode_rule(NfFamily::IP, &args(line), AnonymousCounter::default()),
+                None,
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn comment_is_read_from_unsupported_rules() {
+        assert_eq!(
+            rule_comment(&args("-j LOG -m comment --comment fcnet:1:masq")),
+            Some("fcnet:1:masq")
+        );
+        assert_eq!(rule_comment(&args("-j LOG")), None);
+    }
+
+    #[test]
+    fn jump_round_trips() {
+        let jump_tag = JumpTag {
+            family: NfFamily::INet,
+            table: "fcnet".to_string(),
+            chain: "postrouting".to_string(),
+            priority: -100,
+        };
+        let target = chain_name(jump_tag.family, &jump_tag.table, &jump_tag.chain).unwrap();
+        assert_eq!(target, "FCNET-INET-POSTROUTING");
+
+        let args = encode_jump(&jump_tag, &target).unwrap();
+        assert_eq!(decode_jump(&args), Some((jump_tag, target)));
+    }
+
+    #[test]
+    fn foreign_jumps_are_not_decoded() {
+        assert_eq!(decode_jump(&args("-j DOCKER")), None);
+        assert_eq!(
+            decode_jump(&args("-m comment --comment fcnet:1:masq -j FCNET-POSTROUTING")),
+            None
+        );
+        assert_eq!(JumpTag::parse("fcnet-chain:ip:fcnet:postrouting:0:extra"), None);
+        assert_eq!(JumpTag::parse("fcnet-chain:arp:fcnet:postrouting:0"), None);
+    }
+
+    #[test]
+    fn overlong_chain_names_are_rejected() {
+        assert_eq!(
+            chain_name(NfFamily::IP, "fcnet", "postrouting").as_deref(),
+            Some("FCNET-POSTROUTING")
+        );
+        assert_eq!(chain_name(NfFamily::IP, "a-rather-long-table", "postrouting"), None);
+    }
+}
@@ -0,0 +1,303 @@
+/// An iptables table as printed by iptables-save with counters: the names of its chains and its rules.
+#[derive(Debug, Default)]
+pub struct SavedTable {
+    pub name: String,
+    pub chains: Vec<String>,
+    pub rules: Vec<SavedRule>,
+}
+
+/// A rule of an iptables table: the name of its chain, the arguments that follow it and the packets and bytes it has
+/// counted, which are zero if iptables-save didn't print them.
+#[derive(Debug, Default)]
+pub struct SavedRule {
+    pub chain: String,
+    pub args: Vec<String>,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl SavedTable {
+    pub fn has_chain(&self, chain: &str) -> bool {
+        self.chains.iter().any(|name| name == chain)
+    }
+}
+
+/// Parse the output of iptables-save, returning [None] if it is malformed.
+pub fn parse_save(output: &str) -> Option<Vec<SavedTable>> {
+    let mut tables = Vec::new();
+    let mut current_table: Option<SavedTable> = None;
+
+    for line in output.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('*') {
+            current_table = Some(SavedTable {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        } else if line == "COMMIT" {
+            tables.push(current_table.take()?);
+        } else if let Some(declaration) = line.strip_prefix(':') {
+            let name = declaration.split_whitespace().next()?;
+            current_table.as_mut()?.chains.push(name.to_string());
+        } else {
+            let mut args = tokenize(line)?.into_iter().peekable();
+            let mut rule = SavedRule::default();
+
+            // the counters of a rule precede it as "[packets:bytes]"
+            if let Some(counters) = args.next_if(|arg| arg.starts_with('[')) {
+                let (packets, bytes) = counters.strip_prefix('[')?.strip_suffix(']')?.split_once(':')?;
+                rule.packets = packets.parse().ok()?;
+                rule.bytes = bytes.parse().ok()?;
+            }
+
+            if args.next()?.as_str() != "-A" {
+                return None;
+            }
+
+            rule.chain = args.next()?;
+            rule.args = args.collect();
+            current_table.as_mut()?.rules.push(rule);
+        }
+    }
+
+    Some(tables)
+}
+
+/// Split a line of iptables-save output into its arguments, which are separated by whitespace unless quoted.
+fn tokenize(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars();
+    let mut current = None::<String>;
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_with(String::new);
+            }
+            '\\' if quoted => current.get_or_insert_with(String::new).push(chars.next()?),
+            c if c.is_whitespace() && !quoted => args.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quoted {
+        return None;
+    }
+
+    args.extend(current);
+    Some(args)
+}
+
+/// A script for iptables-restore that modifies the tables it mentions without flushing them. Each table is committed
+/// atomically on its own.
+#[derive(Debug, Default)]
+pub struct RestoreScript {
+    tables: Vec<(&'static str, Vec<String>)>,
+}
+
+impl RestoreScript {
+    /// Append a command given by its arguments, such as "-A" followed by a chain and a rule, to the given table.
+    pub fn push(&mut self, table: &'static str, args: &[String]) {
+        let line = args.iter().map(|arg| quote(arg)).collect::<Vec<_>>().join(" ");
+
+        match self.tables.iter_mut().find(|(name, _)| *name == table) {
+            Some((_, lines)) => lines.push(line),
+            None => self.tables.push((table, vec![line])),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut script = String::new();
+
+        for (table, lines) in self.tables.iter() {
+            script.push('*');
+            script.push_str(table);
+            script.push('\n');
+
+            for line in lines {
+                script.push_str(line);
+                script.push('\n');
+            }
+
+            script.push_str("COMMIT\n");
+        }
+
+        script
+    }
+}
+
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+
+    for c in arg.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+
+        quoted.push(c);
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_save, quote, tokenize, RestoreScript};
+
+    const SAVE_OUTPUT: &str = r#"# Generated by iptables-save v1.8.9 (nf_tables) on Sat Oct 17 10:00:00 2026
+*nat
+:PREROUTING ACCEPT [12:720]
+:INPUT ACCEPT [0:0]
+:OUTPUT ACCEPT [40:2960]
+:POSTROUTING ACCEPT [40:2960]
+:FCNET-POSTROUTING - [0:0]
+[0:0] -A POSTROUTING -m comment --comment fcnet-chain:ip:fcnet:postrouting:100 -j FCNET-POSTROUTING
+[5:300] -A FCNET-POSTROUTING -s 172.16.0.2/32 -o eth0 -m comment --comment fcnet:1:masq -j MASQUERADE
+COMMIT
+# Completed on Sat Oct 17 10:00:00 2026
+# Generated by iptables-save v1.8.9 (nf_tables) on Sat Oct 17 10:00:00 2026
+*filter
+:INPUT ACCEPT [100:8000]
+:FORWARD DROP [0:0]
+:OUTPUT ACCEPT [90:7000]
+[7:420] -A FORWARD -i docker0 ! -o docker0 -m comment --comment "allow \"docker\" out" -j ACCEPT
+COMMIT
+# Completed on Sat Oct 17 10:00:00 2026
+"#;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn save_output_with_counters_parses() {
+        let tables = parse_save(SAVE_OUTPUT).expect("output should be well-formed");
+        assert_eq!(tables.len(), 2);
+
+        let nat = &tables[0];
+        assert_eq!(nat.name, "nat");
+        assert_eq!(
+            nat.chains,
+            ["PREROUTING", "INPUT", "OUTPUT", "POSTROUTING", "FCNET-POSTROUTING"]
+        );
+        assert!(nat.has_chain("FCNET-POSTROUTING"));
+        assert!(!nat.has_chain("FORWARD"));
+        assert_eq!(nat.rules.len(), 2);
+        assert_eq!(nat.rules[1].chain, "FCNET-POSTROUTING");
+        assert_eq!(
+            nat.rules[1].args,
+            strings(&[
+                "-s",
+                "172.16.0.2/32",
+                "-o",
+                "eth0",
+                "-m",
+                "comment",
+                "--comment",
+                "fcnet:1:masq",
+                "-j",
+                "MASQUERADE"
+            ])
+        );
+        assert_eq!((nat.rules[1].packets, nat.rules[1].bytes), (5, 300));
+
+        let filter = &tables[1];
+        assert_eq!(filter.name, "filter");
+        assert_eq!(filter.rules.len(), 1);
+        assert_eq!(filter.rules[0].chain, "FORWARD");
+        assert_eq!(filter.rules[0].args[2], "!");
+        assert_eq!(filter.rules[0].args[8], r#"allow "docker" out"#);
+        assert_eq!((filter.rules[0].packets, filter.rules[0].bytes), (7, 420));
+    }
+
+    #[test]
+    fn save_output_without_counters_parses() {
+        let output = "*nat\n:POSTROUTING ACCEPT [0:0]\n-A POSTROUTING -o eth0 -j MASQUERADE\nCOMMIT\n";
+        let tables = parse_save(output).expect("output should be well-formed");
+
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].rules[0].args, strings(&["-o", "eth0", "-j", "MASQUERADE"]));
+        assert_eq!((tables[0].rules[0].packets, tables[0].rules[0].bytes), (0, 0));
+    }
+
+    #[test]
+    fn empty_save_output_parses() {
+        assert!(parse_save("").unwrap().is_empty());
+        assert!(parse_save("# Generated by iptables-save\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_save_output_is_rejected() {
+        for output in [
+            // rules and chains outside of a table
+            "-A FORWARD -j ACCEPT\n",
+            ":FORWARD ACCEPT [0:0]\n",
+            "COMMIT\n",
+            // commands other than appending a rule
+            "*filter\n-I FORWARD -j ACCEPT\nCOMMIT\n",
+            // malformed counters
+            "*filter\n[1:] -A FORWARD -j ACCEPT\nCOMMIT\n",
+            "*filter\n[a:b] -A FORWARD -j ACCEPT\nCOMMIT\n",
+            "*filter\n[1:2 -A FORWARD -j ACCEPT\nCOMMIT\n",
+            // an unterminated quote
+            "*filter\n-A FORWARD -m comment --comment \"open -j ACCEPT\nCOMMIT\n",
+            // a rule without a chain
+            "*filter\n[0:0] -A\nCOMMIT\n",
+        ] {
+            assert!(parse_save(output).is_none(), "{output:?}");
+        }
+    }
+
+    #[test]
+    fn quoted_arguments_round_trip() {
+        for arg in ["plain", "with space", r#"with "quotes""#, r"with \backslash", ""] {
+            assert_eq!(tokenize(&quote(arg)), Some(vec![arg.to_string()]), "{arg:?}");
+        }
+
+        assert_eq!(quote("fcnet:1:masq"), "fcnet:1:masq");
+        assert_eq!(quote("a b"), r#""a b""#);
+    }
+
+    #[test]
+    fn restore_script_groups_commands_by_table() {
+        let mut script = RestoreScript::default();
+        assert!(script.is_empty());
+
+        script.push("nat", &strings(&["-N", "FCNET-POSTROUTING"]));
+        script.push(
+            "filter",
+            &strings(&["-A", "FORWARD", "-m", "comment", "--comment", "a b", "-j", "ACCEPT"]),
+        );
+        script.push("nat", &strings(&["-A", "FCNET-POSTROUTING", "-j", "MASQUERADE"]));
+        assert!(!script.is_empty());
+
+        let serialized = script.serialize();
+        assert_eq!(
+            serialized,
+            concat!(
+                "*nat\n",
+                "-N FCNET-POSTROUTING\n",
+                "-A FCNET-POSTROUTING -j MASQUERADE\n",
+                "COMMIT\n",
+                "*filter\n",
+                "-A FORWARD -m comment --comment \"a b\" -j ACCEPT\n",
+                "COMMIT\n",
+            )
+        );
+    }
+}
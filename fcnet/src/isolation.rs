@@ -0,0 +1,271 @@
+use fcnet_types::FirecrackerNetwork;
+use nftables::{
+    expr::{Expression, Meta, MetaKey, NamedExpression},
+    schema::{NfListObject, NfObject, Nftables, Rule, Set, SetType, SetTypeValue},
+    stmt::{Match, Operator, Statement},
+};
+
+use crate::{
+    layout::{find_tagged_rule, has_tagged_rule, NfDispatchKey, NfDispatchedRule, NfSet, NfTaggedRule},
+    port_forward::{iifname_match, oifname_match},
+    util::{nf_isolation_rule_tag, parse_nf_rule_tag, FirecrackerNetworkExt},
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState, FirecrackerNetworkObjectType,
+};
+
+/// The prefix of the names of the sets of isolation groups, which are followed by the name of the group.
+pub const ISOLATION_GROUP_SET_PREFIX: &str = "isolation_group-";
+
+/// The rules isolating a network via the given link, which is its tap device or the outer end of its veth pair: for each
+/// of its groups, one accepting the traffic from the link to the links of the group and one accepting the traffic in the
+/// opposite direction, followed by the rules dropping the traffic between the link and anything besides the host
+/// interface if the network is isolated. The rules are dispatched via the link, so that, in both layouts, the rules of
+/// any two networks accept the traffic between them before either of them drops it should they share a group.
+pub fn isolation_rules(network: &FirecrackerNetwork, link: &str) -> Vec<NfDispatchedRule> {
+    isolation_tagged_rules(network, link)
+        .into_iter()
+        .map(|(tagged_rule, key, set)| {
+            let rule = NfDispatchedRule::new(network, tagged_rule.rule, key);
+
+            match set {
+                Some(set) => rule.looking_up(set),
+                None => rule,
+            }
+        })
+        .collect()
+}
+
+/// Whether the given rule is one of the rules isolating a network, which networks added by older versions of fcnet lack.
+pub fn is_isolation_rule(rule: &Rule) -> bool {
+    isolation_rule_object_type(rule).is_some()
+}
+
+/// Locate the rules isolating a network among the given rules of it within the current ruleset by their tags, failing if
+/// any of them is missing.
+pub fn locate_isolation_rules(
+    current_ruleset: &Nftables<'static>,
+    rules: &[NfDispatchedRule],
+) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError> {
+    rules
+        .iter()
+        .filter_map(|rule| Some((rule, isolation_rule_object_type(&rule.rule)?)))
+        .map(|(rule, object_type)| {
+            find_tagged_rule(current_ruleset, &rule.rule)
+                .cloned()
+                .ok_or(FirecrackerNetworkError::ObjectNotFound(object_type))
+        })
+        .collect()
+}
+
+/// Check the rules isolating a network via the given link, which are located by their tags in whichever chain they
+/// reside in, and the sets of its groups. A set of a group that lacks the element of the link is mismatched.
+pub fn check_isolation(
+    network: &FirecrackerNetwork,
+    link: &str,
+    current_ruleset: &Nftables<'static>,
+    report: &mut FirecrackerNetworkCheckReport,
+) {
+    for (tagged_rule, _, set) in isolation_tagged_rules(network, link) {
+        report.push_found(
+            tagged_rule.object_type,
+            tagged_rule.identifier,
+            has_tagged_rule(current_ruleset, &tagged_rule.rule),
+        );
+
+        let Some(set) = set else {
+            continue;
+        };
+
+        let set_state = match current_ruleset.objects.iter().find_map(|object| match object {
+            NfObject::ListObject(NfListObject::Set(current_set))
+                if current_set.family == set.set.family
+                    && current_set.table == set.set.table
+                    && current_set.name == set.set.name =>
+            {
+                Some(current_set)
+            }
+            _ => None,
+        }) {
+            Some(current_set)
+                if current_set.set_type == set.set.set_type
+                    && set
+                        .elements
+                        .iter()
+                        .all(|element| current_set.elem.as_deref().unwrap_or_default().contains(element)) =>
+            {
+                FirecrackerNetworkObjectState::Present
+            }
+            Some(_) => FirecrackerNetworkObjectState::Mismatched,
+            None => FirecrackerNetworkObjectState::Missing,
+        };
+
+        report.push(
+            FirecrackerNetworkObjectType::NfIsolationGroupSet,
+            set.set.name.as_ref(),
+            set_state,
+        );
+    }
+}
+
+/// The rules isolating a network via the given link alongside the identifiers they are reported under, the keys they are
+/// dispatched via and, for the first rule of each group, the shared set of the group with the link as its element.
+fn isolation_tagged_rules(network: &FirecrackerNetwork, link: &str) -> Vec<(NfTaggedRule, NfDispatchKey, Option<NfSet>)> {
+    let mut rules = Vec::new();
+
+    for (index, group) in network.isolation.groups.iter().enumerate() {
+        let set_name = format!("{ISOLATION_GROUP_SET_PREFIX}{group}");
+        let set = NfSet {
+            set: Set {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                name: set_name.clone().into(),
+                handle: None,
+                set_type: SetTypeValue::Single(SetType::Ifname),
+                policy: None,
+                flags: None,
+                elem: None,
+                timeout: None,
+                gc_interval: None,
+                size: None,
+                comment: None,
+            },
+            elements: vec![Expression::String(link.to_string().into())],
+            shared: true,
+        };
+
+        rules.push((
+            NfTaggedRule {
+                object_type: FirecrackerNetworkObjectType::NfIsolationGroupRule,
+                identifier: format!("{link} to @{set_name}"),
+                rule: filter_rule(
+                    network,
+                    vec![
+                        iifname_match(link),
+                        set_match(MetaKey::Oifname, &set_name),
+                        Statement::Accept(None),
+                    ],
+                    nf_isolation_rule_tag(
+                        network.resolved_id(),
+                        FirecrackerNetworkObjectType::NfIsolationGroupRule,
+                        Some(index),
+                        "out",
+                    ),
+                ),
+            },
+            NfDispatchKey::Iifname(link.to_string()),
+            Some(set),
+        ));
+        rules.push((
+            NfTaggedRule {
+                object_type: FirecrackerNetworkObjectType::NfIsolationGroupRule,
+                identifier: format!("@{set_name} to {link}"),
+                rule: filter_rule(
+                    network,
+                    vec![
+                        set_match(MetaKey::Iifname, &set_name),
+                        oifname_match(link),
+                        Statement::Accept(None),
+                    ],
+                    nf_isolation_rule_tag(
+                        network.resolved_id(),
+                        FirecrackerNetworkObjectType::NfIsolationGroupRule,
+                        Some(index),
+                        "in",
+                    ),
+                ),
+            },
+            NfDispatchKey::Oifname(link.to_string()),
+            None,
+        ));
+    }
+
+    if !network.isolation.enabled {
+        return rules;
+    }
+
+    rules.push((
+        NfTaggedRule {
+            object_type: FirecrackerNetworkObjectType::NfIsolationDropRule,
+            identifier: format!("{link} to all but {}", network.iface_name),
+            rule: filter_rule(
+                network,
+                vec![
+                    iifname_match(link),
+                    not_ifname_match(MetaKey::Oifname, &network.iface_name),
+                    Statement::Drop(None),
+                ],
+                nf_isolation_rule_tag(
+                    network.resolved_id(),
+                    FirecrackerNetworkObjectType::NfIsolationDropRule,
+                    None,
+                    "out",
+                ),
+            ),
+        },
+        NfDispatchKey::Iifname(link.to_string()),
+        None,
+    ));
+    rules.push((
+        NfTaggedRule {
+            object_type: FirecrackerNetworkObjectType::NfIsolationDropRule,
+            identifier: format!("all but {} to {link}", network.iface_name),
+            rule: filter_rule(
+                network,
+                vec![
+                    not_ifname_match(MetaKey::Iifname, &network.iface_name),
+                    oifname_match(link),
+                    Statement::Drop(None),
+                ],
+                nf_isolation_rule_tag(
+                    network.resolved_id(),
+                    FirecrackerNetworkObjectType::NfIsolationDropRule,
+                    None,
+                    "in",
+                ),
+            ),
+        },
+        NfDispatchKey::Oifname(link.to_string()),
+        None,
+    ));
+    rules
+}
+
+fn isolation_rule_object_type(rule: &Rule) -> Option<FirecrackerNetworkObjectType> {
+    match rule.comment.as_deref().and_then(parse_nf_rule_tag)? {
+        (
+            _,
+            object_type
+            @ (FirecrackerNetworkObjectType::NfIsolationGroupRule | FirecrackerNetworkObjectType::NfIsolationDropRule),
+        ) => Some(object_type),
+        _ => None,
+    }
+}
+
+fn filter_rule(network: &FirecrackerNetwork, expr: Vec<Statement<'static>>, tag: String) -> Rule<'static> {
+    Rule {
+        family: network.nf_family(),
+        table: network.nf_table.name.clone().into(),
+        chain: network.nf_table.filter_chain.name.clone().into(),
+        expr: expr.into(),
+        handle: None,
+        index: None,
+        comment: Some(tag.into()),
+    }
+}
+
+#[inline]
+fn set_match(key: MetaKey, set_name: &str) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key })),
+        right: Expression::String(format!("@{set_name}").into()),
+        op: Operator::EQ,
+    })
+}
+
+#[inline]
+fn not_ifname_match(key: MetaKey, ifname: &str) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key })),
+        right: Expression::String(ifname.to_string().into()),
+        op: Operator::NEQ,
+    })
+}
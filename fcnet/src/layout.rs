@@ -0,0 +1,640 @@
+use std::{collections::HashSet, future::Future, net::IpAddr};
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerNfLayout};
+use nftables::{
+    batch::Batch,
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Verdict},
+    schema::{Chain, Element, FlushObject, NfCmd, NfListObject, NfObject, Nftables, Rule, Set},
+    stmt::{JumpTarget, Statement, VerdictMap},
+    types::NfFamily,
+};
+
+use crate::{
+    backend::Backend,
+    context::FirecrackerNetworkContext,
+    rollback::{Rollback, RollbackObject},
+    util::{
+        add_base_chains_if_needed, apply_ruleset_with_verdict_maps, nat_proto_from_addr, nf_dispatch_rule_tag, parse_nf_rule_tag,
+        FirecrackerNetworkExt,
+    },
+    vmap::{NfRulesetObject, NfVerdictMap, NfVerdictMapKeyType},
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState, FirecrackerNetworkObjectType,
+};
+
+/// The key via which a base chain dispatches packets into the chain of a network in the verdict map layout.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NfDispatchKey {
+    Iifname(String),
+    Oifname(String),
+    /// The source address, which is used in postrouting since the input interface of a packet isn't known there.
+    Saddr(IpAddr),
+}
+
+impl NfDispatchKey {
+    fn key_type(&self) -> NfVerdictMapKeyType {
+        match self {
+            NfDispatchKey::Iifname(_) | NfDispatchKey::Oifname(_) => NfVerdictMapKeyType::Ifname,
+            NfDispatchKey::Saddr(IpAddr::V4(_)) => NfVerdictMapKeyType::Ipv4Addr,
+            NfDispatchKey::Saddr(IpAddr::V6(_)) => NfVerdictMapKeyType::Ipv6Addr,
+        }
+    }
+
+    fn value(&self) -> String {
+        match self {
+            NfDispatchKey::Iifname(name) | NfDispatchKey::Oifname(name) => name.clone(),
+            NfDispatchKey::Saddr(addr) => addr.to_string(),
+        }
+    }
+
+    fn expr(&self) -> Expression<'static> {
+        match self {
+            NfDispatchKey::Iifname(_) => Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
+            NfDispatchKey::Oifname(_) => Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
+            NfDispatchKey::Saddr(addr) => Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                protocol: nat_proto_from_addr(*addr),
+                field: "saddr".into(),
+            }))),
+        }
+    }
+}
+
+/// A named set that a rule of a network looks up, alongside the elements it is populated with. The set belongs to the
+/// rule: it is added before the rule and deleted after it. A shared set is instead looked up by the rules of many
+/// networks, each of which only adds and deletes its own elements, so that the set itself is never deleted.
+#[derive(Debug, Clone)]
+pub struct NfSet {
+    /// The set without any elements.
+    pub set: Set<'static>,
+    pub elements: Vec<Expression<'static>>,
+    pub shared: bool,
+}
+
+impl NfSet {
+    /// Add this set if needed and replace its elements, which leaves the set empty if there are none. The elements of a
+    /// shared set are added alongside those of other networks instead.
+    pub fn populate(&self, batch: &mut Batch<'static>) {
+        batch.add(NfListObject::Set(Box::new(self.set.clone())));
+
+        if !self.shared {
+            batch.add_cmd(NfCmd::Flush(FlushObject::Set(Box::new(self.set.clone()))));
+        }
+
+        if !self.elements.is_empty() {
+            batch.add(NfListObject::Element(self.element()));
+        }
+    }
+
+    /// Delete this set, or only its elements if it is shared.
+    pub fn delete(&self, batch: &mut Batch<'static>) {
+        match self.shared {
+            true if !self.elements.is_empty() => batch.delete(NfListObject::Element(self.element())),
+            true => {}
+            false => batch.delete(NfListObject::Set(Box::new(self.set.clone()))),
+        }
+    }
+
+    fn element(&self) -> Element<'static> {
+        Element {
+            family: self.set.family,
+            table: self.set.table.clone(),
+            name: self.set.name.clone(),
+            elem: self.elements.clone().into(),
+        }
+    }
+}
+
+/// A rule of a network alongside the key via which its base chain dispatches into the chain of the network that the
+/// rule resides in when using the verdict map layout.
+#[derive(Debug, Clone)]
+pub struct NfDispatchedRule {
+    /// The rule as placed into its base chain when using the flat layout.
+    pub rule: Rule<'static>,
+    /// The key of the rule, which is absent for rules that don't match on anything unique to the network, such as those
+    /// of forwarded ports, and for rules that must come after those, such as the ones of the ingress policy. Such rules
+    /// are placed into their base chain in the verdict map layout as well, and are thus located in the current ruleset
+    /// by their tag when deleting them.
+    pub key: Option<NfDispatchKey>,
+    /// The set that the rule looks up, if any.
+    pub set: Option<NfSet>,
+    network_chain: String,
+}
+
+impl NfDispatchedRule {
+    pub fn new(network: &FirecrackerNetwork, rule: Rule<'static>, key: NfDispatchKey) -> Self {
+        Self {
+            network_chain: format!("{}-{}", rule.chain, network.resolved_id()),
+            rule,
+            key: Some(key),
+            set: None,
+        }
+    }
+
+    /// A rule that is placed into its base chain regardless of the layout, see [NfDispatchedRule::key].
+    pub fn undispatched(network: &FirecrackerNetwork, rule: Rule<'static>) -> Self {
+        Self {
+            network_chain: format!("{}-{}", rule.chain, network.resolved_id()),
+            rule,
+            key: None,
+            set: None,
+        }
+    }
+
+    /// Attach the set that this rule looks up.
+    pub fn looking_up(self, set: NfSet) -> Self {
+        Self { set: Some(set), ..self }
+    }
+
+    fn map_name(&self, key: &NfDispatchKey) -> String {
+        let key_name = match key {
+            NfDispatchKey::Iifname(_) => "iifname",
+            NfDispatchKey::Oifname(_) => "oifname",
+            NfDispatchKey::Saddr(IpAddr::V4(_)) => "ip_saddr",
+            NfDispatchKey::Saddr(IpAddr::V6(_)) => "ip6_saddr",
+        };
+
+        format!("{}_{key_name}", self.rule.chain)
+    }
+
+    /// The rule of the base chain that dispatches via the verdict map of the given key, which is tagged with the name of
+    /// the map, as it is shared by all networks dispatched through it.
+    fn dispatch_rule(&self, key: &NfDispatchKey) -> Rule<'static> {
+        Rule {
+            family: self.rule.family,
+            table: self.rule.table.clone(),
+            chain: self.rule.chain.clone(),
+            expr: vec![Statement::VerdictMap(VerdictMap {
+                key: key.expr(),
+                data: Expression::String(format!("@{}", self.map_name(key)).into()),
+            })]
+            .into(),
+            handle: None,
+            index: None,
+            comment: Some(nf_dispatch_rule_tag(&self.map_name(key)).into()),
+        }
+    }
+
+    fn network_chain(&self) -> Chain<'static> {
+        Chain {
+            family: self.rule.family,
+            table: self.rule.table.clone(),
+            name: self.network_chain.clone().into(),
+            newname: None,
+            handle: None,
+            _type: None,
+            hook: None,
+            prio: None,
+            dev: None,
+            policy: None,
+        }
+    }
+
+    fn element(&self, key: &NfDispatchKey, verdict: Option<Verdict<'static>>) -> Element<'static> {
+        let value = Expression::String(key.value().into());
+
+        Element {
+            family: self.rule.family,
+            table: self.rule.table.clone(),
+            name: self.map_name(key).into(),
+            elem: vec![match verdict {
+                Some(verdict) => Expression::List(vec![value, Expression::Verdict(verdict)]),
+                None => value,
+            }]
+            .into(),
+        }
+    }
+
+    fn jump(&self) -> Verdict<'static> {
+        Verdict::Jump(JumpTarget {
+            target: self.network_chain.clone().into(),
+        })
+    }
+}
+
+/// Iterate over the given rules that are dispatched via a key in the verdict map layout alongside their keys.
+fn dispatched(rules: &[NfDispatchedRule]) -> impl Iterator<Item = (&NfDispatchedRule, &NfDispatchKey)> {
+    rules.iter().filter_map(|rule| Some((rule, rule.key.as_ref()?)))
+}
+
+/// Iterate over the sets that the given rules look up.
+pub fn sets(rules: &[NfDispatchedRule]) -> impl Iterator<Item = &NfSet> {
+    rules.iter().filter_map(|rule| rule.set.as_ref())
+}
+
+/// Whether deleting the given rules of a network requires listing the current ruleset, which is only the case for rules
+/// that reside in the base chains: all of them in the flat layout, and the undispatched ones in the verdict map layout.
+pub fn deletion_needs_current_ruleset(network: &FirecrackerNetwork, rules: &[NfDispatchedRule]) -> bool {
+    network.nf_layout == FirecrackerNfLayout::Flat || rules.iter().any(|rule| rule.key.is_none())
+}
+
+/// Add the given rules of a network in the outer network namespace according to its layout, creating the base chains
+/// if needed.
+pub async fn add_nf_rules<B: Backend>(
+    context: &FirecrackerNetworkContext<B>,
+    network: &FirecrackerNetwork,
+    rules: Vec<NfDispatchedRule>,
+    rollback: &mut Rollback<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    let changeset = context
+        .build_and_apply_nf_changeset(network.nft_program(), false, |nf_table_view| {
+            let mut changeset = NfChangeset::default();
+            changeset.add_rules(network, nf_table_view, &rules)?;
+            Ok(changeset)
+        })
+        .await?;
+
+    // the base objects are pushed first, as they can only be removed once the rules of the network are gone
+    if !changeset.base_objects().is_empty() {
+        rollback.push(RollbackObject::NfBaseObjects(changeset.base_objects().to_vec()));
+    }
+
+    // the sets are pushed next, so that they are only removed once no rule looks them up anymore
+    let sets = sets(&rules).cloned().collect::<Vec<_>>();
+    if !sets.is_empty() {
+        rollback.push(RollbackObject::NfSets(sets));
+    }
+
+    rollback.push(match network.nf_layout {
+        FirecrackerNfLayout::Flat => RollbackObject::NfRules(rules.into_iter().map(|rule| rule.rule).collect()),
+        FirecrackerNfLayout::VerdictMap => RollbackObject::NfDispatchedRules(rules),
+    });
+    Ok(())
+}
+
+/// Delete the given rules of a network in the outer network namespace according to its layout, locating them in the
+/// current ruleset via the given function in the flat layout.
+pub async fn delete_nf_rules<B: Backend>(
+    context: &FirecrackerNetworkContext<B>,
+    network: &FirecrackerNetwork,
+    rules: &[NfDispatchedRule],
+    locate_rules: impl FnOnce(&Nftables<'static>) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError>,
+) -> Result<(), FirecrackerNetworkError> {
+    let current_ruleset = match deletion_needs_current_ruleset(network, rules) {
+        true => context.get_current_ruleset(network.nft_program()).await?,
+        false => Nftables {
+            objects: Vec::new().into(),
+        },
+    };
+
+    let mut changeset = NfChangeset::default();
+    changeset.delete_rules(network, &current_ruleset, rules, locate_rules)?;
+    context.apply_nf_changeset(&changeset, network.nft_program()).await
+}
+
+/// The nftables changes of any number of networks in the outer network namespace, which are accumulated so that they
+/// can be applied in a single transaction.
+#[derive(Debug, Default)]
+pub struct NfChangeset {
+    objects: Vec<NfRulesetObject<'static>>,
+    base_objects: Vec<NfListObject<'static>>,
+    /// The families that the base chains have been added for, alongside whether the prerouting chain was among them.
+    base_chain_families: HashSet<(NfFamily, bool)>,
+    verdict_maps: HashSet<(NfFamily, String)>,
+}
+
+impl NfChangeset {
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// The objects shared between networks that this changeset adds: the table, the base chains and the rules of the
+    /// base chains that look up the verdict maps. These are what a view of the nftables tables needs to know about.
+    pub fn base_objects(&self) -> &[NfListObject<'static>] {
+        &self.base_objects
+    }
+
+    /// Add the given rules of a network according to its layout. The base chains are created if needed, and so are,
+    /// in the verdict map layout, the verdict maps and the rules of the base chains that look them up, after which the
+    /// chains of the network are populated and the elements dispatching into them are added. Adding a map that
+    /// already exists is a no-op in nftables.
+    pub fn add_rules(
+        &mut self,
+        network: &FirecrackerNetwork,
+        current_ruleset: &Nftables<'static>,
+        rules: &[NfDispatchedRule],
+    ) -> Result<(), FirecrackerNetworkError> {
+        if self
+            .base_chain_families
+            .insert((network.nf_family(), !network.port_forwards.is_empty()))
+        {
+            let mut batch = Batch::new();
+            add_base_chains_if_needed(network, current_ruleset, &mut batch)?;
+            let objects = batch_objects(batch);
+            self.base_objects.extend(objects.iter().filter_map(|object| match object {
+                NfRulesetObject::Object(NfObject::CmdObject(NfCmd::Add(object))) => Some(object.clone()),
+                _ => None,
+            }));
+            self.objects.extend(objects);
+        }
+
+        let mut batch = Batch::new();
+
+        for set in sets(rules) {
+            set.populate(&mut batch);
+        }
+
+        if network.nf_layout == FirecrackerNfLayout::Flat {
+            for rule in rules {
+                batch.add(NfListObject::Rule(rule.rule.clone()));
+            }
+
+            self.objects.extend(batch_objects(batch));
+            return Ok(());
+        }
+
+        for rule in rules.iter().filter(|rule| rule.key.is_none()) {
+            batch.add(NfListObject::Rule(rule.rule.clone()));
+        }
+
+        let mut network_chains = HashSet::new();
+
+        for (rule, key) in dispatched(rules) {
+            if self.verdict_maps.insert((rule.rule.family, rule.map_name(key))) {
+                self.objects.push(NfRulesetObject::AddVerdictMap(NfVerdictMap {
+                    family: rule.rule.family,
+                    table: rule.rule.table.to_string(),
+                    name: rule.map_name(key),
+                    key_type: key.key_type(),
+                    elements: Vec::new(),
+                }));
+
+                let dispatch_rule = rule.dispatch_rule(key);
+                if find_tagged_rule(current_ruleset, &dispatch_rule).is_none() {
+                    self.base_objects.push(NfListObject::Rule(dispatch_rule.clone()));
+                    batch.add(NfListObject::Rule(dispatch_rule));
+                }
+            }
+
+            if network_chains.insert(rule.network_chain.clone()) {
+                batch.add(NfListObject::Chain(rule.network_chain()));
+                // a chain left behind by an interrupted deletion is emptied, so that it doesn't end up with duplicate
+                // rules
+                batch.add_cmd(NfCmd::Flush(FlushObject::Chain(rule.network_chain())));
+            }
+        }
+
+        for (rule, _) in dispatched(rules) {
+            batch.add(NfListObject::Rule(Rule {
+                chain: rule.network_chain.clone().into(),
+                ..rule.rule.clone()
+            }));
+        }
+
+        // rules sharing a key, such as those of an egress policy and the egress forward rule, share its element
+        let mut elements = HashSet::new();
+
+        for (rule, key) in dispatched(rules) {
+            if elements.insert((rule.map_name(key), key.clone())) {
+                batch.add(NfListObject::Element(rule.element(key, Some(rule.jump()))));
+            }
+        }
+
+        self.objects.extend(batch_objects(batch));
+        Ok(())
+    }
+
+    /// Delete the given rules of a network according to its layout, followed by the sets they look up. In the flat
+    /// layout, the rules are located in the current ruleset via the given function, while in the verdict map layout,
+    /// only the undispatched ones are located by their tags, see [deletion_needs_current_ruleset].
+    pub fn delete_rules(
+        &mut self,
+        network: &FirecrackerNetwork,
+        current_ruleset: &Nftables<'static>,
+        rules: &[NfDispatchedRule],
+        locate_rules: impl FnOnce(&Nftables<'static>) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError>,
+    ) -> Result<(), FirecrackerNetworkError> {
+        let located_rules = match network.nf_layout {
+            FirecrackerNfLayout::Flat => locate_rules(current_ruleset)?,
+            FirecrackerNfLayout::VerdictMap => locate_undispatched_rules(current_ruleset, rules)?,
+        };
+        let mut batch = Batch::new();
+
+        for rule in located_rules {
+            batch.delete(NfListObject::Rule(rule));
+        }
+
+        self.objects.extend(batch_objects(batch));
+
+        if network.nf_layout == FirecrackerNfLayout::VerdictMap {
+            self.delete_dispatched_rules(rules);
+        }
+
+        let mut batch = Batch::new();
+
+        for set in sets(rules) {
+            set.delete(&mut batch);
+        }
+
+        self.objects.extend(batch_objects(batch));
+        Ok(())
+    }
+
+    /// Delete the given rules of a network in the verdict map layout, see [delete_dispatched_rules].
+    fn delete_dispatched_rules(&mut self, rules: &[NfDispatchedRule]) {
+        self.objects.extend(
+            delete_dispatched_rules(rules)
+                .objects
+                .into_owned()
+                .into_iter()
+                .map(NfRulesetObject::Object),
+        );
+    }
+
+    /// Replace the elements of the sets that the given rules of a network look up, adding any set that is missing.
+    pub fn populate_sets(&mut self, rules: &[NfDispatchedRule]) {
+        let mut batch = Batch::new();
+
+        for set in sets(rules) {
+            set.populate(&mut batch);
+        }
+
+        self.objects.extend(batch_objects(batch));
+    }
+
+    /// Apply all accumulated changes in a single transaction via the nftables helper of the [Backend].
+    pub fn apply<'a, B: Backend>(
+        &self,
+        nft_program: Option<&'a str>,
+    ) -> impl Future<Output = Result<(), FirecrackerNetworkError>> + Send + 'a {
+        apply_ruleset_with_verdict_maps::<B>(&self.objects, nft_program)
+    }
+}
+
+/// Build the ruleset that deletes the given rules of a network in the verdict map layout besides the undispatched ones,
+/// which doesn't require listing the current ruleset: the elements dispatching into the chains of the network are
+/// deleted, after which these chains are flushed and deleted.
+pub fn delete_dispatched_rules(rules: &[NfDispatchedRule]) -> Nftables<'static> {
+    let mut batch = Batch::new();
+    let mut elements = HashSet::new();
+    let mut network_chains = HashSet::new();
+
+    for (rule, key) in dispatched(rules) {
+        if elements.insert((rule.map_name(key), key.clone())) {
+            batch.delete(NfListObject::Element(rule.element(key, None)));
+        }
+    }
+
+    for (rule, _) in dispatched(rules) {
+        if network_chains.insert(rule.network_chain.clone()) {
+            batch.add_cmd(NfCmd::Flush(FlushObject::Chain(rule.network_chain())));
+            batch.delete(NfListObject::Chain(rule.network_chain()));
+        }
+    }
+
+    batch.to_nftables()
+}
+
+/// Locate the undispatched ones of the given rules of a network within the current ruleset by their tags, failing if any
+/// of them is missing.
+pub fn locate_undispatched_rules(
+    current_ruleset: &Nftables<'static>,
+    rules: &[NfDispatchedRule],
+) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError> {
+    rules
+        .iter()
+        .filter(|rule| rule.key.is_none())
+        .map(|rule| {
+            find_tagged_rule(current_ruleset, &rule.rule).cloned().ok_or_else(|| {
+                let object_type = rule
+                    .rule
+                    .comment
+                    .as_deref()
+                    .and_then(parse_nf_rule_tag)
+                    .map(|(_, object_type)| object_type);
+                FirecrackerNetworkError::ObjectNotFound(object_type.unwrap_or(FirecrackerNetworkObjectType::NfPortDnatRule))
+            })
+        })
+        .collect()
+}
+
+/// A rule that exists once per entry of a list in the configuration of a network, such as a forwarded port, alongside
+/// the identifier it is reported under when checking. These rules can't be told apart by their expressions alone, so
+/// they are always located by their tags.
+#[derive(Debug, Clone)]
+pub struct NfTaggedRule {
+    pub object_type: FirecrackerNetworkObjectType,
+    pub identifier: String,
+    pub rule: Rule<'static>,
+}
+
+/// Report whether each of the given tagged rules exists in the current ruleset, all of them being missing without one.
+pub fn check_tagged_rules(
+    current_ruleset: Option<&Nftables<'static>>,
+    rules: &[NfTaggedRule],
+    report: &mut FirecrackerNetworkCheckReport,
+) {
+    for rule in rules {
+        report.push_found(
+            rule.object_type,
+            rule.identifier.clone(),
+            current_ruleset.is_some_and(|current_ruleset| find_tagged_rule(current_ruleset, &rule.rule).is_some()),
+        );
+    }
+}
+
+/// Find the rule in the current ruleset that carries the same tag as the given rule within the same chain.
+pub fn find_tagged_rule<'a>(current_ruleset: &'a Nftables<'static>, rule: &Rule<'static>) -> Option<&'a Rule<'static>> {
+    current_ruleset.objects.iter().find_map(|object| match object {
+        NfObject::ListObject(NfListObject::Rule(current_rule))
+            if current_rule.family == rule.family
+                && current_rule.table == rule.table
+                && current_rule.chain == rule.chain
+                && rule.comment.is_some()
+                && current_rule.comment == rule.comment =>
+        {
+            Some(current_rule)
+        }
+        _ => None,
+    })
+}
+
+/// Whether the current ruleset contains a rule carrying the same tag as the given rule in any chain of its table, which
+/// covers both the base chain of the flat layout and the chain of the network in the verdict map layout.
+pub fn has_tagged_rule(current_ruleset: &Nftables<'static>, rule: &Rule<'static>) -> bool {
+    current_ruleset.objects.iter().any(|object| match object {
+        NfObject::ListObject(NfListObject::Rule(current_rule)) => {
+            current_rule.family == rule.family
+                && current_rule.table == rule.table
+                && rule.comment.is_some()
+                && current_rule.comment == rule.comment
+        }
+        _ => false,
+    })
+}
+
+/// Check the verdict maps, their elements and the chains of a network that the given rules of it are dispatched
+/// through in the verdict map layout. The rules themselves are tagged and checked like those of the flat layout.
+pub fn check_dispatched_rules(
+    current_ruleset: &Nftables<'static>,
+    verdict_maps: &[NfVerdictMap],
+    rules: &[NfDispatchedRule],
+    report: &mut FirecrackerNetworkCheckReport,
+) {
+    let mut map_names = HashSet::new();
+    let mut elements = HashSet::new();
+    let mut network_chains = HashSet::new();
+
+    for (rule, key) in dispatched(rules) {
+        let map_name = rule.map_name(key);
+        let verdict_map = verdict_maps.iter().find(|verdict_map| {
+            verdict_map.family == rule.rule.family && verdict_map.table == rule.rule.table && verdict_map.name == map_name
+        });
+
+        if map_names.insert(map_name.clone()) {
+            let map_state = match verdict_map {
+                Some(verdict_map)
+                    if verdict_map.key_type == key.key_type()
+                        && find_tagged_rule(current_ruleset, &rule.dispatch_rule(key)).is_some() =>
+                {
+                    FirecrackerNetworkObjectState::Present
+                }
+                Some(_) => FirecrackerNetworkObjectState::Mismatched,
+                None => FirecrackerNetworkObjectState::Missing,
+            };
+
+            report.push(FirecrackerNetworkObjectType::NfVerdictMap, map_name.clone(), map_state);
+        }
+
+        if elements.insert((map_name.clone(), key.clone())) {
+            let value = key.value();
+            let element_state = match verdict_map
+                .and_then(|verdict_map| verdict_map.elements.iter().find(|(element_key, _)| *element_key == value))
+            {
+                Some((_, verdict)) if *verdict == rule.jump() => FirecrackerNetworkObjectState::Present,
+                Some(_) => FirecrackerNetworkObjectState::Mismatched,
+                None => FirecrackerNetworkObjectState::Missing,
+            };
+
+            report.push(
+                FirecrackerNetworkObjectType::NfVerdictMapElement,
+                format!("{value} in {map_name}"),
+                element_state,
+            );
+        }
+
+        if network_chains.insert(rule.network_chain.clone()) {
+            let chain_exists = current_ruleset.objects.iter().any(|object| match object {
+                NfObject::ListObject(NfListObject::Chain(chain)) => {
+                    chain.family == rule.rule.family && chain.table == rule.rule.table && chain.name == rule.network_chain
+                }
+                _ => false,
+            });
+
+            report.push_found(
+                FirecrackerNetworkObjectType::NfNetworkChain,
+                rule.network_chain.clone(),
+                chain_exists,
+            );
+        }
+    }
+}
+
+#[inline]
+fn batch_objects(batch: Batch<'static>) -> Vec<NfRulesetObject<'static>> {
+    batch
+        .to_nftables()
+        .objects
+        .into_owned()
+        .into_iter()
+        .map(NfRulesetObject::Object)
+        .collect()
+}
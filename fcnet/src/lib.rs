@@ -1,7 +1,7 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
 use backend::Backend;
-use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerNetworkType};
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkCounters, FirecrackerNetworkOperation};
 use nftables::helper::NftablesError;
 
 #[cfg(feature = "namespaced")]
@@ -11,17 +11,63 @@ mod netns;
 #[cfg(feature = "namespaced")]
 #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
 pub use netns::NetNsError;
+mod anti_spoofing;
+mod context;
+pub use context::FirecrackerNetworkContext;
+mod conntrack;
+#[cfg(feature = "namespaced")]
+#[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+pub use context::DEFAULT_NETNS_WORKER_COUNT;
+mod counters;
+#[cfg(feature = "dhcp")]
+mod dhcp;
+#[cfg(feature = "dhcp")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dhcp")))]
+pub use dhcp::{FirecrackerNetworkDhcpOptions, DEFAULT_DHCP_LEASE_TIME};
+#[cfg(feature = "dns")]
+mod dns;
+#[cfg(feature = "dns")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+pub use dns::FirecrackerNetworkDnsOptions;
+mod egress;
+mod gc;
+mod ingress;
+mod isolation;
+mod layout;
+pub use gc::{FirecrackerNetworkGcEntry, FirecrackerNetworkGcOptions};
+mod inspect;
+#[cfg(feature = "iptables")]
+mod iptables;
+mod many;
+#[cfg(feature = "netlink-nftables")]
+mod netlink_nftables;
+#[cfg(feature = "namespaced")]
+#[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+pub use inspect::FirecrackerNetworkNamespacedSnapshot;
+pub use inspect::{
+    FirecrackerNetworkLinkSnapshot, FirecrackerNetworkRouteSnapshot, FirecrackerNetworkRuleSnapshot, FirecrackerNetworkSnapshot,
+};
+mod report;
+pub use report::{FirecrackerNetworkCheckEntry, FirecrackerNetworkCheckReport, FirecrackerNetworkObjectState};
+mod port_forward;
+mod rollback;
+mod shaping;
 #[cfg(feature = "simple")]
 mod simple;
+mod sysctl;
+mod tap;
 
 pub mod backend;
 pub(crate) mod util;
+mod vmap;
 
-const NFT_TABLE: &str = "fcnet";
-const NFT_POSTROUTING_CHAIN: &str = "postrouting";
-#[cfg(feature = "namespaced")]
-const NFT_PREROUTING_CHAIN: &str = "prerouting";
-const NFT_FILTER_CHAIN: &str = "filter";
+/// The prefix of the comments that tag nftables rules as belonging to a network, which is the same regardless of the
+/// table the rules reside in.
+const NFT_RULE_TAG_PREFIX: &str = "fcnet";
+/// The prefix of the comments that tag the rules of base chains dispatching into the chains of networks in the verdict
+/// map layout, which is distinct from the prefix of the tags of rules of networks, since these rules are shared by all
+/// networks.
+const NFT_DISPATCH_RULE_TAG_PREFIX: &str = "fcnet-dispatch";
 
 /// An error that can be emitted by embedded fcnet.
 #[derive(Debug)]
@@ -37,7 +83,24 @@ pub enum FirecrackerNetworkError {
     ChannelCancelError(futures_channel::oneshot::Canceled),
     NftablesError(NftablesError),
     ObjectNotFound(FirecrackerNetworkObjectType),
+    ObjectMismatched(FirecrackerNetworkObjectType),
     ForbiddenDualStackInRoute,
+    /// Dumping the conntrack table or deleting the conntrack entries of a network over ctnetlink failed.
+    ConntrackError(std::io::Error),
+    /// Receiving a message with the socket of a DHCP server failed.
+    #[cfg(feature = "dhcp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dhcp")))]
+    DhcpError(std::io::Error),
+    /// A DHCP server was run for a network whose guest IP or tap IP isn't an IPv4 address.
+    #[cfg(feature = "dhcp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dhcp")))]
+    ForbiddenIpv6InDhcp,
+    /// Receiving a query or reply with a socket of a DNS forwarder failed.
+    #[cfg(feature = "dns")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+    DnsError(std::io::Error),
+    /// A step shared with other operations run by [run_many] failed, and the actual error was reported for another one.
+    BatchFailed,
 }
 
 impl std::fmt::Display for FirecrackerNetworkError {
@@ -58,27 +121,59 @@ impl std::fmt::Display for FirecrackerNetworkError {
             FirecrackerNetworkError::ObjectNotFound(object_type) => {
                 write!(f, "An nftables object was not found in the current ruleset: {object_type:?}")
             }
+            FirecrackerNetworkError::ObjectMismatched(object_type) => {
+                write!(
+                    f,
+                    "An object exists but differs from the network configuration: {object_type:?}"
+                )
+            }
             FirecrackerNetworkError::ForbiddenDualStackInRoute => write!(
                 f,
                 "In a netlink route, both an IPv4 and an IPv6 support are being used (address, gateway)"
             ),
+            FirecrackerNetworkError::ConntrackError(err) => write!(f, "Flushing conntrack entries failed: {err}"),
+            #[cfg(feature = "dhcp")]
+            FirecrackerNetworkError::DhcpError(err) => write!(f, "Receiving a DHCP message failed: {err}"),
+            #[cfg(feature = "dhcp")]
+            FirecrackerNetworkError::ForbiddenIpv6InDhcp => {
+                write!(
+                    f,
+                    "A DHCP server can only hand out an IPv4 guest IP with an IPv4 tap IP as the gateway"
+                )
+            }
+            #[cfg(feature = "dns")]
+            FirecrackerNetworkError::DnsError(err) => write!(f, "Receiving a DNS message failed: {err}"),
+            FirecrackerNetworkError::BatchFailed => {
+                write!(f, "A step shared with other operations in the same batch failed")
+            }
         }
     }
 }
 
 /// An object created by the integrated Firecracker networking backend.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FirecrackerNetworkObjectType {
     IpLink,
+    IpAddress,
     IpRoute,
-    NfTable,
-    NfPostroutingChain,
     #[cfg(feature = "namespaced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+    NetNs,
+    NfTable,
+    NfPostroutingChain,
     NfPreroutingChain,
     NfFilterChain,
+    /// A verdict map through which a base chain dispatches packets into the chains of networks, alongside the rule in
+    /// the base chain that looks it up.
+    NfVerdictMap,
+    /// An element of a verdict map that dispatches packets into the chain of a network.
+    NfVerdictMapElement,
+    /// The chain of a network that its rules reside in when using the verdict map layout.
+    NfNetworkChain,
     NfMasqueradeRule,
     NfEgressForwardRule,
+    /// A rule that accepts the replies and related traffic from the host interface to connections that the guest
+    /// initiated.
     NfIngressForwardRule,
     #[cfg(feature = "namespaced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
@@ -86,28 +181,192 @@ pub enum FirecrackerNetworkObjectType {
     #[cfg(feature = "namespaced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
     NfIngressDnatRule,
+    /// A rule that DNATs connections to a port forwarded as configured by
+    /// [FirecrackerPortForward](fcnet_types::FirecrackerPortForward).
+    NfPortDnatRule,
+    /// A rule that accepts the forwarding of connections to a forwarded port to the guest.
+    NfPortForwardRule,
+    /// A rule that accepts new connections towards the guest from an exposed network or to an exposed port as
+    /// configured by [FirecrackerIngressPolicy](fcnet_types::FirecrackerIngressPolicy).
+    NfIngressExposedRule,
+    /// A rule that drops the traffic from the host interface towards the guest that no other rule of the network
+    /// accepted.
+    NfIngressDropRule,
+    /// A rule that looks up a set of the egress policy of a network as configured by
+    /// [FirecrackerEgressPolicy](fcnet_types::FirecrackerEgressPolicy).
+    NfEgressPolicyRule,
+    /// A set of addresses or ports of the egress policy of a network, alongside its elements.
+    NfEgressPolicySet,
+    /// A rule that accepts the traffic between a network and the other members of one of its groups as configured by
+    /// [FirecrackerIsolation](fcnet_types::FirecrackerIsolation).
+    NfIsolationGroupRule,
+    /// A set of the links of the networks in an isolation group, which is shared by all of them while each only owns
+    /// the element of its own link.
+    NfIsolationGroupSet,
+    /// A rule that drops the traffic forwarded between an isolated network and any link besides the host interface.
+    NfIsolationDropRule,
+    /// The chain of a network in a netdev table that filters the frames arriving on its tap device as configured by
+    /// [FirecrackerAntiSpoofing](fcnet_types::FirecrackerAntiSpoofing).
+    NfAntiSpoofingChain,
+    /// A rule in the anti-spoofing chain of a network that drops the frames of the guest that are spoofed.
+    NfAntiSpoofingRule,
+    /// A queueing discipline attached to the link of a network or to its IFB device as configured by
+    /// [FirecrackerShaping](fcnet_types::FirecrackerShaping).
+    TcQdisc,
+    /// A filter redirecting the traffic that arrives on the link of a network to its IFB device.
+    TcFilter,
+    /// An option of a tap device as configured by [FirecrackerTapOptions](fcnet_types::FirecrackerTapOptions).
+    TapOption,
+    /// A sysctl that is managed as configured by [FirecrackerSysctls](fcnet_types::FirecrackerSysctls).
+    Sysctl,
 }
 
 /// Run a [FirecrackerNetworkOperation] on a [FirecrackerNetwork] via the given [Backend].
+///
+/// An [FirecrackerNetworkOperation::Add] is transactional: should it fail or should the returned future be dropped
+/// before completion, every object it has already created on the host is removed in reverse order.
+///
+/// This creates a transient [FirecrackerNetworkContext], so callers running many operations over the lifetime of a
+/// process should create one context and use [FirecrackerNetworkContext::run] instead.
 pub async fn run<B: Backend>(
     network: &FirecrackerNetwork,
     operation: FirecrackerNetworkOperation,
 ) -> Result<(), FirecrackerNetworkError> {
-    let (connection, netlink_handle, _) =
-        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
-    B::spawn_connection(connection);
-
-    match &network.network_type {
-        #[cfg(feature = "simple")]
-        FirecrackerNetworkType::Simple => simple::run::<B>(network, netlink_handle, operation).await,
-        #[cfg(feature = "namespaced")]
-        FirecrackerNetworkType::Namespaced {
-            netns_name: _,
-            veth1_name: _,
-            veth2_name: _,
-            veth1_ip: _,
-            veth2_ip: _,
-            forwarded_guest_ip: _,
-        } => namespaced::run::<B>(operation, network, netlink_handle).await,
-    }
+    FirecrackerNetworkContext::<B>::new_transient()?.run(network, operation).await
+}
+
+/// Run many [FirecrackerNetworkOperation]s on [FirecrackerNetwork]s at once via the given [Backend], returning the
+/// result of each operation in order.
+///
+/// The netlink work of all operations is performed concurrently over a single rtnetlink connection, after which the
+/// operations are grouped by the nft program of their networks. For every group, the ruleset is listed once and the
+/// nftables changes of its adds and deletes are coalesced into a single atomic transaction, so a batch mixing nft
+/// programs is applied in one transaction per program instead of atomically as a whole. Checks are run last and share
+/// another listing per group. Should a shared step fail, every operation that took part in it fails, the first of them
+/// with the actual error and the others with [FirecrackerNetworkError::BatchFailed], and every such add is rolled back
+/// like a failed [run] would.
+pub async fn run_many<B: Backend>(
+    operations: &[(FirecrackerNetwork, FirecrackerNetworkOperation)],
+) -> Result<Vec<Result<(), FirecrackerNetworkError>>, FirecrackerNetworkError> {
+    Ok(FirecrackerNetworkContext::<B>::new_transient()?.run_many(operations).await)
+}
+
+/// Check every object that is expected to exist on the host for a [FirecrackerNetwork] via the given [Backend],
+/// producing a [FirecrackerNetworkCheckReport] instead of failing on the first object that isn't present.
+pub async fn check<B: Backend>(network: &FirecrackerNetwork) -> Result<FirecrackerNetworkCheckReport, FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?.check(network).await
+}
+
+/// Inspect the live state of a [FirecrackerNetwork] as it exists on the host via the given [Backend], producing a
+/// [FirecrackerNetworkSnapshot] of its links, addresses, nftables rules and routes.
+pub async fn inspect<B: Backend>(network: &FirecrackerNetwork) -> Result<FirecrackerNetworkSnapshot, FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?.inspect(network).await
+}
+
+/// Replace the addresses and ports of the [FirecrackerEgressPolicy](fcnet_types::FirecrackerEgressPolicy) of a
+/// [FirecrackerNetwork] that has already been added with those of its given configuration via the given [Backend], in a
+/// single atomic transaction that leaves the links and all other rules of the network untouched. Fails with
+/// [FirecrackerNetworkError::ObjectNotFound] should the network not have been added with a policy, and with
+/// [FirecrackerNetworkError::ObjectMismatched] should the default action of its policy have changed or the policy have
+/// been removed, both of which require the network to be deleted and added again.
+pub async fn update_egress_policy<B: Backend>(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?
+        .update_egress_policy(network)
+        .await
+}
+
+/// Read the [FirecrackerNetworkCounters] of a [FirecrackerNetwork] that has already been added via the given [Backend],
+/// which are summed up from the counters of its rules in the current ruleset. Rules created by older versions of fcnet
+/// lack counters and count nothing. Fails with [FirecrackerNetworkError::ObjectNotFound] should the network not have
+/// been added.
+pub async fn get_traffic_counters<B: Backend>(
+    network: &FirecrackerNetwork,
+) -> Result<FirecrackerNetworkCounters, FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?
+        .get_traffic_counters(network)
+        .await
+}
+
+/// Reset the counters of the rules of a [FirecrackerNetwork] that has already been added via the given [Backend],
+/// returning the [FirecrackerNetworkCounters] they amounted to beforehand like [get_traffic_counters] would.
+///
+/// With the [NetlinkNftablesBackend](backend::NetlinkNftablesBackend) on Linux 6.2 or newer, every rule is read and
+/// reset atomically, so no traffic is lost. Otherwise, the counters are reset by replacing the listed rules in a single
+/// atomic transaction, so traffic counted between the ruleset being listed and the rules being replaced is lost, and a
+/// rule changed in between is reverted to its listed form.
+pub async fn reset_traffic_counters<B: Backend>(
+    network: &FirecrackerNetwork,
+) -> Result<FirecrackerNetworkCounters, FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?
+        .reset_traffic_counters(network)
+        .await
+}
+
+/// Delete every conntrack entry in the outer network namespace whose original or reply tuple references the guest IP of a
+/// simple [FirecrackerNetwork] or the veth2 IP or forwarded guest IP of a namespaced one via the given [Backend], so that
+/// a network reusing these addresses doesn't inherit stale NAT bindings. A [FirecrackerNetworkOperation::Delete] already
+/// does this once the network's objects have been removed, so this is only needed for networks deleted otherwise.
+pub async fn flush_conntrack<B: Backend>(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?
+        .flush_conntrack(network)
+        .await
+}
+
+/// Remove every object owned by fcnet on the host that doesn't belong to any of the given [FirecrackerNetwork]s, which
+/// are the networks that should exist, via the given [Backend]. Such orphaned objects are typically left behind by host
+/// crashes. Returns the orphaned objects that were found, which are only reported and not removed when
+/// [FirecrackerNetworkGcOptions::dry_run] is set.
+///
+/// This must not run concurrently with any add: fcnet takes no lock around either, so the objects of a network that is
+/// being added while the garbage collection lists the host aren't yet known to belong to any of the given networks and
+/// would be removed as orphaned.
+pub async fn collect_garbage<B: Backend>(
+    networks: &[FirecrackerNetwork],
+    options: &FirecrackerNetworkGcOptions,
+) -> Result<Vec<FirecrackerNetworkGcEntry>, FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?
+        .collect_garbage(networks, options)
+        .await
+}
+
+/// Serve DHCP to the guest of a [FirecrackerNetwork] that has already been added via the given [Backend], for guests
+/// that don't configure themselves via [FirecrackerNetwork::guest_ip_boot_arg]. The server listens on the tap device,
+/// within the network namespace of a namespaced network, and only ever hands out the guest IP, with the tap IP as the
+/// gateway and the DNS servers of the given [FirecrackerNetworkDhcpOptions]. With
+/// [FirecrackerAntiSpoofing](fcnet_types::FirecrackerAntiSpoofing), only the MAC of the guest is served.
+///
+/// The returned future runs the server on the async runtime of the [Backend] until it is dropped or receiving fails,
+/// and must be polled within that runtime. Only IPv4 networks are supported, others fail with
+/// [FirecrackerNetworkError::ForbiddenIpv6InDhcp].
+#[cfg(feature = "dhcp")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dhcp")))]
+pub async fn serve_dhcp<B: Backend>(
+    network: &FirecrackerNetwork,
+    options: &FirecrackerNetworkDhcpOptions,
+) -> Result<(), FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?
+        .serve_dhcp(network, options)
+        .await
+}
+
+/// Forward the DNS queries that the guest of a [FirecrackerNetwork] that has already been added sends to the tap IP via
+/// the given [Backend] to the upstreams of the given [FirecrackerNetworkDnsOptions], so that guests can use the tap IP
+/// as their resolver. The forwarder listens on port 53 of the tap IP, within the network namespace of a namespaced
+/// network, while it queries the upstreams from the outer network namespace. Queries for domains that aren't allowed
+/// are answered with NXDOMAIN, and every query is logged via tracing at the debug level alongside the identifier of the
+/// network. Queries are forwarded under random IDs from periodically changing random ports, and only replies that echo
+/// the question of a query are relayed. Only queries over UDP are served, so replies that upstreams truncate can't be
+/// retried over TCP by the guest.
+///
+/// The returned future runs the forwarder on the async runtime of the [Backend] until it is dropped or receiving fails,
+/// and must be polled within that runtime.
+#[cfg(feature = "dns")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dns")))]
+pub async fn serve_dns<B: Backend>(
+    network: &FirecrackerNetwork,
+    options: &FirecrackerNetworkDnsOptions,
+) -> Result<(), FirecrackerNetworkError> {
+    FirecrackerNetworkContext::<B>::new_transient()?
+        .serve_dns(network, options)
+        .await
 }
@@ -0,0 +1,349 @@
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerNetworkType};
+use futures_util::future::join_all;
+use nftables::schema::{Nftables, Rule};
+
+#[cfg(feature = "namespaced")]
+use crate::namespaced;
+#[cfg(feature = "simple")]
+use crate::simple;
+use crate::{
+    backend::Backend,
+    conntrack::flush_conntrack,
+    context::FirecrackerNetworkContext,
+    layout::{deletion_needs_current_ruleset, NfChangeset, NfDispatchedRule},
+    rollback::Rollback,
+    util::FirecrackerNetworkExt,
+    vmap::NfVerdictMap,
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError,
+};
+
+/// The progress of a single operation of a batch in between its phases.
+enum Pending<B: Backend> {
+    /// An add whose links were created and whose nftables rules are yet to be added.
+    Add(Rollback<B>),
+    /// A delete whose links were removed and whose nftables rules and then conntrack entries are yet to be deleted.
+    Delete,
+    Check,
+    Done,
+}
+
+type PendingResult<B> = Result<Pending<B>, FirecrackerNetworkError>;
+
+pub async fn run_many<B: Backend>(
+    operations: &[(FirecrackerNetwork, FirecrackerNetworkOperation)],
+    context: &FirecrackerNetworkContext<B>,
+) -> Vec<Result<(), FirecrackerNetworkError>> {
+    // every nft program lists and changes the ruleset on its own, so operations are grouped by it
+    let mut groups: Vec<(Option<&str>, Vec<usize>)> = Vec::new();
+
+    for (idx, (network, _)) in operations.iter().enumerate() {
+        match groups
+            .iter_mut()
+            .find(|(nft_program, _)| *nft_program == network.nft_program())
+        {
+            Some((_, group)) => group.push(idx),
+            None => groups.push((network.nft_program(), vec![idx])),
+        }
+    }
+
+    let mut pending = join_all(
+        operations
+            .iter()
+            .map(|(network, operation)| run_netlink_phase::<B>(network, *operation, context)),
+    )
+    .await;
+
+    for (nft_program, group) in groups.iter() {
+        run_nftables_phase::<B>(operations, &mut pending, group, *nft_program, context).await;
+    }
+
+    run_conntrack_phase::<B>(operations, &mut pending).await;
+
+    for (nft_program, group) in groups.iter() {
+        run_check_phase::<B>(operations, &mut pending, group, *nft_program, context).await;
+    }
+
+    pending.into_iter().map(|pending| pending.map(|_| ())).collect()
+}
+
+/// Perform everything an operation does besides changing the nftables rules in the outer network namespace.
+async fn run_netlink_phase<B: Backend>(
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+    context: &FirecrackerNetworkContext<B>,
+) -> PendingResult<B> {
+    let netlink_handle = &context.netlink_handle()?;
+
+    match operation {
+        FirecrackerNetworkOperation::Add => {
+            let mut rollback = Rollback::<B>::new(network);
+
+            match add_links::<B>(network, context, netlink_handle, &mut rollback).await {
+                Ok(()) => Ok(Pending::Add(rollback)),
+                Err(err) => {
+                    rollback.revert(netlink_handle).await;
+                    Err(err)
+                }
+            }
+        }
+        FirecrackerNetworkOperation::Delete => delete_links::<B>(network, netlink_handle).await.map(|()| Pending::Delete),
+        FirecrackerNetworkOperation::Check => Ok(Pending::Check),
+    }
+}
+
+/// Coalesce the nftables changes of all pending adds and deletes of a group sharing an nft program into a single
+/// transaction and apply it. Should that fail, every add and delete that took part in it fails and every add is rolled
+/// back.
+async fn run_nftables_phase<B: Backend>(
+    operations: &[(FirecrackerNetwork, FirecrackerNetworkOperation)],
+    pending: &mut [PendingResult<B>],
+    group: &[usize],
+    nft_program: Option<&str>,
+    context: &FirecrackerNetworkContext<B>,
+) {
+    let mut participants = group
+        .iter()
+        .copied()
+        .filter(|&idx| matches!(pending[idx], Ok(Pending::Add(_) | Pending::Delete)))
+        .collect::<Vec<_>>();
+
+    if participants.is_empty() {
+        return;
+    }
+
+    // only deletes need to locate rules of networks, adds suffice with a view of the nftables tables
+    let needs_current_ruleset = participants.iter().any(|&idx| {
+        matches!(pending[idx], Ok(Pending::Delete))
+            && deletion_needs_current_ruleset(&operations[idx].0, &nf_rules(&operations[idx].0))
+    });
+    let mut rejected = Vec::new();
+    let result = context
+        .build_and_apply_nf_changeset(nft_program, needs_current_ruleset, |current_ruleset| {
+            let mut changeset = NfChangeset::default();
+            rejected.clear();
+
+            for &idx in participants.iter() {
+                let network = &operations[idx].0;
+                let result = match pending[idx] {
+                    Ok(Pending::Add(_)) => changeset.add_rules(network, current_ruleset, &nf_rules(network)),
+                    _ => changeset.delete_rules(network, current_ruleset, &nf_rules(network), |current_ruleset| {
+                        locate_nf_rules(network, current_ruleset)
+                    }),
+                };
+
+                if let Err(err) = result {
+                    rejected.push((idx, err));
+                }
+            }
+
+            Ok(changeset)
+        })
+        .await;
+
+    for (idx, err) in rejected {
+        participants.retain(|participant| *participant != idx);
+        fail_participants(pending, &[idx], err, context).await;
+    }
+
+    if let Err(err) = result {
+        return fail_participants(pending, &participants, err, context).await;
+    }
+
+    // deletes remain pending until their conntrack entries have been flushed
+    for idx in participants {
+        match std::mem::replace(&mut pending[idx], Ok(Pending::Done)) {
+            Ok(Pending::Add(rollback)) => rollback.commit(),
+            delete => pending[idx] = delete,
+        }
+    }
+}
+
+/// Flush the conntrack entries of all deletes whose nftables rules were deleted concurrently, which is done afterwards so
+/// that no new entries can be created in between.
+async fn run_conntrack_phase<B: Backend>(
+    operations: &[(FirecrackerNetwork, FirecrackerNetworkOperation)],
+    pending: &mut [PendingResult<B>],
+) {
+    let deletes = pending
+        .iter()
+        .enumerate()
+        .filter(|(_, pending)| matches!(pending, Ok(Pending::Delete)))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+
+    let results = join_all(deletes.iter().map(|&idx| flush_conntrack::<B>(&operations[idx].0))).await;
+
+    for (idx, result) in deletes.into_iter().zip(results) {
+        pending[idx] = result.map(|()| Pending::Done);
+    }
+}
+
+/// Run all pending checks of a group sharing an nft program concurrently after every change has been made, sharing a
+/// single listing of the ruleset.
+async fn run_check_phase<B: Backend>(
+    operations: &[(FirecrackerNetwork, FirecrackerNetworkOperation)],
+    pending: &mut [PendingResult<B>],
+    group: &[usize],
+    nft_program: Option<&str>,
+    context: &FirecrackerNetworkContext<B>,
+) {
+    let checks = group
+        .iter()
+        .copied()
+        .filter(|&idx| matches!(pending[idx], Ok(Pending::Check)))
+        .collect::<Vec<_>>();
+
+    if checks.is_empty() {
+        return;
+    }
+
+    let (current_ruleset, verdict_maps) = match context.get_current_ruleset_with_verdict_maps(nft_program).await {
+        Ok(listing) => listing,
+        Err(err) => return fail_participants(pending, &checks, err, context).await,
+    };
+
+    let results = join_all(
+        checks
+            .iter()
+            .map(|&idx| check_with_ruleset::<B>(&operations[idx].0, context, &current_ruleset, &verdict_maps)),
+    )
+    .await;
+
+    for (idx, result) in checks.into_iter().zip(results) {
+        pending[idx] = result.and_then(|report| report.into_result()).map(|()| Pending::Done);
+    }
+}
+
+/// Fail the given operations that shared a failed step, rolling back adds. The first of them receives the actual
+/// error and the others [FirecrackerNetworkError::BatchFailed].
+async fn fail_participants<B: Backend>(
+    pending: &mut [PendingResult<B>],
+    participants: &[usize],
+    err: FirecrackerNetworkError,
+    context: &FirecrackerNetworkContext<B>,
+) {
+    let netlink_handle = context.netlink_handle();
+    let mut err = Some(err);
+
+    for &idx in participants {
+        let failure = Err(err.take().unwrap_or(FirecrackerNetworkError::BatchFailed));
+
+        if let Ok(Pending::Add(rollback)) = std::mem::replace(&mut pending[idx], failure) {
+            match netlink_handle {
+                Ok(ref netlink_handle) => rollback.revert(netlink_handle).await,
+                // the rollback is reverted on a thread of its own once dropped
+                Err(_) => drop(rollback),
+            }
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "namespaced"), allow(unused_variables))]
+async fn add_links<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+    netlink_handle: &rtnetlink::Handle,
+    rollback: &mut Rollback<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    match network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => simple::add_links::<B>(network, netlink_handle, rollback).await,
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced {
+            netns_name: _,
+            veth1_name: _,
+            veth2_name: _,
+            veth1_ip: _,
+            veth2_ip: _,
+            forwarded_guest_ip: _,
+            netns_dir: _,
+            jailer: _,
+        } => namespaced::add_links::<B>(network, context, netlink_handle, rollback).await,
+    }
+}
+
+async fn delete_links<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    match network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => simple::delete_links::<B>(network, netlink_handle).await,
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced {
+            netns_name: _,
+            veth1_name: _,
+            veth2_name: _,
+            veth1_ip: _,
+            veth2_ip: _,
+            forwarded_guest_ip: _,
+            netns_dir: _,
+            jailer: _,
+        } => namespaced::delete_links(network, netlink_handle).await,
+    }
+}
+
+pub fn nf_rules(network: &FirecrackerNetwork) -> Vec<NfDispatchedRule> {
+    match network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => simple::nf_rules(network),
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced {
+            netns_name: _,
+            veth1_name: _,
+            veth2_name: _,
+            veth1_ip: _,
+            veth2_ip: _,
+            forwarded_guest_ip: _,
+            netns_dir: _,
+            jailer: _,
+        } => namespaced::nf_rules(network),
+    }
+}
+
+fn locate_nf_rules(
+    network: &FirecrackerNetwork,
+    current_ruleset: &Nftables<'static>,
+) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError> {
+    match network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => simple::locate_nf_rules(network, current_ruleset),
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced {
+            netns_name: _,
+            veth1_name: _,
+            veth2_name: _,
+            veth1_ip: _,
+            veth2_ip: _,
+            forwarded_guest_ip: _,
+            netns_dir: _,
+            jailer: _,
+        } => namespaced::locate_nf_rules(network, current_ruleset),
+    }
+}
+
+async fn check_with_ruleset<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+    current_ruleset: &Nftables<'static>,
+    verdict_maps: &[NfVerdictMap],
+) -> Result<FirecrackerNetworkCheckReport, FirecrackerNetworkError> {
+    match network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => {
+            let netlink_handle = context.netlink_handle()?;
+            simple::check_with_ruleset::<B>(network, &netlink_handle, current_ruleset, verdict_maps).await
+        }
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced {
+            netns_name: _,
+            veth1_name: _,
+            veth2_name: _,
+            veth1_ip: _,
+            veth2_ip: _,
+            forwarded_guest_ip: _,
+            netns_dir: _,
+            jailer: _,
+        } => namespaced::check_with_ruleset::<B>(network, context, current_ruleset, verdict_maps).await,
+    }
+}
@@ -1,60 +1,83 @@
 use std::{
-    ffi::OsStr,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     os::fd::AsRawFd,
 };
 
 use cidr::IpInet;
+use fcnet_types::FirecrackerTapOptions;
 use nftables::{
     batch::Batch,
-    schema::{Chain, NfListObject, Rule, Table},
-    types::{NfChainPolicy, NfChainType, NfFamily, NfHook},
+    schema::{NfListObject, Rule, Table},
+    types::{NfChainType, NfHook},
 };
-use nftables_async::helper::Helper;
 use rtnetlink::{LinkMessageBuilder, LinkUnspec, LinkVeth, RouteMessageBuilder};
-use tokio_tun::TunBuilder;
 
 use crate::{
+    anti_spoofing::anti_spoofing_objects,
+    context::FirecrackerNetworkContext,
+    layout::add_nf_rules,
     netns::NetNs,
-    util::{add_base_chains_if_needed, get_link_index, FirecrackerNetworkExt, NO_NFT_ARGS},
-    Backend, FirecrackerNetwork, FirecrackerNetworkError, NFT_FILTER_CHAIN, NFT_POSTROUTING_CHAIN, NFT_PREROUTING_CHAIN,
-    NFT_TABLE,
+    rollback::{Rollback, RollbackObject},
+    shaping::add_shaping,
+    sysctl::{ensure_sysctls, required_sysctls},
+    tap::create_tap,
+    util::{apply_ruleset, get_link_index, nf_base_chain, nf_rule_tag},
+    Backend, FirecrackerNetwork, FirecrackerNetworkError, FirecrackerNetworkObjectType,
 };
 
-use super::{
-    inner_dnat_expr, inner_snat_expr, outer_egress_forward_expr, outer_ingress_forward_expr, outer_masq_expr,
-    use_netns_in_thread, NamespacedData,
-};
+use super::{inner_dnat_expr, inner_snat_expr, outer_nf_rules, InnerRuleData, NamespacedData};
 
 pub(super) async fn add<B: Backend>(
     namespaced_data: NamespacedData<'_>,
     network: &FirecrackerNetwork,
-    outer_handle: rtnetlink::Handle,
+    context: &FirecrackerNetworkContext<B>,
+    outer_handle: &rtnetlink::Handle,
+    rollback: &mut Rollback<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    add_without_outer_nf_rules::<B>(&namespaced_data, network, context, outer_handle, rollback).await?;
+    add_nf_rules::<B>(context, network, outer_nf_rules(network, &namespaced_data), rollback).await
+}
+
+/// Create everything of this network besides its nftables rules in the outer netns, which are added last so that they
+/// can be batched with those of other networks: the sysctls, the veth pair and the shaping of its outer end, the netns
+/// with everything inside of it, including the anti-spoofing chain of the tap device, and the route.
+pub(super) async fn add_without_outer_nf_rules<B: Backend>(
+    namespaced_data: &NamespacedData<'_>,
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+    outer_handle: &rtnetlink::Handle,
+    rollback: &mut Rollback<B>,
 ) -> Result<(), FirecrackerNetworkError> {
-    setup_outer_interfaces(&namespaced_data, &outer_handle).await?;
+    ensure_sysctls(&required_sysctls(network, None))?;
+    setup_outer_interfaces(namespaced_data, outer_handle, rollback).await?;
+    add_shaping(network, namespaced_data.veth1_name, outer_handle, rollback).await?;
 
     let tap_name = network.tap_name.clone();
-    let tap_ip = network.tap_ip.clone();
+    let tap_ip = network.tap_ip;
+    let tap_options = network.resolved_tap_options();
     let nft_path = network.nft_path.clone();
     let veth2_name = namespaced_data.veth2_name.to_string();
     let veth1_ip = *namespaced_data.veth1_ip;
     let veth2_ip = *namespaced_data.veth2_ip;
-    let guest_ip = network.guest_ip;
-    let forwarded_guest_ip = *namespaced_data.forwarded_guest_ip;
-    let nf_family = network.nf_family();
-    use_netns_in_thread::<B>(namespaced_data.netns_name.to_string(), async move {
-        setup_inner_interfaces::<B>(tap_name, tap_ip, veth2_name.clone(), veth2_ip, veth1_ip).await?;
-        setup_inner_nf_rules::<B>(nf_family, nft_path, veth2_name, veth2_ip, forwarded_guest_ip, guest_ip).await
-    })
-    .await?;
+    let loopback_up = network.sysctls.managed;
+    let inner_sysctls = required_sysctls(network, Some(&network.tap_name));
+    let inner_rule_data = InnerRuleData::new(network, namespaced_data);
+    let anti_spoofing_objects = anti_spoofing_objects(network);
+    context
+        .run_in_netns(namespaced_data.get_netns()?, async move {
+            setup_inner_interfaces::<B>(tap_name, tap_ip, tap_options, veth2_name, veth2_ip, veth1_ip, loopback_up).await?;
+            ensure_sysctls(&inner_sysctls)?;
+            setup_inner_nf_rules::<B>(inner_rule_data, anti_spoofing_objects, nft_path).await
+        })
+        .await?;
 
-    setup_outer_nf_rules::<B>(&namespaced_data, network).await?;
-    setup_outer_forward_route(&namespaced_data, &outer_handle).await
+    setup_outer_forward_route(namespaced_data, outer_handle).await
 }
 
-async fn setup_outer_interfaces(
+async fn setup_outer_interfaces<B: Backend>(
     namespaced_data: &NamespacedData<'_>,
     outer_handle: &rtnetlink::Handle,
+    rollback: &mut Rollback<B>,
 ) -> Result<(), FirecrackerNetworkError> {
     outer_handle
         .link()
@@ -62,8 +85,9 @@ async fn setup_outer_interfaces(
         .execute()
         .await
         .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    rollback.push(RollbackObject::Link(namespaced_data.veth1_name.to_string()));
 
-    let veth1_idx = get_link_index(namespaced_data.veth1_name.to_string(), &outer_handle).await?;
+    let veth1_idx = get_link_index(namespaced_data.veth1_name.to_string(), outer_handle).await?;
     outer_handle
         .address()
         .add(
@@ -86,18 +110,19 @@ async fn setup_outer_interfaces(
         .await
         .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
 
-    let veth2_idx = get_link_index(namespaced_data.veth2_name.to_string(), &outer_handle).await?;
+    let veth2_idx = get_link_index(namespaced_data.veth2_name.to_string(), outer_handle).await?;
+    let netns = NetNs::new_with_env(namespaced_data.netns_name, namespaced_data.netns_env())
+        .map_err(FirecrackerNetworkError::NetnsError)?;
+    rollback.push(RollbackObject::Netns(
+        namespaced_data.netns_name.to_string(),
+        namespaced_data.netns_env(),
+    ));
     outer_handle
         .link()
         .set(
             LinkMessageBuilder::<LinkUnspec>::new()
                 .index(veth2_idx)
-                .setns_by_fd(
-                    NetNs::new(&namespaced_data.netns_name)
-                        .map_err(FirecrackerNetworkError::NetnsError)?
-                        .file()
-                        .as_raw_fd(),
-                )
+                .setns_by_fd(netns.file().as_raw_fd())
                 .build(),
         )
         .execute()
@@ -105,54 +130,6 @@ async fn setup_outer_interfaces(
         .map_err(FirecrackerNetworkError::NetlinkOperationError)
 }
 
-async fn setup_outer_nf_rules<B: Backend>(
-    namespaced_data: &NamespacedData<'_>,
-    network: &FirecrackerNetwork,
-) -> Result<(), FirecrackerNetworkError> {
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-    let mut batch = Batch::new();
-    add_base_chains_if_needed(network, &current_ruleset, &mut batch)?;
-
-    // masquerade veth packets as host iface packets
-    batch.add(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_POSTROUTING_CHAIN.into(),
-        expr: outer_masq_expr(network, namespaced_data).into(),
-        handle: None,
-        index: None,
-        comment: None,
-    }));
-
-    // forward ingress packets from host iface to veth
-    batch.add(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: outer_ingress_forward_expr(network, namespaced_data).into(),
-        handle: None,
-        index: None,
-        comment: None,
-    }));
-
-    // forward egress packets from veth to host iface
-    batch.add(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: outer_egress_forward_expr(network, namespaced_data).into(),
-        handle: None,
-        index: None,
-        comment: None,
-    }));
-
-    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)
-}
-
 async fn setup_outer_forward_route(
     namespaced_data: &NamespacedData<'_>,
     outer_handle: &rtnetlink::Handle,
@@ -190,20 +167,27 @@ async fn setup_outer_forward_route(
 async fn setup_inner_interfaces<B: Backend>(
     tap_name: String,
     tap_ip: IpInet,
+    tap_options: FirecrackerTapOptions,
     veth2_name: String,
     veth2_ip: IpInet,
     veth1_ip: IpInet,
+    loopback_up: bool,
 ) -> Result<(), FirecrackerNetworkError> {
-    TunBuilder::new()
-        .name(&tap_name)
-        .tap()
-        .persist()
-        .up()
-        .build()
-        .map_err(FirecrackerNetworkError::TapDeviceError)?;
     let (connection, inner_handle, _) =
         rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
     B::spawn_connection(connection);
+    create_tap::<B>(&tap_name, &tap_options, &inner_handle).await?;
+
+    // a new netns starts out with its loopback link down, which breaks anything inside of it connecting to itself
+    if loopback_up {
+        let lo_idx = get_link_index("lo".to_string(), &inner_handle).await?;
+        inner_handle
+            .link()
+            .set(LinkMessageBuilder::<LinkUnspec>::new().index(lo_idx).up().build())
+            .execute()
+            .await
+            .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    }
 
     let veth2_idx = get_link_index(veth2_name.clone(), &inner_handle).await?;
     inner_handle
@@ -247,60 +231,59 @@ async fn setup_inner_interfaces<B: Backend>(
 }
 
 async fn setup_inner_nf_rules<B: Backend>(
-    nf_family: NfFamily,
+    inner_rule_data: InnerRuleData,
+    anti_spoofing_objects: Vec<NfListObject<'static>>,
     nft_path: Option<String>,
-    veth2_name: String,
-    veth2_ip: IpInet,
-    forwarded_guest_ip: Option<IpAddr>,
-    guest_ip: IpInet,
 ) -> Result<(), FirecrackerNetworkError> {
+    let port_forward_rules = inner_rule_data.port_forward_rules();
+    let needs_prerouting_chain = inner_rule_data.needs_prerouting_chain();
+    let InnerRuleData {
+        network_id,
+        veth2_name,
+        veth2_ip,
+        guest_ip,
+        forwarded_guest_ip,
+        port_forwards: _,
+        nf_family,
+        nf_table,
+    } = inner_rule_data;
     let mut batch = Batch::new();
 
     // create table, postrouting and prerouting chains (prerouting only needed when using forwarding)
     batch.add(NfListObject::Table(Table {
         family: nf_family,
-        name: NFT_TABLE.into(),
+        name: nf_table.name.clone().into(),
         handle: None,
     }));
 
-    batch.add(NfListObject::Chain(Chain {
-        family: nf_family,
-        table: NFT_TABLE.into(),
-        name: NFT_POSTROUTING_CHAIN.into(),
-        newname: None,
-        handle: None,
-        _type: Some(NfChainType::NAT),
-        hook: Some(NfHook::Postrouting),
-        prio: Some(100),
-        dev: None,
-        policy: Some(NfChainPolicy::Accept),
-    }));
+    batch.add(NfListObject::Chain(nf_base_chain(
+        nf_family,
+        &nf_table,
+        &nf_table.postrouting_chain,
+        NfChainType::NAT,
+        NfHook::Postrouting,
+    )));
 
-    if let Some(_) = forwarded_guest_ip {
-        batch.add(NfListObject::Chain(Chain {
-            family: nf_family,
-            table: NFT_TABLE.into(),
-            name: NFT_PREROUTING_CHAIN.into(),
-            newname: None,
-            handle: None,
-            _type: Some(NfChainType::NAT),
-            hook: Some(NfHook::Prerouting),
-            prio: Some(-100),
-            policy: Some(NfChainPolicy::Accept),
-            dev: None,
-        }));
+    if needs_prerouting_chain {
+        batch.add(NfListObject::Chain(nf_base_chain(
+            nf_family,
+            &nf_table,
+            &nf_table.prerouting_chain,
+            NfChainType::NAT,
+            NfHook::Prerouting,
+        )));
     }
 
     // SNAT packets coming from the guest ip to the veth2 ip so that outer netns forwards them not from the
     // guest ip local to the inner netns, but from the known veth2 ip
     batch.add(NfListObject::Rule(Rule {
         family: nf_family,
-        table: NFT_TABLE.into(),
-        chain: NFT_POSTROUTING_CHAIN.into(),
+        table: nf_table.name.clone().into(),
+        chain: nf_table.postrouting_chain.name.clone().into(),
         expr: inner_snat_expr(veth2_name.clone(), guest_ip, veth2_ip, nf_family).into(),
         handle: None,
         index: None,
-        comment: None,
+        comment: Some(nf_rule_tag(&network_id, FirecrackerNetworkObjectType::NfEgressSnatRule).into()),
     }));
 
     // DNAT packets coming to the forwarded guest ip via a route in the outer netns to the actual guest
@@ -308,16 +291,24 @@ async fn setup_inner_nf_rules<B: Backend>(
     if let Some(forwarded_guest_ip) = forwarded_guest_ip {
         batch.add(NfListObject::Rule(Rule {
             family: nf_family,
-            table: NFT_TABLE.into(),
-            chain: NFT_PREROUTING_CHAIN.into(),
+            table: nf_table.name.clone().into(),
+            chain: nf_table.prerouting_chain.name.clone().into(),
             expr: inner_dnat_expr(veth2_name, forwarded_guest_ip, guest_ip, nf_family).into(),
             handle: None,
             index: None,
-            comment: None,
+            comment: Some(nf_rule_tag(&network_id, FirecrackerNetworkObjectType::NfIngressDnatRule).into()),
         }));
     }
 
-    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), nft_path.as_deref(), std::iter::empty::<&OsStr>())
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)
+    // DNAT packets coming to forwarded ports, which the outer netns has DNATed to the veth2 ip, to the guest ip
+    for port_forward_rule in port_forward_rules {
+        batch.add(NfListObject::Rule(port_forward_rule.rule));
+    }
+
+    // the tap device was created in the netns beforehand, so its ingress hook can be filtered in the same batch
+    for object in anti_spoofing_objects {
+        batch.add(object);
+    }
+
+    apply_ruleset::<B>(&batch.to_nftables(), nft_path.as_deref()).await
 }
@@ -1,217 +1,291 @@
-use std::net::IpAddr;
-
-use cidr::IpInet;
-use futures_util::TryStreamExt;
 use nftables::{
-    schema::{NfListObject, NfObject},
-    types::NfFamily,
+    schema::{NfListObject, NfObject, Nftables},
+    types::{NfChainType, NfHook},
 };
-use nftables_async::helper::Helper;
-use rtnetlink::{packet_route::route::{RouteAddress, RouteAttribute}, RouteMessageBuilder};
+
+use fcnet_types::FirecrackerNfLayout;
+use rtnetlink::packet_route::link::LinkFlags;
 
 use crate::{
+    anti_spoofing::check_anti_spoofing,
     backend::Backend,
-    util::{check_base_chains, FirecrackerNetworkExt, NO_NFT_ARGS},
-    FirecrackerNetwork, FirecrackerNetworkError, FirecrackerNetworkObjectType, NFT_FILTER_CHAIN, NFT_POSTROUTING_CHAIN,
-    NFT_PREROUTING_CHAIN, NFT_TABLE,
+    context::FirecrackerNetworkContext,
+    egress::check_egress_policy,
+    isolation::check_isolation,
+    layout::{check_dispatched_rules, check_tagged_rules},
+    shaping::check_shaping,
+    sysctl::{check_sysctls, required_sysctls},
+    tap::{check_tap, report_tap},
+    util::{base_chain_state, check_base_chains, check_link, get_current_ruleset, get_link},
+    vmap::NfVerdictMap,
+    FirecrackerNetwork, FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState,
+    FirecrackerNetworkObjectType,
 };
 
 use super::{
-    inner_dnat_expr, inner_snat_expr, outer_egress_forward_expr, outer_ingress_forward_expr, outer_masq_expr,
-    use_netns_in_thread, NamespacedData,
+    find_outer_forward_route, outer_nf_rules, outer_rule_object_type, outer_tagged_rules, InnerRuleData, NamespacedData,
 };
 
 pub(super) async fn check<B: Backend>(
     namespaced_data: NamespacedData<'_>,
     network: &FirecrackerNetwork,
-    netlink_handle: rtnetlink::Handle,
-) -> Result<(), FirecrackerNetworkError> {
-    check_outer_nf_rules::<B>(network, &namespaced_data).await?;
+    context: &FirecrackerNetworkContext<B>,
+    current_ruleset: &Nftables<'static>,
+    verdict_maps: &[NfVerdictMap],
+) -> Result<FirecrackerNetworkCheckReport, FirecrackerNetworkError> {
+    let netlink_handle = &context.netlink_handle()?;
+    let mut report = FirecrackerNetworkCheckReport::default();
+    let netns = namespaced_data.get_netns().ok();
+    report.push_found(
+        FirecrackerNetworkObjectType::NetNs,
+        namespaced_data.netns_name,
+        netns.is_some(),
+    );
 
-    let nft_path = network.nft_path.clone();
-    let forwarded_guest_ip = *namespaced_data.forwarded_guest_ip;
-    let veth2_name = namespaced_data.veth2_name.to_string();
-    let veth2_ip = *namespaced_data.veth2_ip;
-    let guest_ip = network.guest_ip;
-    let nf_family = network.nf_family();
-
-    use_netns_in_thread::<B>(namespaced_data.netns_name.to_string(), async move {
-        check_inner_nf_rules::<B>(nft_path, forwarded_guest_ip, veth2_name, guest_ip, veth2_ip, nf_family).await
-    })
+    check_link(
+        namespaced_data.veth1_name,
+        namespaced_data.veth1_ip,
+        netlink_handle,
+        &mut report,
+    )
     .await?;
+    check_shaping(network, namespaced_data.veth1_name, netlink_handle, &mut report).await?;
+    check_sysctls(&required_sysctls(network, None), true, &mut report);
+    check_outer_nf_rules(network, &namespaced_data, current_ruleset, verdict_maps, &mut report);
+    check_outer_forward_route(&namespaced_data, netlink_handle, &mut report).await;
+
+    let tap_name = network.tap_name.clone();
+    let tap_ip = network.tap_ip;
+    let tap_options = network.resolved_tap_options();
+    let nft_path = network.nft_path.clone();
+    let loopback_up = network.sysctls.managed;
+    let inner_sysctls = required_sysctls(network, Some(&network.tap_name));
+    let inner_rule_data = InnerRuleData::new(network, &namespaced_data);
+    let inner_network = network.clone();
+
+    let inner_report = match netns {
+        Some(netns) => {
+            context
+                .run_in_netns(netns, async move {
+                    let mut inner_report = FirecrackerNetworkCheckReport::default();
+                    let (connection, inner_handle, _) =
+                        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+                    B::spawn_connection(connection);
+
+                    check_link(
+                        &inner_rule_data.veth2_name,
+                        &inner_rule_data.veth2_ip,
+                        &inner_handle,
+                        &mut inner_report,
+                    )
+                    .await?;
+                    check_link(&tap_name, &tap_ip, &inner_handle, &mut inner_report).await?;
+                    check_tap::<B>(&tap_name, &tap_options, &inner_handle, &mut inner_report).await?;
+
+                    if loopback_up {
+                        check_loopback(&inner_handle, &mut inner_report).await?;
+                    }
+
+                    check_sysctls(&inner_sysctls, true, &mut inner_report);
+
+                    let current_ruleset = get_current_ruleset::<B>(nft_path.as_deref()).await?;
+                    check_inner_nf_rules(Some(&current_ruleset), &inner_rule_data, &mut inner_report);
+                    check_anti_spoofing(&inner_network, Some(&current_ruleset), &mut inner_report);
+
+                    Ok(inner_report)
+                })
+                .await?
+        }
+        // nothing can exist inside of a missing netns, so every inner object is reported as missing
+        None => {
+            let mut inner_report = FirecrackerNetworkCheckReport::default();
+
+            for (link, link_ip) in [(&inner_rule_data.veth2_name, &inner_rule_data.veth2_ip), (&tap_name, &tap_ip)] {
+                inner_report.push(
+                    FirecrackerNetworkObjectType::IpLink,
+                    link,
+                    FirecrackerNetworkObjectState::Missing,
+                );
+                inner_report.push(
+                    FirecrackerNetworkObjectType::IpAddress,
+                    link_ip.to_string(),
+                    FirecrackerNetworkObjectState::Missing,
+                );
+            }
+
+            report_tap(&tap_name, &tap_options, None, None, &mut inner_report);
 
-    check_outer_forward_route(namespaced_data, netlink_handle).await
+            if loopback_up {
+                inner_report.push(
+                    FirecrackerNetworkObjectType::IpLink,
+                    "lo",
+                    FirecrackerNetworkObjectState::Missing,
+                );
+            }
+
+            check_sysctls(&inner_sysctls, false, &mut inner_report);
+            check_inner_nf_rules(None, &inner_rule_data, &mut inner_report);
+            check_anti_spoofing(network, None, &mut inner_report);
+            inner_report
+        }
+    };
+
+    report.extend_in_netns(inner_report, namespaced_data.netns_name);
+    Ok(report)
+}
+
+async fn check_loopback(
+    inner_handle: &rtnetlink::Handle,
+    report: &mut FirecrackerNetworkCheckReport,
+) -> Result<(), FirecrackerNetworkError> {
+    let loopback_state = match get_link("lo".to_string(), inner_handle).await? {
+        Some(link_message) if link_message.header.flags.contains(LinkFlags::Up) => FirecrackerNetworkObjectState::Present,
+        Some(_) => FirecrackerNetworkObjectState::Mismatched,
+        None => FirecrackerNetworkObjectState::Missing,
+    };
+
+    report.push(FirecrackerNetworkObjectType::IpLink, "lo", loopback_state);
+    Ok(())
 }
 
-async fn check_outer_nf_rules<B: Backend>(
+fn check_outer_nf_rules(
     network: &FirecrackerNetwork,
     namespaced_data: &NamespacedData<'_>,
-) -> Result<(), FirecrackerNetworkError> {
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-    check_base_chains(network, &current_ruleset)?;
+    current_ruleset: &Nftables<'static>,
+    verdict_maps: &[NfVerdictMap],
+    report: &mut FirecrackerNetworkCheckReport,
+) {
+    let outer_nf_rules = outer_nf_rules(network, namespaced_data);
+    check_base_chains(network, current_ruleset, report);
+
+    if network.nf_layout == FirecrackerNfLayout::VerdictMap {
+        check_dispatched_rules(current_ruleset, verdict_maps, &outer_nf_rules, report);
+    }
 
     let mut outer_masq_rule_exists = false;
     let mut outer_ingress_forward_rule_exists = false;
     let mut outer_egress_forward_rule_exists = false;
 
     for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule) if rule.table == NFT_TABLE.to_string() => {
-                    if rule.chain == NFT_POSTROUTING_CHAIN && rule.expr == outer_masq_expr(network, &namespaced_data) {
-                        outer_masq_rule_exists = true;
-                    } else if rule.chain == NFT_FILTER_CHAIN {
-                        if rule.expr == outer_ingress_forward_expr(network, &namespaced_data) {
-                            outer_ingress_forward_rule_exists = true;
-                        } else if rule.expr == outer_egress_forward_expr(network, &namespaced_data) {
-                            outer_egress_forward_rule_exists = true;
-                        }
-                    }
-                }
+        if let NfObject::ListObject(NfListObject::Rule(rule)) = object {
+            match outer_rule_object_type(network, namespaced_data, rule) {
+                Some(FirecrackerNetworkObjectType::NfMasqueradeRule) => outer_masq_rule_exists = true,
+                Some(FirecrackerNetworkObjectType::NfIngressForwardRule) => outer_ingress_forward_rule_exists = true,
+                Some(FirecrackerNetworkObjectType::NfEgressForwardRule) => outer_egress_forward_rule_exists = true,
                 _ => continue,
-            },
-            _ => continue,
+            }
         }
     }
 
-    if !outer_masq_rule_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfMasqueradeRule,
-        ));
-    }
-
-    if !outer_ingress_forward_rule_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfIngressForwardRule,
-        ));
-    }
-
-    if !outer_egress_forward_rule_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfEgressForwardRule,
-        ));
-    }
-
-    Ok(())
+    report.push_found(
+        FirecrackerNetworkObjectType::NfMasqueradeRule,
+        format!("{} via {}", namespaced_data.veth2_ip.address(), network.iface_name),
+        outer_masq_rule_exists,
+    );
+    report.push_found(
+        FirecrackerNetworkObjectType::NfIngressForwardRule,
+        format!("{} to {}", network.iface_name, namespaced_data.veth1_name),
+        outer_ingress_forward_rule_exists,
+    );
+    report.push_found(
+        FirecrackerNetworkObjectType::NfEgressForwardRule,
+        format!("{} to {}", namespaced_data.veth1_name, network.iface_name),
+        outer_egress_forward_rule_exists,
+    );
+    check_egress_policy(current_ruleset, &outer_nf_rules, report);
+    check_isolation(network, namespaced_data.veth1_name, current_ruleset, report);
+    check_tagged_rules(Some(current_ruleset), &outer_tagged_rules(network, namespaced_data), report);
 }
 
 async fn check_outer_forward_route(
-    namespaced_data: NamespacedData<'_>,
-    netlink_handle: rtnetlink::Handle,
-) -> Result<(), FirecrackerNetworkError> {
+    namespaced_data: &NamespacedData<'_>,
+    netlink_handle: &rtnetlink::Handle,
+    report: &mut FirecrackerNetworkCheckReport,
+) {
     if let Some(forwarded_guest_ip) = namespaced_data.forwarded_guest_ip {
-        let mut route_message = None;
-        let mut route_message_stream = netlink_handle
-            .route()
-            .get(RouteMessageBuilder::<IpAddr>::new().build())
-            .execute();
-
-        while let Ok(Some(current_route_message)) = route_message_stream.try_next().await {
-            for attribute in &current_route_message.attributes {
-                if let RouteAttribute::Destination(route_addr) = attribute {
-                    let ip_addr = match route_addr {
-                        RouteAddress::Inet(i) => IpAddr::V4(*i),
-                        RouteAddress::Inet6(i) => IpAddr::V6(*i),
-                        _ => continue,
-                    };
-
-                    if ip_addr == *forwarded_guest_ip {
-                        route_message = Some(current_route_message);
-                        break;
-                    }
-                }
-            }
-        }
+        let route_state = match find_outer_forward_route(*forwarded_guest_ip, netlink_handle).await {
+            Some(route) if route.gateway == Some(namespaced_data.veth2_ip.address()) => FirecrackerNetworkObjectState::Present,
+            Some(_) => FirecrackerNetworkObjectState::Mismatched,
+            None => FirecrackerNetworkObjectState::Missing,
+        };
 
-        if route_message.is_none() {
-            return Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpRoute));
-        }
+        report.push(
+            FirecrackerNetworkObjectType::IpRoute,
+            forwarded_guest_ip.to_string(),
+            route_state,
+        );
     }
-
-    Ok(())
 }
 
-async fn check_inner_nf_rules<B: Backend>(
-    nft_path: Option<String>,
-    forwarded_guest_ip: Option<IpAddr>,
-    veth2_name: String,
-    guest_ip: IpInet,
-    veth2_ip: IpInet,
-    nf_family: NfFamily,
-) -> Result<(), FirecrackerNetworkError> {
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(nft_path.as_deref(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-
+fn check_inner_nf_rules(
+    current_ruleset: Option<&Nftables<'static>>,
+    inner_rule_data: &InnerRuleData,
+    report: &mut FirecrackerNetworkCheckReport,
+) {
+    let nf_table = &inner_rule_data.nf_table;
     let mut table_exists = false;
-    let mut postrouting_chain_exists = false;
-    let mut prerouting_chain_exists = false;
+    let mut postrouting_chain_state = FirecrackerNetworkObjectState::Missing;
+    let mut prerouting_chain_state = FirecrackerNetworkObjectState::Missing;
     let mut snat_rule_exists = false;
     let mut dnat_rule_exists = false;
 
-    for object in current_ruleset.objects.iter() {
+    for object in current_ruleset.iter().flat_map(|ruleset| ruleset.objects.iter()) {
         match object {
             NfObject::ListObject(object) => match object {
-                NfListObject::Table(table) if table.name == NFT_TABLE => {
+                NfListObject::Table(table) if table.name == nf_table.name && table.family == inner_rule_data.nf_family => {
                     table_exists = true;
                 }
-                NfListObject::Chain(chain) if chain.table == NFT_TABLE => {
-                    if chain.name == NFT_POSTROUTING_CHAIN {
-                        postrouting_chain_exists = true;
-                    } else if chain.name == NFT_PREROUTING_CHAIN {
-                        prerouting_chain_exists = true;
-                    }
-                }
-                NfListObject::Rule(rule) => {
-                    if rule.chain == NFT_POSTROUTING_CHAIN
-                        && rule.expr == inner_snat_expr(veth2_name.clone(), guest_ip, veth2_ip, nf_family)
-                    {
-                        snat_rule_exists = true;
-                    } else if let Some(forwarded_guest_ip) = forwarded_guest_ip {
-                        if rule.chain == NFT_PREROUTING_CHAIN
-                            && rule.expr == inner_dnat_expr(veth2_name.clone(), forwarded_guest_ip, guest_ip, nf_family)
-                        {
-                            dnat_rule_exists = true;
-                        }
+                NfListObject::Chain(chain) if chain.table == nf_table.name && chain.family == inner_rule_data.nf_family => {
+                    if chain.name == nf_table.postrouting_chain.name {
+                        postrouting_chain_state =
+                            base_chain_state(chain, NfChainType::NAT, NfHook::Postrouting, &nf_table.postrouting_chain);
+                    } else if chain.name == nf_table.prerouting_chain.name {
+                        prerouting_chain_state =
+                            base_chain_state(chain, NfChainType::NAT, NfHook::Prerouting, &nf_table.prerouting_chain);
                     }
                 }
+                NfListObject::Rule(rule) => match inner_rule_data.rule_object_type(rule) {
+                    Some(FirecrackerNetworkObjectType::NfEgressSnatRule) => snat_rule_exists = true,
+                    Some(FirecrackerNetworkObjectType::NfIngressDnatRule) => dnat_rule_exists = true,
+                    _ => continue,
+                },
                 _ => continue,
             },
             _ => continue,
         }
     }
 
-    if !table_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::NfTable));
-    }
-
-    if !postrouting_chain_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfPostroutingChain,
-        ));
-    }
+    report.push_found(FirecrackerNetworkObjectType::NfTable, &nf_table.name, table_exists);
+    report.push(
+        FirecrackerNetworkObjectType::NfPostroutingChain,
+        &nf_table.postrouting_chain.name,
+        postrouting_chain_state,
+    );
+    report.push_found(
+        FirecrackerNetworkObjectType::NfEgressSnatRule,
+        format!(
+            "{} to {}",
+            inner_rule_data.guest_ip.address(),
+            inner_rule_data.veth2_ip.address()
+        ),
+        snat_rule_exists,
+    );
 
-    if !snat_rule_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfEgressSnatRule,
-        ));
+    if inner_rule_data.needs_prerouting_chain() {
+        report.push(
+            FirecrackerNetworkObjectType::NfPreroutingChain,
+            &nf_table.prerouting_chain.name,
+            prerouting_chain_state,
+        );
     }
 
-    if forwarded_guest_ip.is_some() {
-        if !prerouting_chain_exists {
-            return Err(FirecrackerNetworkError::ObjectNotFound(
-                FirecrackerNetworkObjectType::NfPreroutingChain,
-            ));
-        }
-
-        if !dnat_rule_exists {
-            return Err(FirecrackerNetworkError::ObjectNotFound(
-                FirecrackerNetworkObjectType::NfIngressDnatRule,
-            ));
-        }
+    if let Some(forwarded_guest_ip) = inner_rule_data.forwarded_guest_ip {
+        report.push_found(
+            FirecrackerNetworkObjectType::NfIngressDnatRule,
+            format!("{} to {}", forwarded_guest_ip, inner_rule_data.guest_ip.address()),
+            dnat_rule_exists,
+        );
     }
 
-    Ok(())
+    check_tagged_rules(current_ruleset, &inner_rule_data.port_forward_rules(), report);
 }
@@ -1,104 +1,101 @@
-use nftables::{
-    batch::Batch,
-    schema::{NfListObject, NfObject, Rule},
-};
-use nftables_async::helper::Helper;
+use nftables::schema::{NfListObject, NfObject, Nftables, Rule};
 
 use crate::{
     backend::Backend,
-    netns::NetNs,
-    util::{FirecrackerNetworkExt, NO_NFT_ARGS},
-    FirecrackerNetwork, FirecrackerNetworkError, FirecrackerNetworkObjectType, NFT_FILTER_CHAIN, NFT_POSTROUTING_CHAIN,
-    NFT_TABLE,
+    conntrack::flush_conntrack,
+    context::FirecrackerNetworkContext,
+    counters::statements_match,
+    egress::locate_egress_policy_rules,
+    ingress::ingress_forward_expr,
+    isolation::{is_isolation_rule, locate_isolation_rules},
+    layout::{delete_nf_rules, locate_undispatched_rules},
+    shaping::delete_shaping,
+    FirecrackerNetwork, FirecrackerNetworkError, FirecrackerNetworkObjectType,
 };
 
-use super::{outer_egress_forward_expr, outer_ingress_forward_expr, outer_masq_expr, NamespacedData};
+use super::{outer_nf_rules, outer_rule_object_type, NamespacedData};
 
 pub(super) async fn delete<B: Backend>(
     namespaced_data: NamespacedData<'_>,
     network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
 ) -> Result<(), FirecrackerNetworkError> {
-    NetNs::get(&namespaced_data.netns_name)
-        .map_err(FirecrackerNetworkError::NetnsError)?
-        .remove()
-        .map_err(FirecrackerNetworkError::NetnsError)?;
+    delete_netns(&namespaced_data)?;
+    delete_shaping(network, &*context.netlink_handle()?).await?;
+    delete_nf_rules::<B>(
+        context,
+        network,
+        &outer_nf_rules(network, &namespaced_data),
+        |current_ruleset| locate_outer_nf_rules(network, &namespaced_data, current_ruleset),
+    )
+    .await?;
+    flush_conntrack::<B>(network).await
+}
 
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
+/// Remove the netns of this network, which also removes everything inside of it including the inner end of the veth
+/// pair and thereby the outer one alongside the qdiscs attached to it. This is everything a delete does besides the IFB
+/// device and the nftables rules and conntrack entries in the outer netns.
+pub(super) fn delete_netns(namespaced_data: &NamespacedData<'_>) -> Result<(), FirecrackerNetworkError> {
+    namespaced_data
+        .get_netns()?
+        .remove()
+        .map_err(FirecrackerNetworkError::NetnsError)
+}
 
-    let mut outer_masq_rule_handle = None;
-    let mut outer_ingress_forward_rule_handle = None;
-    let mut outer_egress_forward_rule_handle = None;
+/// Locate the rules of this network in the outer netns in the flat layout within the current ruleset, failing if any
+/// of them is missing.
+pub(super) fn locate_outer_nf_rules(
+    network: &FirecrackerNetwork,
+    namespaced_data: &NamespacedData<'_>,
+    current_ruleset: &Nftables<'static>,
+) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError> {
+    let mut outer_masq_rule = None;
+    let mut outer_ingress_forward_rule = None;
+    let mut outer_egress_forward_rule = None;
 
     for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule) if rule.table == NFT_TABLE.to_string() => {
-                    if rule.chain == NFT_POSTROUTING_CHAIN && rule.expr == outer_masq_expr(network, &namespaced_data) {
-                        outer_masq_rule_handle = rule.handle;
-                    } else if rule.chain == NFT_FILTER_CHAIN {
-                        if rule.expr == outer_ingress_forward_expr(network, &namespaced_data) {
-                            outer_ingress_forward_rule_handle = rule.handle;
-                        } else if rule.expr == outer_egress_forward_expr(network, &namespaced_data) {
-                            outer_egress_forward_rule_handle = rule.handle;
-                        }
-                    }
-                }
+        if let NfObject::ListObject(NfListObject::Rule(rule)) = object {
+            match outer_rule_object_type(network, namespaced_data, rule) {
+                Some(FirecrackerNetworkObjectType::NfMasqueradeRule) => outer_masq_rule = Some(rule),
+                Some(FirecrackerNetworkObjectType::NfIngressForwardRule) => outer_ingress_forward_rule = Some(rule),
+                Some(FirecrackerNetworkObjectType::NfEgressForwardRule) => outer_egress_forward_rule = Some(rule),
                 _ => continue,
-            },
-            _ => continue,
+            }
         }
     }
 
-    if outer_masq_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfMasqueradeRule,
-        ));
-    }
+    let outer_masq_rule = outer_masq_rule.ok_or(FirecrackerNetworkError::ObjectNotFound(
+        FirecrackerNetworkObjectType::NfMasqueradeRule,
+    ))?;
+    let outer_ingress_forward_rule = outer_ingress_forward_rule.ok_or(FirecrackerNetworkError::ObjectNotFound(
+        FirecrackerNetworkObjectType::NfIngressForwardRule,
+    ))?;
+    let outer_egress_forward_rule = outer_egress_forward_rule.ok_or(FirecrackerNetworkError::ObjectNotFound(
+        FirecrackerNetworkObjectType::NfEgressForwardRule,
+    ))?;
 
-    if outer_ingress_forward_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfIngressForwardRule,
-        ));
-    }
+    let mut outer_nf_rules = outer_nf_rules(network, namespaced_data);
 
-    if outer_egress_forward_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfEgressForwardRule,
-        ));
+    // an ingress forward rule accepting all ingress packets was created by an older version of fcnet, which didn't
+    // create the undispatched rules of the filter chain: those accepting forwarded ports and exposed entries towards
+    // the veth and the one dropping everything else, nor the rules isolating the network
+    if !statements_match(
+        &outer_ingress_forward_rule.expr,
+        &ingress_forward_expr(network, namespaced_data.veth1_name),
+    ) {
+        outer_nf_rules.retain(|nf_rule| {
+            (nf_rule.key.is_some() || nf_rule.rule.chain != network.nf_table.filter_chain.name)
+                && !is_isolation_rule(&nf_rule.rule)
+        });
     }
 
-    let mut batch = Batch::new();
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_POSTROUTING_CHAIN.into(),
-        expr: outer_masq_expr(network, &namespaced_data).into(),
-        handle: outer_masq_rule_handle,
-        index: None,
-        comment: None,
-    }));
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: outer_ingress_forward_expr(network, &namespaced_data).into(),
-        handle: outer_ingress_forward_rule_handle,
-        index: None,
-        comment: None,
-    }));
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: outer_egress_forward_expr(network, &namespaced_data).into(),
-        handle: outer_egress_forward_rule_handle,
-        index: None,
-        comment: None,
-    }));
-
-    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)
+    let mut rules = vec![
+        outer_masq_rule.clone(),
+        outer_ingress_forward_rule.clone(),
+        outer_egress_forward_rule.clone(),
+    ];
+    rules.extend(locate_egress_policy_rules(current_ruleset, &outer_nf_rules)?);
+    rules.extend(locate_isolation_rules(current_ruleset, &outer_nf_rules)?);
+    rules.extend(locate_undispatched_rules(current_ruleset, &outer_nf_rules)?);
+    Ok(rules)
 }
@@ -0,0 +1,93 @@
+use nftables::schema::{NfListObject, NfObject};
+
+use crate::{
+    backend::Backend,
+    context::FirecrackerNetworkContext,
+    inspect::{inspect_link, FirecrackerNetworkNamespacedSnapshot, FirecrackerNetworkRuleSnapshot, FirecrackerNetworkSnapshot},
+    util::{get_current_ruleset, FirecrackerNetworkExt},
+    FirecrackerNetwork, FirecrackerNetworkError,
+};
+
+use super::{find_outer_forward_route, outer_rule_object_type, InnerRuleData, NamespacedData};
+
+pub(super) async fn inspect<B: Backend>(
+    namespaced_data: NamespacedData<'_>,
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<FirecrackerNetworkSnapshot, FirecrackerNetworkError> {
+    let netlink_handle = &context.netlink_handle()?;
+    let netns = namespaced_data.get_netns().ok();
+    let netns_path = netns.as_ref().map(|netns| netns.path().to_path_buf());
+    let veth1_link = inspect_link(namespaced_data.veth1_name, netlink_handle).await?;
+
+    let current_ruleset = context.get_current_ruleset(network.nft_program()).await?;
+    let nf_rules = current_ruleset
+        .objects
+        .iter()
+        .filter_map(|object| match object {
+            NfObject::ListObject(NfListObject::Rule(rule)) => {
+                outer_rule_object_type(network, &namespaced_data, rule).map(|object_type| FirecrackerNetworkRuleSnapshot {
+                    object_type,
+                    rule: rule.clone(),
+                })
+            }
+            _ => None,
+        })
+        .collect();
+
+    let forward_route = match namespaced_data.forwarded_guest_ip {
+        Some(forwarded_guest_ip) => find_outer_forward_route(*forwarded_guest_ip, netlink_handle).await,
+        None => None,
+    };
+
+    let tap_name = network.tap_name.clone();
+    let nft_path = network.nft_path.clone();
+    let inner_rule_data = InnerRuleData::new(network, &namespaced_data);
+
+    let (tap_link, veth2_link, inner_nf_rules) = match netns {
+        Some(netns) => {
+            context
+                .run_in_netns(netns, async move {
+                    let (connection, inner_handle, _) =
+                        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+                    B::spawn_connection(connection);
+
+                    let tap_link = inspect_link(&tap_name, &inner_handle).await?;
+                    let veth2_link = inspect_link(&inner_rule_data.veth2_name, &inner_handle).await?;
+
+                    let current_ruleset = get_current_ruleset::<B>(nft_path.as_deref()).await?;
+                    let inner_nf_rules = current_ruleset
+                        .objects
+                        .iter()
+                        .filter_map(|object| match object {
+                            NfObject::ListObject(NfListObject::Rule(rule)) => {
+                                inner_rule_data
+                                    .rule_object_type(rule)
+                                    .map(|object_type| FirecrackerNetworkRuleSnapshot {
+                                        object_type,
+                                        rule: rule.clone(),
+                                    })
+                            }
+                            _ => None,
+                        })
+                        .collect();
+
+                    Ok((tap_link, veth2_link, inner_nf_rules))
+                })
+                .await?
+        }
+        None => (None, None, Vec::new()),
+    };
+
+    Ok(FirecrackerNetworkSnapshot {
+        tap_link,
+        nf_rules,
+        namespaced: Some(FirecrackerNetworkNamespacedSnapshot {
+            netns_path,
+            veth1_link,
+            veth2_link,
+            inner_nf_rules,
+            forward_route,
+        }),
+    })
+}
@@ -1,24 +1,53 @@
-use std::net::IpAddr;
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use cidr::IpInet;
+use fcnet_types::{FirecrackerNetworkType, FirecrackerNfTable, FirecrackerPortForward, DEFAULT_NETNS_DIR};
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::{StreamExt, TryStreamExt};
 use nftables::{
     expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField},
+    schema::{Nftables, Rule},
     stmt::{Match, NATFamily, Operator, Statement, NAT},
     types::NfFamily,
 };
+use rtnetlink::{
+    packet_route::route::{RouteAddress, RouteAttribute},
+    RouteMessageBuilder,
+};
 
 use crate::{
-    backend::Backend, util::nat_proto_from_addr, FirecrackerNetwork, FirecrackerNetworkError, FirecrackerNetworkOperation,
-    FirecrackerNetworkType,
+    backend::Backend,
+    context::FirecrackerNetworkContext,
+    counters::{counted, statements_match},
+    egress::{egress_forward_verdict, egress_policy_rules},
+    ingress::{ingress_forward_expr, ingress_policy_rules},
+    inspect::{FirecrackerNetworkRouteSnapshot, FirecrackerNetworkSnapshot},
+    isolation::isolation_rules,
+    layout::{NfDispatchKey, NfDispatchedRule, NfTaggedRule},
+    netns::{DirNetNsEnvironment, NetNs},
+    port_forward::{daddr_match, dnat_statement, guest_dport_match, iifname_match, outer_port_forward_rules},
+    rollback::Rollback,
+    shaping::delete_shaping,
+    util::{nat_proto_from_addr, nf_port_rule_tag, nf_rule_tag, nf_rule_tag_object_type, FirecrackerNetworkExt},
+    vmap::NfVerdictMap,
+    FirecrackerNetwork, FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectType,
+    FirecrackerNetworkOperation,
 };
 use std::future::Future;
 
 mod add;
-use add::add;
+use add::{add, add_without_outer_nf_rules};
 mod check;
 use check::check;
 mod delete;
-use delete::delete;
+use delete::{delete, delete_netns, locate_outer_nf_rules};
+mod inspect;
+use inspect::inspect;
 
 struct NamespacedData<'a> {
     netns_name: &'a str,
@@ -27,68 +56,462 @@ struct NamespacedData<'a> {
     veth1_ip: &'a IpInet,
     veth2_ip: &'a IpInet,
     forwarded_guest_ip: &'a Option<IpAddr>,
+    netns_dir: &'a str,
 }
 
 pub async fn run<B: Backend>(
     operation: FirecrackerNetworkOperation,
     network: &FirecrackerNetwork,
-    netlink_handle: rtnetlink::Handle,
+    context: &FirecrackerNetworkContext<B>,
 ) -> Result<(), FirecrackerNetworkError> {
-    let namespaced_data = match network.network_type {
-        #[cfg(feature = "simple")]
-        FirecrackerNetworkType::Simple => unreachable!(),
-        FirecrackerNetworkType::Namespaced {
-            ref netns_name,
-            ref veth1_name,
-            ref veth2_name,
-            ref veth1_ip,
-            ref veth2_ip,
-            ref forwarded_guest_ip,
-        } => NamespacedData {
-            netns_name,
-            veth1_name,
-            veth2_name,
-            veth1_ip,
-            veth2_ip,
-            forwarded_guest_ip,
-        },
-    };
+    let namespaced_data = NamespacedData::from_network(network);
 
     match operation {
-        FirecrackerNetworkOperation::Add => add::<B>(namespaced_data, network, netlink_handle).await,
-        FirecrackerNetworkOperation::Check => check::<B>(namespaced_data, network, netlink_handle).await,
-        FirecrackerNetworkOperation::Delete => delete::<B>(namespaced_data, network).await,
+        FirecrackerNetworkOperation::Add => {
+            let netlink_handle = context.netlink_handle()?;
+            let mut rollback = Rollback::<B>::new(network);
+            let result = add::<B>(namespaced_data, network, context, &netlink_handle, &mut rollback).await;
+            rollback.finish(result, context, &netlink_handle).await
+        }
+        FirecrackerNetworkOperation::Check => check_report::<B>(network, context).await?.into_result(),
+        FirecrackerNetworkOperation::Delete => delete::<B>(namespaced_data, network, context).await,
     }
 }
 
-#[cfg(feature = "namespaced")]
-async fn use_netns_in_thread<B: Backend>(
-    netns_name: String,
-    future: impl 'static + Send + Future<Output = Result<(), FirecrackerNetworkError>>,
+pub async fn check_report<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<FirecrackerNetworkCheckReport, FirecrackerNetworkError> {
+    let (current_ruleset, verdict_maps) = context.get_current_ruleset_with_verdict_maps(network.nft_program()).await?;
+    check_with_ruleset::<B>(network, context, &current_ruleset, &verdict_maps).await
+}
+
+/// Check this network against an already listed ruleset of the outer netns, which allows many networks to share a
+/// single listing.
+pub async fn check_with_ruleset<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+    current_ruleset: &Nftables<'static>,
+    verdict_maps: &[NfVerdictMap],
+) -> Result<FirecrackerNetworkCheckReport, FirecrackerNetworkError> {
+    check::<B>(
+        NamespacedData::from_network(network),
+        network,
+        context,
+        current_ruleset,
+        verdict_maps,
+    )
+    .await
+}
+
+/// Create everything of this network besides its nftables rules in the outer netns.
+pub async fn add_links<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+    netlink_handle: &rtnetlink::Handle,
+    rollback: &mut Rollback<B>,
 ) -> Result<(), FirecrackerNetworkError> {
-    use crate::netns::NetNs;
+    add_without_outer_nf_rules::<B>(
+        &NamespacedData::from_network(network),
+        network,
+        context,
+        netlink_handle,
+        rollback,
+    )
+    .await
+}
+
+/// Remove everything of this network besides its nftables rules in the outer netns.
+pub async fn delete_links(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    delete_netns(&NamespacedData::from_network(network))?;
+    delete_shaping(network, netlink_handle).await
+}
+
+/// Get the netns of this network, which must already exist.
+#[cfg(feature = "udp")]
+pub fn get_netns(network: &FirecrackerNetwork) -> Result<NetNs, FirecrackerNetworkError> {
+    NamespacedData::from_network(network).get_netns()
+}
+
+/// The rules of this network in the outer netns, see [outer_nf_rules].
+pub fn nf_rules(network: &FirecrackerNetwork) -> Vec<NfDispatchedRule> {
+    outer_nf_rules(network, &NamespacedData::from_network(network))
+}
+
+/// Locate the rules of this network in the outer netns in the flat layout, see [locate_outer_nf_rules].
+pub fn locate_nf_rules(
+    network: &FirecrackerNetwork,
+    current_ruleset: &Nftables<'static>,
+) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError> {
+    locate_outer_nf_rules(network, &NamespacedData::from_network(network), current_ruleset)
+}
+
+pub async fn inspect_snapshot<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<FirecrackerNetworkSnapshot, FirecrackerNetworkError> {
+    inspect::<B>(NamespacedData::from_network(network), network, context).await
+}
 
-    let netns = NetNs::get(netns_name).map_err(FirecrackerNetworkError::NetnsError)?;
+impl<'a> NamespacedData<'a> {
+    fn from_network(network: &'a FirecrackerNetwork) -> Self {
+        match network.network_type {
+            #[cfg(feature = "simple")]
+            FirecrackerNetworkType::Simple => unreachable!(),
+            FirecrackerNetworkType::Namespaced {
+                ref netns_name,
+                ref veth1_name,
+                ref veth2_name,
+                ref veth1_ip,
+                ref veth2_ip,
+                ref forwarded_guest_ip,
+                ref netns_dir,
+                jailer: _,
+            } => NamespacedData {
+                netns_name,
+                veth1_name,
+                veth2_name,
+                veth1_ip,
+                veth2_ip,
+                forwarded_guest_ip,
+                netns_dir: netns_dir.as_deref().unwrap_or(DEFAULT_NETNS_DIR),
+            },
+        }
+    }
+
+    fn netns_env(&self) -> DirNetNsEnvironment {
+        DirNetNsEnvironment(PathBuf::from(self.netns_dir))
+    }
+
+    fn get_netns(&self) -> Result<NetNs, FirecrackerNetworkError> {
+        NetNs::get_from_env(self.netns_name, self.netns_env()).map_err(FirecrackerNetworkError::NetnsError)
+    }
+}
+
+type NetNsJob = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A pool of OS threads that each run a single async executor of a [Backend], onto which jobs that enter network
+/// namespaces are dispatched in a round-robin manner. The jobs of a thread are run one after another, and the thread
+/// returns to the netns it was spawned in after each of them, since a thread left in a netns keeps it alive even once
+/// it has been removed. The threads exit once the pool is dropped.
+pub struct NetNsWorkers {
+    senders: Vec<UnboundedSender<NetNsJob>>,
+    next_worker: AtomicUsize,
+}
+
+impl NetNsWorkers {
+    pub fn new<B: Backend>(worker_count: usize) -> Result<Self, FirecrackerNetworkError> {
+        let mut senders = Vec::with_capacity(worker_count.max(1));
+
+        for _ in 0..worker_count.max(1) {
+            let home_netns = NetNs::current().map_err(FirecrackerNetworkError::NetnsError)?;
+            let (sender, mut receiver) = futures_channel::mpsc::unbounded::<NetNsJob>();
+
+            std::thread::spawn(move || {
+                B::block_on_current_thread(async move {
+                    while let Some(job) = receiver.next().await {
+                        job.await;
+
+                        // a worker that can't return home would run later jobs in a foreign netns, so it exits instead
+                        if home_netns.enter().is_err() {
+                            break;
+                        }
+                    }
+                })
+            });
+            senders.push(sender);
+        }
+
+        Ok(Self {
+            senders,
+            next_worker: AtomicUsize::new(0),
+        })
+    }
+
+    /// Dispatch the job onto the next worker, handing it back should that worker have exited after a panic.
+    fn dispatch(&self, job: NetNsJob) -> Result<(), NetNsJob> {
+        let worker = self.next_worker.fetch_add(1, Ordering::Relaxed) % self.senders.len();
+        self.senders[worker].unbounded_send(job).map_err(|err| err.into_inner())
+    }
+}
+
+/// Run the given future on a thread that has entered the given netns: one of the given workers if present, or otherwise
+/// a new OS thread running an executor of the [Backend].
+pub async fn run_in_netns<B: Backend, O: 'static + Send>(
+    netns_workers: Option<&NetNsWorkers>,
+    netns: NetNs,
+    future: impl 'static + Send + Future<Output = Result<O, FirecrackerNetworkError>>,
+) -> Result<O, FirecrackerNetworkError> {
     let (sender, receiver) = futures_channel::oneshot::channel();
 
-    std::thread::spawn(move || {
-        let result = B::block_on_current_thread(async move {
+    let job: NetNsJob = Box::pin(async move {
+        let result = async move {
             netns.enter().map_err(FirecrackerNetworkError::NetnsError)?;
             future.await
-        });
+        }
+        .await;
 
         let _ = sender.send(result);
     });
 
+    let job = match netns_workers {
+        Some(netns_workers) => netns_workers.dispatch(job).err(),
+        None => Some(job),
+    };
+
+    if let Some(job) = job {
+        std::thread::spawn(move || B::block_on_current_thread(job));
+    }
+
     match receiver.await {
         Ok(result) => result,
         Err(err) => Err(FirecrackerNetworkError::ChannelCancelError(err)),
     }
 }
 
+async fn find_outer_forward_route(
+    forwarded_guest_ip: IpAddr,
+    netlink_handle: &rtnetlink::Handle,
+) -> Option<FirecrackerNetworkRouteSnapshot> {
+    let mut route_message_stream = netlink_handle
+        .route()
+        .get(RouteMessageBuilder::<IpAddr>::new().build())
+        .execute();
+
+    while let Ok(Some(current_route_message)) = route_message_stream.try_next().await {
+        let mut destination = None;
+        let mut gateway = None;
+        let mut output_link_index = None;
+
+        for attribute in &current_route_message.attributes {
+            match attribute {
+                RouteAttribute::Destination(route_addr) => destination = route_addr_to_ip(route_addr),
+                RouteAttribute::Gateway(route_addr) => gateway = route_addr_to_ip(route_addr),
+                RouteAttribute::Oif(index) => output_link_index = Some(*index),
+                _ => continue,
+            }
+        }
+
+        if destination == Some(forwarded_guest_ip) {
+            return Some(FirecrackerNetworkRouteSnapshot {
+                destination: forwarded_guest_ip,
+                gateway,
+                output_link_index,
+            });
+        }
+    }
+
+    None
+}
+
+#[inline]
+fn route_addr_to_ip(route_addr: &RouteAddress) -> Option<IpAddr> {
+    match route_addr {
+        RouteAddress::Inet(i) => Some(IpAddr::V4(*i)),
+        RouteAddress::Inet6(i) => Some(IpAddr::V6(*i)),
+        _ => None,
+    }
+}
+
+/// The rules of this network in the outer netns alongside the keys they are dispatched via in the verdict map layout.
+fn outer_nf_rules(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<NfDispatchedRule> {
+    let mut rules = vec![
+        // masquerade veth packets as host iface packets
+        NfDispatchedRule::new(
+            network,
+            Rule {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                chain: network.nf_table.postrouting_chain.name.clone().into(),
+                expr: outer_masq_expr(network, namespaced_data).into(),
+                handle: None,
+                index: None,
+                comment: Some(nf_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfMasqueradeRule).into()),
+            },
+            NfDispatchKey::Saddr(namespaced_data.veth2_ip.address()),
+        ),
+        // forward ingress packets of connections initiated by the guest from host iface to veth
+        NfDispatchedRule::new(
+            network,
+            Rule {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                chain: network.nf_table.filter_chain.name.clone().into(),
+                expr: ingress_forward_expr(network, namespaced_data.veth1_name).into(),
+                handle: None,
+                index: None,
+                comment: Some(nf_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfIngressForwardRule).into()),
+            },
+            NfDispatchKey::Oifname(namespaced_data.veth1_name.to_string()),
+        ),
+    ];
+
+    // filter egress packets from veth to host iface by the egress policy before forwarding the rest
+    rules.extend(egress_policy_rules(network, namespaced_data.veth1_name));
+    rules.push(
+        // forward egress packets from veth to host iface
+        NfDispatchedRule::new(
+            network,
+            Rule {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                chain: network.nf_table.filter_chain.name.clone().into(),
+                expr: outer_egress_forward_expr(network, namespaced_data).into(),
+                handle: None,
+                index: None,
+                comment: Some(nf_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfEgressForwardRule).into()),
+            },
+            NfDispatchKey::Iifname(namespaced_data.veth1_name.to_string()),
+        ),
+    );
+
+    // drop packets forwarded between the veth and the links of other networks unless they share a group
+    rules.extend(isolation_rules(network, namespaced_data.veth1_name));
+    rules.extend(
+        outer_tagged_rules(network, namespaced_data)
+            .into_iter()
+            .map(|tagged_rule| NfDispatchedRule::undispatched(network, tagged_rule.rule)),
+    );
+    rules
+}
+
+/// The rules of this network in the outer netns that exist once per forwarded port or entry of the ingress policy,
+/// followed by the rule dropping all other ingress packets towards the veth.
+fn outer_tagged_rules(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<NfTaggedRule> {
+    // DNAT connections to forwarded ports to the veth2 ip, from where the inner netns DNATs them once more to the guest
+    let mut rules = outer_port_forward_rules(network, namespaced_data.veth2_ip.address(), namespaced_data.veth1_name);
+    rules.extend(ingress_policy_rules(network, namespaced_data.veth1_name));
+    rules
+}
+
+/// Determine which of this network's rules in the outer netns the given rule is, if any.
+fn outer_rule_object_type(
+    network: &FirecrackerNetwork,
+    namespaced_data: &NamespacedData,
+    rule: &Rule,
+) -> Option<FirecrackerNetworkObjectType> {
+    if rule.table != network.nf_table.name || rule.family != network.nf_family() {
+        return None;
+    }
+
+    if let Some(ref comment) = rule.comment {
+        return nf_rule_tag_object_type(comment, network.resolved_id());
+    }
+
+    // untagged rules can only have been created by older versions of fcnet, so fall back to comparing expressions
+    if rule.chain == network.nf_table.postrouting_chain.name
+        && statements_match(&rule.expr, &outer_masq_expr(network, namespaced_data))
+    {
+        Some(FirecrackerNetworkObjectType::NfMasqueradeRule)
+    } else if rule.chain == network.nf_table.filter_chain.name
+        && statements_match(&rule.expr, &legacy_outer_ingress_forward_expr(network, namespaced_data))
+    {
+        Some(FirecrackerNetworkObjectType::NfIngressForwardRule)
+    } else if rule.chain == network.nf_table.filter_chain.name
+        && statements_match(&rule.expr, &outer_egress_forward_expr(network, namespaced_data))
+    {
+        Some(FirecrackerNetworkObjectType::NfEgressForwardRule)
+    } else {
+        None
+    }
+}
+
+/// The owned data identifying this network's rules in the inner netns, which can be moved onto the thread that
+/// enters the netns.
+struct InnerRuleData {
+    network_id: String,
+    veth2_name: String,
+    veth2_ip: IpInet,
+    guest_ip: IpInet,
+    forwarded_guest_ip: Option<IpAddr>,
+    port_forwards: Vec<FirecrackerPortForward>,
+    nf_family: NfFamily,
+    nf_table: FirecrackerNfTable,
+}
+
+impl InnerRuleData {
+    fn new(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Self {
+        Self {
+            network_id: network.resolved_id().to_string(),
+            veth2_name: namespaced_data.veth2_name.to_string(),
+            veth2_ip: *namespaced_data.veth2_ip,
+            guest_ip: network.guest_ip,
+            forwarded_guest_ip: *namespaced_data.forwarded_guest_ip,
+            port_forwards: network.port_forwards.clone(),
+            nf_family: network.nf_family(),
+            nf_table: network.nf_table.clone(),
+        }
+    }
+
+    /// Whether this network needs the prerouting chain in the inner netns, which is only the case when forwarding.
+    fn needs_prerouting_chain(&self) -> bool {
+        self.forwarded_guest_ip.is_some() || !self.port_forwards.is_empty()
+    }
+
+    /// The rules in the inner netns that DNAT connections to the forwarded ports of this network, which arrive already
+    /// DNATed to the veth2 ip and with the port of the guest, to the guest ip.
+    fn port_forward_rules(&self) -> Vec<NfTaggedRule> {
+        self.port_forwards
+            .iter()
+            .enumerate()
+            .map(|(index, port_forward)| NfTaggedRule {
+                object_type: FirecrackerNetworkObjectType::NfPortDnatRule,
+                identifier: format!("{port_forward} to {}", self.guest_ip.address()),
+                rule: Rule {
+                    family: self.nf_family,
+                    table: self.nf_table.name.clone().into(),
+                    chain: self.nf_table.prerouting_chain.name.clone().into(),
+                    expr: vec![
+                        iifname_match(&self.veth2_name),
+                        daddr_match(self.veth2_ip.address()),
+                        guest_dport_match(port_forward),
+                        dnat_statement(self.guest_ip.address(), None, self.nf_family),
+                    ]
+                    .into(),
+                    handle: None,
+                    index: None,
+                    comment: Some(nf_port_rule_tag(&self.network_id, FirecrackerNetworkObjectType::NfPortDnatRule, index).into()),
+                },
+            })
+            .collect()
+    }
+
+    /// Determine which of this network's rules in the inner netns the given rule is, if any.
+    fn rule_object_type(&self, rule: &Rule) -> Option<FirecrackerNetworkObjectType> {
+        if rule.table != self.nf_table.name || rule.family != self.nf_family {
+            return None;
+        }
+
+        if let Some(ref comment) = rule.comment {
+            return nf_rule_tag_object_type(comment, &self.network_id);
+        }
+
+        // untagged rules can only have been created by older versions of fcnet, so fall back to comparing expressions
+        if rule.chain == self.nf_table.postrouting_chain.name
+            && rule.expr == inner_snat_expr(self.veth2_name.clone(), self.guest_ip, self.veth2_ip, self.nf_family)
+        {
+            return Some(FirecrackerNetworkObjectType::NfEgressSnatRule);
+        }
+
+        match self.forwarded_guest_ip {
+            Some(forwarded_guest_ip)
+                if rule.chain == self.nf_table.prerouting_chain.name
+                    && rule.expr
+                        == inner_dnat_expr(self.veth2_name.clone(), forwarded_guest_ip, self.guest_ip, self.nf_family) =>
+            {
+                Some(FirecrackerNetworkObjectType::NfIngressDnatRule)
+            }
+            _ => None,
+        }
+    }
+}
+
 #[inline]
 fn outer_masq_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<Statement<'static>> {
-    vec![
+    [
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
                 protocol: nat_proto_from_addr(namespaced_data.veth2_ip.address()),
@@ -102,12 +525,16 @@ fn outer_masq_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedDat
             right: Expression::String(network.iface_name.clone().into()),
             op: Operator::EQ,
         }),
-        Statement::Masquerade(None),
     ]
+    .into_iter()
+    .chain(counted(Statement::Masquerade(None)))
+    .collect()
 }
 
+/// The expression of the ingress forward rule that older versions of fcnet created, which accepted all ingress packets
+/// towards the veth rather than only those of connections initiated by the guest.
 #[inline]
-fn outer_ingress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<Statement<'static>> {
+fn legacy_outer_ingress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<Statement<'static>> {
     vec![
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
@@ -125,7 +552,7 @@ fn outer_ingress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &Na
 
 #[inline]
 fn outer_egress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<Statement<'static>> {
-    vec![
+    [
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
             right: Expression::String(network.iface_name.clone().into()),
@@ -136,8 +563,10 @@ fn outer_egress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &Nam
             right: Expression::String(namespaced_data.veth1_name.to_string().into()),
             op: Operator::EQ,
         }),
-        Statement::Accept(None),
     ]
+    .into_iter()
+    .chain(counted(egress_forward_verdict(network)))
+    .collect()
 }
 
 #[inline]
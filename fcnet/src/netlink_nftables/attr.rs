@@ -0,0 +1,95 @@
+const NLA_HEADER_LEN: usize = 4;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_F_NET_BYTEORDER: u16 = 1 << 14;
+const NLA_TYPE_MASK: u16 = !(NLA_F_NESTED | NLA_F_NET_BYTEORDER);
+
+#[inline]
+pub fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[inline]
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(align(buf.len()), 0);
+}
+
+pub fn put_bytes(buf: &mut Vec<u8>, attr_type: u16, data: &[u8]) {
+    buf.extend_from_slice(&((NLA_HEADER_LEN + data.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&attr_type.to_ne_bytes());
+    buf.extend_from_slice(data);
+    pad(buf);
+}
+
+pub fn put_str(buf: &mut Vec<u8>, attr_type: u16, value: &str) {
+    let mut data = Vec::with_capacity(value.len() + 1);
+    data.extend_from_slice(value.as_bytes());
+    data.push(0);
+    put_bytes(buf, attr_type, &data);
+}
+
+pub fn put_be32(buf: &mut Vec<u8>, attr_type: u16, value: u32) {
+    put_bytes(buf, attr_type, &value.to_be_bytes());
+}
+
+pub fn put_be64(buf: &mut Vec<u8>, attr_type: u16, value: u64) {
+    put_bytes(buf, attr_type, &value.to_be_bytes());
+}
+
+/// Start a nested attribute, returning its offset that must be passed to [end_nested] once all of its inner attributes
+/// have been written.
+pub fn begin_nested(buf: &mut Vec<u8>, attr_type: u16) -> usize {
+    let offset = buf.len();
+    buf.extend_from_slice(&0u16.to_ne_bytes());
+    buf.extend_from_slice(&(attr_type | NLA_F_NESTED).to_ne_bytes());
+    offset
+}
+
+pub fn end_nested(buf: &mut [u8], offset: usize) {
+    let len = (buf.len() - offset) as u16;
+    buf[offset..offset + 2].copy_from_slice(&len.to_ne_bytes());
+}
+
+/// An iterator over the netlink attributes in a buffer, yielding their types (without flags) and payloads.
+pub struct Attrs<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Attrs<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> Iterator for Attrs<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.len() < NLA_HEADER_LEN {
+            return None;
+        }
+
+        let len = u16::from_ne_bytes([self.buf[0], self.buf[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([self.buf[2], self.buf[3]]) & NLA_TYPE_MASK;
+
+        if len < NLA_HEADER_LEN || len > self.buf.len() {
+            return None;
+        }
+
+        let payload = &self.buf[NLA_HEADER_LEN..len];
+        self.buf = &self.buf[align(len).min(self.buf.len())..];
+        Some((attr_type, payload))
+    }
+}
+
+pub fn parse_str(payload: &[u8]) -> Option<String> {
+    let end = payload.iter().position(|byte| *byte == 0).unwrap_or(payload.len());
+    String::from_utf8(payload[..end].to_vec()).ok()
+}
+
+pub fn parse_be32(payload: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(payload.get(..4)?.try_into().ok()?))
+}
+
+pub fn parse_be64(payload: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(payload.get(..8)?.try_into().ok()?))
+}
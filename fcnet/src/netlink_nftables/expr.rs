@@ -0,0 +1,1522 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use fcnet_types::FirecrackerMacAddr;
+use nftables::{
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Prefix, Range, Verdict, CT},
+    stmt::{AnonymousCounter, Counter, JumpTarget, Match, NATFamily, Operator, Statement, VerdictMap, NAT},
+    types::NfFamily,
+};
+
+use super::{
+    attr::{begin_nested, end_nested, parse_be32, parse_be64, parse_str, put_be32, put_be64, put_bytes, put_str, Attrs},
+    message::{NFPROTO_IPV4, NFPROTO_IPV6},
+};
+
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFT_META_PROTOCOL: u32 = 1;
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_IIFTYPE: u32 = 8;
+const NFT_META_NFPROTO: u32 = 15;
+const NFT_META_L4PROTO: u32 = 16;
+
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_NEQ: u32 = 1;
+const NFT_CMP_LTE: u32 = 3;
+const NFT_CMP_GTE: u32 = 5;
+
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFT_PAYLOAD_LL_HEADER: u32 = 0;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+
+const NFTA_CT_DREG: u16 = 1;
+const NFTA_CT_KEY: u16 = 2;
+const NFT_CT_STATE: u32 = 0;
+
+/// The names of the conntrack states in nft alongside their bits in the value of "ct state", in the order that nft
+/// lists them in.
+const CT_STATES: [(&str, u32); 5] = [
+    ("invalid", 1),
+    ("established", 2),
+    ("related", 4),
+    ("new", 8),
+    ("untracked", 64),
+];
+
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_BITWISE_OP: u16 = 6;
+const NFT_BITWISE_BOOL: u32 = 0;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+
+const ARPHRD_ETHER: u16 = 1;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_ARP: u16 = 0x0806;
+const ETH_P_IPV6: u16 = 0x86dd;
+
+/// The names of the EtherTypes in nft alongside their values.
+const ETHERTYPES: [(&str, u16); 3] = [("ip", ETH_P_IP), ("arp", ETH_P_ARP), ("ip6", ETH_P_IPV6)];
+
+/// The names of the ICMPv6 types of neighbor discovery in nft alongside their values.
+const ICMPV6_TYPES: [(&str, u8); 5] = [
+    ("nd-router-solicit", 133),
+    ("nd-router-advert", 134),
+    ("nd-neighbor-solicit", 135),
+    ("nd-neighbor-advert", 136),
+    ("nd-redirect", 137),
+];
+
+const NFTA_LOOKUP_SET: u16 = 1;
+const NFTA_LOOKUP_SREG: u16 = 2;
+const NFTA_LOOKUP_DREG: u16 = 3;
+const NFTA_LOOKUP_FLAGS: u16 = 5;
+const NFT_LOOKUP_F_INV: u32 = 0x1;
+
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_VERDICT_CHAIN: u16 = 2;
+
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+const NFT_CONTINUE: u32 = -1i32 as u32;
+const NFT_JUMP: u32 = -3i32 as u32;
+const NFT_GOTO: u32 = -4i32 as u32;
+const NFT_RETURN: u32 = -5i32 as u32;
+
+const NFTA_NAT_TYPE: u16 = 1;
+const NFTA_NAT_FAMILY: u16 = 2;
+const NFTA_NAT_REG_ADDR_MIN: u16 = 3;
+const NFTA_NAT_REG_ADDR_MAX: u16 = 4;
+const NFTA_NAT_REG_PROTO_MIN: u16 = 5;
+const NFTA_NAT_REG_PROTO_MAX: u16 = 6;
+const NFTA_NAT_FLAGS: u16 = 7;
+const NF_NAT_RANGE_MAP_IPS: u32 = 1;
+const NF_NAT_RANGE_PROTO_SPECIFIED: u32 = 2;
+const NFT_NAT_SNAT: u32 = 0;
+const NFT_NAT_DNAT: u32 = 1;
+
+const NFTA_COUNTER_BYTES: u16 = 1;
+const NFTA_COUNTER_PACKETS: u16 = 2;
+
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_REG_2: u32 = 2;
+
+pub const IFNAMSIZ: usize = 16;
+
+/// Encode the statements of a rule into the contents of its NFTA_RULE_EXPRESSIONS attribute, or return [None] if any of
+/// them isn't supported natively.
+pub fn encode_statements(buf: &mut Vec<u8>, family: NfFamily, statements: &[Statement]) -> Option<()> {
+    for statement in statements {
+        match statement {
+            // like nft, a match of conntrack states tests whether any of their bits are set
+            Statement::Match(Match {
+                left:
+                    Expression::Named(NamedExpression::CT(CT {
+                        key,
+                        family: None,
+                        dir: None,
+                    })),
+                right,
+                op: Operator::IN,
+            }) if key == "state" => {
+                let mask = encode_ct_states(right)?.to_ne_bytes();
+                encode_expr(buf, "ct", |buf| {
+                    put_be32(buf, NFTA_CT_KEY, NFT_CT_STATE);
+                    put_be32(buf, NFTA_CT_DREG, NFT_REG_1);
+                });
+                encode_bitwise(buf, &mask);
+                encode_cmp(buf, NFT_CMP_NEQ, &[0; 4]);
+            }
+            Statement::Match(Match { left, right, op }) => {
+                let cmp_op = match op {
+                    Operator::EQ => NFT_CMP_EQ,
+                    Operator::NEQ => NFT_CMP_NEQ,
+                    _ => return None,
+                };
+
+                // a string naming a set matches the membership of the value in it
+                if let Expression::String(set) = right {
+                    if let Some(set) = set.strip_prefix('@') {
+                        encode_load(buf, family, left)?;
+                        encode_expr(buf, "lookup", |buf| {
+                            put_str(buf, NFTA_LOOKUP_SET, set);
+                            put_be32(buf, NFTA_LOOKUP_SREG, NFT_REG_1);
+                            put_be32(
+                                buf,
+                                NFTA_LOOKUP_FLAGS,
+                                match cmp_op {
+                                    NFT_CMP_NEQ => NFT_LOOKUP_F_INV,
+                                    _ => 0,
+                                },
+                            );
+                        });
+                        continue;
+                    }
+                }
+
+                let data = match (encode_load(buf, family, left)?, right) {
+                    (Load::Ifname, Expression::String(value)) => {
+                        if value.len() >= IFNAMSIZ {
+                            return None;
+                        }
+
+                        let mut ifname = value.as_bytes().to_vec();
+                        ifname.resize(IFNAMSIZ, 0);
+                        ifname
+                    }
+                    (Load::Addr(nfproto), Expression::String(value)) => match value.parse::<IpAddr>().ok()? {
+                        IpAddr::V4(addr) if nfproto == NFPROTO_IPV4 => addr.octets().to_vec(),
+                        IpAddr::V6(addr) if nfproto == NFPROTO_IPV6 => addr.octets().to_vec(),
+                        _ => return None,
+                    },
+                    // like nft, a prefix is matched by masking the address before comparing it
+                    (Load::Addr(nfproto), Expression::Named(NamedExpression::Prefix(Prefix { addr, len }))) => {
+                        let Expression::String(addr) = addr.as_ref() else {
+                            return None;
+                        };
+                        let addr = match addr.parse::<IpAddr>().ok()? {
+                            IpAddr::V4(addr) if nfproto == NFPROTO_IPV4 => addr.octets().to_vec(),
+                            IpAddr::V6(addr) if nfproto == NFPROTO_IPV6 => addr.octets().to_vec(),
+                            _ => return None,
+                        };
+                        let mask = prefix_mask(addr.len(), *len)?;
+
+                        encode_bitwise(buf, &mask);
+                        addr.iter().zip(mask).map(|(byte, mask)| byte & mask).collect()
+                    }
+                    (Load::Port, Expression::Number(port)) => u16::try_from(*port).ok()?.to_be_bytes().to_vec(),
+                    (Load::Lladdr, Expression::String(value)) => value.parse::<FirecrackerMacAddr>().ok()?.0.to_vec(),
+                    (Load::Ethertype, Expression::String(value)) => {
+                        let (_, ethertype) = ETHERTYPES.iter().find(|(name, _)| name == value)?;
+                        ethertype.to_be_bytes().to_vec()
+                    }
+                    (Load::Icmpv6Type, Expression::String(value)) => {
+                        let (_, icmpv6_type) = ICMPV6_TYPES.iter().find(|(name, _)| name == value)?;
+                        vec![*icmpv6_type]
+                    }
+                    // like nft, a range is matched via a pair of comparisons of the same register
+                    (Load::Port, Expression::Range(range)) if *op == Operator::EQ => {
+                        let [Expression::Number(start), Expression::Number(end)] = &range.range else {
+                            return None;
+                        };
+
+                        encode_cmp(buf, NFT_CMP_GTE, &u16::try_from(*start).ok()?.to_be_bytes());
+                        encode_cmp(buf, NFT_CMP_LTE, &u16::try_from(*end).ok()?.to_be_bytes());
+                        continue;
+                    }
+                    _ => return None,
+                };
+                encode_cmp(buf, cmp_op, &data);
+            }
+            Statement::VerdictMap(VerdictMap {
+                key,
+                data: Expression::String(set),
+            }) => {
+                let set = set.strip_prefix('@')?;
+                encode_load(buf, family, key)?;
+                encode_expr(buf, "lookup", |buf| {
+                    put_str(buf, NFTA_LOOKUP_SET, set);
+                    put_be32(buf, NFTA_LOOKUP_SREG, NFT_REG_1);
+                    put_be32(buf, NFTA_LOOKUP_DREG, NFT_REG_VERDICT);
+                });
+            }
+            Statement::Accept(None) => encode_verdict(buf, &Verdict::Accept),
+            Statement::Drop(None) => encode_verdict(buf, &Verdict::Drop),
+            Statement::Continue(None) => encode_verdict(buf, &Verdict::Continue),
+            Statement::Return(None) => encode_verdict(buf, &Verdict::Return),
+            Statement::Jump(target) => encode_verdict(buf, &Verdict::Jump(target.clone())),
+            Statement::Goto(target) => encode_verdict(buf, &Verdict::Goto(target.clone())),
+            // the values of an anonymous counter are the ones it starts counting from
+            Statement::Counter(Counter::Anonymous(counter)) => {
+                let counter = counter.clone().unwrap_or_default();
+                encode_expr(buf, "counter", |buf| {
+                    put_be64(buf, NFTA_COUNTER_BYTES, counter.bytes.unwrap_or_default() as u64);
+                    put_be64(buf, NFTA_COUNTER_PACKETS, counter.packets.unwrap_or_default() as u64);
+                });
+            }
+            Statement::Masquerade(None) => encode_expr(buf, "masq", |_| {}),
+            Statement::SNAT(Some(nat)) => encode_nat(buf, family, NFT_NAT_SNAT, nat)?,
+            Statement::DNAT(Some(nat)) => encode_nat(buf, family, NFT_NAT_DNAT, nat)?,
+            _ => return None,
+        }
+    }
+
+    Some(())
+}
+
+fn encode_expr<F: FnOnce(&mut Vec<u8>)>(buf: &mut Vec<u8>, name: &str, encode_data: F) {
+    let elem_offset = begin_nested(buf, NFTA_LIST_ELEM);
+    put_str(buf, NFTA_EXPR_NAME, name);
+    let data_offset = begin_nested(buf, NFTA_EXPR_DATA);
+    encode_data(buf);
+    end_nested(buf, data_offset);
+    end_nested(buf, elem_offset);
+}
+
+fn encode_meta(buf: &mut Vec<u8>, meta_key: u32) {
+    encode_expr(buf, "meta", |buf| {
+        put_be32(buf, NFTA_META_KEY, meta_key);
+        put_be32(buf, NFTA_META_DREG, NFT_REG_1);
+    });
+}
+
+fn encode_cmp(buf: &mut Vec<u8>, cmp_op: u32, data: &[u8]) {
+    encode_expr(buf, "cmp", |buf| {
+        put_be32(buf, NFTA_CMP_SREG, NFT_REG_1);
+        put_be32(buf, NFTA_CMP_OP, cmp_op);
+        let data_offset = begin_nested(buf, NFTA_CMP_DATA);
+        put_bytes(buf, NFTA_DATA_VALUE, data);
+        end_nested(buf, data_offset);
+    });
+}
+
+/// Encode the bits of the conntrack states named by an expression, which is either a single name or a list of them.
+fn encode_ct_states(expr: &Expression) -> Option<u32> {
+    let names = match expr {
+        Expression::String(name) => vec![name.as_ref()],
+        Expression::List(names) => names
+            .iter()
+            .map(|name| match name {
+                Expression::String(name) => Some(name.as_ref()),
+                _ => None,
+            })
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+
+    names.into_iter().try_fold(0, |bits, name| {
+        let (_, bit) = CT_STATES.iter().find(|(state, _)| *state == name)?;
+        Some(bits | bit)
+    })
+}
+
+/// The mask of a prefix of the given length over an address of the given number of bytes.
+fn prefix_mask(addr_len: usize, prefix_len: u32) -> Option<Vec<u8>> {
+    if prefix_len as usize > addr_len * 8 {
+        return None;
+    }
+
+    Some(
+        (0..addr_len as u32)
+            .map(|byte| match prefix_len.saturating_sub(byte * 8) {
+                0 => 0,
+                bits @ 1..=7 => !(0xffu8 >> bits),
+                _ => 0xff,
+            })
+            .collect(),
+    )
+}
+
+/// Encode a bitwise expression that masks the first register in place, which nft uses with a zero XOR value.
+fn encode_bitwise(buf: &mut Vec<u8>, mask: &[u8]) {
+    encode_expr(buf, "bitwise", |buf| {
+        put_be32(buf, NFTA_BITWISE_SREG, NFT_REG_1);
+        put_be32(buf, NFTA_BITWISE_DREG, NFT_REG_1);
+        put_be32(buf, NFTA_BITWISE_LEN, mask.len() as u32);
+        let mask_offset = begin_nested(buf, NFTA_BITWISE_MASK);
+        put_bytes(buf, NFTA_DATA_VALUE, mask);
+        end_nested(buf, mask_offset);
+        let xor_offset = begin_nested(buf, NFTA_BITWISE_XOR);
+        put_bytes(buf, NFTA_DATA_VALUE, &vec![0; mask.len()]);
+        end_nested(buf, xor_offset);
+    });
+}
+
+/// What a register was loaded with by [encode_load] or, when decoding, by the expressions that [decode_load] turns back
+/// into one, which determines the format of the data it is compared to.
+enum Load {
+    Ifname,
+    /// An IP address of the version with the given nfproto.
+    Addr(u8),
+    Port,
+    Lladdr,
+    Ethertype,
+    Icmpv6Type,
+    /// The conntrack state, which is only ever matched by its bits.
+    CtState,
+}
+
+/// Encode the expressions loading the value of the given expression into the first register, or return [None] if it
+/// isn't supported natively.
+fn encode_load(buf: &mut Vec<u8>, family: NfFamily, expr: &Expression) -> Option<Load> {
+    match expr {
+        Expression::Named(NamedExpression::Meta(Meta { key })) => {
+            let (meta_key, load) = match key {
+                MetaKey::Iifname => (NFT_META_IIFNAME, Load::Ifname),
+                MetaKey::Oifname => (NFT_META_OIFNAME, Load::Ifname),
+                MetaKey::Protocol => (NFT_META_PROTOCOL, Load::Ethertype),
+                _ => return None,
+            };
+
+            encode_meta(buf, meta_key);
+            Some(load)
+        }
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field })))
+            if l4proto_from_name(protocol).is_some() =>
+        {
+            let (offset, len, load) = match (protocol.as_ref(), field.as_ref()) {
+                ("tcp" | "udp", "sport") => (0, 2, Load::Port),
+                ("tcp" | "udp", "dport") => (2, 2, Load::Port),
+                ("icmpv6", "type") => (0, 1, Load::Icmpv6Type),
+                ("icmpv6", "taddr") => (8, 16, Load::Addr(NFPROTO_IPV6)),
+                _ => return None,
+            };
+
+            // the transport header is only known to be of the protocol once it has been matched, which nft does
+            // implicitly before the payload in every family
+            encode_meta(buf, NFT_META_L4PROTO);
+            encode_cmp(buf, NFT_CMP_EQ, &[l4proto_from_name(protocol)?]);
+            encode_payload(buf, NFT_PAYLOAD_TRANSPORT_HEADER, offset, len);
+            Some(load)
+        }
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field })))
+            if protocol == "ether" =>
+        {
+            let offset = match field.as_ref() {
+                "daddr" => 0,
+                "saddr" => 6,
+                _ => return None,
+            };
+
+            // the link layer header is only known to be an Ethernet header once the type of the input interface has
+            // been matched, which nft does implicitly in netdev tables, the only ones it is supported in natively
+            if family != NfFamily::NetDev {
+                return None;
+            }
+
+            encode_meta(buf, NFT_META_IIFTYPE);
+            encode_cmp(buf, NFT_CMP_EQ, &ARPHRD_ETHER.to_ne_bytes());
+            encode_payload(buf, NFT_PAYLOAD_LL_HEADER, offset, 6);
+            Some(Load::Lladdr)
+        }
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field }))) => {
+            let (ethertype, offset, len, load) = match (protocol.as_ref(), field.as_ref()) {
+                ("ip", "saddr") => (ETH_P_IP, 12, 4, Load::Addr(NFPROTO_IPV4)),
+                ("ip", "daddr") => (ETH_P_IP, 16, 4, Load::Addr(NFPROTO_IPV4)),
+                ("ip6", "saddr") => (ETH_P_IPV6, 8, 16, Load::Addr(NFPROTO_IPV6)),
+                ("ip6", "daddr") => (ETH_P_IPV6, 24, 16, Load::Addr(NFPROTO_IPV6)),
+                ("arp", "saddr ether") => (ETH_P_ARP, 8, 6, Load::Lladdr),
+                ("arp", "saddr ip") => (ETH_P_ARP, 14, 4, Load::Addr(NFPROTO_IPV4)),
+                ("arp", "daddr ether") => (ETH_P_ARP, 18, 6, Load::Lladdr),
+                ("arp", "daddr ip") => (ETH_P_ARP, 24, 4, Load::Addr(NFPROTO_IPV4)),
+                _ => return None,
+            };
+
+            match (family, ethertype) {
+                (NfFamily::IP, ETH_P_IP) | (NfFamily::IP6, ETH_P_IPV6) => {}
+                // the network header of a packet in an inet table can be of either protocol, which nft guards against
+                // by implicitly matching the protocol before the payload
+                (NfFamily::INet, ETH_P_IP | ETH_P_IPV6) => {
+                    encode_meta(buf, NFT_META_NFPROTO);
+                    encode_cmp(
+                        buf,
+                        NFT_CMP_EQ,
+                        &[match ethertype {
+                            ETH_P_IP => NFPROTO_IPV4,
+                            _ => NFPROTO_IPV6,
+                        }],
+                    );
+                }
+                // the same goes for the network header of a frame in a netdev table, which can be of any protocol
+                (NfFamily::NetDev, _) => {
+                    encode_meta(buf, NFT_META_PROTOCOL);
+                    encode_cmp(buf, NFT_CMP_EQ, &ethertype.to_be_bytes());
+                }
+                _ => return None,
+            }
+
+            encode_payload(buf, NFT_PAYLOAD_NETWORK_HEADER, offset, len);
+            Some(load)
+        }
+        _ => None,
+    }
+}
+
+fn encode_payload(buf: &mut Vec<u8>, base: u32, offset: u32, len: u32) {
+    encode_expr(buf, "payload", |buf| {
+        put_be32(buf, NFTA_PAYLOAD_DREG, NFT_REG_1);
+        put_be32(buf, NFTA_PAYLOAD_BASE, base);
+        put_be32(buf, NFTA_PAYLOAD_OFFSET, offset);
+        put_be32(buf, NFTA_PAYLOAD_LEN, len);
+    });
+}
+
+fn l4proto_from_name(name: &str) -> Option<u8> {
+    match name {
+        "tcp" => Some(IPPROTO_TCP),
+        "udp" => Some(IPPROTO_UDP),
+        "icmpv6" => Some(IPPROTO_ICMPV6),
+        _ => None,
+    }
+}
+
+fn encode_verdict(buf: &mut Vec<u8>, verdict: &Verdict) {
+    encode_expr(buf, "immediate", |buf| {
+        put_be32(buf, NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT);
+        put_verdict(buf, NFTA_IMMEDIATE_DATA, verdict);
+    });
+}
+
+/// Write a verdict as a data attribute of the given type.
+pub fn put_verdict(buf: &mut Vec<u8>, attr_type: u16, verdict: &Verdict) {
+    let (code, chain) = match verdict {
+        Verdict::Accept => (NF_ACCEPT, None),
+        Verdict::Drop => (NF_DROP, None),
+        Verdict::Continue => (NFT_CONTINUE, None),
+        Verdict::Return => (NFT_RETURN, None),
+        Verdict::Jump(JumpTarget { target }) => (NFT_JUMP, Some(target)),
+        Verdict::Goto(JumpTarget { target }) => (NFT_GOTO, Some(target)),
+    };
+
+    let data_offset = begin_nested(buf, attr_type);
+    let verdict_offset = begin_nested(buf, NFTA_DATA_VERDICT);
+    put_be32(buf, NFTA_VERDICT_CODE, code);
+    if let Some(chain) = chain {
+        put_str(buf, NFTA_VERDICT_CHAIN, chain);
+    }
+    end_nested(buf, verdict_offset);
+    end_nested(buf, data_offset);
+}
+
+fn encode_nat(buf: &mut Vec<u8>, family: NfFamily, nat_type: u32, nat: &NAT) -> Option<()> {
+    let NAT {
+        addr: Some(Expression::String(addr)),
+        family: nat_family,
+        port,
+        flags: None,
+    } = nat
+    else {
+        return None;
+    };
+    let port = match port {
+        Some(Expression::Number(port)) => Some(u16::try_from(*port).ok()?),
+        Some(_) => return None,
+        None => None,
+    };
+
+    let addr = addr.parse::<IpAddr>().ok()?;
+    let nfproto = match (family, nat_family, addr) {
+        (NfFamily::IP, None, IpAddr::V4(_)) | (NfFamily::INet, Some(NATFamily::IP), IpAddr::V4(_)) => NFPROTO_IPV4,
+        (NfFamily::IP6, None, IpAddr::V6(_)) | (NfFamily::INet, Some(NATFamily::IP6), IpAddr::V6(_)) => NFPROTO_IPV6,
+        _ => return None,
+    };
+    let addr = match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    };
+
+    encode_expr(buf, "immediate", |buf| {
+        put_be32(buf, NFTA_IMMEDIATE_DREG, NFT_REG_1);
+        let data_offset = begin_nested(buf, NFTA_IMMEDIATE_DATA);
+        put_bytes(buf, NFTA_DATA_VALUE, &addr);
+        end_nested(buf, data_offset);
+    });
+
+    if let Some(port) = port {
+        encode_expr(buf, "immediate", |buf| {
+            put_be32(buf, NFTA_IMMEDIATE_DREG, NFT_REG_2);
+            let data_offset = begin_nested(buf, NFTA_IMMEDIATE_DATA);
+            put_bytes(buf, NFTA_DATA_VALUE, &port.to_be_bytes());
+            end_nested(buf, data_offset);
+        });
+    }
+
+    encode_expr(buf, "nat", |buf| {
+        put_be32(buf, NFTA_NAT_TYPE, nat_type);
+        put_be32(buf, NFTA_NAT_FAMILY, nfproto as u32);
+        put_be32(buf, NFTA_NAT_REG_ADDR_MIN, NFT_REG_1);
+
+        if port.is_some() {
+            put_be32(buf, NFTA_NAT_REG_PROTO_MIN, NFT_REG_2);
+        }
+    });
+    Some(())
+}
+
+/// The value a register was loaded with by an earlier expression of the rule being decoded.
+#[derive(Clone)]
+enum Register {
+    Meta(u32),
+    Ct(u32),
+    LinkLayerHeader {
+        offset: u32,
+        len: u32,
+    },
+    NetworkHeader {
+        offset: u32,
+        len: u32,
+    },
+    TransportHeader {
+        offset: u32,
+        len: u32,
+    },
+    Value(Vec<u8>),
+    /// The value of another register masked by a bitwise expression with a zero XOR value.
+    Masked {
+        register: Box<Register>,
+        mask: Vec<u8>,
+    },
+}
+
+/// Matches that may be the implicit dependencies of a subsequent payload match, which nft omits: an nfproto match for
+/// network header payloads in inet tables, an l4proto match for transport header payloads, and in netdev tables, a
+/// protocol match for network header payloads and an iiftype match for link layer header payloads.
+#[derive(Default)]
+struct PendingDependencies {
+    nfproto: Option<u8>,
+    l4proto: Option<u8>,
+    protocol: Option<u16>,
+    iiftype: Option<u16>,
+}
+
+impl PendingDependencies {
+    /// Turn the pending dependencies into explicit matches, as nothing turned out to depend on them.
+    fn flush(&mut self, statements: &mut Vec<Statement<'static>>) {
+        flush_nfproto(statements, self.nfproto.take());
+        flush_l4proto(statements, self.l4proto.take());
+        flush_protocol(statements, self.protocol.take());
+        flush_iiftype(statements, self.iiftype.take());
+    }
+}
+
+/// Decode the contents of an NFTA_RULE_EXPRESSIONS attribute into statements shaped like those in the JSON output of
+/// nft, or return [None] if any of the expressions isn't supported natively.
+pub fn decode_statements(family: NfFamily, payload: &[u8]) -> Option<Vec<Statement<'static>>> {
+    let mut statements = Vec::new();
+    let mut registers = HashMap::new();
+    let mut pending = PendingDependencies::default();
+    // the lower bound of a range match, which is completed by a comparison of the same register right after it
+    let mut range_start = None;
+
+    for (attr_type, elem) in Attrs::new(payload) {
+        if attr_type != NFTA_LIST_ELEM {
+            return None;
+        }
+
+        let mut name = None;
+        let mut data: &[u8] = &[];
+        for (attr_type, payload) in Attrs::new(elem) {
+            match attr_type {
+                NFTA_EXPR_NAME => name = parse_str(payload),
+                NFTA_EXPR_DATA => data = payload,
+                _ => {}
+            }
+        }
+
+        let name = name?;
+        if range_start.is_some() && name != "cmp" {
+            return None;
+        }
+
+        let attrs = Attrs::new(data).collect::<HashMap<_, _>>();
+        let be32 = |attr_type: u16| attrs.get(&attr_type).and_then(|payload| parse_be32(payload));
+
+        match name.as_str() {
+            "meta" => {
+                registers.insert(be32(NFTA_META_DREG)?, Register::Meta(be32(NFTA_META_KEY)?));
+            }
+            "ct" => {
+                if attrs.keys().any(|attr_type| ![NFTA_CT_DREG, NFTA_CT_KEY].contains(attr_type)) {
+                    return None;
+                }
+
+                registers.insert(be32(NFTA_CT_DREG)?, Register::Ct(be32(NFTA_CT_KEY)?));
+            }
+            "bitwise" => {
+                let mask = decode_data_value(attrs.get(&NFTA_BITWISE_MASK)?)?;
+                let xor = decode_data_value(attrs.get(&NFTA_BITWISE_XOR)?)?;
+
+                // newer kernels report the operation, of which only the boolean one combines a mask and an XOR value
+                if be32(NFTA_BITWISE_OP).is_some_and(|op| op != NFT_BITWISE_BOOL)
+                    || be32(NFTA_BITWISE_LEN)? as usize != mask.len()
+                    || xor.len() != mask.len()
+                    || xor.iter().any(|byte| *byte != 0)
+                {
+                    return None;
+                }
+
+                let register = registers.get(&be32(NFTA_BITWISE_SREG)?)?.clone();
+                registers.insert(
+                    be32(NFTA_BITWISE_DREG)?,
+                    Register::Masked {
+                        register: Box::new(register),
+                        mask,
+                    },
+                );
+            }
+            "payload" => {
+                let offset = be32(NFTA_PAYLOAD_OFFSET)?;
+                let len = be32(NFTA_PAYLOAD_LEN)?;
+                let register = match be32(NFTA_PAYLOAD_BASE)? {
+                    NFT_PAYLOAD_LL_HEADER => Register::LinkLayerHeader { offset, len },
+                    NFT_PAYLOAD_NETWORK_HEADER => Register::NetworkHeader { offset, len },
+                    NFT_PAYLOAD_TRANSPORT_HEADER => Register::TransportHeader { offset, len },
+                    _ => return None,
+                };
+
+                registers.insert(be32(NFTA_PAYLOAD_DREG)?, register);
+            }
+            "cmp" => {
+                let sreg = be32(NFTA_CMP_SREG)?;
+                let value = decode_data_value(attrs.get(&NFTA_CMP_DATA)?)?;
+                let cmp_op = be32(NFTA_CMP_OP)?;
+
+                if cmp_op == NFT_CMP_GTE && range_start.is_none() {
+                    range_start = Some((sreg, value));
+                    continue;
+                }
+
+                let range_start = match (cmp_op, range_start.take()) {
+                    (NFT_CMP_LTE, Some((start_sreg, start))) if start_sreg == sreg => Some(start),
+                    (_, Some(_)) => return None,
+                    (_, None) => None,
+                };
+                let op = match cmp_op {
+                    NFT_CMP_EQ => Operator::EQ,
+                    NFT_CMP_NEQ => Operator::NEQ,
+                    NFT_CMP_LTE if range_start.is_some() => Operator::EQ,
+                    _ => return None,
+                };
+
+                let register = registers.get(&sreg)?;
+
+                if op == Operator::EQ && range_start.is_none() {
+                    match register {
+                        Register::Meta(NFT_META_NFPROTO) if family == NfFamily::INet => {
+                            pending.flush(&mut statements);
+                            pending.nfproto = Some(*value.first()?);
+                            continue;
+                        }
+                        Register::Meta(NFT_META_L4PROTO) => {
+                            pending.flush(&mut statements);
+                            pending.l4proto = Some(*value.first()?);
+                            continue;
+                        }
+                        Register::Meta(NFT_META_PROTOCOL) if family == NfFamily::NetDev => {
+                            pending.flush(&mut statements);
+                            pending.protocol = Some(u16::from_be_bytes(value.as_slice().try_into().ok()?));
+                            continue;
+                        }
+                        Register::Meta(NFT_META_IIFTYPE) if family == NfFamily::NetDev => {
+                            pending.flush(&mut statements);
+                            pending.iiftype = Some(u16::from_ne_bytes(value.as_slice().try_into().ok()?));
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+
+                let (left, load) = decode_load(family, register, &mut statements, &mut pending)?;
+                let (right, op) = match (register, range_start) {
+                    (Register::Masked { register, mask }, None) => match (register.as_ref(), load, op) {
+                        (Register::Ct(NFT_CT_STATE), _, Operator::NEQ) if value == [0; 4] => (
+                            decode_ct_states(u32::from_ne_bytes(mask.as_slice().try_into().ok()?))?,
+                            Operator::IN,
+                        ),
+                        (_, Load::Addr(_), _) => {
+                            let len = decode_prefix_len(mask)?;
+
+                            // a value with bits outside of the mask could never match, which a prefix can't express
+                            if value.iter().zip(mask).any(|(byte, mask)| byte & !mask != 0) {
+                                return None;
+                            }
+
+                            (
+                                Expression::Named(NamedExpression::Prefix(Prefix {
+                                    addr: Box::new(Expression::String(decode_addr(&value)?.to_string().into())),
+                                    len,
+                                })),
+                                op,
+                            )
+                        }
+                        _ => return None,
+                    },
+                    (_, None) => (decode_value(&load, &value)?, op),
+                    (_, Some(start)) if matches!(load, Load::Port) => (
+                        Expression::Range(Box::new(Range {
+                            range: [
+                                Expression::Number(decode_port(&start)?.into()),
+                                Expression::Number(decode_port(&value)?.into()),
+                            ],
+                        })),
+                        op,
+                    ),
+                    _ => return None,
+                };
+
+                pending.flush(&mut statements);
+                statements.push(Statement::Match(Match {
+                    left: Expression::Named(left),
+                    right,
+                    op,
+                }));
+            }
+            "lookup" => {
+                let set = parse_str(attrs.get(&NFTA_LOOKUP_SET)?)?;
+                let (key, _) = decode_load(
+                    family,
+                    registers.get(&be32(NFTA_LOOKUP_SREG)?)?,
+                    &mut statements,
+                    &mut pending,
+                )?;
+
+                pending.flush(&mut statements);
+                statements.push(match (be32(NFTA_LOOKUP_DREG), be32(NFTA_LOOKUP_FLAGS).unwrap_or_default()) {
+                    // a lookup into a verdict map
+                    (Some(NFT_REG_VERDICT), 0) => Statement::VerdictMap(VerdictMap {
+                        key: Expression::Named(key),
+                        data: Expression::String(format!("@{set}").into()),
+                    }),
+                    // a match of the membership in a plain set
+                    (None, flags @ (0 | NFT_LOOKUP_F_INV)) => Statement::Match(Match {
+                        left: Expression::Named(key),
+                        right: Expression::String(format!("@{set}").into()),
+                        op: match flags {
+                            NFT_LOOKUP_F_INV => Operator::NEQ,
+                            _ => Operator::EQ,
+                        },
+                    }),
+                    _ => return None,
+                });
+            }
+            "immediate" => {
+                let dreg = be32(NFTA_IMMEDIATE_DREG)?;
+                let data = attrs.get(&NFTA_IMMEDIATE_DATA)?;
+
+                if dreg != NFT_REG_VERDICT {
+                    registers.insert(dreg, Register::Value(decode_data_value(data)?));
+                    continue;
+                }
+
+                pending.flush(&mut statements);
+                statements.push(decode_verdict(data)?);
+            }
+            "counter" => {
+                let be64 = |attr_type: u16| attrs.get(&attr_type).and_then(|payload| parse_be64(payload));
+
+                pending.flush(&mut statements);
+                statements.push(Statement::Counter(Counter::Anonymous(Some(AnonymousCounter {
+                    packets: Some(be64(NFTA_COUNTER_PACKETS)? as usize),
+                    bytes: Some(be64(NFTA_COUNTER_BYTES)? as usize),
+                }))));
+            }
+            "masq" => {
+                if !attrs.is_empty() {
+                    return None;
+                }
+
+                pending.flush(&mut statements);
+                statements.push(Statement::Masquerade(None));
+            }
+            "nat" => {
+                let addr_reg = be32(NFTA_NAT_REG_ADDR_MIN)?;
+                let proto_reg = be32(NFTA_NAT_REG_PROTO_MIN);
+
+                // the kernel reports a single address or port as a range ending at the same register, marked with
+                // MAP_IPS or PROTO_SPECIFIED respectively
+                if attrs.keys().any(|attr_type| {
+                    ![
+                        NFTA_NAT_TYPE,
+                        NFTA_NAT_FAMILY,
+                        NFTA_NAT_REG_ADDR_MIN,
+                        NFTA_NAT_REG_ADDR_MAX,
+                        NFTA_NAT_REG_PROTO_MIN,
+                        NFTA_NAT_REG_PROTO_MAX,
+                        NFTA_NAT_FLAGS,
+                    ]
+                    .contains(attr_type)
+                }) || be32(NFTA_NAT_REG_ADDR_MAX).is_some_and(|reg| reg != addr_reg)
+                    || be32(NFTA_NAT_REG_PROTO_MAX).is_some_and(|reg| Some(reg) != proto_reg)
+                    || be32(NFTA_NAT_FLAGS)
+                        .is_some_and(|flags| flags & !(NF_NAT_RANGE_MAP_IPS | NF_NAT_RANGE_PROTO_SPECIFIED) != 0)
+                {
+                    return None;
+                }
+
+                let Register::Value(addr) = registers.get(&addr_reg)? else {
+                    return None;
+                };
+                let port = match proto_reg {
+                    Some(proto_reg) => match registers.get(&proto_reg)? {
+                        Register::Value(port) => Some(Expression::Number(decode_port(port)?.into())),
+                        _ => return None,
+                    },
+                    None => None,
+                };
+                let nat_family = match be32(NFTA_NAT_FAMILY)? as u8 {
+                    NFPROTO_IPV4 => NATFamily::IP,
+                    NFPROTO_IPV6 => NATFamily::IP6,
+                    _ => return None,
+                };
+                let nat = NAT {
+                    addr: Some(Expression::String(decode_addr(addr)?.to_string().into())),
+                    family: match family {
+                        NfFamily::INet => Some(nat_family),
+                        _ => None,
+                    },
+                    port,
+                    flags: None,
+                };
+
+                pending.flush(&mut statements);
+                statements.push(match be32(NFTA_NAT_TYPE)? {
+                    NFT_NAT_SNAT => Statement::SNAT(Some(nat)),
+                    NFT_NAT_DNAT => Statement::DNAT(Some(nat)),
+                    _ => return None,
+                });
+            }
+            _ => return None,
+        }
+    }
+
+    if range_start.is_some() {
+        return None;
+    }
+
+    pending.flush(&mut statements);
+    Some(statements)
+}
+
+/// Decode the value a register was loaded with into the expression that loads it alongside what it loads, consuming a
+/// pending dependency of a payload load.
+fn decode_load(
+    family: NfFamily,
+    register: &Register,
+    statements: &mut Vec<Statement<'static>>,
+    pending: &mut PendingDependencies,
+) -> Option<(NamedExpression<'static>, Load)> {
+    let (protocol, field, load) = match register {
+        Register::Meta(meta_key @ (NFT_META_IIFNAME | NFT_META_OIFNAME | NFT_META_PROTOCOL)) => {
+            let (key, load) = match *meta_key {
+                NFT_META_IIFNAME => (MetaKey::Iifname, Load::Ifname),
+                NFT_META_OIFNAME => (MetaKey::Oifname, Load::Ifname),
+                _ => (MetaKey::Protocol, Load::Ethertype),
+            };
+
+            return Some((NamedExpression::Meta(Meta { key }), load));
+        }
+        Register::Ct(NFT_CT_STATE) => {
+            return Some((
+                NamedExpression::CT(CT {
+                    key: "state".into(),
+                    family: None,
+                    dir: None,
+                }),
+                Load::CtState,
+            ))
+        }
+        Register::Masked { register, .. } => return decode_load(family, register, statements, pending),
+        Register::LinkLayerHeader { offset, len } => {
+            let field = match (offset, len) {
+                (0, 6) => "daddr",
+                (6, 6) => "saddr",
+                _ => return None,
+            };
+
+            // like the encoder, a link layer header is only decoded behind the match of the type of the input interface
+            if family != NfFamily::NetDev || pending.iiftype.take() != Some(ARPHRD_ETHER) {
+                return None;
+            }
+
+            flush_nfproto(statements, pending.nfproto.take());
+            flush_l4proto(statements, pending.l4proto.take());
+            flush_protocol(statements, pending.protocol.take());
+            ("ether", field, Load::Lladdr)
+        }
+        Register::NetworkHeader { offset, len } => {
+            let (ethertype, protocol, field, load) = match (pending.protocol == Some(ETH_P_ARP), offset, len) {
+                (true, 8, 6) => (ETH_P_ARP, "arp", "saddr ether", Load::Lladdr),
+                (true, 14, 4) => (ETH_P_ARP, "arp", "saddr ip", Load::Addr(NFPROTO_IPV4)),
+                (true, 18, 6) => (ETH_P_ARP, "arp", "daddr ether", Load::Lladdr),
+                (true, 24, 4) => (ETH_P_ARP, "arp", "daddr ip", Load::Addr(NFPROTO_IPV4)),
+                (false, 12, 4) => (ETH_P_IP, "ip", "saddr", Load::Addr(NFPROTO_IPV4)),
+                (false, 16, 4) => (ETH_P_IP, "ip", "daddr", Load::Addr(NFPROTO_IPV4)),
+                (false, 8, 16) => (ETH_P_IPV6, "ip6", "saddr", Load::Addr(NFPROTO_IPV6)),
+                (false, 24, 16) => (ETH_P_IPV6, "ip6", "daddr", Load::Addr(NFPROTO_IPV6)),
+                _ => return None,
+            };
+
+            // like the encoder, a network header in a netdev table is only decoded behind the match of its protocol
+            if family == NfFamily::NetDev && pending.protocol.take() != Some(ethertype) {
+                return None;
+            }
+
+            match (pending.nfproto.take(), &load) {
+                (Some(pending_nfproto), Load::Addr(nfproto)) if ethertype != ETH_P_ARP && pending_nfproto == *nfproto => {}
+                (pending_nfproto, _) => flush_nfproto(statements, pending_nfproto),
+            }
+            flush_l4proto(statements, pending.l4proto.take());
+            flush_iiftype(statements, pending.iiftype.take());
+            (protocol, field, load)
+        }
+        Register::TransportHeader { offset, len } => {
+            // the protocol of a transport header is only known from the l4proto match in front of it
+            let (protocol, field, load) = match (pending.l4proto.take()?, offset, len) {
+                (IPPROTO_TCP, 0, 2) => ("tcp", "sport", Load::Port),
+                (IPPROTO_TCP, 2, 2) => ("tcp", "dport", Load::Port),
+                (IPPROTO_UDP, 0, 2) => ("udp", "sport", Load::Port),
+                (IPPROTO_UDP, 2, 2) => ("udp", "dport", Load::Port),
+                (IPPROTO_ICMPV6, 0, 1) => ("icmpv6", "type", Load::Icmpv6Type),
+                (IPPROTO_ICMPV6, 8, 16) => ("icmpv6", "taddr", Load::Addr(NFPROTO_IPV6)),
+                _ => return None,
+            };
+            flush_nfproto(statements, pending.nfproto.take());
+            flush_protocol(statements, pending.protocol.take());
+            flush_iiftype(statements, pending.iiftype.take());
+            (protocol, field, load)
+        }
+        _ => return None,
+    };
+
+    Some((
+        NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: protocol.into(),
+            field: field.into(),
+        })),
+        load,
+    ))
+}
+
+/// Decode the data that a register is compared to into the expression it is matched against, shaped like in the JSON
+/// output of nft.
+fn decode_value(load: &Load, value: &[u8]) -> Option<Expression<'static>> {
+    Some(match load {
+        Load::Ifname => {
+            // names shorter than IFNAMSIZ are wildcard matches, which aren't supported
+            if value.len() != IFNAMSIZ || !value.contains(&0) {
+                return None;
+            }
+
+            Expression::String(parse_str(value)?.into())
+        }
+        Load::Addr(_) => Expression::String(decode_addr(value)?.to_string().into()),
+        Load::Port => Expression::Number(decode_port(value)?.into()),
+        Load::Lladdr => Expression::String(FirecrackerMacAddr(value.try_into().ok()?).to_string().into()),
+        Load::Ethertype => {
+            let ethertype = u16::from_be_bytes(value.try_into().ok()?);
+
+            match ETHERTYPES.iter().find(|(_, value)| *value == ethertype) {
+                Some((name, _)) => Expression::String((*name).into()),
+                None => Expression::Number(ethertype.into()),
+            }
+        }
+        Load::Icmpv6Type => {
+            let [icmpv6_type] = value else {
+                return None;
+            };
+
+            match ICMPV6_TYPES.iter().find(|(_, value)| value == icmpv6_type) {
+                Some((name, _)) => Expression::String((*name).into()),
+                None => Expression::Number((*icmpv6_type).into()),
+            }
+        }
+        Load::CtState => return None,
+    })
+}
+
+fn flush_nfproto(statements: &mut Vec<Statement<'static>>, nfproto: Option<u8>) {
+    let Some(nfproto) = nfproto else {
+        return;
+    };
+
+    statements.push(Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Nfproto })),
+        right: match nfproto {
+            NFPROTO_IPV4 => Expression::String("ipv4".into()),
+            NFPROTO_IPV6 => Expression::String("ipv6".into()),
+            nfproto => Expression::Number(nfproto as u32),
+        },
+        op: Operator::EQ,
+    }));
+}
+
+fn flush_l4proto(statements: &mut Vec<Statement<'static>>, l4proto: Option<u8>) {
+    let Some(l4proto) = l4proto else {
+        return;
+    };
+
+    statements.push(Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::L4proto })),
+        right: match l4proto {
+            IPPROTO_TCP => Expression::String("tcp".into()),
+            IPPROTO_UDP => Expression::String("udp".into()),
+            l4proto => Expression::Number(l4proto as u32),
+        },
+        op: Operator::EQ,
+    }));
+}
+
+fn flush_protocol(statements: &mut Vec<Statement<'static>>, protocol: Option<u16>) {
+    let Some(protocol) = protocol else {
+        return;
+    };
+
+    statements.push(Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Protocol })),
+        right: match ETHERTYPES.iter().find(|(_, ethertype)| *ethertype == protocol) {
+            Some((name, _)) => Expression::String((*name).into()),
+            None => Expression::Number(protocol as u32),
+        },
+        op: Operator::EQ,
+    }));
+}
+
+fn flush_iiftype(statements: &mut Vec<Statement<'static>>, iiftype: Option<u16>) {
+    let Some(iiftype) = iiftype else {
+        return;
+    };
+
+    statements.push(Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iiftype })),
+        right: match iiftype {
+            ARPHRD_ETHER => Expression::String("ether".into()),
+            iiftype => Expression::Number(iiftype as u32),
+        },
+        op: Operator::EQ,
+    }));
+}
+
+/// Decode the bits of "ct state" into the names of the conntrack states, shaped like in the JSON output of nft: a
+/// single name or a list of them.
+fn decode_ct_states(bits: u32) -> Option<Expression<'static>> {
+    let mut names = CT_STATES
+        .iter()
+        .filter(|(_, bit)| bits & bit != 0)
+        .map(|(name, _)| Expression::String((*name).into()))
+        .collect::<Vec<_>>();
+
+    if bits & !CT_STATES.iter().fold(0, |all, (_, bit)| all | bit) != 0 {
+        return None;
+    }
+
+    match names.len() {
+        0 => None,
+        1 => names.pop(),
+        _ => Some(Expression::List(names)),
+    }
+}
+
+/// Decode the length of the prefix that a mask consists of, or return [None] if it isn't one.
+fn decode_prefix_len(mask: &[u8]) -> Option<u32> {
+    let len = mask
+        .iter()
+        .map(|byte| byte.leading_ones())
+        .take_while(|ones| *ones == 8)
+        .count() as u32
+        * 8;
+    let len = len + mask.get(len as usize / 8).map_or(0, |byte| byte.leading_ones());
+
+    (prefix_mask(mask.len(), len)?.as_slice() == mask).then_some(len)
+}
+
+fn decode_data_value(payload: &[u8]) -> Option<Vec<u8>> {
+    Attrs::new(payload)
+        .find(|(attr_type, _)| *attr_type == NFTA_DATA_VALUE)
+        .map(|(_, value)| value.to_vec())
+}
+
+fn decode_verdict(payload: &[u8]) -> Option<Statement<'static>> {
+    Some(match parse_verdict(payload)? {
+        Verdict::Accept => Statement::Accept(None),
+        Verdict::Drop => Statement::Drop(None),
+        Verdict::Continue => Statement::Continue(None),
+        Verdict::Return => Statement::Return(None),
+        Verdict::Jump(target) => Statement::Jump(target),
+        Verdict::Goto(target) => Statement::Goto(target),
+    })
+}
+
+/// Parse the contents of a data attribute holding a verdict.
+pub fn parse_verdict(payload: &[u8]) -> Option<Verdict<'static>> {
+    let (_, verdict) = Attrs::new(payload).find(|(attr_type, _)| *attr_type == NFTA_DATA_VERDICT)?;
+    let mut code = None;
+    let mut chain = None;
+
+    for (attr_type, payload) in Attrs::new(verdict) {
+        match attr_type {
+            NFTA_VERDICT_CODE => code = parse_be32(payload),
+            NFTA_VERDICT_CHAIN => chain = parse_str(payload),
+            _ => {}
+        }
+    }
+
+    Some(match code? {
+        NF_ACCEPT => Verdict::Accept,
+        NF_DROP => Verdict::Drop,
+        NFT_CONTINUE => Verdict::Continue,
+        NFT_RETURN => Verdict::Return,
+        NFT_JUMP => Verdict::Jump(JumpTarget { target: chain?.into() }),
+        NFT_GOTO => Verdict::Goto(JumpTarget { target: chain?.into() }),
+        _ => return None,
+    })
+}
+
+fn decode_port(value: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(value.try_into().ok()?))
+}
+
+pub fn decode_addr(value: &[u8]) -> Option<IpAddr> {
+    match value.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(value).ok()?))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(value).ok()?))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nftables::{
+        expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Prefix, Range, CT},
+        stmt::{AnonymousCounter, Counter, JumpTarget, Match, NATFamily, Operator, Statement, VerdictMap, NAT},
+        types::NfFamily,
+    };
+
+    use super::{decode_statements, encode_statements};
+
+    fn meta(key: MetaKey) -> Expression<'static> {
+        Expression::Named(NamedExpression::Meta(Meta { key }))
+    }
+
+    fn payload(protocol: &'static str, field: &'static str) -> Expression<'static> {
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: protocol.into(),
+            field: field.into(),
+        })))
+    }
+
+    fn string(value: &'static str) -> Expression<'static> {
+        Expression::String(value.into())
+    }
+
+    fn matching(left: Expression<'static>, op: Operator, right: Expression<'static>) -> Statement<'static> {
+        Statement::Match(Match { left, right, op })
+    }
+
+    fn nat(addr: &'static str, family: Option<NATFamily>, port: Option<u32>) -> NAT<'static> {
+        NAT {
+            addr: Some(string(addr)),
+            family,
+            port: port.map(Expression::Number),
+            flags: None,
+        }
+    }
+
+    fn encode(family: NfFamily, statements: &[Statement<'static>]) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        encode_statements(&mut buf, family, statements)?;
+        Some(buf)
+    }
+
+    #[track_caller]
+    fn assert_round_trip(family: NfFamily, statements: Vec<Statement<'static>>) {
+        let buf = encode(family, &statements).expect("statements should be supported natively");
+        assert_eq!(decode_statements(family, &buf), Some(statements));
+    }
+
+    #[test]
+    fn interface_name_match_round_trips() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(meta(MetaKey::Iifname), Operator::EQ, string("tap0")),
+                matching(meta(MetaKey::Oifname), Operator::NEQ, string("eth0")),
+                Statement::Accept(None),
+            ],
+        );
+    }
+
+    #[test]
+    fn interface_name_of_ifnamsiz_is_unsupported() {
+        let statements = [matching(meta(MetaKey::Iifname), Operator::EQ, string("tap0123456789abc"))];
+        assert_eq!(encode(NfFamily::IP, &statements), None);
+    }
+
+    #[test]
+    fn address_match_round_trips() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(payload("ip", "saddr"), Operator::EQ, string("172.16.0.2")),
+                matching(payload("ip", "daddr"), Operator::NEQ, string("10.0.0.1")),
+            ],
+        );
+        assert_round_trip(
+            NfFamily::IP6,
+            vec![matching(payload("ip6", "saddr"), Operator::EQ, string("fd00::2"))],
+        );
+    }
+
+    #[test]
+    fn address_of_other_version_is_unsupported() {
+        let statements = [matching(payload("ip", "saddr"), Operator::EQ, string("fd00::2"))];
+        assert_eq!(encode(NfFamily::IP, &statements), None);
+    }
+
+    #[test]
+    fn prefix_match_round_trips() {
+        let prefix = |addr, len| {
+            Expression::Named(NamedExpression::Prefix(Prefix {
+                addr: Box::new(string(addr)),
+                len,
+            }))
+        };
+
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(payload("ip", "daddr"), Operator::EQ, prefix("10.0.0.0", 8)),
+                matching(payload("ip", "saddr"), Operator::NEQ, prefix("172.16.0.0", 13)),
+            ],
+        );
+        assert_round_trip(
+            NfFamily::IP6,
+            vec![matching(payload("ip6", "daddr"), Operator::EQ, prefix("fe80::", 10))],
+        );
+    }
+
+    #[test]
+    fn inet_address_match_round_trips_through_implicit_nfproto() {
+        assert_round_trip(
+            NfFamily::INet,
+            vec![
+                matching(payload("ip", "saddr"), Operator::EQ, string("172.16.0.2")),
+                matching(payload("ip6", "daddr"), Operator::EQ, string("fd00::2")),
+            ],
+        );
+    }
+
+    #[test]
+    fn port_match_round_trips_through_implicit_l4proto() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(payload("tcp", "dport"), Operator::EQ, Expression::Number(8080)),
+                matching(payload("udp", "sport"), Operator::NEQ, Expression::Number(53)),
+            ],
+        );
+    }
+
+    #[test]
+    fn port_range_match_round_trips() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![matching(
+                payload("udp", "dport"),
+                Operator::EQ,
+                Expression::Range(Box::new(Range {
+                    range: [Expression::Number(9000), Expression::Number(9010)],
+                })),
+            )],
+        );
+    }
+
+    #[test]
+    fn ct_state_match_round_trips() {
+        let ct_state = Expression::Named(NamedExpression::CT(CT {
+            key: "state".into(),
+            family: None,
+            dir: None,
+        }));
+
+        assert_round_trip(
+            NfFamily::IP,
+            vec![matching(ct_state.clone(), Operator::IN, string("established"))],
+        );
+        assert_round_trip(
+            NfFamily::IP,
+            vec![matching(
+                ct_state,
+                Operator::IN,
+                Expression::List(vec![string("established"), string("related")]),
+            )],
+        );
+    }
+
+    #[test]
+    fn set_lookup_round_trips() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(payload("ip", "daddr"), Operator::EQ, string("@allowed")),
+                matching(payload("tcp", "dport"), Operator::NEQ, string("@denied_ports")),
+                Statement::Drop(None),
+            ],
+        );
+    }
+
+    #[test]
+    fn verdict_map_round_trips() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![Statement::VerdictMap(VerdictMap {
+                key: meta(MetaKey::Iifname),
+                data: string("@filter_iifname"),
+            })],
+        );
+    }
+
+    #[test]
+    fn verdicts_round_trip() {
+        for statement in [
+            Statement::Accept(None),
+            Statement::Drop(None),
+            Statement::Continue(None),
+            Statement::Return(None),
+            Statement::Jump(JumpTarget {
+                target: "filter-net".into(),
+            }),
+            Statement::Goto(JumpTarget {
+                target: "filter-net".into(),
+            }),
+        ] {
+            assert_round_trip(NfFamily::IP, vec![statement]);
+        }
+    }
+
+    #[test]
+    fn counter_round_trips() {
+        assert_round_trip(
+            NfFamily::IP,
+            vec![Statement::Counter(Counter::Anonymous(Some(AnonymousCounter {
+                packets: Some(3),
+                bytes: Some(180),
+            })))],
+        );
+
+        // a counter without values starts counting from zero
+        let buf = encode(NfFamily::IP, &[Statement::Counter(Counter::Anonymous(None))]).unwrap();
+        assert_eq!(
+            decode_statements(NfFamily::IP, &buf),
+            Some(vec![Statement::Counter(Counter::Anonymous(Some(AnonymousCounter {
+                packets: Some(0),
+                bytes: Some(0),
+            })))])
+        );
+    }
+
+    #[test]
+    fn nat_round_trips() {
+        assert_round_trip(NfFamily::IP, vec![Statement::Masquerade(None)]);
+        assert_round_trip(NfFamily::IP, vec![Statement::SNAT(Some(nat("10.0.0.2", None, None)))]);
+        assert_round_trip(
+            NfFamily::IP,
+            vec![
+                matching(payload("tcp", "dport"), Operator::EQ, Expression::Number(8080)),
+                Statement::DNAT(Some(nat("172.16.0.2", None, Some(80)))),
+            ],
+        );
+        assert_round_trip(
+            NfFamily::INet,
+            vec![Statement::DNAT(Some(nat("fd00::2", Some(NATFamily::IP6), None)))],
+        );
+    }
+
+    #[test]
+    fn nat_of_other_version_is_unsupported() {
+        let statements = [Statement::SNAT(Some(nat("fd00::2", None, None)))];
+        assert_eq!(encode(NfFamily::IP, &statements), None);
+    }
+
+    #[test]
+    fn netdev_match_round_trips_through_implicit_dependencies() {
+        assert_round_trip(
+            NfFamily::NetDev,
+            vec![
+                matching(payload("ether", "saddr"), Operator::NEQ, string("06:00:ac:10:00:02")),
+                Statement::Drop(None),
+            ],
+        );
+        assert_round_trip(
+            NfFamily::NetDev,
+            vec![
+                matching(meta(MetaKey::Protocol), Operator::EQ, string("arp")),
+                Statement::Accept(None),
+            ],
+        );
+        assert_round_trip(
+            NfFamily::NetDev,
+            vec![
+                matching(payload("ip", "saddr"), Operator::NEQ, string("172.16.0.2")),
+                matching(payload("arp", "saddr ip"), Operator::NEQ, string("172.16.0.2")),
+                matching(payload("arp", "saddr ether"), Operator::NEQ, string("06:00:ac:10:00:02")),
+                Statement::Drop(None),
+            ],
+        );
+    }
+
+    #[test]
+    fn icmpv6_match_round_trips() {
+        assert_round_trip(
+            NfFamily::NetDev,
+            vec![
+                matching(payload("icmpv6", "type"), Operator::EQ, string("nd-neighbor-advert")),
+                matching(payload("icmpv6", "taddr"), Operator::NEQ, string("fe80::2")),
+                Statement::Drop(None),
+            ],
+        );
+    }
+
+    #[test]
+    fn ether_match_outside_netdev_is_unsupported() {
+        let statements = [matching(payload("ether", "saddr"), Operator::EQ, string("06:00:ac:10:00:02"))];
+        assert_eq!(encode(NfFamily::IP, &statements), None);
+    }
+
+    #[test]
+    fn unsupported_operator_is_rejected() {
+        let statements = [matching(payload("tcp", "dport"), Operator::LT, Expression::Number(1024))];
+        assert_eq!(encode(NfFamily::IP, &statements), None);
+    }
+
+    #[test]
+    fn unknown_expression_is_rejected() {
+        let mut buf = Vec::new();
+        super::encode_expr(&mut buf, "quota", |_| {});
+        assert_eq!(decode_statements(NfFamily::IP, &buf), None);
+    }
+
+    #[test]
+    fn truncated_expressions_are_rejected() {
+        let buf = encode(
+            NfFamily::IP,
+            &[
+                matching(payload("ip", "saddr"), Operator::EQ, string("172.16.0.2")),
+                Statement::Accept(None),
+            ],
+        )
+        .unwrap();
+
+        for len in 0..buf.len() {
+            // a cut at the boundary between two expressions leaves a shorter, valid rule
+            if let Some(statements) = decode_statements(NfFamily::IP, &buf[..len]) {
+                assert!(statements.len() < 2);
+            }
+        }
+    }
+}
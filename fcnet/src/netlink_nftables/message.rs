@@ -0,0 +1,203 @@
+use nftables::types::NfFamily;
+
+use super::attr::align;
+
+pub const NLMSG_HEADER_LEN: usize = 16;
+pub const NFGENMSG_LEN: usize = 4;
+
+pub const NLMSG_ERROR: u16 = 2;
+pub const NLMSG_DONE: u16 = 3;
+
+pub const NLM_F_REQUEST: u16 = 0x1;
+pub const NLM_F_ACK: u16 = 0x4;
+pub const NLM_F_DUMP: u16 = 0x300;
+pub const NLM_F_REPLACE: u16 = 0x100;
+pub const NLM_F_EXCL: u16 = 0x200;
+pub const NLM_F_CREATE: u16 = 0x400;
+pub const NLM_F_APPEND: u16 = 0x800;
+
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+
+pub const NFT_MSG_NEWTABLE: u16 = 0;
+pub const NFT_MSG_GETTABLE: u16 = 1;
+pub const NFT_MSG_DELTABLE: u16 = 2;
+pub const NFT_MSG_NEWCHAIN: u16 = 3;
+pub const NFT_MSG_GETCHAIN: u16 = 4;
+pub const NFT_MSG_DELCHAIN: u16 = 5;
+pub const NFT_MSG_NEWRULE: u16 = 6;
+pub const NFT_MSG_GETRULE: u16 = 7;
+pub const NFT_MSG_DELRULE: u16 = 8;
+pub const NFT_MSG_NEWSET: u16 = 9;
+pub const NFT_MSG_GETSET: u16 = 10;
+pub const NFT_MSG_DELSET: u16 = 11;
+pub const NFT_MSG_NEWSETELEM: u16 = 12;
+pub const NFT_MSG_GETSETELEM: u16 = 13;
+pub const NFT_MSG_DELSETELEM: u16 = 14;
+pub const NFT_MSG_NEWGEN: u16 = 15;
+pub const NFT_MSG_GETGEN: u16 = 16;
+pub const NFT_MSG_GETRULE_RESET: u16 = 25;
+
+pub const NFPROTO_UNSPEC: u8 = 0;
+pub const NFPROTO_INET: u8 = 1;
+pub const NFPROTO_IPV4: u8 = 2;
+pub const NFPROTO_ARP: u8 = 3;
+pub const NFPROTO_NETDEV: u8 = 5;
+pub const NFPROTO_BRIDGE: u8 = 7;
+pub const NFPROTO_IPV6: u8 = 10;
+
+/// A buffer of consecutive netlink messages addressed to the nf_tables subsystem, each carrying a nfgenmsg header.
+pub struct MessageWriter {
+    pub buf: Vec<u8>,
+    next_seq: u32,
+    acked_seqs: Vec<u32>,
+}
+
+impl MessageWriter {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            next_seq: 1,
+            acked_seqs: Vec::new(),
+        }
+    }
+
+    /// Start a message of the given nf_tables type, returning its offset that must be passed to [MessageWriter::end]
+    /// once all of its attributes have been written into the buffer.
+    pub fn begin(&mut self, nft_msg_type: u16, flags: u16, family: u8) -> usize {
+        self.begin_raw((NFNL_SUBSYS_NFTABLES << 8) | nft_msg_type, flags, family, 0)
+    }
+
+    pub fn end(&mut self, offset: usize) {
+        let len = (self.buf.len() - offset) as u32;
+        self.buf[offset..offset + 4].copy_from_slice(&len.to_ne_bytes());
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    pub fn begin_batch(&mut self) {
+        let offset = self.begin_raw(NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, NFPROTO_UNSPEC, NFNL_SUBSYS_NFTABLES);
+        self.end(offset);
+    }
+
+    pub fn end_batch(&mut self) {
+        let offset = self.begin_raw(NFNL_MSG_BATCH_END, NLM_F_REQUEST, NFPROTO_UNSPEC, NFNL_SUBSYS_NFTABLES);
+        self.end(offset);
+    }
+
+    /// The sequence numbers of all written messages that requested an acknowledgement.
+    pub fn acked_seqs(&self) -> &[u32] {
+        &self.acked_seqs
+    }
+
+    fn begin_raw(&mut self, msg_type: u16, flags: u16, family: u8, res_id: u16) -> usize {
+        let offset = self.buf.len();
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if flags & NLM_F_ACK != 0 {
+            self.acked_seqs.push(seq);
+        }
+
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self.buf.extend_from_slice(&msg_type.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&seq.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        // nfgenmsg: family, version and big-endian resource id
+        self.buf.push(family);
+        self.buf.push(0);
+        self.buf.extend_from_slice(&res_id.to_be_bytes());
+        offset
+    }
+}
+
+/// A netlink message received from the kernel.
+pub struct Message<'a> {
+    pub msg_type: u16,
+    pub seq: u32,
+    pub payload: &'a [u8],
+}
+
+impl Message<'_> {
+    /// The nf_tables message type, if this message belongs to the nf_tables subsystem.
+    pub fn nft_msg_type(&self) -> Option<u16> {
+        match self.msg_type >> 8 == NFNL_SUBSYS_NFTABLES {
+            true => Some(self.msg_type & 0xff),
+            false => None,
+        }
+    }
+
+    /// The negated errno carried by an [NLMSG_ERROR] message, which is 0 for acknowledgements.
+    pub fn error_code(&self) -> Option<i32> {
+        match self.msg_type == NLMSG_ERROR {
+            true => Some(i32::from_ne_bytes(self.payload.get(..4)?.try_into().ok()?)),
+            false => None,
+        }
+    }
+
+    /// The family and attributes of an nf_tables message.
+    pub fn nft_body(&self) -> Option<(u8, &[u8])> {
+        match self.payload.len() >= NFGENMSG_LEN {
+            true => Some((self.payload[0], &self.payload[NFGENMSG_LEN..])),
+            false => None,
+        }
+    }
+}
+
+/// An iterator over the netlink messages in a received datagram.
+pub struct Messages<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Messages<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Message<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.len() < NLMSG_HEADER_LEN {
+            return None;
+        }
+
+        let len = u32::from_ne_bytes(self.buf[0..4].try_into().ok()?) as usize;
+        if len < NLMSG_HEADER_LEN || len > self.buf.len() {
+            return None;
+        }
+
+        let message = Message {
+            msg_type: u16::from_ne_bytes(self.buf[4..6].try_into().ok()?),
+            seq: u32::from_ne_bytes(self.buf[8..12].try_into().ok()?),
+            payload: &self.buf[NLMSG_HEADER_LEN..len],
+        };
+        self.buf = &self.buf[align(len).min(self.buf.len())..];
+        Some(message)
+    }
+}
+
+pub fn family_to_nfproto(family: NfFamily) -> u8 {
+    match family {
+        NfFamily::IP => NFPROTO_IPV4,
+        NfFamily::IP6 => NFPROTO_IPV6,
+        NfFamily::INet => NFPROTO_INET,
+        NfFamily::ARP => NFPROTO_ARP,
+        NfFamily::Bridge => NFPROTO_BRIDGE,
+        NfFamily::NetDev => NFPROTO_NETDEV,
+    }
+}
+
+pub fn nfproto_to_family(nfproto: u8) -> Option<NfFamily> {
+    match nfproto {
+        NFPROTO_IPV4 => Some(NfFamily::IP),
+        NFPROTO_IPV6 => Some(NfFamily::IP6),
+        NFPROTO_INET => Some(NfFamily::INet),
+        NFPROTO_ARP => Some(NfFamily::ARP),
+        NFPROTO_BRIDGE => Some(NfFamily::Bridge),
+        NFPROTO_NETDEV => Some(NfFamily::NetDev),
+        _ => None,
+    }
+}
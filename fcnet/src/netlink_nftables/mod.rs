@@ -0,0 +1,430 @@
+//! A native nf_tables driver that communicates with the kernel over a netfilter netlink socket instead of spawning
+//! the "nft" binary. Only the subset of nftables that fcnet itself creates is encoded and decoded natively, with any
+//! other ruleset being routed to a fallback [Helper].
+
+use std::{collections::HashSet, ffi::OsStr, io, marker::PhantomData};
+
+use nftables::{
+    helper::NftablesError,
+    schema::{NfListObject, NfObject, Nftables, Rule},
+};
+use nftables_async::helper::Helper;
+use rtnetlink::sys::AsyncSocket;
+
+use crate::{
+    util::{open_netfilter_socket, parse_nf_rule_tag, recv_netlink, send_netlink},
+    vmap::{parse_ruleset, serialize_ruleset, split_ruleset, NfRulesetObject},
+};
+
+mod attr;
+mod expr;
+mod message;
+mod object;
+mod set;
+
+use attr::{parse_be32, Attrs};
+use message::{
+    MessageWriter, Messages, NFPROTO_UNSPEC, NFT_MSG_GETCHAIN, NFT_MSG_GETGEN, NFT_MSG_GETRULE, NFT_MSG_GETSET,
+    NFT_MSG_GETSETELEM, NFT_MSG_GETTABLE, NFT_MSG_NEWGEN, NFT_MSG_NEWRULE, NLMSG_DONE, NLM_F_DUMP, NLM_F_REQUEST,
+};
+use object::{decode_chain, decode_rule, decode_table, encode_batch, encode_rule_reset, rule_comment, table_name};
+use set::{decode_elements, decode_set, decode_set_keys, decode_verdict_map, put_set_elem_list_header, set_elements};
+
+const NFTA_GEN_ID: u16 = 1;
+
+/// An [nftables_async] [Helper] that applies and lists rulesets by talking nf_tables over netlink directly, using a
+/// socket of type `S` in the network namespace of the calling thread.
+///
+/// Rulesets are applied as a single in-process netlink batch, so they are committed atomically just like with "nft".
+/// Listing returns every table with its chains, rules, verdict maps and plain sets, except for tables that contain
+/// constructs that can't be decoded natively but no rules tagged by fcnet, since those are of no interest to fcnet.
+/// Verdict maps can't be represented by the nftables crate, so they are only part of raw rulesets, which are also the
+/// only ones that can add them.
+///
+/// Whenever a ruleset to apply contains commands or statements that aren't supported natively, or a table with rules
+/// tagged by fcnet contains rules that can't be decoded natively (for example, ones added manually by an
+/// administrator), the call is transparently delegated to the fallback [Helper] `F`, which receives the program and
+/// arguments that are otherwise ignored.
+pub struct NetlinkNftablesDriver<S, F> {
+    _marker: PhantomData<fn() -> (S, F)>,
+}
+
+impl<S: AsyncSocket + Send, F: Helper> Helper for NetlinkNftablesDriver<S, F> {
+    async fn apply_ruleset_with_args<
+        'a,
+        P: AsRef<OsStr> + Sync + ?Sized,
+        A: AsRef<OsStr> + Sync + ?Sized + 'a,
+        I: IntoIterator<Item = &'a A> + Send,
+    >(
+        nftables: &Nftables<'_>,
+        program: Option<&P>,
+        args: I,
+    ) -> Result<(), NftablesError> {
+        let objects = nftables
+            .objects
+            .iter()
+            .cloned()
+            .map(NfRulesetObject::Object)
+            .collect::<Vec<_>>();
+        let mut writer = MessageWriter::new();
+
+        match encode_batch(&objects, &mut writer) {
+            Some(()) => send_batch::<S>(writer).await.map_err(netlink_error),
+            None => F::apply_ruleset_with_args(nftables, program, args).await,
+        }
+    }
+
+    async fn apply_ruleset_raw<
+        'a,
+        P: AsRef<OsStr> + Sync + ?Sized,
+        A: AsRef<OsStr> + Sync + ?Sized + 'a,
+        I: IntoIterator<Item = &'a A> + Send,
+    >(
+        payload: String,
+        program: Option<&P>,
+        args: I,
+    ) -> Result<(), NftablesError> {
+        let mut writer = MessageWriter::new();
+
+        match parse_ruleset(&payload)
+            .ok()
+            .and_then(|objects| encode_batch(&objects, &mut writer))
+        {
+            Some(()) => send_batch::<S>(writer).await.map_err(netlink_error),
+            None => F::apply_ruleset_raw(payload, program, args).await,
+        }
+    }
+
+    async fn get_current_ruleset_with_args<
+        'a,
+        P: AsRef<OsStr> + Sync + ?Sized,
+        A: AsRef<OsStr> + Sync + ?Sized + 'a,
+        I: IntoIterator<Item = &'a A> + Send,
+    >(
+        program: Option<&P>,
+        args: I,
+    ) -> Result<Nftables<'static>, NftablesError> {
+        match list_ruleset::<S>().await.map_err(netlink_error)? {
+            Some(objects) => Ok(split_ruleset(objects).0),
+            None => F::get_current_ruleset_with_args(program, args).await,
+        }
+    }
+
+    async fn get_current_ruleset_raw<
+        'a,
+        P: AsRef<OsStr> + Sync + ?Sized,
+        A: AsRef<OsStr> + Sync + ?Sized + 'a,
+        I: IntoIterator<Item = &'a A> + Send,
+    >(
+        program: Option<&P>,
+        args: I,
+    ) -> Result<String, NftablesError> {
+        match list_ruleset::<S>().await.map_err(netlink_error)? {
+            Some(objects) => Ok(serialize_ruleset(&objects)),
+            None => F::get_current_ruleset_raw(program, args).await,
+        }
+    }
+}
+
+impl<S: AsyncSocket + Send, F: Helper> NetlinkNftablesDriver<S, F> {
+    /// Read the given rules by their handles and reset their counters, which the kernel does atomically for every rule,
+    /// returning them as they were beforehand. Rules that have been deleted since they were listed are omitted. Returns
+    /// [None] if the kernel predates resetting rules, which was introduced in Linux 6.2.
+    pub(crate) async fn reset_rules(rules: &[Rule<'_>]) -> Result<Option<Vec<Rule<'static>>>, NftablesError> {
+        reset_rules::<S>(rules).await.map_err(netlink_error)
+    }
+}
+
+#[inline]
+fn netlink_error(err: io::Error) -> NftablesError {
+    NftablesError::NftExecution {
+        program: "netlink".into(),
+        inner: err,
+    }
+}
+
+async fn send_batch<S: AsyncSocket + Send>(writer: MessageWriter) -> io::Result<()> {
+    let mut pending_seqs = writer.acked_seqs().iter().copied().collect::<HashSet<_>>();
+
+    if pending_seqs.is_empty() {
+        return Ok(());
+    }
+
+    let mut socket = open_netfilter_socket::<S>()?;
+    send_netlink(&mut socket, &writer.buf).await?;
+    let mut first_error = None;
+
+    // the kernel reports an acknowledgement or error for every message in the batch, even when aborting it
+    while !pending_seqs.is_empty() {
+        let buf = recv_netlink(&mut socket).await?;
+
+        for message in Messages::new(&buf) {
+            let Some(code) = message.error_code() else {
+                continue;
+            };
+
+            let is_batch_error = !pending_seqs.remove(&message.seq);
+
+            if code != 0 && first_error.is_none() {
+                first_error = Some(io::Error::from_raw_os_error(-code));
+            }
+
+            // an error not attributed to any message means the batch as a whole was rejected
+            if is_batch_error && code != 0 {
+                pending_seqs.clear();
+            }
+        }
+    }
+
+    match first_error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+async fn dump<S: AsyncSocket + Send>(
+    socket: &mut S,
+    nft_msg_type: u16,
+    nfproto: u8,
+    attrs: &[u8],
+) -> io::Result<Vec<(u8, Vec<u8>)>> {
+    let mut writer = MessageWriter::new();
+    let offset = writer.begin(nft_msg_type, NLM_F_REQUEST | NLM_F_DUMP, nfproto);
+    writer.buf.extend_from_slice(attrs);
+    writer.end(offset);
+    send_netlink(socket, &writer.buf).await?;
+
+    let mut bodies = Vec::new();
+
+    loop {
+        let buf = recv_netlink(socket).await?;
+
+        for message in Messages::new(&buf) {
+            if message.msg_type == NLMSG_DONE {
+                return Ok(bodies);
+            }
+
+            if let Some(code) = message.error_code() {
+                if code != 0 {
+                    return Err(io::Error::from_raw_os_error(-code));
+                }
+            }
+
+            // objects are dumped as NEW* messages, each of which directly precedes its GET* counterpart
+            if message.nft_msg_type() == Some(nft_msg_type - 1) {
+                if let Some((nfproto, attrs)) = message.nft_body() {
+                    bodies.push((nfproto, attrs.to_vec()));
+                }
+            }
+        }
+    }
+}
+
+async fn reset_rules<S: AsyncSocket + Send>(rules: &[Rule<'_>]) -> io::Result<Option<Vec<Rule<'static>>>> {
+    let mut socket = open_netfilter_socket::<S>()?;
+    let mut reset_rules = Vec::with_capacity(rules.len());
+    let mut is_supported = false;
+
+    for rule in rules {
+        let mut writer = MessageWriter::new();
+        encode_rule_reset(&mut writer, rule)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Rule to reset without its handle"))?;
+        send_netlink(&mut socket, &writer.buf).await?;
+
+        match recv_rule(&mut socket).await {
+            Ok(rule) => reset_rules.push(rule),
+            // a rule deleted in the meantime has no counters left to reset
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => {}
+            // unknown message types are rejected before any rule is looked up, so nothing has been reset yet
+            Err(err) if !is_supported && err.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        is_supported = true;
+    }
+
+    Ok(Some(reset_rules))
+}
+
+/// Receive the rule that the kernel answers a request for a single rule with.
+async fn recv_rule<S: AsyncSocket + Send>(socket: &mut S) -> io::Result<Rule<'static>> {
+    loop {
+        let buf = recv_netlink(socket).await?;
+
+        for message in Messages::new(&buf) {
+            if let Some(code) = message.error_code() {
+                if code != 0 {
+                    return Err(io::Error::from_raw_os_error(-code));
+                }
+            }
+
+            if message.nft_msg_type() == Some(NFT_MSG_NEWRULE) {
+                return message
+                    .nft_body()
+                    .and_then(|(nfproto, attrs)| decode_rule(nfproto, attrs))
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Rule that can't be decoded natively"));
+            }
+        }
+    }
+}
+
+/// Get the generation of the ruleset, which the kernel increments with every committed transaction.
+async fn get_gen_id<S: AsyncSocket + Send>(socket: &mut S) -> io::Result<u32> {
+    let mut writer = MessageWriter::new();
+    let offset = writer.begin(NFT_MSG_GETGEN, NLM_F_REQUEST, NFPROTO_UNSPEC);
+    writer.end(offset);
+    send_netlink(socket, &writer.buf).await?;
+
+    loop {
+        let buf = recv_netlink(socket).await?;
+
+        for message in Messages::new(&buf) {
+            if let Some(code) = message.error_code() {
+                if code != 0 {
+                    return Err(io::Error::from_raw_os_error(-code));
+                }
+            }
+
+            if message.nft_msg_type() == Some(NFT_MSG_NEWGEN) {
+                return message
+                    .nft_body()
+                    .and_then(|(_, attrs)| Attrs::new(attrs).find(|(attr_type, _)| *attr_type == NFTA_GEN_ID))
+                    .and_then(|(_, payload)| parse_be32(payload))
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Generation message without its ID"));
+            }
+        }
+    }
+}
+
+/// List every table with its chains, rules, verdict maps and plain sets. Tables containing anything that can't be
+/// decoded natively are skipped, unless they also contain rules tagged by fcnet, in which case [None] is returned.
+///
+/// The objects are dumped one type after another, so like nft, the listing is retried whenever the generation of the
+/// ruleset changed in between, which would otherwise leave it inconsistent.
+async fn list_ruleset<S: AsyncSocket + Send>() -> io::Result<Option<Vec<NfRulesetObject<'static>>>> {
+    let mut socket = open_netfilter_socket::<S>()?;
+
+    loop {
+        let gen_id = get_gen_id(&mut socket).await?;
+        // a dump can also fail because an object vanished in between, which is likewise resolved by retrying
+        let result = list_ruleset_once(&mut socket).await;
+
+        if get_gen_id(&mut socket).await? == gen_id {
+            return result;
+        }
+    }
+}
+
+async fn list_ruleset_once<S: AsyncSocket + Send>(socket: &mut S) -> io::Result<Option<Vec<NfRulesetObject<'static>>>> {
+    let mut objects = Vec::new();
+
+    let tables = dump(socket, NFT_MSG_GETTABLE, NFPROTO_UNSPEC, &[]).await?;
+    let chains = dump(socket, NFT_MSG_GETCHAIN, NFPROTO_UNSPEC, &[]).await?;
+    let rules = dump(socket, NFT_MSG_GETRULE, NFPROTO_UNSPEC, &[]).await?;
+    let sets = dump(socket, NFT_MSG_GETSET, NFPROTO_UNSPEC, &[]).await?;
+
+    for (nfproto, attrs) in tables {
+        let Some(name) = table_name(&attrs) else {
+            continue;
+        };
+        let in_table = |(object_nfproto, attrs): &&(u8, Vec<u8>)| {
+            *object_nfproto == nfproto && table_name(attrs).as_deref() == Some(name.as_str())
+        };
+
+        match list_table(socket, nfproto, &name, &attrs, &chains, &rules, &sets).await? {
+            Some(table_objects) => objects.extend(table_objects),
+            None => {
+                let has_tagged_rules = rules
+                    .iter()
+                    .filter(in_table)
+                    .any(|(_, attrs)| rule_comment(attrs).is_some_and(|comment| parse_nf_rule_tag(&comment).is_some()));
+
+                if has_tagged_rules {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    Ok(Some(objects))
+}
+
+/// List a single table with its chains, rules, verdict maps and plain sets, returning [None] if anything within it
+/// can't be decoded natively.
+async fn list_table<S: AsyncSocket + Send>(
+    socket: &mut S,
+    nfproto: u8,
+    name: &str,
+    attrs: &[u8],
+    chains: &[(u8, Vec<u8>)],
+    rules: &[(u8, Vec<u8>)],
+    sets: &[(u8, Vec<u8>)],
+) -> io::Result<Option<Vec<NfRulesetObject<'static>>>> {
+    let mut objects = Vec::new();
+    let in_table =
+        |(object_nfproto, attrs): &&(u8, Vec<u8>)| *object_nfproto == nfproto && table_name(attrs).as_deref() == Some(name);
+
+    match decode_table(nfproto, attrs) {
+        Some(table) => objects.push(NfRulesetObject::Object(NfObject::ListObject(NfListObject::Table(table)))),
+        None => return Ok(None),
+    }
+
+    for (_, attrs) in chains.iter().filter(in_table) {
+        match decode_chain(nfproto, attrs) {
+            Some(chain) => objects.push(NfRulesetObject::Object(NfObject::ListObject(NfListObject::Chain(chain)))),
+            None => return Ok(None),
+        }
+    }
+
+    for (_, attrs) in rules.iter().filter(in_table) {
+        match decode_rule(nfproto, attrs) {
+            Some(rule) => objects.push(NfRulesetObject::Object(NfObject::ListObject(NfListObject::Rule(rule)))),
+            None => return Ok(None),
+        }
+    }
+
+    for (_, attrs) in sets.iter().filter(in_table) {
+        let Some(mut verdict_map) = decode_verdict_map(nfproto, attrs) else {
+            let Some(mut set) = decode_set(nfproto, attrs) else {
+                return Ok(None);
+            };
+
+            let mut header = Vec::new();
+            put_set_elem_list_header(&mut header, name, &set.name);
+            let mut keys = Vec::new();
+
+            for (_, attrs) in dump(socket, NFT_MSG_GETSETELEM, nfproto, &header).await? {
+                match decode_set_keys(&attrs, &set) {
+                    Some(set_keys) => keys.extend(set_keys),
+                    None => return Ok(None),
+                }
+            }
+
+            // like nft, a set without elements is listed without an empty list of them
+            match set_elements(&set, keys) {
+                Some(elements) if elements.is_empty() => {}
+                Some(elements) => set.elem = Some(elements.into()),
+                None => return Ok(None),
+            }
+
+            objects.push(NfRulesetObject::Object(NfObject::ListObject(NfListObject::Set(Box::new(
+                set,
+            )))));
+            continue;
+        };
+
+        let mut header = Vec::new();
+        put_set_elem_list_header(&mut header, name, &verdict_map.name);
+
+        for (_, attrs) in dump(socket, NFT_MSG_GETSETELEM, nfproto, &header).await? {
+            match decode_elements(&attrs, verdict_map.key_type) {
+                Some(elements) => verdict_map.elements.extend(elements),
+                None => return Ok(None),
+            }
+        }
+
+        objects.push(NfRulesetObject::VerdictMap(verdict_map));
+    }
+
+    Ok(Some(objects))
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+
+use nftables::{
+    schema::{Chain, FlushObject, NfCmd, NfListObject, NfObject, Rule, Set, Table},
+    types::{NfChainPolicy, NfChainType, NfFamily, NfHook},
+};
+
+use super::{
+    attr::{begin_nested, end_nested, parse_be32, parse_be64, parse_str, put_be32, put_be64, put_bytes, put_str, Attrs},
+    expr::{decode_statements, encode_statements},
+    message::{
+        family_to_nfproto, nfproto_to_family, MessageWriter, NFPROTO_ARP, NFPROTO_NETDEV, NFT_MSG_DELCHAIN, NFT_MSG_DELRULE,
+        NFT_MSG_DELSET, NFT_MSG_DELSETELEM, NFT_MSG_DELTABLE, NFT_MSG_GETRULE_RESET, NFT_MSG_NEWCHAIN, NFT_MSG_NEWRULE,
+        NFT_MSG_NEWSETELEM, NFT_MSG_NEWTABLE, NLM_F_ACK, NLM_F_APPEND, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REPLACE, NLM_F_REQUEST,
+    },
+    set::{encode_elements, encode_set, encode_set_elements, encode_verdict_map, put_set_elem_list_header},
+};
+use crate::vmap::NfRulesetObject;
+
+const NFTA_TABLE_NAME: u16 = 1;
+
+const NFTA_SET_TABLE: u16 = 1;
+const NFTA_SET_NAME: u16 = 2;
+const NFTA_TABLE_HANDLE: u16 = 4;
+
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_HANDLE: u16 = 2;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_HOOK_DEV: u16 = 3;
+
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_HANDLE: u16 = 3;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_RULE_POSITION: u16 = 6;
+const NFTA_RULE_USERDATA: u16 = 7;
+
+const NFTNL_UDATA_RULE_COMMENT: u8 = 0;
+const NFT_USERDATA_MAXLEN: usize = 256;
+
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+
+/// Encode the commands of a ruleset into the messages of a batch, or return [None] if any of them isn't supported
+/// natively. Every message requests an acknowledgement so that errors can be attributed to it. Elements can only be
+/// added natively to plain sets that are added earlier in the same batch, as their type isn't known otherwise.
+pub fn encode_batch(objects: &[NfRulesetObject], writer: &mut MessageWriter) -> Option<()> {
+    let mut sets = HashMap::new();
+    writer.begin_batch();
+
+    for object in objects {
+        let cmd = match object {
+            NfRulesetObject::Object(NfObject::CmdObject(cmd)) => cmd,
+            NfRulesetObject::AddVerdictMap(verdict_map) => {
+                encode_verdict_map(writer, verdict_map, NLM_F_CREATE);
+                continue;
+            }
+            _ => return None,
+        };
+
+        match cmd {
+            NfCmd::Add(object) => encode_object(writer, object, &mut sets, NLM_F_CREATE)?,
+            NfCmd::Create(object) => encode_object(writer, object, &mut sets, NLM_F_CREATE | NLM_F_EXCL)?,
+            NfCmd::Insert(NfListObject::Rule(rule)) => encode_rule(writer, rule, NLM_F_CREATE)?,
+            NfCmd::Replace(rule) => encode_rule(writer, rule, NLM_F_REPLACE)?,
+            NfCmd::Delete(NfListObject::Table(table)) => {
+                let offset = writer.begin(NFT_MSG_DELTABLE, NLM_F_REQUEST | NLM_F_ACK, family_to_nfproto(table.family));
+                put_str(&mut writer.buf, NFTA_TABLE_NAME, &table.name);
+                writer.end(offset);
+            }
+            NfCmd::Delete(NfListObject::Chain(chain)) => {
+                let offset = writer.begin(NFT_MSG_DELCHAIN, NLM_F_REQUEST | NLM_F_ACK, family_to_nfproto(chain.family));
+                put_str(&mut writer.buf, NFTA_CHAIN_TABLE, &chain.table);
+                put_str(&mut writer.buf, NFTA_CHAIN_NAME, &chain.name);
+                writer.end(offset);
+            }
+            NfCmd::Delete(NfListObject::Rule(rule)) => {
+                let offset = writer.begin(NFT_MSG_DELRULE, NLM_F_REQUEST | NLM_F_ACK, family_to_nfproto(rule.family));
+                put_str(&mut writer.buf, NFTA_RULE_TABLE, &rule.table);
+                put_str(&mut writer.buf, NFTA_RULE_CHAIN, &rule.chain);
+                put_be64(&mut writer.buf, NFTA_RULE_HANDLE, rule.handle? as u64);
+                writer.end(offset);
+            }
+            NfCmd::Delete(NfListObject::Set(set)) => {
+                let offset = writer.begin(NFT_MSG_DELSET, NLM_F_REQUEST | NLM_F_ACK, family_to_nfproto(set.family));
+                put_str(&mut writer.buf, NFTA_SET_TABLE, &set.table);
+                put_str(&mut writer.buf, NFTA_SET_NAME, &set.name);
+                writer.end(offset);
+            }
+            NfCmd::Delete(NfListObject::Element(element)) => encode_elements(writer, NFT_MSG_DELSETELEM, element, 0)?,
+            // deleting the elements of a set without naming any of them flushes it
+            NfCmd::Flush(FlushObject::Set(set)) => {
+                let offset = writer.begin(NFT_MSG_DELSETELEM, NLM_F_REQUEST | NLM_F_ACK, family_to_nfproto(set.family));
+                put_set_elem_list_header(&mut writer.buf, &set.table, &set.name);
+                writer.end(offset);
+            }
+            // deleting the rules of a chain without naming any of them flushes it
+            NfCmd::Flush(FlushObject::Chain(chain)) => {
+                let offset = writer.begin(NFT_MSG_DELRULE, NLM_F_REQUEST | NLM_F_ACK, family_to_nfproto(chain.family));
+                put_str(&mut writer.buf, NFTA_RULE_TABLE, &chain.table);
+                put_str(&mut writer.buf, NFTA_RULE_CHAIN, &chain.name);
+                writer.end(offset);
+            }
+            _ => return None,
+        }
+    }
+
+    writer.end_batch();
+    Some(())
+}
+
+/// The plain sets added so far in a batch by their family, table and name.
+type BatchSets<'a, 'b> = HashMap<(u8, String, String), &'a Set<'b>>;
+
+fn encode_object<'a, 'b>(
+    writer: &mut MessageWriter,
+    object: &'a NfListObject<'b>,
+    sets: &mut BatchSets<'a, 'b>,
+    flags: u16,
+) -> Option<()> {
+    match object {
+        NfListObject::Table(table) => {
+            let offset = writer.begin(
+                NFT_MSG_NEWTABLE,
+                NLM_F_REQUEST | NLM_F_ACK | flags,
+                family_to_nfproto(table.family),
+            );
+            put_str(&mut writer.buf, NFTA_TABLE_NAME, &table.name);
+            writer.end(offset);
+        }
+        NfListObject::Chain(chain) => {
+            if chain.newname.is_some() {
+                return None;
+            }
+
+            let offset = writer.begin(
+                NFT_MSG_NEWCHAIN,
+                NLM_F_REQUEST | NLM_F_ACK | flags,
+                family_to_nfproto(chain.family),
+            );
+            put_str(&mut writer.buf, NFTA_CHAIN_TABLE, &chain.table);
+            put_str(&mut writer.buf, NFTA_CHAIN_NAME, &chain.name);
+
+            match (chain.hook, chain.prio, chain._type) {
+                (Some(hook), Some(prio), Some(chain_type)) => {
+                    let hook_offset = begin_nested(&mut writer.buf, NFTA_CHAIN_HOOK);
+                    put_be32(&mut writer.buf, NFTA_HOOK_HOOKNUM, hook_to_num(chain.family, hook)?);
+                    put_be32(&mut writer.buf, NFTA_HOOK_PRIORITY, prio as u32);
+                    if let Some(ref dev) = chain.dev {
+                        put_str(&mut writer.buf, NFTA_HOOK_DEV, dev);
+                    }
+                    end_nested(&mut writer.buf, hook_offset);
+
+                    put_str(
+                        &mut writer.buf,
+                        NFTA_CHAIN_TYPE,
+                        match chain_type {
+                            NfChainType::Filter => "filter",
+                            NfChainType::Route => "route",
+                            NfChainType::NAT => "nat",
+                        },
+                    );
+
+                    if let Some(policy) = chain.policy {
+                        put_be32(
+                            &mut writer.buf,
+                            NFTA_CHAIN_POLICY,
+                            match policy {
+                                NfChainPolicy::Accept => NF_ACCEPT,
+                                NfChainPolicy::Drop => NF_DROP,
+                            },
+                        );
+                    }
+                }
+                (None, None, None) => {}
+                _ => return None,
+            }
+
+            writer.end(offset);
+        }
+        NfListObject::Rule(rule) => encode_rule(writer, rule, flags | NLM_F_APPEND)?,
+        NfListObject::Set(set) => {
+            encode_set(writer, set, flags)?;
+            sets.insert(
+                (family_to_nfproto(set.family), set.table.to_string(), set.name.to_string()),
+                set.as_ref(),
+            );
+        }
+        NfListObject::Element(element) => {
+            match sets.get(&(
+                family_to_nfproto(element.family),
+                element.table.to_string(),
+                element.name.to_string(),
+            )) {
+                Some(set) => encode_set_elements(writer, set, element, flags)?,
+                None => encode_elements(writer, NFT_MSG_NEWSETELEM, element, flags)?,
+            }
+        }
+        _ => return None,
+    }
+
+    Some(())
+}
+
+fn encode_rule(writer: &mut MessageWriter, rule: &Rule, flags: u16) -> Option<()> {
+    // an index refers to the position of a rule in the chain, which can only be resolved by listing the chain
+    if rule.index.is_some() {
+        return None;
+    }
+
+    let offset = writer.begin(
+        NFT_MSG_NEWRULE,
+        NLM_F_REQUEST | NLM_F_ACK | flags,
+        family_to_nfproto(rule.family),
+    );
+    put_str(&mut writer.buf, NFTA_RULE_TABLE, &rule.table);
+    put_str(&mut writer.buf, NFTA_RULE_CHAIN, &rule.chain);
+
+    // the handle of a rule being replaced identifies it, while that of a rule being added is the one it is placed next to
+    match rule.handle {
+        Some(handle) if flags & NLM_F_REPLACE != 0 => put_be64(&mut writer.buf, NFTA_RULE_HANDLE, handle as u64),
+        Some(handle) => put_be64(&mut writer.buf, NFTA_RULE_POSITION, handle as u64),
+        None if flags & NLM_F_REPLACE != 0 => return None,
+        None => {}
+    }
+
+    let expressions_offset = begin_nested(&mut writer.buf, NFTA_RULE_EXPRESSIONS);
+    encode_statements(&mut writer.buf, rule.family, &rule.expr)?;
+    end_nested(&mut writer.buf, expressions_offset);
+
+    if let Some(ref comment) = rule.comment {
+        // the comment is stored as a TLV with a single-byte length, in the same format as nft uses
+        if comment.len() + 1 > u8::MAX as usize || comment.len() + 3 > NFT_USERDATA_MAXLEN {
+            return None;
+        }
+
+        let mut userdata = vec![NFTNL_UDATA_RULE_COMMENT, (comment.len() + 1) as u8];
+        userdata.extend_from_slice(comment.as_bytes());
+        userdata.push(0);
+        put_bytes(&mut writer.buf, NFTA_RULE_USERDATA, &userdata);
+    }
+
+    writer.end(offset);
+    Some(())
+}
+
+/// The name of the table an object belongs to, which is the first attribute of tables, chains and rules alike.
+pub fn table_name(attrs: &[u8]) -> Option<String> {
+    Attrs::new(attrs)
+        .find(|(attr_type, _)| *attr_type == NFTA_TABLE_NAME)
+        .and_then(|(_, payload)| parse_str(payload))
+}
+
+pub fn decode_table(nfproto: u8, attrs: &[u8]) -> Option<Table<'static>> {
+    let mut table = Table {
+        family: nfproto_to_family(nfproto)?,
+        name: "".into(),
+        handle: None,
+    };
+
+    for (attr_type, payload) in Attrs::new(attrs) {
+        match attr_type {
+            NFTA_TABLE_NAME => table.name = parse_str(payload)?.into(),
+            NFTA_TABLE_HANDLE => table.handle = Some(parse_be64(payload)? as u32),
+            _ => {}
+        }
+    }
+
+    Some(table)
+}
+
+pub fn decode_chain(nfproto: u8, attrs: &[u8]) -> Option<Chain<'static>> {
+    let family = nfproto_to_family(nfproto)?;
+    let mut chain = Chain {
+        family,
+        table: "".into(),
+        name: "".into(),
+        newname: None,
+        handle: None,
+        _type: None,
+        hook: None,
+        prio: None,
+        dev: None,
+        policy: None,
+    };
+
+    for (attr_type, payload) in Attrs::new(attrs) {
+        match attr_type {
+            NFTA_CHAIN_TABLE => chain.table = parse_str(payload)?.into(),
+            NFTA_CHAIN_NAME => chain.name = parse_str(payload)?.into(),
+            NFTA_CHAIN_HANDLE => chain.handle = Some(parse_be64(payload)? as u32),
+            NFTA_CHAIN_TYPE => {
+                chain._type = Some(match parse_str(payload)?.as_str() {
+                    "filter" => NfChainType::Filter,
+                    "route" => NfChainType::Route,
+                    "nat" => NfChainType::NAT,
+                    _ => return None,
+                })
+            }
+            NFTA_CHAIN_POLICY => {
+                chain.policy = Some(match parse_be32(payload)? {
+                    NF_ACCEPT => NfChainPolicy::Accept,
+                    NF_DROP => NfChainPolicy::Drop,
+                    _ => return None,
+                })
+            }
+            NFTA_CHAIN_HOOK => {
+                for (attr_type, payload) in Attrs::new(payload) {
+                    match attr_type {
+                        NFTA_HOOK_HOOKNUM => chain.hook = Some(num_to_hook(family, parse_be32(payload)?)?),
+                        NFTA_HOOK_PRIORITY => chain.prio = Some(parse_be32(payload)? as i32),
+                        NFTA_HOOK_DEV => chain.dev = Some(parse_str(payload)?.into()),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(chain)
+}
+
+/// Encode a request to read the given rule by its handle and reset its stateful expressions such as counters, which the
+/// kernel answers with the rule as it was beforehand. Returns [None] if the rule lacks a handle.
+pub fn encode_rule_reset(writer: &mut MessageWriter, rule: &Rule) -> Option<()> {
+    let offset = writer.begin(NFT_MSG_GETRULE_RESET, NLM_F_REQUEST, family_to_nfproto(rule.family));
+    put_str(&mut writer.buf, NFTA_RULE_TABLE, &rule.table);
+    put_str(&mut writer.buf, NFTA_RULE_CHAIN, &rule.chain);
+    put_be64(&mut writer.buf, NFTA_RULE_HANDLE, rule.handle? as u64);
+    writer.end(offset);
+    Some(())
+}
+
+/// Decode a rule, returning [None] if any of its expressions isn't supported natively.
+pub fn decode_rule(nfproto: u8, attrs: &[u8]) -> Option<Rule<'static>> {
+    let family = nfproto_to_family(nfproto)?;
+    let mut rule = Rule {
+        family,
+        table: "".into(),
+        chain: "".into(),
+        expr: Vec::new().into(),
+        handle: None,
+        index: None,
+        comment: None,
+    };
+
+    for (attr_type, payload) in Attrs::new(attrs) {
+        match attr_type {
+            NFTA_RULE_TABLE => rule.table = parse_str(payload)?.into(),
+            NFTA_RULE_CHAIN => rule.chain = parse_str(payload)?.into(),
+            NFTA_RULE_HANDLE => rule.handle = Some(parse_be64(payload)? as u32),
+            NFTA_RULE_EXPRESSIONS => rule.expr = decode_statements(family, payload)?.into(),
+            NFTA_RULE_USERDATA => rule.comment = decode_comment(payload)?.map(Into::into),
+            _ => {}
+        }
+    }
+
+    Some(rule)
+}
+
+/// The comment of a rule, which can be read even if the rest of the rule can't be decoded natively.
+pub fn rule_comment(attrs: &[u8]) -> Option<String> {
+    Attrs::new(attrs)
+        .find(|(attr_type, _)| *attr_type == NFTA_RULE_USERDATA)
+        .and_then(|(_, payload)| decode_comment(payload)?)
+}
+
+fn decode_comment(mut userdata: &[u8]) -> Option<Option<String>> {
+    while userdata.len() >= 2 {
+        let (udata_type, len) = (userdata[0], userdata[1] as usize);
+        let value = userdata.get(2..2 + len)?;
+
+        if udata_type == NFTNL_UDATA_RULE_COMMENT {
+            return Some(Some(parse_str(value)?));
+        }
+
+        userdata = &userdata[2 + len..];
+    }
+
+    Some(None)
+}
+
+fn hook_to_num(family: NfFamily, hook: NfHook) -> Option<u32> {
+    match (family_to_nfproto(family), hook) {
+        (NFPROTO_NETDEV, NfHook::Ingress) => Some(0),
+        (NFPROTO_NETDEV, NfHook::Egress) => Some(1),
+        (NFPROTO_NETDEV, _) => None,
+        (NFPROTO_ARP, NfHook::Input) => Some(0),
+        (NFPROTO_ARP, NfHook::Output) => Some(1),
+        (NFPROTO_ARP, NfHook::Forward) => Some(2),
+        (NFPROTO_ARP, _) => None,
+        (_, NfHook::Prerouting) => Some(0),
+        (_, NfHook::Input) => Some(1),
+        (_, NfHook::Forward) => Some(2),
+        (_, NfHook::Output) => Some(3),
+        (_, NfHook::Postrouting) => Some(4),
+        (_, NfHook::Ingress) => Some(5),
+        (_, NfHook::Egress) => None,
+    }
+}
+
+fn num_to_hook(family: NfFamily, num: u32) -> Option<NfHook> {
+    match (family_to_nfproto(family), num) {
+        (NFPROTO_NETDEV, 0) => Some(NfHook::Ingress),
+        (NFPROTO_NETDEV, 1) => Some(NfHook::Egress),
+        (NFPROTO_NETDEV, _) => None,
+        (NFPROTO_ARP, 0) => Some(NfHook::Input),
+        (NFPROTO_ARP, 1) => Some(NfHook::Output),
+        (NFPROTO_ARP, 2) => Some(NfHook::Forward),
+        (NFPROTO_ARP, _) => None,
+        (_, 0) => Some(NfHook::Prerouting),
+        (_, 1) => Some(NfHook::Input),
+        (_, 2) => Some(NfHook::Forward),
+        (_, 3) => Some(NfHook::Output),
+        (_, 4) => Some(NfHook::Postrouting),
+        (_, 5) => Some(NfHook::Ingress),
+        _ => None,
+    }
+}
@@ -0,0 +1,491 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use nftables::{
+    expr::{Expression, NamedExpression, Prefix, Range, Verdict},
+    schema::{Element, Set, SetFlag, SetType, SetTypeValue},
+};
+
+use super::{
+    attr::{begin_nested, end_nested, parse_be32, parse_str, put_be32, put_bytes, put_str, Attrs},
+    expr::{decode_addr, parse_verdict, put_verdict, IFNAMSIZ},
+    message::{
+        family_to_nfproto, nfproto_to_family, MessageWriter, NFT_MSG_NEWSET, NFT_MSG_NEWSETELEM, NLM_F_ACK, NLM_F_REQUEST,
+    },
+};
+use crate::vmap::{NfVerdictMap, NfVerdictMapKeyType};
+
+const NFTA_SET_TABLE: u16 = 1;
+const NFTA_SET_NAME: u16 = 2;
+const NFTA_SET_FLAGS: u16 = 3;
+const NFTA_SET_KEY_TYPE: u16 = 4;
+const NFTA_SET_KEY_LEN: u16 = 5;
+const NFTA_SET_DATA_TYPE: u16 = 6;
+const NFTA_SET_ID: u16 = 10;
+const NFT_SET_INTERVAL: u32 = 0x4;
+const NFT_SET_MAP: u32 = 0x8;
+const NFT_DATA_VERDICT: u32 = 0xffffff00;
+
+// the identifiers of the data types of nft, which the kernel stores opaquely on its behalf
+const NFT_TYPE_IPADDR: u32 = 7;
+const NFT_TYPE_IP6ADDR: u32 = 8;
+const NFT_TYPE_INET_SERVICE: u32 = 13;
+const NFT_TYPE_IFNAME: u32 = 41;
+
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_DATA: u16 = 2;
+const NFTA_SET_ELEM_FLAGS: u16 = 3;
+const NFT_SET_ELEM_INTERVAL_END: u32 = 0x1;
+const NFTA_DATA_VALUE: u16 = 1;
+
+pub fn encode_verdict_map(writer: &mut MessageWriter, verdict_map: &NfVerdictMap, flags: u16) {
+    let (key_type, key_len) = match verdict_map.key_type {
+        NfVerdictMapKeyType::Ifname => (NFT_TYPE_IFNAME, IFNAMSIZ),
+        NfVerdictMapKeyType::Ipv4Addr => (NFT_TYPE_IPADDR, 4),
+        NfVerdictMapKeyType::Ipv6Addr => (NFT_TYPE_IP6ADDR, 16),
+    };
+
+    let offset = writer.begin(
+        NFT_MSG_NEWSET,
+        NLM_F_REQUEST | NLM_F_ACK | flags,
+        family_to_nfproto(verdict_map.family),
+    );
+    put_str(&mut writer.buf, NFTA_SET_TABLE, &verdict_map.table);
+    put_str(&mut writer.buf, NFTA_SET_NAME, &verdict_map.name);
+    put_be32(&mut writer.buf, NFTA_SET_FLAGS, NFT_SET_MAP);
+    put_be32(&mut writer.buf, NFTA_SET_KEY_TYPE, key_type);
+    put_be32(&mut writer.buf, NFTA_SET_KEY_LEN, key_len as u32);
+    put_be32(&mut writer.buf, NFTA_SET_DATA_TYPE, NFT_DATA_VERDICT);
+    // the kernel requires an identifier that is unique within the batch, for which the sequence number is reused
+    let set_id = writer.acked_seqs().last().copied().unwrap_or_default();
+    put_be32(&mut writer.buf, NFTA_SET_ID, set_id);
+    writer.end(offset);
+}
+
+/// Encode a message adding a plain set, or return [None] if it isn't supported natively. Only sets without any elements
+/// of their own, properties or flags besides that of holding intervals, and whose keys are addresses, ports or interface
+/// names are supported, the latter only without intervals.
+pub fn encode_set(writer: &mut MessageWriter, set: &Set, flags: u16) -> Option<()> {
+    let (key_type, key_len) = set_key(set)?;
+
+    if set.elem.is_some()
+        || set.policy.is_some()
+        || set.timeout.is_some()
+        || set.gc_interval.is_some()
+        || set.size.is_some()
+        || set.comment.is_some()
+    {
+        return None;
+    }
+
+    let offset = writer.begin(
+        NFT_MSG_NEWSET,
+        NLM_F_REQUEST | NLM_F_ACK | flags,
+        family_to_nfproto(set.family),
+    );
+    put_str(&mut writer.buf, NFTA_SET_TABLE, &set.table);
+    put_str(&mut writer.buf, NFTA_SET_NAME, &set.name);
+    put_be32(
+        &mut writer.buf,
+        NFTA_SET_FLAGS,
+        match is_interval(set) {
+            true => NFT_SET_INTERVAL,
+            false => 0,
+        },
+    );
+    put_be32(&mut writer.buf, NFTA_SET_KEY_TYPE, key_type);
+    put_be32(&mut writer.buf, NFTA_SET_KEY_LEN, key_len as u32);
+    let set_id = writer.acked_seqs().last().copied().unwrap_or_default();
+    put_be32(&mut writer.buf, NFTA_SET_ID, set_id);
+    writer.end(offset);
+    Some(())
+}
+
+/// Encode a message adding the elements of a plain set that was added earlier in the same batch, whose type is thereby
+/// known, or return [None] if any of them isn't supported natively. Like nft does, every interval of an interval set is
+/// encoded as an element starting it and another one right after its last key that ends it, with the latter being
+/// omitted for an interval that ends at the last possible key.
+pub fn encode_set_elements(writer: &mut MessageWriter, set: &Set, element: &Element, flags: u16) -> Option<()> {
+    let (key_type, key_len) = set_key(set)?;
+    let max_key = max_key(key_len);
+
+    let offset = writer.begin(
+        NFT_MSG_NEWSETELEM,
+        NLM_F_REQUEST | NLM_F_ACK | flags,
+        family_to_nfproto(element.family),
+    );
+    put_set_elem_list_header(&mut writer.buf, &element.table, &element.name);
+
+    let elements_offset = begin_nested(&mut writer.buf, NFTA_SET_ELEM_LIST_ELEMENTS);
+    for elem in element.elem.iter() {
+        let (start, end) = match elem {
+            Expression::Named(NamedExpression::Prefix(Prefix { addr, len })) if is_addr(key_type) => {
+                let host_mask = max_key.checked_shr(*len).unwrap_or_default();
+                let addr = parse_key(addr, key_type)?;
+                (addr & !host_mask, addr | host_mask)
+            }
+            Expression::Range(range) => (parse_key(&range.range[0], key_type)?, parse_key(&range.range[1], key_type)?),
+            elem => {
+                let key = parse_key(elem, key_type)?;
+                (key, key)
+            }
+        };
+
+        if (start != end && !is_interval(set)) || start > end {
+            return None;
+        }
+
+        put_set_elem(&mut writer.buf, &start.to_be_bytes()[16 - key_len..], 0);
+
+        if is_interval(set) && end != max_key {
+            put_set_elem(
+                &mut writer.buf,
+                &(end + 1).to_be_bytes()[16 - key_len..],
+                NFT_SET_ELEM_INTERVAL_END,
+            );
+        }
+    }
+    end_nested(&mut writer.buf, elements_offset);
+
+    writer.end(offset);
+    Some(())
+}
+
+fn put_set_elem(buf: &mut Vec<u8>, key: &[u8], elem_flags: u32) {
+    let elem_offset = begin_nested(buf, NFTA_LIST_ELEM);
+    let key_offset = begin_nested(buf, NFTA_SET_ELEM_KEY);
+    put_bytes(buf, NFTA_DATA_VALUE, key);
+    end_nested(buf, key_offset);
+    if elem_flags != 0 {
+        put_be32(buf, NFTA_SET_ELEM_FLAGS, elem_flags);
+    }
+    end_nested(buf, elem_offset);
+}
+
+/// Encode a message adding or deleting the elements of a set, or return [None] if any of them isn't supported
+/// natively. Only keys that are interface names or addresses and verdict data are supported, and as the type of the
+/// set isn't known here, keys are encoded as addresses whenever they parse as one.
+pub fn encode_elements(writer: &mut MessageWriter, nft_msg_type: u16, element: &Element, flags: u16) -> Option<()> {
+    let offset = writer.begin(
+        nft_msg_type,
+        NLM_F_REQUEST | NLM_F_ACK | flags,
+        family_to_nfproto(element.family),
+    );
+    put_set_elem_list_header(&mut writer.buf, &element.table, &element.name);
+
+    let elements_offset = begin_nested(&mut writer.buf, NFTA_SET_ELEM_LIST_ELEMENTS);
+    for elem in element.elem.iter() {
+        let (key, verdict) = match elem {
+            Expression::String(key) => (key, None),
+            Expression::List(pair) => match pair.as_slice() {
+                [Expression::String(key), Expression::Verdict(verdict)] => (key, Some(verdict)),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let key = match key.parse::<IpAddr>() {
+            Ok(IpAddr::V4(addr)) => addr.octets().to_vec(),
+            Ok(IpAddr::V6(addr)) => addr.octets().to_vec(),
+            Err(_) if key.len() < IFNAMSIZ => {
+                let mut ifname = key.as_bytes().to_vec();
+                ifname.resize(IFNAMSIZ, 0);
+                ifname
+            }
+            Err(_) => return None,
+        };
+
+        let elem_offset = begin_nested(&mut writer.buf, NFTA_LIST_ELEM);
+        let key_offset = begin_nested(&mut writer.buf, NFTA_SET_ELEM_KEY);
+        put_bytes(&mut writer.buf, NFTA_DATA_VALUE, &key);
+        end_nested(&mut writer.buf, key_offset);
+        if let Some(verdict) = verdict {
+            put_verdict(&mut writer.buf, NFTA_SET_ELEM_DATA, verdict);
+        }
+        end_nested(&mut writer.buf, elem_offset);
+    }
+    end_nested(&mut writer.buf, elements_offset);
+
+    writer.end(offset);
+    Some(())
+}
+
+/// Write the attributes identifying a set in a message about its elements.
+pub fn put_set_elem_list_header(buf: &mut Vec<u8>, table: &str, set: &str) {
+    put_str(buf, NFTA_SET_ELEM_LIST_TABLE, table);
+    put_str(buf, NFTA_SET_ELEM_LIST_SET, set);
+}
+
+/// Decode a set without its elements, returning [None] if it isn't a verdict map with a supported key type.
+pub fn decode_verdict_map(nfproto: u8, attrs: &[u8]) -> Option<NfVerdictMap> {
+    let mut table = None;
+    let mut name = None;
+    let mut flags = None;
+    let mut key_type = None;
+    let mut data_type = None;
+
+    for (attr_type, payload) in Attrs::new(attrs) {
+        match attr_type {
+            NFTA_SET_TABLE => table = parse_str(payload),
+            NFTA_SET_NAME => name = parse_str(payload),
+            NFTA_SET_FLAGS => flags = parse_be32(payload),
+            NFTA_SET_KEY_TYPE => key_type = parse_be32(payload),
+            NFTA_SET_DATA_TYPE => data_type = parse_be32(payload),
+            _ => {}
+        }
+    }
+
+    if flags? != NFT_SET_MAP || data_type? != NFT_DATA_VERDICT {
+        return None;
+    }
+
+    Some(NfVerdictMap {
+        family: nfproto_to_family(nfproto)?,
+        table: table?,
+        name: name?,
+        key_type: match key_type? {
+            NFT_TYPE_IFNAME => NfVerdictMapKeyType::Ifname,
+            NFT_TYPE_IPADDR => NfVerdictMapKeyType::Ipv4Addr,
+            NFT_TYPE_IP6ADDR => NfVerdictMapKeyType::Ipv6Addr,
+            _ => return None,
+        },
+        elements: Vec::new(),
+    })
+}
+
+/// Decode a set without its elements, returning [None] if it isn't a plain set that [encode_set] supports.
+pub fn decode_set(nfproto: u8, attrs: &[u8]) -> Option<Set<'static>> {
+    let mut table = None;
+    let mut name = None;
+    let mut flags = None;
+    let mut key_type = None;
+    let mut key_len = None;
+
+    for (attr_type, payload) in Attrs::new(attrs) {
+        match attr_type {
+            NFTA_SET_TABLE => table = parse_str(payload),
+            NFTA_SET_NAME => name = parse_str(payload),
+            NFTA_SET_FLAGS => flags = parse_be32(payload),
+            NFTA_SET_KEY_TYPE => key_type = parse_be32(payload),
+            NFTA_SET_KEY_LEN => key_len = parse_be32(payload),
+            NFTA_SET_DATA_TYPE => return None,
+            _ => {}
+        }
+    }
+
+    let set_type = match (key_type?, key_len?) {
+        (NFT_TYPE_IPADDR, 4) => SetType::Ipv4Addr,
+        (NFT_TYPE_IP6ADDR, 16) => SetType::Ipv6Addr,
+        (NFT_TYPE_INET_SERVICE, 2) => SetType::InetService,
+        (NFT_TYPE_IFNAME, 16) => SetType::Ifname,
+        _ => return None,
+    };
+
+    Some(Set {
+        family: nfproto_to_family(nfproto)?,
+        table: table?.into(),
+        name: name?.into(),
+        handle: None,
+        set_type: SetTypeValue::Single(set_type),
+        policy: None,
+        flags: match flags.unwrap_or_default() {
+            0 => None,
+            NFT_SET_INTERVAL => Some(HashSet::from([SetFlag::Interval])),
+            _ => return None,
+        },
+        elem: None,
+        timeout: None,
+        gc_interval: None,
+        size: None,
+        comment: None,
+    })
+}
+
+/// Decode the keys of the elements of a plain set from a message listing them, each alongside whether it ends an
+/// interval, or return [None] if any of them can't be decoded.
+pub fn decode_set_keys(attrs: &[u8], set: &Set) -> Option<Vec<(u128, bool)>> {
+    let (_, key_len) = set_key(set)?;
+    let mut keys = Vec::new();
+
+    for (attr_type, payload) in Attrs::new(attrs) {
+        if attr_type != NFTA_SET_ELEM_LIST_ELEMENTS {
+            continue;
+        }
+
+        for (attr_type, elem) in Attrs::new(payload) {
+            if attr_type != NFTA_LIST_ELEM {
+                return None;
+            }
+
+            let mut key = None;
+            let mut elem_flags = 0;
+
+            for (attr_type, payload) in Attrs::new(elem) {
+                match attr_type {
+                    NFTA_SET_ELEM_KEY => {
+                        let (_, value) = Attrs::new(payload).find(|(attr_type, _)| *attr_type == NFTA_DATA_VALUE)?;
+
+                        if value.len() != key_len {
+                            return None;
+                        }
+
+                        key = Some(value.iter().fold(0u128, |key, byte| (key << 8) | *byte as u128));
+                    }
+                    NFTA_SET_ELEM_FLAGS => elem_flags = parse_be32(payload)?,
+                    _ => {}
+                }
+            }
+
+            keys.push((key?, elem_flags & NFT_SET_ELEM_INTERVAL_END != 0));
+        }
+    }
+
+    Some(keys)
+}
+
+/// Turn the decoded keys of the elements of a plain set into elements shaped like those in the JSON output of nft. The
+/// interval that an element starts lasts until right before the next element, or otherwise until the last possible
+/// key, and is turned into a single key, a prefix or a range.
+pub fn set_elements(set: &Set, mut keys: Vec<(u128, bool)>) -> Option<Vec<Expression<'static>>> {
+    let (key_type, key_len) = set_key(set)?;
+
+    // interface names are as long as IPv6 addresses, but never part of intervals
+    if key_type == NFT_TYPE_IFNAME {
+        return keys
+            .into_iter()
+            .map(|(key, _)| Some(Expression::String(parse_str(&key.to_be_bytes())?.into())))
+            .collect();
+    }
+
+    let value = |key: u128| match key_len {
+        4 => Expression::String(IpAddr::from((key as u32).to_be_bytes()).to_string().into()),
+        16 => Expression::String(IpAddr::from(key.to_be_bytes()).to_string().into()),
+        _ => Expression::Number(key as u32),
+    };
+
+    if !is_interval(set) {
+        return Some(keys.into_iter().map(|(key, _)| value(key)).collect());
+    }
+
+    keys.sort_unstable();
+    let mut elements = Vec::new();
+
+    for (index, (start, is_end)) in keys.iter().copied().enumerate() {
+        if is_end {
+            continue;
+        }
+
+        let end = keys.get(index + 1).map_or(max_key(key_len), |(next, _)| next - 1);
+        let host_mask = end - start;
+
+        elements.push(if start == end {
+            value(start)
+        } else if is_addr(key_type) && host_mask & host_mask.wrapping_add(1) == 0 && start & host_mask == 0 {
+            Expression::Named(NamedExpression::Prefix(Prefix {
+                addr: Box::new(value(start)),
+                len: key_len as u32 * 8 - host_mask.count_ones(),
+            }))
+        } else {
+            Expression::Range(Box::new(Range {
+                range: [value(start), value(end)],
+            }))
+        });
+    }
+
+    Some(elements)
+}
+
+/// The nft data type and length of the keys of a plain set, or [None] if they aren't supported natively.
+fn set_key(set: &Set) -> Option<(u32, usize)> {
+    if set
+        .flags
+        .as_ref()
+        .is_some_and(|flags| flags.iter().any(|flag| *flag != SetFlag::Interval))
+    {
+        return None;
+    }
+
+    match set.set_type {
+        SetTypeValue::Single(SetType::Ipv4Addr) => Some((NFT_TYPE_IPADDR, 4)),
+        SetTypeValue::Single(SetType::Ipv6Addr) => Some((NFT_TYPE_IP6ADDR, 16)),
+        SetTypeValue::Single(SetType::InetService) => Some((NFT_TYPE_INET_SERVICE, 2)),
+        SetTypeValue::Single(SetType::Ifname) if !is_interval(set) => Some((NFT_TYPE_IFNAME, IFNAMSIZ)),
+        _ => None,
+    }
+}
+
+#[inline]
+fn is_interval(set: &Set) -> bool {
+    set.flags.as_ref().is_some_and(|flags| flags.contains(&SetFlag::Interval))
+}
+
+#[inline]
+fn is_addr(key_type: u32) -> bool {
+    key_type == NFT_TYPE_IPADDR || key_type == NFT_TYPE_IP6ADDR
+}
+
+#[inline]
+fn max_key(key_len: usize) -> u128 {
+    u128::MAX >> (128 - key_len * 8)
+}
+
+fn parse_key(expr: &Expression, key_type: u32) -> Option<u128> {
+    match (expr, key_type) {
+        (Expression::Number(port), NFT_TYPE_INET_SERVICE) => Some(u16::try_from(*port).ok()? as u128),
+        (Expression::String(addr), NFT_TYPE_IPADDR) => match addr.parse::<IpAddr>().ok()? {
+            IpAddr::V4(addr) => Some(u32::from(addr) as u128),
+            IpAddr::V6(_) => None,
+        },
+        (Expression::String(addr), NFT_TYPE_IP6ADDR) => match addr.parse::<IpAddr>().ok()? {
+            IpAddr::V6(addr) => Some(u128::from(addr)),
+            IpAddr::V4(_) => None,
+        },
+        (Expression::String(ifname), NFT_TYPE_IFNAME) if ifname.len() < IFNAMSIZ => {
+            let mut key = [0; IFNAMSIZ];
+            key[..ifname.len()].copy_from_slice(ifname.as_bytes());
+            Some(u128::from_be_bytes(key))
+        }
+        _ => None,
+    }
+}
+
+/// Decode the elements of a verdict map from a message listing them, or return [None] if any of them can't be decoded.
+pub fn decode_elements(attrs: &[u8], key_type: NfVerdictMapKeyType) -> Option<Vec<(String, Verdict<'static>)>> {
+    let mut elements = Vec::new();
+
+    for (attr_type, payload) in Attrs::new(attrs) {
+        if attr_type != NFTA_SET_ELEM_LIST_ELEMENTS {
+            continue;
+        }
+
+        for (attr_type, elem) in Attrs::new(payload) {
+            if attr_type != NFTA_LIST_ELEM {
+                return None;
+            }
+
+            let mut key = None;
+            let mut verdict = None;
+
+            for (attr_type, payload) in Attrs::new(elem) {
+                match attr_type {
+                    NFTA_SET_ELEM_KEY => {
+                        let (_, value) = Attrs::new(payload).find(|(attr_type, _)| *attr_type == NFTA_DATA_VALUE)?;
+                        key = Some(match key_type {
+                            NfVerdictMapKeyType::Ifname => parse_str(value)?,
+                            NfVerdictMapKeyType::Ipv4Addr | NfVerdictMapKeyType::Ipv6Addr => decode_addr(value)?.to_string(),
+                        });
+                    }
+                    NFTA_SET_ELEM_DATA => verdict = Some(parse_verdict(payload)?),
+                    _ => {}
+                }
+            }
+
+            elements.push((key?, verdict?));
+        }
+    }
+
+    Some(elements)
+}
@@ -5,6 +5,7 @@ use std::os::unix::io::IntoRawFd;
 use std::path::{Path, PathBuf};
 use std::thread::{self, JoinHandle};
 
+use fcnet_types::DEFAULT_NETNS_DIR;
 use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::sched::{setns, unshare, CloneFlags};
 use nix::unistd::gettid;
@@ -13,6 +14,7 @@ use nix::unistd::gettid;
 #[derive(Debug)]
 pub enum NetNsError {
     CreateNsDirError(std::io::Error),
+    ReadNsDirError(std::io::Error),
     CreateNsError(std::io::Error),
     OpenNsError(std::path::PathBuf, std::io::Error),
     CloseNsError(nix::Error),
@@ -29,6 +31,7 @@ impl std::fmt::Display for NetNsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             NetNsError::CreateNsDirError(err) => write!(f, "Cannot create netns directory: {err}"),
+            NetNsError::ReadNsDirError(err) => write!(f, "Cannot read netns directory: {err}"),
             NetNsError::CreateNsError(err) => write!(f, "Cannot create netns: {err}"),
             NetNsError::OpenNsError(path, err) => write!(f, "Cannot open netns {}: {err}", path.display()),
             NetNsError::CloseNsError(err) => write!(f, "Cannot close netns: {err}"),
@@ -87,17 +90,24 @@ pub trait NetNsEnvironment {
     }
 }
 
-#[derive(Copy, Clone, Default, Debug)]
-pub struct DefaultNetNsEnvironment;
+/// An environment persisting network namespaces in the given directory, which is [DEFAULT_NETNS_DIR] by default.
+#[derive(Clone, Debug)]
+pub struct DirNetNsEnvironment(pub PathBuf);
 
-impl NetNsEnvironment for DefaultNetNsEnvironment {
+impl Default for DirNetNsEnvironment {
+    fn default() -> Self {
+        Self(PathBuf::from(DEFAULT_NETNS_DIR))
+    }
+}
+
+impl NetNsEnvironment for DirNetNsEnvironment {
     fn persist_dir(&self) -> PathBuf {
-        PathBuf::from("/var/run/netns")
+        self.0.clone()
     }
 }
 
 #[derive(Debug)]
-pub struct NetNs<E: NetNsEnvironment = DefaultNetNsEnvironment> {
+pub struct NetNs<E: NetNsEnvironment = DirNetNsEnvironment> {
     file: File,
     path: PathBuf,
     env: Option<E>,
@@ -110,11 +120,10 @@ impl<E: NetNsEnvironment> NetNs<E> {
         // create an empty file at the mount point
         let ns_path = env.persist_dir().join(ns_name.as_ref());
         let _ = File::create(&ns_path).map_err(NetNsError::CreateNsError)?;
-        Self::persistent(&ns_path, true).map_err(|e| {
+        Self::persistent(&ns_path, true).inspect_err(|_| {
             // Ensure the mount point is cleaned up on errors; if the namespace was successfully
             // mounted this will have no effect because the file is in-use
             std::fs::remove_file(&ns_path).ok();
-            e
         })?;
         Self::get_from_env(ns_name, env)
     }
@@ -124,11 +133,7 @@ impl<E: NetNsEnvironment> NetNs<E> {
             let ns_path_clone = ns_path.as_ref().to_path_buf();
             let new_thread: JoinHandle<Result<(), NetNsError>> = thread::spawn(move || Self::persistent(&ns_path_clone, false));
             match new_thread.join() {
-                Ok(t) => {
-                    if let Err(e) = t {
-                        return Err(e);
-                    }
-                }
+                Ok(t) => t?,
                 Err(e) => {
                     return Err(NetNsError::JoinThreadError(format!("{:?}", e)));
                 }
@@ -156,6 +161,10 @@ impl<E: NetNsEnvironment> NetNs<E> {
         &self.file
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn enter(&self) -> Result<(), NetNsError> {
         setns(&self.file, CloneFlags::CLONE_NEWNET).map_err(NetNsError::SetnsError)
     }
@@ -171,6 +180,25 @@ impl<E: NetNsEnvironment> NetNs<E> {
         })
     }
 
+    pub fn list_names_from_env(env: E) -> Result<Vec<String>, NetNsError> {
+        let read_dir = match std::fs::read_dir(env.persist_dir()) {
+            Ok(read_dir) => read_dir,
+            // no netns has ever been persisted
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(NetNsError::ReadNsDirError(err)),
+        };
+
+        let mut names = Vec::new();
+        for entry in read_dir {
+            let entry = entry.map_err(NetNsError::ReadNsDirError)?;
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
     pub fn remove(self) -> Result<(), NetNsError> {
         // need close first
         nix::unistd::close(self.file.into_raw_fd()).map_err(NetNsError::CloseNsError)?;
@@ -193,12 +221,16 @@ impl<E: NetNsEnvironment> NetNs<E> {
 }
 
 impl NetNs {
-    pub fn new<S: AsRef<str>>(ns_name: S) -> Result<Self, NetNsError> {
-        Self::new_with_env(ns_name, DefaultNetNsEnvironment)
-    }
+    /// Get the netns the current thread is in, which isn't persisted and thus is never unmounted by [NetNs::remove].
+    pub fn current() -> Result<Self, NetNsError> {
+        let ns_path = get_current_thread_netns_path();
+        let file = File::open(&ns_path).map_err(|e| NetNsError::OpenNsError(ns_path.clone(), e))?;
 
-    pub fn get<S: AsRef<str>>(ns_name: S) -> Result<Self, NetNsError> {
-        Self::get_from_env(ns_name, DefaultNetNsEnvironment)
+        Ok(Self {
+            file,
+            path: ns_path,
+            env: None,
+        })
     }
 }
 
@@ -0,0 +1,152 @@
+use std::net::IpAddr;
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerPortForward};
+use nftables::{
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Range},
+    schema::Rule,
+    stmt::{Match, NATFamily, Operator, Statement, NAT},
+    types::NfFamily,
+};
+
+use crate::{
+    counters::counted,
+    layout::NfTaggedRule,
+    util::{nat_proto_from_addr, nf_port_rule_tag, FirecrackerNetworkExt},
+    FirecrackerNetworkObjectType,
+};
+
+/// The rules of a network in the outer netns that DNAT connections to its forwarded ports to the given address, which
+/// is the guest for simple networks and the inner end of the veth pair for namespaced ones, and that accept the
+/// forwarding of these connections via the given link towards the guest, which is the tap device or the outer end of the
+/// veth pair respectively. The latter precede the rule dropping any other new connections towards the guest.
+pub fn outer_port_forward_rules(network: &FirecrackerNetwork, dnat_addr: IpAddr, link: &str) -> Vec<NfTaggedRule> {
+    let nf_table = &network.nf_table;
+    let mut rules = Vec::new();
+
+    for (index, port_forward) in network.port_forwards.iter().enumerate() {
+        let mut dnat_expr = vec![iifname_match(&network.iface_name)];
+
+        if let Some(host_addr) = port_forward.host_addr {
+            dnat_expr.push(daddr_match(host_addr));
+        }
+
+        dnat_expr.push(host_dport_match(port_forward));
+        dnat_expr.push(dnat_statement(dnat_addr, port_forward.guest_port, network.nf_family()));
+
+        rules.push(NfTaggedRule {
+            object_type: FirecrackerNetworkObjectType::NfPortDnatRule,
+            identifier: format!("{port_forward} to {dnat_addr}"),
+            rule: Rule {
+                family: network.nf_family(),
+                table: nf_table.name.clone().into(),
+                chain: nf_table.prerouting_chain.name.clone().into(),
+                expr: dnat_expr.into(),
+                handle: None,
+                index: None,
+                comment: Some(
+                    nf_port_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfPortDnatRule, index).into(),
+                ),
+            },
+        });
+
+        rules.push(NfTaggedRule {
+            object_type: FirecrackerNetworkObjectType::NfPortForwardRule,
+            identifier: format!("{port_forward} to {link}"),
+            rule: Rule {
+                family: network.nf_family(),
+                table: nf_table.name.clone().into(),
+                chain: nf_table.filter_chain.name.clone().into(),
+                expr: [
+                    iifname_match(&network.iface_name),
+                    oifname_match(link),
+                    daddr_match(dnat_addr),
+                    guest_dport_match(port_forward),
+                ]
+                .into_iter()
+                .chain(counted(Statement::Accept(None)))
+                .collect(),
+                handle: None,
+                index: None,
+                comment: Some(
+                    nf_port_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfPortForwardRule, index).into(),
+                ),
+            },
+        });
+    }
+
+    rules
+}
+
+pub fn iifname_match(iifname: &str) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
+        right: Expression::String(iifname.to_string().into()),
+        op: Operator::EQ,
+    })
+}
+
+pub fn oifname_match(oifname: &str) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
+        right: Expression::String(oifname.to_string().into()),
+        op: Operator::EQ,
+    })
+}
+
+pub fn daddr_match(daddr: IpAddr) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: nat_proto_from_addr(daddr),
+            field: "daddr".into(),
+        }))),
+        right: Expression::String(daddr.to_string().into()),
+        op: Operator::EQ,
+    })
+}
+
+/// Match the destination port of connections to a forwarded port as they arrive on the host.
+fn host_dport_match(port_forward: &FirecrackerPortForward) -> Statement<'static> {
+    let right = match port_forward.host_port_end {
+        Some(host_port_end) => Expression::Range(Box::new(Range {
+            range: [
+                Expression::Number(port_forward.host_port.into()),
+                Expression::Number(host_port_end.into()),
+            ],
+        })),
+        None => Expression::Number(port_forward.host_port.into()),
+    };
+
+    dport_match(port_forward, right)
+}
+
+/// Match the destination port of connections to a forwarded port once they have been DNATed towards the guest.
+pub fn guest_dport_match(port_forward: &FirecrackerPortForward) -> Statement<'static> {
+    match port_forward.guest_port {
+        Some(guest_port) => dport_match(port_forward, Expression::Number(guest_port.into())),
+        None => host_dport_match(port_forward),
+    }
+}
+
+fn dport_match(port_forward: &FirecrackerPortForward, right: Expression<'static>) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: port_forward.protocol.name().into(),
+            field: "dport".into(),
+        }))),
+        right,
+        op: Operator::EQ,
+    })
+}
+
+pub fn dnat_statement(addr: IpAddr, port: Option<u16>, nf_family: NfFamily) -> Statement<'static> {
+    Statement::DNAT(Some(NAT {
+        addr: Some(Expression::String(addr.to_string().into())),
+        family: match (nf_family, addr) {
+            (NfFamily::INet, IpAddr::V4(_)) => Some(NATFamily::IP),
+            (NfFamily::INet, IpAddr::V6(_)) => Some(NATFamily::IP6),
+            _ => None,
+        },
+        port: port.map(|port| Expression::Number(port.into())),
+        flags: None,
+    }))
+}
@@ -0,0 +1,115 @@
+use crate::{FirecrackerNetworkError, FirecrackerNetworkObjectType};
+
+/// The state of an object that is expected to exist on the host for a [FirecrackerNetwork](fcnet_types::FirecrackerNetwork).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FirecrackerNetworkObjectState {
+    /// The object exists and matches the configuration of the network.
+    Present,
+    /// The object doesn't exist.
+    Missing,
+    /// The object exists, but differs from the configuration of the network (i.e. a link is down, a chain is bound to
+    /// the wrong hook or a route has the wrong gateway).
+    Mismatched,
+}
+
+/// A single entry of a [FirecrackerNetworkCheckReport], describing one expected object.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FirecrackerNetworkCheckEntry {
+    /// The type of the object.
+    pub object_type: FirecrackerNetworkObjectType,
+    /// A human-readable identifier of the object, such as the name of a link or chain, or an IP address.
+    pub identifier: String,
+    /// The name of the network namespace the object resides in, or [None] if it resides in the host network namespace.
+    pub netns_name: Option<String>,
+    /// The state the object was found in.
+    pub state: FirecrackerNetworkObjectState,
+}
+
+/// A report produced by [check](crate::check) that lists every object expected to exist on the host for a
+/// [FirecrackerNetwork](fcnet_types::FirecrackerNetwork) alongside the state it was found in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FirecrackerNetworkCheckReport {
+    entries: Vec<FirecrackerNetworkCheckEntry>,
+}
+
+impl FirecrackerNetworkCheckReport {
+    /// All entries of this report in the order they were checked.
+    pub fn entries(&self) -> &[FirecrackerNetworkCheckEntry] {
+        &self.entries
+    }
+
+    /// The entries of all objects that are present.
+    pub fn present(&self) -> impl Iterator<Item = &FirecrackerNetworkCheckEntry> {
+        self.with_state(FirecrackerNetworkObjectState::Present)
+    }
+
+    /// The entries of all objects that are missing.
+    pub fn missing(&self) -> impl Iterator<Item = &FirecrackerNetworkCheckEntry> {
+        self.with_state(FirecrackerNetworkObjectState::Missing)
+    }
+
+    /// The entries of all objects that are mismatched.
+    pub fn mismatched(&self) -> impl Iterator<Item = &FirecrackerNetworkCheckEntry> {
+        self.with_state(FirecrackerNetworkObjectState::Mismatched)
+    }
+
+    /// Whether all expected objects are present.
+    pub fn is_healthy(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|entry| entry.state == FirecrackerNetworkObjectState::Present)
+    }
+
+    /// Convert this report into a [Result] that contains an error for the first object that isn't present, which is
+    /// the behavior of [FirecrackerNetworkOperation::Check](fcnet_types::FirecrackerNetworkOperation::Check).
+    pub fn into_result(self) -> Result<(), FirecrackerNetworkError> {
+        match self
+            .entries
+            .into_iter()
+            .find(|entry| entry.state != FirecrackerNetworkObjectState::Present)
+        {
+            Some(entry) if entry.state == FirecrackerNetworkObjectState::Missing => {
+                Err(FirecrackerNetworkError::ObjectNotFound(entry.object_type))
+            }
+            Some(entry) => Err(FirecrackerNetworkError::ObjectMismatched(entry.object_type)),
+            None => Ok(()),
+        }
+    }
+
+    fn with_state(&self, state: FirecrackerNetworkObjectState) -> impl Iterator<Item = &FirecrackerNetworkCheckEntry> {
+        self.entries.iter().filter(move |entry| entry.state == state)
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        object_type: FirecrackerNetworkObjectType,
+        identifier: impl Into<String>,
+        state: FirecrackerNetworkObjectState,
+    ) {
+        self.entries.push(FirecrackerNetworkCheckEntry {
+            object_type,
+            identifier: identifier.into(),
+            netns_name: None,
+            state,
+        });
+    }
+
+    pub(crate) fn push_found(&mut self, object_type: FirecrackerNetworkObjectType, identifier: impl Into<String>, found: bool) {
+        self.push(
+            object_type,
+            identifier,
+            match found {
+                true => FirecrackerNetworkObjectState::Present,
+                false => FirecrackerNetworkObjectState::Missing,
+            },
+        );
+    }
+
+    #[cfg(feature = "namespaced")]
+    pub(crate) fn extend_in_netns(&mut self, other: FirecrackerNetworkCheckReport, netns_name: &str) {
+        self.entries.extend(other.entries.into_iter().map(|mut entry| {
+            entry.netns_name = Some(netns_name.to_string());
+            entry
+        }));
+    }
+}
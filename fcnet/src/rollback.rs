@@ -0,0 +1,309 @@
+use std::marker::PhantomData;
+
+use fcnet_types::FirecrackerNetwork;
+use nftables::{
+    batch::Batch,
+    schema::{Chain, NfListObject, NfObject, Rule},
+    types::NfFamily,
+};
+
+#[cfg(feature = "namespaced")]
+use crate::netns::{DirNetNsEnvironment, NetNs};
+use crate::{
+    anti_spoofing::delete_chain,
+    backend::Backend,
+    context::FirecrackerNetworkContext,
+    layout::{delete_dispatched_rules, find_tagged_rule, NfDispatchedRule, NfSet},
+    util::{
+        apply_ruleset, get_current_ruleset, get_current_ruleset_with_verdict_maps, get_link_index, parse_nf_dispatch_rule_tag,
+    },
+    FirecrackerNetworkError,
+};
+
+/// An object that was created on the host during an add operation and needs to be removed if the operation
+/// doesn't run to completion.
+#[derive(Debug)]
+pub enum RollbackObject {
+    /// A link in the outer network namespace, identified by its name.
+    Link(String),
+    /// A persistent network namespace, identified by its name and the environment it was persisted in. Removing it also
+    /// removes everything inside it.
+    #[cfg(feature = "namespaced")]
+    Netns(String, DirNetNsEnvironment),
+    /// A set of nftables rules in the outer network namespace.
+    NfRules(Vec<Rule<'static>>),
+    /// A set of nftables rules in the outer network namespace that use the verdict map layout, alongside the chains
+    /// and verdict map elements they are dispatched through. The undispatched ones reside in the base chains and are
+    /// removed like [RollbackObject::NfRules].
+    NfDispatchedRules(Vec<NfDispatchedRule>),
+    /// A set of nftables sets in the outer network namespace that rules look up, which is recorded before the rules so
+    /// that it is removed after them. Only the elements that were added to shared sets are removed.
+    NfSets(Vec<NfSet>),
+    /// A chain in the outer network namespace alongside the rules within, which is flushed and deleted.
+    NfChain(Chain<'static>),
+    /// The table, base chains and rules dispatching into verdict maps that were missing in the outer network namespace
+    /// and thus created alongside the rules of a network, which are recorded before those rules so that they are
+    /// removed after them. Only the objects that no other network has come to use in the meantime are removed.
+    NfBaseObjects(Vec<NfListObject<'static>>),
+}
+
+/// A record of every object created by an add operation. If the operation fails, [Rollback::finish] removes these
+/// objects in reverse order of creation. If the operation is instead cancelled by dropping its future, the same is done
+/// in the background from a separate OS thread, as no async context is available inside [Drop].
+pub struct Rollback<B: Backend> {
+    nft_path: Option<String>,
+    objects: Vec<RollbackObject>,
+    phantom: PhantomData<B>,
+}
+
+impl<B: Backend> Rollback<B> {
+    pub fn new(network: &FirecrackerNetwork) -> Self {
+        Self {
+            nft_path: network.nft_path.clone(),
+            objects: Vec::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn push(&mut self, object: RollbackObject) {
+        self.objects.push(object);
+    }
+
+    pub async fn finish(
+        self,
+        result: Result<(), FirecrackerNetworkError>,
+        context: &FirecrackerNetworkContext<B>,
+        netlink_handle: &rtnetlink::Handle,
+    ) -> Result<(), FirecrackerNetworkError> {
+        if result.is_ok() {
+            self.commit();
+            return result;
+        }
+
+        let removes_base_objects = self
+            .objects
+            .iter()
+            .any(|object| matches!(object, RollbackObject::NfBaseObjects(_)));
+        self.revert(netlink_handle).await;
+
+        // the cached view of the nftables tables would otherwise still contain the removed base objects
+        if removes_base_objects {
+            context.invalidate_nf_table_view();
+        }
+
+        result
+    }
+
+    /// Keep every recorded object, since the operation has completed.
+    pub fn commit(mut self) {
+        self.objects.clear();
+    }
+
+    /// Remove every recorded object in reverse order of creation, ignoring failures.
+    pub async fn revert(mut self, netlink_handle: &rtnetlink::Handle) {
+        // objects are popped one by one so that, if this future gets dropped midway, the remaining ones are still
+        // removed by the Drop implementation
+        while let Some(object) = self.objects.pop() {
+            let _ = remove_object::<B>(object, self.nft_path.as_deref(), netlink_handle).await;
+        }
+    }
+}
+
+impl<B: Backend> Drop for Rollback<B> {
+    fn drop(&mut self) {
+        if self.objects.is_empty() {
+            return;
+        }
+
+        let mut objects = std::mem::take(&mut self.objects);
+        let nft_path = self.nft_path.take();
+
+        std::thread::spawn(move || {
+            B::block_on_current_thread(async move {
+                let Ok((connection, netlink_handle, _)) = rtnetlink::new_connection_with_socket::<B::NetlinkSocket>() else {
+                    return;
+                };
+                B::spawn_connection(connection);
+
+                while let Some(object) = objects.pop() {
+                    let _ = remove_object::<B>(object, nft_path.as_deref(), &netlink_handle).await;
+                }
+            });
+        });
+    }
+}
+
+async fn remove_object<B: Backend>(
+    object: RollbackObject,
+    nft_path: Option<&str>,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    match object {
+        RollbackObject::Link(name) => {
+            let link_idx = get_link_index(name, netlink_handle).await?;
+            netlink_handle
+                .link()
+                .del(link_idx)
+                .execute()
+                .await
+                .map_err(FirecrackerNetworkError::NetlinkOperationError)
+        }
+        #[cfg(feature = "namespaced")]
+        RollbackObject::Netns(name, env) => NetNs::get_from_env(name, env)
+            .and_then(|netns| netns.remove())
+            .map_err(FirecrackerNetworkError::NetnsError),
+        RollbackObject::NfRules(rules) => remove_nf_rules::<B>(rules, nft_path).await,
+        RollbackObject::NfDispatchedRules(rules) => {
+            let undispatched_rules = rules
+                .iter()
+                .filter(|rule| rule.key.is_none())
+                .map(|rule| rule.rule.clone())
+                .collect::<Vec<_>>();
+
+            // the dispatched rules are removed even if the undispatched ones couldn't be
+            let undispatched_result = match undispatched_rules.is_empty() {
+                true => Ok(()),
+                false => remove_nf_rules::<B>(undispatched_rules, nft_path).await,
+            };
+
+            apply_ruleset::<B>(&delete_dispatched_rules(&rules), nft_path)
+                .await
+                .and(undispatched_result)
+        }
+        RollbackObject::NfSets(sets) => {
+            let mut batch = Batch::new();
+
+            for set in sets {
+                set.delete(&mut batch);
+            }
+
+            apply_ruleset::<B>(&batch.to_nftables(), nft_path).await
+        }
+        RollbackObject::NfChain(chain) => {
+            let mut batch = Batch::new();
+            delete_chain(&mut batch, chain);
+            apply_ruleset::<B>(&batch.to_nftables(), nft_path).await
+        }
+        RollbackObject::NfBaseObjects(objects) => remove_nf_base_objects::<B>(objects, nft_path).await,
+    }
+}
+
+async fn remove_nf_rules<B: Backend>(rules: Vec<Rule<'static>>, nft_path: Option<&str>) -> Result<(), FirecrackerNetworkError> {
+    let current_ruleset = get_current_ruleset::<B>(nft_path).await?;
+    let mut batch = Batch::new();
+
+    // created rules are located by their tags, which carry the identifier of their network, so that an identical rule
+    // of another network is never removed
+    for rule in rules.iter() {
+        if let Some(current_rule) = find_tagged_rule(&current_ruleset, rule) {
+            batch.delete(NfListObject::Rule(current_rule.clone()));
+        }
+    }
+
+    apply_ruleset::<B>(&batch.to_nftables(), nft_path).await
+}
+
+async fn remove_nf_base_objects<B: Backend>(
+    objects: Vec<NfListObject<'static>>,
+    nft_path: Option<&str>,
+) -> Result<(), FirecrackerNetworkError> {
+    let (current_ruleset, verdict_maps) = get_current_ruleset_with_verdict_maps::<B>(nft_path).await?;
+    let current_objects = || {
+        current_ruleset.objects.iter().filter_map(|object| match object {
+            NfObject::ListObject(object) => Some(object),
+            _ => None,
+        })
+    };
+    let is_created = |object: &NfListObject<'static>| {
+        objects.iter().any(|created_object| match (created_object, object) {
+            (NfListObject::Chain(created_chain), NfListObject::Chain(chain)) => {
+                created_chain.family == chain.family && created_chain.table == chain.table && created_chain.name == chain.name
+            }
+            // the only created rules are those dispatching into the chains of networks, which are tagged
+            (NfListObject::Rule(created_rule), NfListObject::Rule(rule)) => {
+                created_rule.family == rule.family
+                    && created_rule.table == rule.table
+                    && created_rule.chain == rule.chain
+                    && created_rule.comment.is_some()
+                    && created_rule.comment == rule.comment
+            }
+            _ => false,
+        })
+    };
+    let is_dispatched_into = |family: NfFamily, table: &str, map_name: &str| {
+        verdict_maps.iter().any(|verdict_map| {
+            verdict_map.family == family
+                && verdict_map.table == table
+                && verdict_map.name == map_name
+                && !verdict_map.elements.is_empty()
+        })
+    };
+    let mut batch = Batch::new();
+    let mut deleted_tables = Vec::new();
+
+    // a created table that holds nothing besides the other created objects and verdict maps no network is dispatched
+    // through is deleted as a whole, which deletes everything within it
+    for object in objects.iter() {
+        let NfListObject::Table(table) = object else {
+            continue;
+        };
+
+        let is_in_use = current_objects().any(|object| match object {
+            NfListObject::Chain(chain) => chain.family == table.family && chain.table == table.name && !is_created(object),
+            NfListObject::Rule(rule) => rule.family == table.family && rule.table == table.name && !is_created(object),
+            NfListObject::Set(set) => set.family == table.family && set.table == table.name,
+            _ => false,
+        }) || verdict_maps.iter().any(|verdict_map| {
+            verdict_map.family == table.family && verdict_map.table == table.name && !verdict_map.elements.is_empty()
+        });
+
+        if !is_in_use {
+            deleted_tables.push((table.family, table.name.clone()));
+            batch.delete(object.clone());
+        }
+    }
+
+    // otherwise, created rules are removed unless a network is dispatched through the verdict map they look up, and
+    // created chains are removed if no other rule resides in them
+    let mut deleted_rules = Vec::new();
+
+    for object in current_objects() {
+        if let NfListObject::Rule(rule) = object {
+            if !deleted_tables.contains(&(rule.family, rule.table.clone()))
+                && is_created(object)
+                && !rule
+                    .comment
+                    .as_deref()
+                    .and_then(parse_nf_dispatch_rule_tag)
+                    .is_some_and(|map_name| is_dispatched_into(rule.family, &rule.table, map_name))
+            {
+                deleted_rules.push(rule);
+                batch.delete(object.clone());
+            }
+        }
+    }
+
+    for object in current_objects() {
+        if let NfListObject::Chain(chain) = object {
+            if deleted_tables.contains(&(chain.family, chain.table.clone())) || !is_created(object) {
+                continue;
+            }
+
+            let is_in_use = current_objects().any(|object| match object {
+                NfListObject::Rule(rule) => {
+                    rule.family == chain.family
+                        && rule.table == chain.table
+                        && rule.chain == chain.name
+                        && !deleted_rules.contains(&rule)
+                }
+                _ => false,
+            });
+
+            if !is_in_use {
+                batch.delete(object.clone());
+            }
+        }
+    }
+
+    apply_ruleset::<B>(&batch.to_nftables(), nft_path).await
+}
@@ -0,0 +1,333 @@
+use fcnet_types::{FirecrackerNetwork, FirecrackerTokenBucket};
+use futures_util::{StreamExt, TryStreamExt};
+use rtnetlink::{
+    packet_core::{
+        DefaultNla, NetlinkMessage, NetlinkPayload, Nla, NlasIterator, NLM_F_ACK, NLM_F_CREATE, NLM_F_REPLACE, NLM_F_REQUEST,
+    },
+    packet_route::{
+        link::{InfoKind, LinkFlags},
+        tc::{
+            TcActionAttribute, TcActionMirrorOption, TcActionOption, TcAttribute, TcFilterU32Option, TcHandle, TcMessage,
+            TcOption,
+        },
+        RouteNetlinkMessage,
+    },
+    LinkMessageBuilder, LinkUnspec,
+};
+
+use crate::{
+    backend::Backend,
+    rollback::{Rollback, RollbackObject},
+    util::{get_link, get_link_index},
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState, FirecrackerNetworkObjectType,
+};
+
+const TBF_KIND: &str = "tbf";
+const TCA_TBF_PARMS: u16 = 1;
+const TCA_TBF_RATE64: u16 = 4;
+const TCA_TBF_BURST: u16 = 6;
+const TC_LINKLAYER_ETHERNET: u8 = 1;
+/// The parent of the filters of an ingress qdisc, which is its handle of "ffff:".
+const TC_H_INGRESS_QDISC: u32 = 0xffff0000;
+/// The shift from nanoseconds to the ticks of the packet scheduler that the buffer of a TBF qdisc is reported in.
+const PSCHED_SHIFT: u32 = 6;
+
+/// Attach the queueing disciplines shaping the traffic of this network to its given link, which is its tap device or
+/// the outer end of its veth pair, and create its IFB device should its ingress be shaped. Everything attached to the
+/// link is removed alongside it, so only the IFB device is recorded for rollback.
+pub async fn add_shaping<B: Backend>(
+    network: &FirecrackerNetwork,
+    link: &str,
+    netlink_handle: &rtnetlink::Handle,
+    rollback: &mut Rollback<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    if network.shaping.egress.is_none() && network.shaping.ingress.is_none() {
+        return Ok(());
+    }
+
+    let link_idx = get_link_index(link.to_string(), netlink_handle).await?;
+
+    if let Some(ref bucket) = network.shaping.egress {
+        replace_root_tbf(link_idx, bucket, netlink_handle).await?;
+    }
+
+    let Some(ref ingress) = network.shaping.ingress else {
+        return Ok(());
+    };
+
+    netlink_handle
+        .link()
+        .add(
+            LinkMessageBuilder::<LinkUnspec>::new_with_info_kind(InfoKind::Ifb)
+                .name(ingress.ifb_name.clone())
+                .up()
+                .build(),
+        )
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    rollback.push(RollbackObject::Link(ingress.ifb_name.clone()));
+
+    let ifb_idx = get_link_index(ingress.ifb_name.clone(), netlink_handle).await?;
+    replace_root_tbf(ifb_idx, &ingress.bucket, netlink_handle).await?;
+
+    netlink_handle
+        .qdisc()
+        .replace(link_idx as i32)
+        .ingress()
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    netlink_handle
+        .traffic_filter(link_idx as i32)
+        .add()
+        .parent(TC_H_INGRESS_QDISC)
+        .protocol((libc::ETH_P_ALL as u16).to_be())
+        .redirect(ifb_idx)
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
+
+/// Delete the IFB device of this network, which is the only object of its shaping that isn't removed alongside its link.
+pub async fn delete_shaping(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(ref ingress) = network.shaping.ingress else {
+        return Ok(());
+    };
+
+    let ifb_idx = get_link_index(ingress.ifb_name.clone(), netlink_handle).await?;
+    netlink_handle
+        .link()
+        .del(ifb_idx)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
+
+/// Check the queueing disciplines shaping the traffic of this network on its given link and its IFB device, as well as
+/// the filter redirecting the traffic arriving on the link to the IFB device. A TBF qdisc whose token bucket differs
+/// from the configured one, or any other root qdisc in its place, is mismatched.
+pub async fn check_shaping(
+    network: &FirecrackerNetwork,
+    link: &str,
+    netlink_handle: &rtnetlink::Handle,
+    report: &mut FirecrackerNetworkCheckReport,
+) -> Result<(), FirecrackerNetworkError> {
+    if network.shaping.egress.is_none() && network.shaping.ingress.is_none() {
+        return Ok(());
+    }
+
+    let link_idx = get_link(link.to_string(), netlink_handle)
+        .await?
+        .map(|link_message| link_message.header.index);
+    let qdiscs = netlink_handle
+        .qdisc()
+        .get()
+        .execute()
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    if let Some(ref bucket) = network.shaping.egress {
+        report.push(
+            FirecrackerNetworkObjectType::TcQdisc,
+            format!("{link} {TBF_KIND}"),
+            root_tbf_state(&qdiscs, link_idx, bucket),
+        );
+    }
+
+    let Some(ref ingress) = network.shaping.ingress else {
+        return Ok(());
+    };
+
+    let ifb_message = get_link(ingress.ifb_name.clone(), netlink_handle).await?;
+    let ifb_idx = ifb_message.as_ref().map(|ifb_message| ifb_message.header.index);
+    report.push(
+        FirecrackerNetworkObjectType::IpLink,
+        ingress.ifb_name.as_str(),
+        match ifb_message {
+            Some(ifb_message) if ifb_message.header.flags.contains(LinkFlags::Up) => FirecrackerNetworkObjectState::Present,
+            Some(_) => FirecrackerNetworkObjectState::Mismatched,
+            None => FirecrackerNetworkObjectState::Missing,
+        },
+    );
+    report.push(
+        FirecrackerNetworkObjectType::TcQdisc,
+        format!("{} {TBF_KIND}", ingress.ifb_name),
+        root_tbf_state(&qdiscs, ifb_idx, &ingress.bucket),
+    );
+    report.push_found(
+        FirecrackerNetworkObjectType::TcQdisc,
+        format!("{link} ingress"),
+        qdiscs.iter().any(|qdisc| {
+            Some(qdisc.header.index as u32) == link_idx
+                && qdisc.header.parent == TcHandle::INGRESS
+                && qdisc.attributes.contains(&TcAttribute::Kind("ingress".to_string()))
+        }),
+    );
+
+    let redirects_to_ifb = match (link_idx, ifb_idx) {
+        (Some(link_idx), Some(ifb_idx)) => netlink_handle
+            .traffic_filter(link_idx as i32)
+            .get()
+            .ingress()
+            .execute()
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+            .iter()
+            .any(|filter| redirects_to(filter, ifb_idx)),
+        _ => false,
+    };
+
+    report.push_found(
+        FirecrackerNetworkObjectType::TcFilter,
+        format!("{link} to {}", ingress.ifb_name),
+        redirects_to_ifb,
+    );
+    Ok(())
+}
+
+/// Replace the root qdisc of the link with the given index by a TBF qdisc with the given token bucket. rtnetlink has no
+/// request builder for qdiscs with options, so the request is assembled by hand like "tc qdisc replace" would.
+async fn replace_root_tbf(
+    link_idx: u32,
+    bucket: &FirecrackerTokenBucket,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let mut message = TcMessage::with_index(link_idx as i32);
+    message.header.parent = TcHandle::ROOT;
+    message.attributes.push(TcAttribute::Kind(TBF_KIND.to_string()));
+    message.attributes.push(TcAttribute::Options(tbf_options(bucket)));
+
+    let mut request = NetlinkMessage::from(RouteNetlinkMessage::NewQueueDiscipline(message));
+    request.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE;
+    let mut response = netlink_handle
+        .clone()
+        .request(request)
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    while let Some(message) = response.next().await {
+        if let NetlinkPayload::Error(err) = message.payload {
+            if err.code.is_some() {
+                return Err(FirecrackerNetworkError::NetlinkOperationError(
+                    rtnetlink::Error::NetlinkError(err),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The options of a TBF qdisc with the given token bucket. The link layer of its rate is set, which spares passing a
+/// rate table, and its burst is passed in bytes, so that the kernel derives the buffer in ticks on its own.
+fn tbf_options(bucket: &FirecrackerTokenBucket) -> Vec<TcOption> {
+    let rate = u32::try_from(bucket.rate).unwrap_or(u32::MAX);
+    // struct tc_tbf_qopt: the rate and the unused peak rate as struct tc_ratespec, the limit, the buffer and the MTU
+    let mut qopt = Vec::with_capacity(36);
+    qopt.extend([0, TC_LINKLAYER_ETHERNET, 0, 0, 0, 0, 0, 0]);
+    qopt.extend(rate.to_ne_bytes());
+    qopt.extend([0; 12]);
+    qopt.extend(bucket.resolved_limit().to_ne_bytes());
+    qopt.extend(buffer_ticks(bucket).to_ne_bytes());
+    qopt.extend(0u32.to_ne_bytes());
+
+    let mut options = vec![
+        TcOption::Other(DefaultNla::new(TCA_TBF_PARMS, qopt)),
+        TcOption::Other(DefaultNla::new(TCA_TBF_BURST, bucket.burst.to_ne_bytes().to_vec())),
+    ];
+
+    if rate == u32::MAX {
+        options.push(TcOption::Other(DefaultNla::new(
+            TCA_TBF_RATE64,
+            bucket.rate.to_ne_bytes().to_vec(),
+        )));
+    }
+
+    options
+}
+
+/// The state of the root qdisc of the link with the given index, which is present if it is a TBF qdisc with the given
+/// token bucket. The buffer of the bucket is reported in ticks rather than bytes, so it is compared after rounding.
+fn root_tbf_state(qdiscs: &[TcMessage], link_idx: Option<u32>, bucket: &FirecrackerTokenBucket) -> FirecrackerNetworkObjectState {
+    let Some(qdisc) = qdiscs
+        .iter()
+        .find(|qdisc| Some(qdisc.header.index as u32) == link_idx && qdisc.header.parent == TcHandle::ROOT)
+    else {
+        return FirecrackerNetworkObjectState::Missing;
+    };
+
+    if !qdisc.attributes.contains(&TcAttribute::Kind(TBF_KIND.to_string())) {
+        return FirecrackerNetworkObjectState::Mismatched;
+    }
+
+    let mut qopt = None;
+    let mut rate64 = None;
+
+    for attribute in qdisc.attributes.iter() {
+        // the options of qdiscs unknown to netlink-packet-route are kept as a single attribute with the nested ones
+        if let TcAttribute::Options(options) = attribute {
+            for option in options {
+                let mut value = vec![0; option.value_len()];
+                option.emit_value(&mut value);
+
+                for nla in NlasIterator::new(value.as_slice()).flatten() {
+                    match nla.kind() {
+                        TCA_TBF_PARMS => qopt = Some(nla.value().to_vec()),
+                        TCA_TBF_RATE64 => rate64 = nla.value().try_into().ok().map(u64::from_ne_bytes),
+                        _ => continue,
+                    }
+                }
+            }
+        }
+    }
+
+    let qopt_u32 = |offset: usize| {
+        qopt.as_ref()
+            .and_then(|qopt| Some(u32::from_ne_bytes(qopt.get(offset..offset + 4)?.try_into().ok()?)))
+    };
+    let rate = rate64.or(qopt_u32(8).map(u64::from));
+    let matches = rate == Some(bucket.rate)
+        && qopt_u32(24) == Some(bucket.resolved_limit())
+        && qopt_u32(28).is_some_and(|buffer| buffer.abs_diff(buffer_ticks(bucket)) <= 1);
+
+    match matches {
+        true => FirecrackerNetworkObjectState::Present,
+        false => FirecrackerNetworkObjectState::Mismatched,
+    }
+}
+
+/// The time that the burst of the token bucket takes to pass at its rate in ticks of the packet scheduler.
+fn buffer_ticks(bucket: &FirecrackerTokenBucket) -> u32 {
+    let nanos = u128::from(bucket.burst) * 1_000_000_000 / u128::from(bucket.rate.max(1));
+    u32::try_from(nanos >> PSCHED_SHIFT).unwrap_or(u32::MAX)
+}
+
+/// Whether the given filter is a u32 filter with a mirred action redirecting to the link with the given index.
+fn redirects_to(filter: &TcMessage, ifb_idx: u32) -> bool {
+    filter
+        .attributes
+        .iter()
+        .filter_map(|attribute| match attribute {
+            TcAttribute::Options(options) => Some(options),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|option| match option {
+            TcOption::U32(TcFilterU32Option::Action(actions)) => Some(actions),
+            _ => None,
+        })
+        .flatten()
+        .flat_map(|action| action.attributes.iter())
+        .filter_map(|attribute| match attribute {
+            TcActionAttribute::Options(options) => Some(options),
+            _ => None,
+        })
+        .flatten()
+        .any(|option| matches!(option, TcActionOption::Mirror(TcActionMirrorOption::Parms(mirror)) if mirror.ifindex == ifb_idx))
+}
@@ -1,43 +1,79 @@
-use fcnet_types::FirecrackerNetwork;
+use fcnet_types::{FirecrackerNetwork, FirecrackerNfLayout};
 use nftables::{
-    batch::Batch,
     expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField},
-    schema::{NfListObject, NfObject, Rule},
+    schema::{NfListObject, NfObject, Nftables, Rule},
     stmt::{Match, Operator, Statement},
 };
-use nftables_async::helper::Helper;
-use tokio_tun::TunBuilder;
 
 use crate::{
+    anti_spoofing::{add_anti_spoofing, check_anti_spoofing, delete_anti_spoofing},
     backend::Backend,
+    conntrack::flush_conntrack,
+    context::FirecrackerNetworkContext,
+    counters::{counted, statements_match},
+    egress::{check_egress_policy, egress_forward_verdict, egress_policy_rules, locate_egress_policy_rules},
+    ingress::{ingress_forward_expr, ingress_policy_rules, is_ingress_policy_rule},
+    inspect::{inspect_link, FirecrackerNetworkRuleSnapshot, FirecrackerNetworkSnapshot},
+    isolation::{check_isolation, is_isolation_rule, isolation_rules, locate_isolation_rules},
+    layout::{
+        add_nf_rules, check_dispatched_rules, check_tagged_rules, delete_nf_rules, locate_undispatched_rules, NfDispatchKey,
+        NfDispatchedRule, NfTaggedRule,
+    },
+    port_forward::outer_port_forward_rules,
+    rollback::{Rollback, RollbackObject},
+    shaping::{add_shaping, check_shaping, delete_shaping},
+    sysctl::{check_sysctls, ensure_sysctls, required_sysctls},
+    tap::{check_tap, create_tap},
     util::{
-        add_base_chains_if_needed, check_base_chains, get_link_index, nat_proto_from_addr, FirecrackerNetworkExt, NO_NFT_ARGS,
+        check_base_chains, check_link, get_link_index, nat_proto_from_addr, nf_rule_tag, nf_rule_tag_object_type,
+        FirecrackerNetworkExt,
     },
-    FirecrackerNetworkError, FirecrackerNetworkObjectType, FirecrackerNetworkOperation, NFT_FILTER_CHAIN, NFT_POSTROUTING_CHAIN,
-    NFT_TABLE,
+    vmap::NfVerdictMap,
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectType, FirecrackerNetworkOperation,
 };
 
 pub async fn run<B: Backend>(
     network: &FirecrackerNetwork,
-    netlink_handle: rtnetlink::Handle,
+    context: &FirecrackerNetworkContext<B>,
     operation: FirecrackerNetworkOperation,
 ) -> Result<(), FirecrackerNetworkError> {
     match operation {
-        FirecrackerNetworkOperation::Add => add::<B>(network, netlink_handle).await,
-        FirecrackerNetworkOperation::Check => check::<B>(network, netlink_handle).await,
-        FirecrackerNetworkOperation::Delete => delete::<B>(network, netlink_handle).await,
+        FirecrackerNetworkOperation::Add => {
+            let netlink_handle = context.netlink_handle()?;
+            let mut rollback = Rollback::<B>::new(network);
+            let result = add::<B>(network, context, &netlink_handle, &mut rollback).await;
+            rollback.finish(result, context, &netlink_handle).await
+        }
+        FirecrackerNetworkOperation::Check => check::<B>(network, context).await?.into_result(),
+        FirecrackerNetworkOperation::Delete => delete::<B>(network, context).await,
     }
 }
 
-async fn add<B: Backend>(network: &FirecrackerNetwork, netlink_handle: rtnetlink::Handle) -> Result<(), FirecrackerNetworkError> {
-    TunBuilder::new()
-        .name(&network.tap_name)
-        .tap()
-        .persist()
-        .up()
-        .build()
-        .map_err(FirecrackerNetworkError::TapDeviceError)?;
-    let tap_idx = crate::util::get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+async fn add<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+    netlink_handle: &rtnetlink::Handle,
+    rollback: &mut Rollback<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    add_links::<B>(network, netlink_handle, rollback).await?;
+    add_nf_rules::<B>(context, network, nf_rules(network), rollback).await
+}
+
+/// Create the tap device of this network, assign its IP, filter its traffic against spoofing, shape it and ensure its
+/// sysctls, which is everything an add does besides the nftables rules in the table of the network.
+pub async fn add_links<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    rollback: &mut Rollback<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    // a pre-existing tap is reused by the tap builder and must not be removed on rollback
+    let tap_existed = get_link_index(network.tap_name.clone(), netlink_handle).await.is_ok();
+    create_tap::<B>(&network.tap_name, &network.tap_options, netlink_handle).await?;
+    if !tap_existed {
+        rollback.push(RollbackObject::Link(network.tap_name.clone()));
+    }
+
+    let tap_idx = get_link_index(network.tap_name.clone(), netlink_handle).await?;
     netlink_handle
         .address()
         .add(tap_idx, network.tap_ip.address(), network.tap_ip.network_length())
@@ -45,174 +81,272 @@ async fn add<B: Backend>(network: &FirecrackerNetwork, netlink_handle: rtnetlink
         .await
         .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
 
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-    let mut masquerade_rule_exists = false;
-
-    for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule)
-                    if rule.chain == NFT_POSTROUTING_CHAIN && rule.table == NFT_TABLE && rule.expr == masq_expr(network) =>
-                {
-                    masquerade_rule_exists = true;
-                }
-                _ => continue,
-            },
-            _ => continue,
-        }
-    }
-
-    let mut batch = Batch::new();
-    add_base_chains_if_needed(&network, &current_ruleset, &mut batch)?;
-
-    batch.add(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: forward_expr(network).into(),
-        handle: None,
-        index: None,
-        comment: None,
-    }));
-
-    if !masquerade_rule_exists {
-        batch.add(NfListObject::Rule(Rule {
-            family: network.nf_family(),
-            table: NFT_TABLE.into(),
-            chain: NFT_POSTROUTING_CHAIN.into(),
-            expr: masq_expr(network).into(),
-            handle: None,
-            index: None,
-            comment: None,
-        }));
-    }
-
-    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)
+    add_anti_spoofing(network, rollback).await?;
+    add_shaping(network, &network.tap_name, netlink_handle, rollback).await?;
+    ensure_sysctls(&required_sysctls(network, Some(&network.tap_name)))
 }
 
 async fn delete<B: Backend>(
     network: &FirecrackerNetwork,
-    netlink_handle: rtnetlink::Handle,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<(), FirecrackerNetworkError> {
+    delete_links::<B>(network, &*context.netlink_handle()?).await?;
+    delete_nf_rules::<B>(context, network, &nf_rules(network), |current_ruleset| {
+        locate_nf_rules(network, current_ruleset)
+    })
+    .await?;
+    flush_conntrack::<B>(network).await
+}
+
+/// Delete the tap device of this network alongside its anti-spoofing chain and IFB device, if any, which is everything a
+/// delete does besides the nftables rules in the table of the network and conntrack entries.
+pub async fn delete_links<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
 ) -> Result<(), FirecrackerNetworkError> {
-    let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    delete_anti_spoofing::<B>(network).await?;
+    let tap_idx = get_link_index(network.tap_name.clone(), netlink_handle).await?;
     netlink_handle
         .link()
         .del(tap_idx)
         .execute()
         .await
         .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    delete_shaping(network, netlink_handle).await
+}
 
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-
-    let mut forward_rule_handle = None;
-    let mut masquerade_rule_handle = None;
+/// Locate the rules of this network in the flat layout within the current ruleset, failing if any of them is missing.
+pub fn locate_nf_rules(
+    network: &FirecrackerNetwork,
+    current_ruleset: &Nftables<'static>,
+) -> Result<Vec<Rule<'static>>, FirecrackerNetworkError> {
+    let mut forward_rule = None;
+    let mut masquerade_rule = None;
+    let mut ingress_forward_rule = None;
 
     for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule) if rule.table == NFT_TABLE => {
-                    if rule.chain == NFT_FILTER_CHAIN && rule.expr == forward_expr(network) {
-                        forward_rule_handle = rule.handle;
-                    } else if rule.chain == NFT_POSTROUTING_CHAIN && rule.expr == masq_expr(network) {
-                        masquerade_rule_handle = rule.handle;
-                    }
+        if let NfObject::ListObject(NfListObject::Rule(rule)) = object {
+            match rule_object_type(network, rule) {
+                Some(FirecrackerNetworkObjectType::NfEgressForwardRule) => forward_rule = Some(rule),
+                Some(FirecrackerNetworkObjectType::NfIngressForwardRule) => ingress_forward_rule = Some(rule),
+                // the network's own tagged rule takes precedence over an equivalent untagged one, which may be shared
+                // with other networks created by older versions of fcnet
+                Some(FirecrackerNetworkObjectType::NfMasqueradeRule) if masquerade_rule.is_none() || rule.comment.is_some() => {
+                    masquerade_rule = Some(rule)
                 }
                 _ => continue,
-            },
-            _ => continue,
+            }
         }
     }
 
-    if forward_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfEgressForwardRule,
-        ));
-    }
-    if masquerade_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfMasqueradeRule,
-        ));
+    let forward_rule = forward_rule.ok_or(FirecrackerNetworkError::ObjectNotFound(
+        FirecrackerNetworkObjectType::NfEgressForwardRule,
+    ))?;
+    let masquerade_rule = masquerade_rule.ok_or(FirecrackerNetworkError::ObjectNotFound(
+        FirecrackerNetworkObjectType::NfMasqueradeRule,
+    ))?;
+
+    let mut nf_rules = nf_rules(network);
+    let mut rules = vec![forward_rule.clone(), masquerade_rule.clone()];
+
+    // networks added by older versions of fcnet have no ingress or isolation rules at all, which leaves nothing to delete
+    match ingress_forward_rule {
+        Some(ingress_forward_rule) => rules.push(ingress_forward_rule.clone()),
+        None => nf_rules.retain(|nf_rule| !is_ingress_policy_rule(&nf_rule.rule) && !is_isolation_rule(&nf_rule.rule)),
     }
 
-    let mut batch = Batch::new();
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: forward_expr(network).into(),
-        handle: forward_rule_handle,
-        index: None,
-        comment: None,
-    }));
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_POSTROUTING_CHAIN.into(),
-        expr: masq_expr(network).into(),
-        handle: masquerade_rule_handle,
-        index: None,
-        comment: None,
-    }));
-
-    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)
+    rules.extend(locate_egress_policy_rules(current_ruleset, &nf_rules)?);
+    rules.extend(locate_isolation_rules(current_ruleset, &nf_rules)?);
+    rules.extend(locate_undispatched_rules(current_ruleset, &nf_rules)?);
+    Ok(rules)
 }
 
-async fn check<B: Backend>(
+pub async fn check<B: Backend>(
     network: &FirecrackerNetwork,
-    netlink_handle: rtnetlink::Handle,
-) -> Result<(), FirecrackerNetworkError> {
-    get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<FirecrackerNetworkCheckReport, FirecrackerNetworkError> {
+    let (current_ruleset, verdict_maps) = context.get_current_ruleset_with_verdict_maps(network.nft_program()).await?;
+    let netlink_handle = context.netlink_handle()?;
+    check_with_ruleset::<B>(network, &netlink_handle, &current_ruleset, &verdict_maps).await
+}
+
+/// Check this network against an already listed ruleset, which allows many networks to share a single listing.
+pub async fn check_with_ruleset<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    current_ruleset: &Nftables<'static>,
+    verdict_maps: &[NfVerdictMap],
+) -> Result<FirecrackerNetworkCheckReport, FirecrackerNetworkError> {
+    let mut report = FirecrackerNetworkCheckReport::default();
+    check_link(&network.tap_name, &network.tap_ip, netlink_handle, &mut report).await?;
+    check_tap::<B>(&network.tap_name, &network.tap_options, netlink_handle, &mut report).await?;
+    check_shaping(network, &network.tap_name, netlink_handle, &mut report).await?;
+    check_sysctls(&required_sysctls(network, Some(&network.tap_name)), true, &mut report);
 
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
     let mut masquerade_rule_exists = false;
     let mut forward_rule_exists = false;
+    let mut ingress_forward_rule_exists = false;
+
+    check_base_chains(network, current_ruleset, &mut report);
 
-    check_base_chains(network, &current_ruleset)?;
+    if network.nf_layout == FirecrackerNfLayout::VerdictMap {
+        check_dispatched_rules(current_ruleset, verdict_maps, &nf_rules(network), &mut report);
+    }
 
     for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule) => {
-                    if rule.chain == NFT_POSTROUTING_CHAIN && rule.expr == masq_expr(network) {
-                        masquerade_rule_exists = true;
-                    } else if rule.chain == NFT_FILTER_CHAIN && rule.expr == forward_expr(network) {
-                        forward_rule_exists = true;
-                    }
-                }
+        if let NfObject::ListObject(NfListObject::Rule(rule)) = object {
+            match rule_object_type(network, rule) {
+                Some(FirecrackerNetworkObjectType::NfMasqueradeRule) => masquerade_rule_exists = true,
+                Some(FirecrackerNetworkObjectType::NfEgressForwardRule) => forward_rule_exists = true,
+                Some(FirecrackerNetworkObjectType::NfIngressForwardRule) => ingress_forward_rule_exists = true,
                 _ => continue,
-            },
-            _ => continue,
+            }
         }
     }
 
-    if !masquerade_rule_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfMasqueradeRule,
-        ));
+    report.push_found(
+        FirecrackerNetworkObjectType::NfMasqueradeRule,
+        format!("{} via {}", network.guest_ip.address(), network.iface_name),
+        masquerade_rule_exists,
+    );
+    report.push_found(
+        FirecrackerNetworkObjectType::NfEgressForwardRule,
+        format!("{} to {}", network.tap_name, network.iface_name),
+        forward_rule_exists,
+    );
+    report.push_found(
+        FirecrackerNetworkObjectType::NfIngressForwardRule,
+        format!("{} to {}", network.iface_name, network.tap_name),
+        ingress_forward_rule_exists,
+    );
+    check_egress_policy(current_ruleset, &nf_rules(network), &mut report);
+    check_isolation(network, &network.tap_name, current_ruleset, &mut report);
+    check_tagged_rules(Some(current_ruleset), &tagged_rules(network), &mut report);
+    check_anti_spoofing(network, Some(current_ruleset), &mut report);
+
+    Ok(report)
+}
+
+pub async fn inspect<B: Backend>(
+    network: &FirecrackerNetwork,
+    context: &FirecrackerNetworkContext<B>,
+) -> Result<FirecrackerNetworkSnapshot, FirecrackerNetworkError> {
+    let tap_link = inspect_link(&network.tap_name, &*context.netlink_handle()?).await?;
+
+    let current_ruleset = context.get_current_ruleset(network.nft_program()).await?;
+    let nf_rules = current_ruleset
+        .objects
+        .iter()
+        .filter_map(|object| match object {
+            NfObject::ListObject(NfListObject::Rule(rule)) => {
+                rule_object_type(network, rule).map(|object_type| FirecrackerNetworkRuleSnapshot {
+                    object_type,
+                    rule: rule.clone(),
+                })
+            }
+            _ => None,
+        })
+        .collect();
+
+    Ok(FirecrackerNetworkSnapshot {
+        tap_link,
+        nf_rules,
+        #[cfg(feature = "namespaced")]
+        namespaced: None,
+    })
+}
+
+/// The rules of this network alongside the keys they are dispatched via in the verdict map layout.
+pub fn nf_rules(network: &FirecrackerNetwork) -> Vec<NfDispatchedRule> {
+    // the egress policy precedes the forward rule, which carries its default action
+    let mut rules = egress_policy_rules(network, &network.tap_name);
+
+    // every network gets its own tagged masquerade rule, even if an equivalent one already exists for another network
+    // with the same guest IP and host interface: the duplicates act as a reference count, so that deleting one network
+    // never removes a rule that another live network depends on
+    rules.extend([
+        NfDispatchedRule::new(
+            network,
+            Rule {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                chain: network.nf_table.filter_chain.name.clone().into(),
+                expr: forward_expr(network).into(),
+                handle: None,
+                index: None,
+                comment: Some(nf_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfEgressForwardRule).into()),
+            },
+            NfDispatchKey::Iifname(network.tap_name.clone()),
+        ),
+        NfDispatchedRule::new(
+            network,
+            Rule {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                chain: network.nf_table.postrouting_chain.name.clone().into(),
+                expr: masq_expr(network).into(),
+                handle: None,
+                index: None,
+                comment: Some(nf_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfMasqueradeRule).into()),
+            },
+            NfDispatchKey::Saddr(network.guest_ip.address()),
+        ),
+        NfDispatchedRule::new(
+            network,
+            Rule {
+                family: network.nf_family(),
+                table: network.nf_table.name.clone().into(),
+                chain: network.nf_table.filter_chain.name.clone().into(),
+                expr: ingress_forward_expr(network, &network.tap_name).into(),
+                handle: None,
+                index: None,
+                comment: Some(nf_rule_tag(network.resolved_id(), FirecrackerNetworkObjectType::NfIngressForwardRule).into()),
+            },
+            NfDispatchKey::Oifname(network.tap_name.clone()),
+        ),
+    ]);
+
+    rules.extend(isolation_rules(network, &network.tap_name));
+    rules.extend(
+        tagged_rules(network)
+            .into_iter()
+            .map(|tagged_rule| NfDispatchedRule::undispatched(network, tagged_rule.rule)),
+    );
+    rules
+}
+
+/// The rules of this network that exist once per forwarded port or entry of the ingress policy, followed by the rule
+/// dropping all other traffic towards the guest.
+fn tagged_rules(network: &FirecrackerNetwork) -> Vec<NfTaggedRule> {
+    let mut rules = outer_port_forward_rules(network, network.guest_ip.address(), &network.tap_name);
+    rules.extend(ingress_policy_rules(network, &network.tap_name));
+    rules
+}
+
+/// Determine which of this network's rules the given rule is, if any.
+fn rule_object_type(network: &FirecrackerNetwork, rule: &Rule) -> Option<FirecrackerNetworkObjectType> {
+    if rule.table != network.nf_table.name || rule.family != network.nf_family() {
+        return None;
     }
 
-    if !forward_rule_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfEgressForwardRule,
-        ));
+    if let Some(ref comment) = rule.comment {
+        return nf_rule_tag_object_type(comment, network.resolved_id());
     }
 
-    Ok(())
+    // untagged rules can only have been created by older versions of fcnet, so fall back to comparing expressions
+
+    if rule.chain == network.nf_table.postrouting_chain.name && statements_match(&rule.expr, &masq_expr(network)) {
+        Some(FirecrackerNetworkObjectType::NfMasqueradeRule)
+    } else if rule.chain == network.nf_table.filter_chain.name && statements_match(&rule.expr, &forward_expr(network)) {
+        Some(FirecrackerNetworkObjectType::NfEgressForwardRule)
+    } else {
+        None
+    }
 }
 
 #[inline]
 fn masq_expr(network: &FirecrackerNetwork) -> Vec<Statement<'static>> {
-    vec![
+    [
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
                 protocol: nat_proto_from_addr(network.guest_ip.address()),
@@ -226,13 +360,15 @@ fn masq_expr(network: &FirecrackerNetwork) -> Vec<Statement<'static>> {
             right: Expression::String(network.iface_name.clone().into()),
             op: Operator::EQ,
         }),
-        Statement::Masquerade(None),
     ]
+    .into_iter()
+    .chain(counted(Statement::Masquerade(None)))
+    .collect()
 }
 
 #[inline]
 fn forward_expr(network: &FirecrackerNetwork) -> Vec<Statement<'static>> {
-    vec![
+    [
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
             right: Expression::String(network.tap_name.clone().into()),
@@ -243,6 +379,8 @@ fn forward_expr(network: &FirecrackerNetwork) -> Vec<Statement<'static>> {
             right: Expression::String(network.iface_name.clone().into()),
             op: Operator::EQ,
         }),
-        Statement::Accept(None),
     ]
+    .into_iter()
+    .chain(counted(egress_forward_verdict(network)))
+    .collect()
 }
@@ -0,0 +1,113 @@
+use std::path::PathBuf;
+
+use fcnet_types::{FirecrackerIpStack, FirecrackerNetwork};
+
+use crate::{
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState, FirecrackerNetworkObjectType,
+};
+
+/// A sysctl of the network namespace of the current thread alongside the value that a network requires it to have.
+/// Since "/proc/sys/net" always reflects the network namespace of the thread accessing it, a sysctl is read and written
+/// in whichever netns the thread has entered.
+#[derive(Debug, Clone)]
+pub struct Sysctl {
+    components: Vec<String>,
+    value: &'static str,
+}
+
+impl Sysctl {
+    fn new(components: &[&str], value: &'static str) -> Self {
+        Self {
+            components: components.iter().map(|component| component.to_string()).collect(),
+            value,
+        }
+    }
+
+    /// The name of this sysctl in the notation of sysctl(8), such as "net.ipv4.ip_forward".
+    pub fn name(&self) -> String {
+        format!("net.{}", self.components.join("."))
+    }
+
+    fn path(&self) -> PathBuf {
+        // components are joined as path segments, since interface names may themselves contain dots
+        let mut path = PathBuf::from("/proc/sys/net");
+        path.extend(self.components.iter());
+        path
+    }
+
+    fn state(&self) -> FirecrackerNetworkObjectState {
+        match std::fs::read_to_string(self.path()) {
+            Ok(value) if value.trim() == self.value => FirecrackerNetworkObjectState::Present,
+            Ok(_) => FirecrackerNetworkObjectState::Mismatched,
+            Err(_) => FirecrackerNetworkObjectState::Missing,
+        }
+    }
+
+    fn ensure(&self) -> Result<(), FirecrackerNetworkError> {
+        // sysctls that already have the right value aren't written, so that a host configured beforehand doesn't need
+        // "/proc/sys" to be writable
+        if self.state() == FirecrackerNetworkObjectState::Present {
+            return Ok(());
+        }
+
+        std::fs::write(self.path(), self.value).map_err(FirecrackerNetworkError::IoError)
+    }
+}
+
+/// The sysctls that the given network requires in a netns, which are none unless they are managed: IP forwarding for
+/// its IP stack and, if the tap device of the network resides in the netns, the options enabled on the tap device.
+pub fn required_sysctls(network: &FirecrackerNetwork, tap_name: Option<&str>) -> Vec<Sysctl> {
+    let options = &network.sysctls;
+    let mut sysctls = Vec::new();
+
+    if !options.managed {
+        return sysctls;
+    }
+
+    let ipv4 = matches!(network.ip_stack, FirecrackerIpStack::V4 | FirecrackerIpStack::Dual);
+    let ipv6 = matches!(network.ip_stack, FirecrackerIpStack::V6 | FirecrackerIpStack::Dual);
+
+    if ipv4 {
+        sysctls.push(Sysctl::new(&["ipv4", "ip_forward"], "1"));
+    }
+
+    if ipv6 {
+        sysctls.push(Sysctl::new(&["ipv6", "conf", "all", "forwarding"], "1"));
+    }
+
+    if let Some(tap_name) = tap_name {
+        if ipv4 && options.route_localnet {
+            sysctls.push(Sysctl::new(&["ipv4", "conf", tap_name, "route_localnet"], "1"));
+        }
+
+        if ipv4 && options.proxy_arp {
+            sysctls.push(Sysctl::new(&["ipv4", "conf", tap_name, "proxy_arp"], "1"));
+        }
+
+        if ipv6 && options.proxy_ndp {
+            sysctls.push(Sysctl::new(&["ipv6", "conf", tap_name, "proxy_ndp"], "1"));
+        }
+    }
+
+    sysctls
+}
+
+/// Set every given sysctl that doesn't already have its required value. The sysctls aren't reverted on deletion or
+/// rollback, as other networks or software on the host may depend on them as well.
+pub fn ensure_sysctls(sysctls: &[Sysctl]) -> Result<(), FirecrackerNetworkError> {
+    sysctls.iter().try_for_each(Sysctl::ensure)
+}
+
+/// Report the state of every given sysctl, or report all of them as missing if the netns they reside in doesn't exist.
+pub fn check_sysctls(sysctls: &[Sysctl], netns_exists: bool, report: &mut FirecrackerNetworkCheckReport) {
+    for sysctl in sysctls {
+        report.push(
+            FirecrackerNetworkObjectType::Sysctl,
+            sysctl.name(),
+            match netns_exists {
+                true => sysctl.state(),
+                false => FirecrackerNetworkObjectState::Missing,
+            },
+        );
+    }
+}
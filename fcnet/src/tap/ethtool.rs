@@ -0,0 +1,222 @@
+//! A minimal client of the ethtool generic netlink family, which is only capable of reading and changing the features
+//! (offloads) of a network device in the network namespace of the calling thread.
+
+use std::{collections::BTreeMap, future::poll_fn, io};
+
+use rtnetlink::{
+    packet_core::{
+        DefaultNla, Emitable, NetlinkBuffer, NlasIterator, NLA_F_NESTED, NLA_HEADER_SIZE, NLMSG_DONE, NLMSG_ERROR, NLM_F_ACK,
+        NLM_F_REQUEST,
+    },
+    sys::{protocols::NETLINK_GENERIC, AsyncSocket, SocketAddr},
+};
+
+const NLMSG_HEADER_LEN: usize = 16;
+const GENL_HEADER_LEN: usize = 4;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const ETHTOOL_GENL_NAME: &str = "ethtool";
+const ETHTOOL_GENL_VERSION: u8 = 1;
+const ETHTOOL_MSG_FEATURES_GET: u8 = 11;
+const ETHTOOL_MSG_FEATURES_SET: u8 = 12;
+
+const ETHTOOL_A_HEADER_DEV_NAME: u16 = 2;
+const ETHTOOL_A_FEATURES_HEADER: u16 = 1;
+const ETHTOOL_A_FEATURES_WANTED: u16 = 3;
+const ETHTOOL_A_FEATURES_ACTIVE: u16 = 4;
+const ETHTOOL_A_BITSET_NOMASK: u16 = 1;
+const ETHTOOL_A_BITSET_BITS: u16 = 3;
+const ETHTOOL_A_BITSET_BITS_BIT: u16 = 1;
+const ETHTOOL_A_BITSET_BIT_NAME: u16 = 2;
+const ETHTOOL_A_BITSET_BIT_VALUE: u16 = 3;
+
+/// Get the names of all features that are currently active on the given device.
+pub async fn get_active_features<S: AsyncSocket>(dev_name: &str) -> io::Result<Vec<String>> {
+    let mut socket = open_socket::<S>()?;
+    let family_id = resolve_family(&mut socket).await?;
+    let replies = request(
+        &mut socket,
+        family_id,
+        ETHTOOL_MSG_FEATURES_GET,
+        ETHTOOL_GENL_VERSION,
+        &[header(dev_name)],
+    )
+    .await?;
+
+    replies
+        .iter()
+        .find_map(|attrs| parse_active_features(attrs))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "ethtool replied without the active features"))
+}
+
+/// Enable or disable the given features on the given device, leaving all other features unchanged.
+pub async fn set_features<S: AsyncSocket>(dev_name: &str, features: &BTreeMap<String, bool>) -> io::Result<()> {
+    let bits = features
+        .iter()
+        .map(|(name, enabled)| {
+            let mut bit = vec![string(ETHTOOL_A_BITSET_BIT_NAME, name)];
+
+            // a bit listed without a value is part of the mask, and is thus disabled
+            if *enabled {
+                bit.push(DefaultNla::new(ETHTOOL_A_BITSET_BIT_VALUE, Vec::new()));
+            }
+
+            nested(ETHTOOL_A_BITSET_BITS_BIT, &bit)
+        })
+        .collect::<Vec<_>>();
+
+    let mut socket = open_socket::<S>()?;
+    let family_id = resolve_family(&mut socket).await?;
+    request(
+        &mut socket,
+        family_id,
+        ETHTOOL_MSG_FEATURES_SET,
+        ETHTOOL_GENL_VERSION,
+        &[
+            header(dev_name),
+            nested(ETHTOOL_A_FEATURES_WANTED, &[nested(ETHTOOL_A_BITSET_BITS, &bits)]),
+        ],
+    )
+    .await
+    .map(|_| ())
+}
+
+fn open_socket<S: AsyncSocket>() -> io::Result<S> {
+    let mut socket = S::new(NETLINK_GENERIC)?;
+    socket.socket_mut().bind_auto()?;
+    socket.socket_mut().connect(&SocketAddr::new(0, 0))?;
+    Ok(socket)
+}
+
+async fn resolve_family<S: AsyncSocket>(socket: &mut S) -> io::Result<u16> {
+    let replies = request(
+        socket,
+        GENL_ID_CTRL,
+        CTRL_CMD_GETFAMILY,
+        1,
+        &[string(CTRL_ATTR_FAMILY_NAME, ETHTOOL_GENL_NAME)],
+    )
+    .await?;
+
+    replies
+        .iter()
+        .find_map(|attrs| find_attr(attrs, CTRL_ATTR_FAMILY_ID)?.get(..2)?.try_into().ok())
+        .map(u16::from_ne_bytes)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "The kernel doesn't support ethtool over netlink"))
+}
+
+/// Send a generic netlink request and collect the attributes of every reply to it until it is acknowledged.
+async fn request<S: AsyncSocket>(
+    socket: &mut S,
+    family_id: u16,
+    cmd: u8,
+    version: u8,
+    attrs: &[DefaultNla],
+) -> io::Result<Vec<Vec<u8>>> {
+    let len = NLMSG_HEADER_LEN + GENL_HEADER_LEN + attrs.buffer_len();
+    let mut buf = vec![0; len];
+    let mut message = NetlinkBuffer::new(&mut buf);
+    message.set_length(len as u32);
+    message.set_message_type(family_id);
+    message.set_flags(NLM_F_REQUEST | NLM_F_ACK);
+    message.set_sequence_number(1);
+
+    let payload = message.payload_mut();
+    payload[0] = cmd;
+    payload[1] = version;
+    attrs.emit(&mut payload[GENL_HEADER_LEN..]);
+
+    send(socket, &buf).await?;
+    let mut replies = Vec::new();
+
+    loop {
+        let buf = recv(socket).await?;
+        let mut offset = 0;
+
+        while offset < buf.len() {
+            let message = NetlinkBuffer::new_checked(&buf[offset..])
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+            match message.message_type() {
+                NLMSG_ERROR => {
+                    let code = message
+                        .payload()
+                        .get(..4)
+                        .and_then(|code| code.try_into().ok())
+                        .map(i32::from_ne_bytes)
+                        .unwrap_or_default();
+
+                    return match code {
+                        0 => Ok(replies),
+                        code => Err(io::Error::from_raw_os_error(-code)),
+                    };
+                }
+                NLMSG_DONE => return Ok(replies),
+                _ => replies.push(message.payload().get(GENL_HEADER_LEN..).unwrap_or_default().to_vec()),
+            }
+
+            offset += (message.length() as usize + 3) & !3;
+        }
+    }
+}
+
+// the socket is only ever moved into polling closures as a mutable reference, since some versions of netlink-sys poll
+// through a shared reference, which would require the socket to be Sync for the future to be Send
+async fn send<S: AsyncSocket>(socket: &mut S, buf: &[u8]) -> io::Result<()> {
+    poll_fn(move |cx| socket.poll_send(cx, buf)).await.map(|_| ())
+}
+
+async fn recv<S: AsyncSocket>(socket: &mut S) -> io::Result<Vec<u8>> {
+    poll_fn(move |cx| socket.poll_recv_from_full(cx)).await.map(|(buf, _)| buf)
+}
+
+/// Parse the verbose bitset of active features out of the attributes of a features reply.
+fn parse_active_features(attrs: &[u8]) -> Option<Vec<String>> {
+    let bitset = find_attr(attrs, ETHTOOL_A_FEATURES_ACTIVE)?;
+    // a bitset without a mask only lists the bits that are set
+    let nomask = find_attr(bitset, ETHTOOL_A_BITSET_NOMASK).is_some();
+    let mut names = Vec::new();
+
+    for bit in attrs_of_kind(find_attr(bitset, ETHTOOL_A_BITSET_BITS)?, ETHTOOL_A_BITSET_BITS_BIT) {
+        let name = find_attr(bit, ETHTOOL_A_BITSET_BIT_NAME)?;
+
+        if nomask || find_attr(bit, ETHTOOL_A_BITSET_BIT_VALUE).is_some() {
+            let end = name.iter().position(|byte| *byte == 0).unwrap_or(name.len());
+            names.push(String::from_utf8_lossy(&name[..end]).into_owned());
+        }
+    }
+
+    Some(names)
+}
+
+fn header(dev_name: &str) -> DefaultNla {
+    nested(ETHTOOL_A_FEATURES_HEADER, &[string(ETHTOOL_A_HEADER_DEV_NAME, dev_name)])
+}
+
+fn string(kind: u16, value: &str) -> DefaultNla {
+    let mut data = value.as_bytes().to_vec();
+    data.push(0);
+    DefaultNla::new(kind, data)
+}
+
+fn nested(kind: u16, attrs: &[DefaultNla]) -> DefaultNla {
+    let mut data = vec![0; attrs.buffer_len()];
+    attrs.emit(&mut data);
+    DefaultNla::new(kind | NLA_F_NESTED, data)
+}
+
+fn attrs_of_kind(attrs: &[u8], kind: u16) -> impl Iterator<Item = &[u8]> {
+    NlasIterator::new(attrs).filter_map(move |nla| {
+        let nla = nla.ok()?;
+        let len = nla.length() as usize;
+        (nla.kind() == kind).then(|| &nla.into_inner()[NLA_HEADER_SIZE..len])
+    })
+}
+
+fn find_attr(attrs: &[u8], kind: u16) -> Option<&[u8]> {
+    attrs_of_kind(attrs, kind).next()
+}
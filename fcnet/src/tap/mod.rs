@@ -0,0 +1,218 @@
+use std::{fs::OpenOptions, io, os::fd::AsRawFd};
+
+use fcnet_types::FirecrackerTapOptions;
+use rtnetlink::{
+    packet_core::Nla,
+    packet_route::link::{InfoData, LinkAttribute, LinkInfo, LinkMessage},
+    LinkMessageBuilder, LinkUnspec,
+};
+use tokio_tun::TunBuilder;
+
+use crate::{
+    backend::Backend,
+    util::{get_link, get_link_index},
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState, FirecrackerNetworkObjectType,
+};
+
+mod ethtool;
+
+const IFLA_TUN_OWNER: u16 = 1;
+const IFLA_TUN_GROUP: u16 = 2;
+const IFLA_TUN_VNET_HDR: u16 = 5;
+const IFLA_TUN_MULTI_QUEUE: u16 = 7;
+
+/// Create the tap device with the given name and options in the network namespace of the current thread and bring it
+/// up. A pre-existing tap device with the same name is reused and has the options applied to it.
+pub async fn create_tap<B: Backend>(
+    tap_name: &str,
+    options: &FirecrackerTapOptions,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let mut tun_builder = TunBuilder::new().name(tap_name).tap().persist().up();
+
+    if let Some(owner) = options.owner {
+        tun_builder = tun_builder.owner(owner as i32);
+    }
+
+    if let Some(group) = options.group {
+        tun_builder = tun_builder.group(group as i32);
+    }
+
+    // tokio-tun only requests multiple queues when opening more than one of them
+    if options.multi_queue {
+        tun_builder = tun_builder.queues(2);
+    }
+
+    if let Some(mtu) = options.mtu {
+        tun_builder = tun_builder.mtu(mtu as i32);
+    }
+
+    tun_builder.build().map_err(FirecrackerNetworkError::TapDeviceError)?;
+
+    if options.vnet_hdr {
+        set_vnet_hdr(tap_name, options.multi_queue)
+            .map_err(|err| FirecrackerNetworkError::TapDeviceError(tokio_tun::Error::IoError(err)))?;
+    }
+
+    if let Some(txqueuelen) = options.txqueuelen {
+        let tap_idx = get_link_index(tap_name.to_string(), netlink_handle).await?;
+        netlink_handle
+            .link()
+            .set(
+                LinkMessageBuilder::<LinkUnspec>::new()
+                    .index(tap_idx)
+                    .append_extra_attribute(LinkAttribute::TxQueueLen(txqueuelen))
+                    .build(),
+            )
+            .execute()
+            .await
+            .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    }
+
+    if !options.offloads.is_empty() {
+        ethtool::set_features::<B::NetlinkSocket>(tap_name, &options.offloads)
+            .await
+            .map_err(FirecrackerNetworkError::IoError)?;
+    }
+
+    Ok(())
+}
+
+/// Attach to the tap device once more with the IFF_VNET_HDR flag. The flags of a tap device are replaced by those of
+/// every process that attaches to it and persist once it detaches, which tokio-tun has no option for.
+fn set_vnet_hdr(tap_name: &str, multi_queue: bool) -> io::Result<()> {
+    let tun_file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+    // SAFETY: ifreq is a plain C struct for which all zeroes is a valid value
+    let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+
+    for (dst, src) in ifreq.ifr_name.iter_mut().zip(tap_name.bytes().take(libc::IFNAMSIZ - 1)) {
+        *dst = src as libc::c_char;
+    }
+
+    let mut flags = libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR;
+
+    if multi_queue {
+        flags |= libc::IFF_MULTI_QUEUE;
+    }
+
+    ifreq.ifr_ifru.ifru_flags = flags as libc::c_short;
+
+    // SAFETY: the fd is valid for the duration of the call and TUNSETIFF only reads the ifreq
+    match unsafe { libc::ioctl(tun_file.as_raw_fd(), libc::TUNSETIFF, &ifreq) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Check every option that is set for the tap device with the given name in the network namespace of the current
+/// thread.
+pub async fn check_tap<B: Backend>(
+    tap_name: &str,
+    options: &FirecrackerTapOptions,
+    netlink_handle: &rtnetlink::Handle,
+    report: &mut FirecrackerNetworkCheckReport,
+) -> Result<(), FirecrackerNetworkError> {
+    let link_message = get_link(tap_name.to_string(), netlink_handle).await?;
+    let active_features = match link_message.is_some() && !options.offloads.is_empty() {
+        true => Some(
+            ethtool::get_active_features::<B::NetlinkSocket>(tap_name)
+                .await
+                .map_err(FirecrackerNetworkError::IoError)?,
+        ),
+        false => None,
+    };
+
+    report_tap(tap_name, options, link_message.as_ref(), active_features.as_deref(), report);
+    Ok(())
+}
+
+/// Report every option that is set for the tap device, all of which are missing if the tap device itself is.
+pub fn report_tap(
+    tap_name: &str,
+    options: &FirecrackerTapOptions,
+    link_message: Option<&LinkMessage>,
+    active_features: Option<&[String]>,
+    report: &mut FirecrackerNetworkCheckReport,
+) {
+    let mut tun_attrs = Vec::new();
+    let mut mtu = None;
+    let mut txqueuelen = None;
+
+    for attribute in link_message.iter().flat_map(|link_message| link_message.attributes.iter()) {
+        match attribute {
+            LinkAttribute::Mtu(value) => mtu = Some(*value),
+            LinkAttribute::TxQueueLen(value) => txqueuelen = Some(*value),
+            LinkAttribute::LinkInfo(link_infos) => {
+                for link_info in link_infos {
+                    if let LinkInfo::Data(InfoData::Tun(info_tuns)) = link_info {
+                        for info_tun in info_tuns {
+                            let mut value = vec![0; info_tun.value_len()];
+                            info_tun.emit_value(&mut value);
+                            tun_attrs.push((info_tun.kind(), value));
+                        }
+                    }
+                }
+            }
+            _ => continue,
+        }
+    }
+
+    let tun_u32 = |kind: u16| {
+        tun_attrs
+            .iter()
+            .find(|(attr_kind, _)| *attr_kind == kind)
+            .and_then(|(_, value)| Some(u32::from_ne_bytes(value.get(..4)?.try_into().ok()?)))
+    };
+    let tun_flag = |kind: u16| {
+        tun_attrs
+            .iter()
+            .find(|(attr_kind, _)| *attr_kind == kind)
+            .is_some_and(|(_, value)| value.first().is_some_and(|value| *value != 0))
+    };
+    let mut push = |option: String, matches: bool| {
+        report.push(
+            FirecrackerNetworkObjectType::TapOption,
+            format!("{tap_name} {option}"),
+            match (link_message.is_some(), matches) {
+                (false, _) => FirecrackerNetworkObjectState::Missing,
+                (true, true) => FirecrackerNetworkObjectState::Present,
+                (true, false) => FirecrackerNetworkObjectState::Mismatched,
+            },
+        );
+    };
+
+    if let Some(owner) = options.owner {
+        push(format!("owner {owner}"), tun_u32(IFLA_TUN_OWNER) == Some(owner));
+    }
+
+    if let Some(group) = options.group {
+        push(format!("group {group}"), tun_u32(IFLA_TUN_GROUP) == Some(group));
+    }
+
+    if options.multi_queue {
+        push("multi_queue".to_string(), tun_flag(IFLA_TUN_MULTI_QUEUE));
+    }
+
+    if options.vnet_hdr {
+        push("vnet_hdr".to_string(), tun_flag(IFLA_TUN_VNET_HDR));
+    }
+
+    if let Some(expected_mtu) = options.mtu {
+        push(format!("mtu {expected_mtu}"), mtu == Some(expected_mtu));
+    }
+
+    if let Some(expected_txqueuelen) = options.txqueuelen {
+        push(
+            format!("txqueuelen {expected_txqueuelen}"),
+            txqueuelen == Some(expected_txqueuelen),
+        );
+    }
+
+    for (feature, enabled) in options.offloads.iter() {
+        let active = active_features.is_some_and(|active_features| active_features.contains(feature));
+        push(
+            format!("{feature} {}", if *enabled { "on" } else { "off" }),
+            active == *enabled,
+        );
+    }
+}
@@ -1,29 +1,141 @@
-use std::{borrow::Cow, ffi::OsStr, net::IpAddr};
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    future::{poll_fn, Future},
+    io,
+    net::IpAddr,
+};
 
-use fcnet_types::{FirecrackerIpStack, FirecrackerNetwork};
-use futures_util::TryStreamExt;
+use cidr::IpInet;
+use fcnet_types::{FirecrackerIpStack, FirecrackerNetwork, FirecrackerNfBaseChain, FirecrackerNfChainPolicy, FirecrackerNfTable};
+use futures_util::{FutureExt, TryFutureExt, TryStreamExt};
 use nftables::{
     batch::Batch,
+    helper::NftablesError,
     schema::{Chain, NfListObject, NfObject, Nftables, Table},
     types::{NfChainPolicy, NfChainType, NfFamily, NfHook},
 };
+use nftables_async::helper::Helper;
+use rtnetlink::{
+    packet_route::link::{LinkFlags, LinkMessage},
+    sys::{protocols::NETLINK_NETFILTER, AsyncSocket, SocketAddr},
+};
+
+use crate::{
+    backend::Backend,
+    vmap::{parse_ruleset, serialize_ruleset, split_ruleset, NfRulesetObject, NfVerdictMap},
+    FirecrackerNetworkCheckReport, FirecrackerNetworkError, FirecrackerNetworkObjectState, FirecrackerNetworkObjectType,
+    NFT_DISPATCH_RULE_TAG_PREFIX, NFT_RULE_TAG_PREFIX,
+};
 
-use crate::{FirecrackerNetworkError, FirecrackerNetworkObjectType, NFT_FILTER_CHAIN, NFT_POSTROUTING_CHAIN, NFT_TABLE};
+/// Get the current ruleset via the nftables helper of the [Backend]. The future is returned from a non-async function
+/// so that it is known to be [Send] from the helper's signature alone, since proving that for an async fn in a trait
+/// through all layers of fcnet's own async fns runs into a limitation of the compiler.
+pub fn get_current_ruleset<'a, B: Backend>(
+    nft_program: Option<&'a str>,
+) -> impl Future<Output = Result<Nftables<'static>, FirecrackerNetworkError>> + Send + 'a {
+    get_current_ruleset_with_verdict_maps::<B>(nft_program).map_ok(|(nftables, _)| nftables)
+}
 
-pub const NO_NFT_ARGS: std::iter::Empty<&OsStr> = std::iter::empty();
+/// Get the current ruleset via the nftables helper of the [Backend] alongside the verdict maps within it, which the
+/// nftables crate can't represent. The ruleset is therefore always listed in its raw form and parsed by fcnet.
+pub fn get_current_ruleset_with_verdict_maps<'a, B: Backend>(
+    nft_program: Option<&'a str>,
+) -> impl Future<Output = Result<(Nftables<'static>, Vec<NfVerdictMap>), FirecrackerNetworkError>> + Send + 'a {
+    B::NftablesDriver::get_current_ruleset_raw(nft_program, std::iter::empty::<&'a OsStr>()).map(|result| {
+        let raw = result.map_err(FirecrackerNetworkError::NftablesError)?;
+        let objects =
+            parse_ruleset(&raw).map_err(|err| FirecrackerNetworkError::NftablesError(NftablesError::NftInvalidJson(err)))?;
+        Ok(split_ruleset(objects))
+    })
+}
+
+/// Apply a ruleset via the nftables helper of the [Backend], see [get_current_ruleset] on why this isn't an async fn.
+pub fn apply_ruleset<'a, 'b, B: Backend>(
+    nftables: &'a Nftables<'b>,
+    nft_program: Option<&'a str>,
+) -> impl Future<Output = Result<(), FirecrackerNetworkError>> + Send + use<'a, 'b, B> {
+    B::NftablesDriver::apply_ruleset_with_args(nftables, nft_program, std::iter::empty::<&'a OsStr>())
+        .map_err(FirecrackerNetworkError::NftablesError)
+}
+
+/// Apply a ruleset that may add verdict maps via the nftables helper of the [Backend] in its raw form.
+pub fn apply_ruleset_with_verdict_maps<'a, B: Backend>(
+    objects: &[NfRulesetObject],
+    nft_program: Option<&'a str>,
+) -> impl Future<Output = Result<(), FirecrackerNetworkError>> + Send + 'a {
+    B::NftablesDriver::apply_ruleset_raw(serialize_ruleset(objects), nft_program, std::iter::empty::<&'a OsStr>())
+        .map_err(FirecrackerNetworkError::NftablesError)
+}
+
+pub async fn get_link(link: String, netlink_handle: &rtnetlink::Handle) -> Result<Option<LinkMessage>, FirecrackerNetworkError> {
+    match netlink_handle.link().get().match_name(link).execute().try_next().await {
+        Ok(link_message) => Ok(link_message),
+        Err(rtnetlink::Error::NetlinkError(message)) if message.raw_code() == -libc::ENODEV => Ok(None),
+        Err(err) => Err(FirecrackerNetworkError::NetlinkOperationError(err)),
+    }
+}
 
 pub async fn get_link_index(link: String, netlink_handle: &rtnetlink::Handle) -> Result<u32, FirecrackerNetworkError> {
-    Ok(netlink_handle
-        .link()
+    Ok(get_link(link, netlink_handle)
+        .await?
+        .ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink))?
+        .header
+        .index)
+}
+
+pub async fn check_link(
+    link: &str,
+    link_ip: &IpInet,
+    netlink_handle: &rtnetlink::Handle,
+    report: &mut FirecrackerNetworkCheckReport,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(link_message) = get_link(link.to_string(), netlink_handle).await? else {
+        report.push(
+            FirecrackerNetworkObjectType::IpLink,
+            link,
+            FirecrackerNetworkObjectState::Missing,
+        );
+        report.push(
+            FirecrackerNetworkObjectType::IpAddress,
+            link_ip.to_string(),
+            FirecrackerNetworkObjectState::Missing,
+        );
+        return Ok(());
+    };
+
+    report.push(
+        FirecrackerNetworkObjectType::IpLink,
+        link,
+        match link_message.header.flags.contains(LinkFlags::Up) {
+            true => FirecrackerNetworkObjectState::Present,
+            false => FirecrackerNetworkObjectState::Mismatched,
+        },
+    );
+
+    let mut address_state = FirecrackerNetworkObjectState::Missing;
+    let mut address_message_stream = netlink_handle
+        .address()
         .get()
-        .match_name(link)
-        .execute()
+        .set_link_index_filter(link_message.header.index)
+        .set_address_filter(link_ip.address())
+        .execute();
+
+    while let Some(address_message) = address_message_stream
         .try_next()
         .await
         .map_err(FirecrackerNetworkError::NetlinkOperationError)?
-        .ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink))?
-        .header
-        .index)
+    {
+        if address_message.header.prefix_len == link_ip.network_length() {
+            address_state = FirecrackerNetworkObjectState::Present;
+            break;
+        }
+
+        address_state = FirecrackerNetworkObjectState::Mismatched;
+    }
+
+    report.push(FirecrackerNetworkObjectType::IpAddress, link_ip.to_string(), address_state);
+    Ok(())
 }
 
 pub fn add_base_chains_if_needed(
@@ -31,20 +143,24 @@ pub fn add_base_chains_if_needed(
     current_ruleset: &Nftables,
     batch: &mut Batch,
 ) -> Result<(), FirecrackerNetworkError> {
+    let nf_table = &network.nf_table;
     let mut table_exists = false;
     let mut postrouting_chain_exists = false;
+    let mut prerouting_chain_exists = false;
     let mut filter_chain_exists = false;
 
     for object in current_ruleset.objects.iter() {
         match object {
             NfObject::ListObject(object) => match object {
-                NfListObject::Table(table) if table.name == NFT_TABLE && table.family == network.nf_family() => {
+                NfListObject::Table(table) if table.name == nf_table.name && table.family == network.nf_family() => {
                     table_exists = true;
                 }
-                NfListObject::Chain(chain) => {
-                    if chain.name == NFT_POSTROUTING_CHAIN && chain.table == NFT_TABLE {
+                NfListObject::Chain(chain) if chain.table == nf_table.name && chain.family == network.nf_family() => {
+                    if chain.name == nf_table.postrouting_chain.name {
                         postrouting_chain_exists = true;
-                    } else if chain.name == NFT_FILTER_CHAIN && chain.table == NFT_TABLE {
+                    } else if chain.name == nf_table.prerouting_chain.name {
+                        prerouting_chain_exists = true;
+                    } else if chain.name == nf_table.filter_chain.name {
                         filter_chain_exists = true;
                     }
                 }
@@ -57,60 +173,90 @@ pub fn add_base_chains_if_needed(
     if !table_exists {
         batch.add(NfListObject::Table(Table {
             family: network.nf_family(),
-            name: NFT_TABLE.into(),
+            name: nf_table.name.clone().into(),
             handle: None,
         }));
     }
 
     if !postrouting_chain_exists {
-        batch.add(NfListObject::Chain(Chain {
-            family: network.nf_family(),
-            table: NFT_TABLE.into(),
-            name: NFT_POSTROUTING_CHAIN.into(),
-            _type: Some(NfChainType::NAT),
-            hook: Some(NfHook::Postrouting),
-            prio: Some(100),
-            policy: Some(NfChainPolicy::Accept),
-            newname: None,
-            dev: None,
-            handle: None,
-        }));
+        batch.add(NfListObject::Chain(nf_base_chain(
+            network.nf_family(),
+            nf_table,
+            &nf_table.postrouting_chain,
+            NfChainType::NAT,
+            NfHook::Postrouting,
+        )));
+    }
+
+    // the prerouting chain is only needed for forwarding ports
+    if !prerouting_chain_exists && !network.port_forwards.is_empty() {
+        batch.add(NfListObject::Chain(nf_base_chain(
+            network.nf_family(),
+            nf_table,
+            &nf_table.prerouting_chain,
+            NfChainType::NAT,
+            NfHook::Prerouting,
+        )));
     }
 
     if !filter_chain_exists {
-        batch.add(NfListObject::Chain(Chain {
-            family: network.nf_family(),
-            table: NFT_TABLE.into(),
-            name: NFT_FILTER_CHAIN.into(),
-            _type: Some(NfChainType::Filter),
-            hook: Some(NfHook::Forward),
-            prio: Some(0),
-            policy: Some(NfChainPolicy::Accept),
-            handle: None,
-            newname: None,
-            dev: None,
-        }));
+        batch.add(NfListObject::Chain(nf_base_chain(
+            network.nf_family(),
+            nf_table,
+            &nf_table.filter_chain,
+            NfChainType::Filter,
+            NfHook::Forward,
+        )));
     }
 
     Ok(())
 }
 
-pub fn check_base_chains(network: &FirecrackerNetwork, current_ruleset: &Nftables) -> Result<(), FirecrackerNetworkError> {
+/// Build the given base chain of the given table in the given family.
+pub fn nf_base_chain(
+    family: NfFamily,
+    nf_table: &FirecrackerNfTable,
+    base_chain: &FirecrackerNfBaseChain,
+    chain_type: NfChainType,
+    hook: NfHook,
+) -> Chain<'static> {
+    Chain {
+        family,
+        table: nf_table.name.clone().into(),
+        name: base_chain.name.clone().into(),
+        _type: Some(chain_type),
+        hook: Some(hook),
+        prio: Some(base_chain.priority),
+        policy: Some(nf_chain_policy(base_chain.policy)),
+        newname: None,
+        dev: None,
+        handle: None,
+    }
+}
+
+pub fn check_base_chains(network: &FirecrackerNetwork, current_ruleset: &Nftables, report: &mut FirecrackerNetworkCheckReport) {
+    let nf_table = &network.nf_table;
     let mut table_exists = false;
-    let mut postrouting_chain_exists = false;
-    let mut filter_chain_exists = false;
+    let mut postrouting_chain_state = FirecrackerNetworkObjectState::Missing;
+    let mut prerouting_chain_state = FirecrackerNetworkObjectState::Missing;
+    let mut filter_chain_state = FirecrackerNetworkObjectState::Missing;
 
     for object in current_ruleset.objects.iter() {
         match object {
             NfObject::ListObject(object) => match object {
-                NfListObject::Table(table) if table.name == NFT_TABLE && table.family == network.nf_family() => {
+                NfListObject::Table(table) if table.name == nf_table.name && table.family == network.nf_family() => {
                     table_exists = true;
                 }
-                NfListObject::Chain(chain) if chain.table == NFT_TABLE => {
-                    if chain.name == NFT_POSTROUTING_CHAIN {
-                        postrouting_chain_exists = true;
-                    } else if chain.name == NFT_FILTER_CHAIN {
-                        filter_chain_exists = true;
+                NfListObject::Chain(chain) if chain.table == nf_table.name && chain.family == network.nf_family() => {
+                    if chain.name == nf_table.postrouting_chain.name {
+                        postrouting_chain_state =
+                            base_chain_state(chain, NfChainType::NAT, NfHook::Postrouting, &nf_table.postrouting_chain);
+                    } else if chain.name == nf_table.prerouting_chain.name {
+                        prerouting_chain_state =
+                            base_chain_state(chain, NfChainType::NAT, NfHook::Prerouting, &nf_table.prerouting_chain);
+                    } else if chain.name == nf_table.filter_chain.name {
+                        filter_chain_state =
+                            base_chain_state(chain, NfChainType::Filter, NfHook::Forward, &nf_table.filter_chain);
                     }
                 }
                 _ => continue,
@@ -119,23 +265,178 @@ pub fn check_base_chains(network: &FirecrackerNetwork, current_ruleset: &Nftable
         }
     }
 
-    if !table_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::NfTable));
+    report.push_found(FirecrackerNetworkObjectType::NfTable, &nf_table.name, table_exists);
+    report.push(
+        FirecrackerNetworkObjectType::NfPostroutingChain,
+        &nf_table.postrouting_chain.name,
+        postrouting_chain_state,
+    );
+
+    if !network.port_forwards.is_empty() {
+        report.push(
+            FirecrackerNetworkObjectType::NfPreroutingChain,
+            &nf_table.prerouting_chain.name,
+            prerouting_chain_state,
+        );
     }
 
-    if !postrouting_chain_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfPostroutingChain,
-        ));
+    report.push(
+        FirecrackerNetworkObjectType::NfFilterChain,
+        &nf_table.filter_chain.name,
+        filter_chain_state,
+    );
+}
+
+pub fn base_chain_state(
+    chain: &Chain,
+    chain_type: NfChainType,
+    hook: NfHook,
+    base_chain: &FirecrackerNfBaseChain,
+) -> FirecrackerNetworkObjectState {
+    match chain._type == Some(chain_type)
+        && chain.hook == Some(hook)
+        && chain.prio == Some(base_chain.priority)
+        && chain.policy.unwrap_or(NfChainPolicy::Accept) == nf_chain_policy(base_chain.policy)
+    {
+        true => FirecrackerNetworkObjectState::Present,
+        false => FirecrackerNetworkObjectState::Mismatched,
     }
+}
 
-    if !filter_chain_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfFilterChain,
-        ));
+#[inline]
+fn nf_chain_policy(policy: FirecrackerNfChainPolicy) -> NfChainPolicy {
+    match policy {
+        FirecrackerNfChainPolicy::Accept => NfChainPolicy::Accept,
+        FirecrackerNfChainPolicy::Drop => NfChainPolicy::Drop,
     }
+}
 
-    Ok(())
+/// Format the comment that tags an nftables rule as the given object of the network with the given identifier, so that
+/// it can be located regardless of how nftables normalizes its expression and so that operators can see which network
+/// it belongs to when listing the ruleset.
+pub fn nf_rule_tag(network_id: &str, object_type: FirecrackerNetworkObjectType) -> String {
+    // every caller passes the object type of an nftables rule, and any other one yields a tag that is never parsed
+    let role = nf_rule_role(object_type).unwrap_or_default();
+    format!("{NFT_RULE_TAG_PREFIX}:{network_id}:{role}")
+}
+
+/// Format the comment that tags an nftables rule as the given object of the network with the given identifier that
+/// exists once per forwarded port, which carries the index of the port after its role.
+pub fn nf_port_rule_tag(network_id: &str, object_type: FirecrackerNetworkObjectType, index: usize) -> String {
+    format!("{}/{index}", nf_rule_tag(network_id, object_type))
+}
+
+/// Format the comment that tags an nftables rule of the egress policy of the network with the given identifier, which
+/// carries the kind of the set that the rule looks up after its role.
+pub fn nf_egress_policy_rule_tag(network_id: &str, set_kind: &str) -> String {
+    format!(
+        "{}/{set_kind}",
+        nf_rule_tag(network_id, FirecrackerNetworkObjectType::NfEgressPolicyRule)
+    )
+}
+
+/// Format the comment that tags an nftables rule isolating the network with the given identifier, which carries the
+/// direction of the traffic it matches after its role, preceded by the index of the group for rules of groups.
+pub fn nf_isolation_rule_tag(
+    network_id: &str,
+    object_type: FirecrackerNetworkObjectType,
+    group_index: Option<usize>,
+    direction: &str,
+) -> String {
+    match group_index {
+        Some(group_index) => format!("{}/{group_index}/{direction}", nf_rule_tag(network_id, object_type)),
+        None => format!("{}/{direction}", nf_rule_tag(network_id, object_type)),
+    }
+}
+
+/// Format the comment that tags an nftables rule in the anti-spoofing chain of the network with the given identifier,
+/// which carries the kind of the spoofing that the rule drops after its role.
+pub fn nf_anti_spoofing_rule_tag(network_id: &str, kind: &str) -> String {
+    format!(
+        "{}/{kind}",
+        nf_rule_tag(network_id, FirecrackerNetworkObjectType::NfAntiSpoofingRule)
+    )
+}
+
+/// Format the comment that tags the rule of a base chain dispatching via the verdict map with the given name, so that it
+/// can be located regardless of how nftables normalizes its expression.
+pub fn nf_dispatch_rule_tag(map_name: &str) -> String {
+    format!("{NFT_DISPATCH_RULE_TAG_PREFIX}:{map_name}")
+}
+
+/// Parse the comment of an nftables rule into the name of the verdict map it dispatches via, if it is tagged as the rule
+/// of a base chain doing so.
+pub fn parse_nf_dispatch_rule_tag(comment: &str) -> Option<&str> {
+    comment.strip_prefix(NFT_DISPATCH_RULE_TAG_PREFIX)?.strip_prefix(':')
+}
+
+/// Determine which object of the network with the given identifier an nftables rule is tagged as via its comment, if any.
+pub fn nf_rule_tag_object_type(comment: &str, network_id: &str) -> Option<FirecrackerNetworkObjectType> {
+    match parse_nf_rule_tag(comment)? {
+        (tagged_network_id, object_type) if tagged_network_id == network_id => Some(object_type),
+        _ => None,
+    }
+}
+
+/// Parse the comment of an nftables rule into the identifier of the network it belongs to and the object it is, if the
+/// rule is tagged with a known role.
+pub fn parse_nf_rule_tag(comment: &str) -> Option<(&str, FirecrackerNetworkObjectType)> {
+    // the role is split off from the right, as the network identifier may itself contain colons
+    let (network_id, role) = comment
+        .strip_prefix(NFT_RULE_TAG_PREFIX)?
+        .strip_prefix(':')?
+        .rsplit_once(':')?;
+    let role = role.split_once('/').map_or(role, |(role, _)| role);
+    let (object_type, _) = NF_RULE_ROLES.iter().find(|(_, known_role)| *known_role == role)?;
+
+    Some((network_id, *object_type))
+}
+
+/// The roles that tags carry for the objects that are nftables rules.
+const NF_RULE_ROLES: &[(FirecrackerNetworkObjectType, &str)] = &[
+    (FirecrackerNetworkObjectType::NfMasqueradeRule, "masquerade"),
+    (FirecrackerNetworkObjectType::NfEgressForwardRule, "egress-forward"),
+    (FirecrackerNetworkObjectType::NfIngressForwardRule, "ingress-forward"),
+    #[cfg(feature = "namespaced")]
+    (FirecrackerNetworkObjectType::NfEgressSnatRule, "egress-snat"),
+    #[cfg(feature = "namespaced")]
+    (FirecrackerNetworkObjectType::NfIngressDnatRule, "ingress-dnat"),
+    (FirecrackerNetworkObjectType::NfPortDnatRule, "port-dnat"),
+    (FirecrackerNetworkObjectType::NfPortForwardRule, "port-forward"),
+    (FirecrackerNetworkObjectType::NfEgressPolicyRule, "egress-policy"),
+    (FirecrackerNetworkObjectType::NfIngressExposedRule, "ingress-exposed"),
+    (FirecrackerNetworkObjectType::NfIngressDropRule, "ingress-drop"),
+    (FirecrackerNetworkObjectType::NfIsolationGroupRule, "isolation-group"),
+    (FirecrackerNetworkObjectType::NfIsolationDropRule, "isolation-drop"),
+    (FirecrackerNetworkObjectType::NfAntiSpoofingRule, "anti-spoofing"),
+];
+
+/// The role that tags carry for the given object, which is [None] for objects that aren't nftables rules.
+fn nf_rule_role(object_type: FirecrackerNetworkObjectType) -> Option<&'static str> {
+    NF_RULE_ROLES
+        .iter()
+        .find(|(known_object_type, _)| *known_object_type == object_type)
+        .map(|(_, role)| *role)
+}
+
+/// Open a netfilter netlink socket of type `S` in the network namespace of the calling thread, which is connected to the
+/// kernel.
+pub fn open_netfilter_socket<S: AsyncSocket>() -> io::Result<S> {
+    let mut socket = S::new(NETLINK_NETFILTER)?;
+    socket.socket_mut().bind_auto()?;
+    socket.socket_mut().connect(&SocketAddr::new(0, 0))?;
+    Ok(socket)
+}
+
+// AsyncSocketExt isn't used and the socket is only ever moved into polling closures as a mutable reference, since
+// some versions of netlink-sys poll through a shared reference, which would require the socket to be Sync for the
+// future to be Send
+pub async fn send_netlink<S: AsyncSocket>(socket: &mut S, buf: &[u8]) -> io::Result<()> {
+    poll_fn(move |cx| socket.poll_send(cx, buf)).await.map(|_| ())
+}
+
+pub async fn recv_netlink<S: AsyncSocket>(socket: &mut S) -> io::Result<Vec<u8>> {
+    poll_fn(move |cx| socket.poll_recv_from_full(cx)).await.map(|(buf, _)| buf)
 }
 
 #[inline]
@@ -163,6 +464,6 @@ impl FirecrackerNetworkExt for FirecrackerNetwork {
 
     #[inline]
     fn nft_program(&self) -> Option<&str> {
-        self.nft_path.as_ref().map(|p| p.as_str())
+        self.nft_path.as_deref()
     }
 }
@@ -0,0 +1,151 @@
+use nftables::{
+    expr::Verdict,
+    schema::{NfObject, Nftables},
+    types::NfFamily,
+};
+use serde_json::{json, Value};
+
+/// The type of the keys of an [NfVerdictMap], named as in the JSON format of nftables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NfVerdictMapKeyType {
+    Ifname,
+    Ipv4Addr,
+    Ipv6Addr,
+}
+
+impl NfVerdictMapKeyType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NfVerdictMapKeyType::Ifname => "ifname",
+            NfVerdictMapKeyType::Ipv4Addr => "ipv4_addr",
+            NfVerdictMapKeyType::Ipv6Addr => "ipv6_addr",
+        }
+    }
+
+    fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "ifname" => Some(NfVerdictMapKeyType::Ifname),
+            "ipv4_addr" => Some(NfVerdictMapKeyType::Ipv4Addr),
+            "ipv6_addr" => Some(NfVerdictMapKeyType::Ipv6Addr),
+            _ => None,
+        }
+    }
+}
+
+/// A named nftables map from interface names or addresses to verdicts. The schema of the nftables crate has no
+/// verdict data type, so such maps are (de)serialized manually and carried alongside the objects it can represent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NfVerdictMap {
+    pub family: NfFamily,
+    pub table: String,
+    pub name: String,
+    pub key_type: NfVerdictMapKeyType,
+    /// The elements of the map, which are only populated when it has been listed.
+    pub elements: Vec<(String, Verdict<'static>)>,
+}
+
+impl NfVerdictMap {
+    fn from_json(value: &Value) -> Option<Self> {
+        if value.get("map")?.as_str()? != "verdict" {
+            return None;
+        }
+
+        let mut elements = Vec::new();
+
+        for element in value.get("elem").and_then(Value::as_array).into_iter().flatten() {
+            let [key, verdict] = element.as_array()?.as_slice() else {
+                return None;
+            };
+
+            elements.push((key.as_str()?.to_string(), serde_json::from_value(verdict.clone()).ok()?));
+        }
+
+        Some(Self {
+            family: serde_json::from_value(value.get("family")?.clone()).ok()?,
+            table: value.get("table")?.as_str()?.to_string(),
+            name: value.get("name")?.as_str()?.to_string(),
+            key_type: NfVerdictMapKeyType::from_str(value.get("type")?.as_str()?)?,
+            elements,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut value = json!({
+            "family": self.family,
+            "table": self.table,
+            "name": self.name,
+            "type": self.key_type.as_str(),
+            "map": "verdict",
+        });
+
+        if !self.elements.is_empty() {
+            value["elem"] = self.elements.iter().map(|(key, verdict)| json!([key, verdict])).collect();
+        }
+
+        value
+    }
+}
+
+/// An object of a ruleset in the JSON format of nftables: either one that the nftables crate can represent, or a
+/// verdict map that is listed or added.
+#[derive(Debug, Clone)]
+pub enum NfRulesetObject<'a> {
+    Object(NfObject<'a>),
+    VerdictMap(NfVerdictMap),
+    AddVerdictMap(NfVerdictMap),
+}
+
+/// Parse a ruleset in the JSON format of nftables, failing if it contains objects that are neither representable by
+/// the nftables crate nor verdict maps.
+pub fn parse_ruleset(raw: &str) -> Result<Vec<NfRulesetObject<'static>>, serde_json::Error> {
+    let mut root = serde_json::from_str::<Value>(raw)?;
+    let values = serde_json::from_value::<Vec<Value>>(root.get_mut("nftables").map(Value::take).unwrap_or_default())?;
+    let mut objects = Vec::with_capacity(values.len());
+
+    for value in values {
+        if let Some(verdict_map) = value.get("map").and_then(NfVerdictMap::from_json) {
+            objects.push(NfRulesetObject::VerdictMap(verdict_map));
+        } else if let Some(verdict_map) = value.pointer("/add/map").and_then(NfVerdictMap::from_json) {
+            objects.push(NfRulesetObject::AddVerdictMap(verdict_map));
+        } else {
+            objects.push(NfRulesetObject::Object(serde_json::from_value(value)?));
+        }
+    }
+
+    Ok(objects)
+}
+
+pub fn serialize_ruleset(objects: &[NfRulesetObject]) -> String {
+    let values = objects
+        .iter()
+        .map(|object| match object {
+            NfRulesetObject::Object(object) => serde_json::to_value(object).expect("Failed to serialize NfObject to JSON"),
+            NfRulesetObject::VerdictMap(verdict_map) => json!({ "map": verdict_map.to_json() }),
+            NfRulesetObject::AddVerdictMap(verdict_map) => json!({ "add": { "map": verdict_map.to_json() } }),
+        })
+        .collect::<Vec<_>>();
+
+    json!({ "nftables": values }).to_string()
+}
+
+/// Split a ruleset into the part that the nftables crate can represent and the verdict maps within it.
+pub fn split_ruleset<'a>(objects: Vec<NfRulesetObject<'a>>) -> (Nftables<'a>, Vec<NfVerdictMap>) {
+    let mut nf_objects = Vec::with_capacity(objects.len());
+    let mut verdict_maps = Vec::new();
+
+    for object in objects {
+        match object {
+            NfRulesetObject::Object(object) => nf_objects.push(object),
+            NfRulesetObject::VerdictMap(verdict_map) | NfRulesetObject::AddVerdictMap(verdict_map) => {
+                verdict_maps.push(verdict_map)
+            }
+        }
+    }
+
+    (
+        Nftables {
+            objects: nf_objects.into(),
+        },
+        verdict_maps,
+    )
+}
@@ -6,7 +6,7 @@ use std::path::Path;
 #[cfg(feature = "deadpool")]
 use std::path::PathBuf;
 
-use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation};
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkCounters, FirecrackerNetworkOperation};
 use serde::Serialize;
 use socket::Socket;
 
@@ -21,6 +21,7 @@ pub enum FcnetdError {
     ResponseReadError(std::io::Error),
     ConnectionClosed,
     OperationFailed(String),
+    ResponseDeserializeError(serde_json::Error),
 }
 
 impl std::error::Error for FcnetdError {}
@@ -35,6 +36,7 @@ impl std::fmt::Display for FcnetdError {
             FcnetdError::OperationFailed(detail) => {
                 write!(f, "The daemon returned a failure of the requested operation: {detail}")
             }
+            FcnetdError::ResponseDeserializeError(err) => write!(f, "Deserializing the response from JSON failed: {err}"),
         }
     }
 }
@@ -48,6 +50,18 @@ struct Request<'net> {
     network: &'net FirecrackerNetwork,
 }
 
+#[derive(Serialize)]
+struct CountersRequest<'net> {
+    counters: CountersOperation,
+    network: &'net FirecrackerNetwork,
+}
+
+#[derive(Serialize)]
+enum CountersOperation {
+    Get,
+    Reset,
+}
+
 #[derive(Debug)]
 #[cfg(feature = "connection-pool")]
 #[cfg_attr(docsrs, doc(cfg(feature = "connection-pool")))]
@@ -114,23 +128,58 @@ impl<S: Socket> FcnetdConnection<S> {
     }
 
     pub async fn run(&mut self, network: &FirecrackerNetwork, operation: FirecrackerNetworkOperation) -> Result<(), FcnetdError> {
-        let request = Request { operation, network };
-        let request_json = serde_json::to_string(&request).map_err(FcnetdError::RequestSerializeError)?;
+        self.request(&Request { operation, network }).await
+    }
+
+    /// Read the traffic counters of a network that the daemon has added, see fcnet's get_traffic_counters.
+    pub async fn get_traffic_counters(
+        &mut self,
+        network: &FirecrackerNetwork,
+    ) -> Result<FirecrackerNetworkCounters, FcnetdError> {
+        self.request_counters(network, CountersOperation::Get).await
+    }
+
+    /// Read and then reset the traffic counters of a network that the daemon has added, see fcnet's
+    /// reset_traffic_counters.
+    pub async fn reset_traffic_counters(
+        &mut self,
+        network: &FirecrackerNetwork,
+    ) -> Result<FirecrackerNetworkCounters, FcnetdError> {
+        self.request_counters(network, CountersOperation::Reset).await
+    }
+
+    async fn request_counters(
+        &mut self,
+        network: &FirecrackerNetwork,
+        counters: CountersOperation,
+    ) -> Result<FirecrackerNetworkCounters, FcnetdError> {
+        self.request(&CountersRequest { counters, network }).await?;
+
+        // the counters follow on the line after the OK response
+        let counters_json = self.read_response_line().await?;
+        serde_json::from_str(&counters_json).map_err(FcnetdError::ResponseDeserializeError)
+    }
+
+    async fn request(&mut self, request: &impl Serialize) -> Result<(), FcnetdError> {
+        let request_json = serde_json::to_string(request).map_err(FcnetdError::RequestSerializeError)?;
         self.0
             .write_line(request_json)
             .await
             .map_err(FcnetdError::RequestWriteError)?;
 
-        let response = match self.0.read_line().await {
-            Ok(Some(response)) => response,
-            Ok(None) => return Err(FcnetdError::ConnectionClosed),
-            Err(err) => return Err(FcnetdError::ResponseReadError(err)),
-        };
-
+        let response = self.read_response_line().await?;
         if response != OK_RESPONSE {
             return Err(FcnetdError::OperationFailed(response));
         }
 
         Ok(())
     }
+
+    async fn read_response_line(&mut self) -> Result<String, FcnetdError> {
+        match self.0.read_line().await {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => Err(FcnetdError::ConnectionClosed),
+            Err(err) => Err(FcnetdError::ResponseReadError(err)),
+        }
+    }
 }
@@ -41,16 +41,15 @@ enum CliLogLevel {
     Error,
 }
 
-impl ToString for CliLogLevel {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for CliLogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             CliLogLevel::Trace => "trace",
             CliLogLevel::Debug => "debug",
             CliLogLevel::Info => "info",
             CliLogLevel::Warn => "warn",
             CliLogLevel::Error => "error",
-        }
-        .to_string()
+        })
     }
 }
 
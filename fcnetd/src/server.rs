@@ -1,6 +1,6 @@
 use std::{path::PathBuf, sync::Arc};
 
-use fcnet::backend::TokioBackend;
+use fcnet::{backend::TokioBackend, FirecrackerNetworkContext};
 use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation};
 use nix::unistd::{Gid, Uid};
 use serde::Deserialize;
@@ -11,10 +11,27 @@ use tokio::{
 
 use crate::Cli;
 
+const OK_RESPONSE: &str = "OK";
+
 #[derive(Deserialize, Debug)]
-struct Request {
-    operation: FirecrackerNetworkOperation,
-    network: FirecrackerNetwork,
+#[serde(untagged)]
+enum Request {
+    Operation {
+        operation: FirecrackerNetworkOperation,
+        network: FirecrackerNetwork,
+    },
+    Counters {
+        counters: CountersOperation,
+        network: FirecrackerNetwork,
+    },
+}
+
+/// What a request for the traffic counters of a network does, both of which respond with "OK" like any other request
+/// and then with the counters as JSON on the following line.
+#[derive(Deserialize, Debug, Clone, Copy)]
+enum CountersOperation {
+    Get,
+    Reset,
 }
 
 #[tracing::instrument(skip(cli))]
@@ -23,6 +40,7 @@ pub async fn start(cli: Cli) {
     tracing::info!("Starting to serve over the socket: {}", cli.socket_path);
 
     let cli = Arc::new(cli);
+    let context = Arc::new(FirecrackerNetworkContext::<TokioBackend>::new().expect("Could not create the network context"));
     let mut connection_id = 0;
 
     loop {
@@ -37,13 +55,18 @@ pub async fn start(cli: Cli) {
             }
         };
 
-        tokio::task::spawn(serve_connection(cli.clone(), stream, connection_id));
+        tokio::task::spawn(serve_connection(cli.clone(), context.clone(), stream, connection_id));
         connection_id += 1;
     }
 }
 
-#[tracing::instrument(skip(cli, stream))]
-async fn serve_connection(cli: Arc<Cli>, mut stream: UnixStream, connection_id: u64) {
+#[tracing::instrument(skip(cli, context, stream))]
+async fn serve_connection(
+    cli: Arc<Cli>,
+    context: Arc<FirecrackerNetworkContext<TokioBackend>>,
+    mut stream: UnixStream,
+    connection_id: u64,
+) {
     if let Some(ref password) = cli.password {
         let mut line_reader = BufReader::new(&mut stream).lines();
         let provided_password = match line_reader.next_line().await {
@@ -91,15 +114,46 @@ async fn serve_connection(cli: Arc<Cli>, mut stream: UnixStream, connection_id:
             continue;
         };
 
-        match fcnet::run::<TokioBackend>(&request.network, request.operation).await {
-            Ok(_) => {
-                tracing::info!(operation = ?request.operation, "Network operation succeeded");
-                if let Err(err) = stream.write_all(b"OK\n").await {
+        let (operation, result) = match request {
+            Request::Operation { operation, network } => (
+                format!("{operation:?}"),
+                context
+                    .run(&network, operation)
+                    .await
+                    .map(|_| None)
+                    .map_err(|err| err.to_string()),
+            ),
+            Request::Counters { counters, network } => {
+                let result = match counters {
+                    CountersOperation::Get => context.get_traffic_counters(&network).await,
+                    CountersOperation::Reset => context.reset_traffic_counters(&network).await,
+                };
+
+                (
+                    format!("{counters:?}Counters"),
+                    result.map_err(|err| err.to_string()).and_then(|counters| {
+                        serde_json::to_string(&counters)
+                            .map(Some)
+                            .map_err(|err| format!("Could not serialize the counters: {err}"))
+                    }),
+                )
+            }
+        };
+
+        match result {
+            Ok(payload) => {
+                tracing::info!(operation, "Network operation succeeded");
+                let response = match payload {
+                    Some(payload) => format!("{OK_RESPONSE}\n{payload}\n"),
+                    None => format!("{OK_RESPONSE}\n"),
+                };
+
+                if let Err(err) = stream.write_all(response.as_bytes()).await {
                     tracing::error!(?err, "Could not write OK response to the connection");
                 }
             }
             Err(err) => {
-                tracing::warn!(?err, operation = ?request.operation, "Network operation failed");
+                tracing::warn!(err, operation, "Network operation failed");
                 if let Err(err) = stream.write_all(format!("{err}\n").as_bytes()).await {
                     tracing::error!(?err, "Could not write error response to the connection");
                 }